@@ -5,13 +5,70 @@
 use ethers_contract::Abigen;
 
 use std::env;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process;
 
-fn generate_abi(names: &[&str]) {
-    let mut abi_path =
-        PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").unwrap());
-    abi_path.push("abi");
+/// Contracts the relayer's code uses the bindings of, so they're generated
+/// whatever `UTXO_RELAYER_ABIS` says.
+const REQUIRED_ABIS: &[&str] = &["Utxo", "Dropsafe"];
 
+/// Comma separated contract names, each read from `<name>.abi`. Must include
+/// every one of `REQUIRED_ABIS`.
+const ABIS_VAR: &str = "UTXO_RELAYER_ABIS";
+
+/// Directory holding the `.abi` files, `abi/` in the crate by default.
+const ABI_DIR_VAR: &str = "UTXO_RELAYER_ABI_DIR";
+
+fn fail(message: String) -> ! {
+    eprintln!("error: {}", message);
+    process::exit(1);
+}
+
+fn abi_names() -> Vec<String> {
+    let names = match env::var(ABIS_VAR) {
+        Ok(names) => names,
+        Err(env::VarError::NotPresent) => {
+            return REQUIRED_ABIS.iter().map(|n| n.to_string()).collect();
+        }
+        Err(e) => fail(format!("{} is unusable: {}", ABIS_VAR, e)),
+    };
+
+    let names: Vec<String> = names
+        .split(',')
+        .map(str::trim)
+        .filter(|n| !n.is_empty())
+        .map(str::to_owned)
+        .collect();
+
+    if names.is_empty() {
+        fail(format!("{} doesn't name any contracts", ABIS_VAR));
+    }
+
+    for required in REQUIRED_ABIS {
+        if !names.iter().any(|n| n == required) {
+            fail(format!(
+                "{} must include `{}`, which the relayer is built against",
+                ABIS_VAR, required,
+            ));
+        }
+    }
+
+    names
+}
+
+fn abi_dir() -> PathBuf {
+    match env::var_os(ABI_DIR_VAR) {
+        Some(dir) => PathBuf::from(dir),
+        None => {
+            let mut dir =
+                PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").unwrap());
+            dir.push("abi");
+            dir
+        }
+    }
+}
+
+fn generate_abi<S: AsRef<str>>(abi_dir: &Path, names: &[S]) {
     let mut out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
     out_dir.push("abi");
 
@@ -19,13 +76,32 @@ fn generate_abi(names: &[&str]) {
         .expect("unable to create output directory");
 
     for name in names {
-        let mut abi_path = abi_path.join(name);
+        let name = name.as_ref();
+
+        let mut abi_path = abi_dir.join(name);
         abi_path.set_extension("abi");
 
+        if !abi_path.is_file() {
+            fail(format!(
+                "no abi for `{}` at {} (check {} and {})",
+                name,
+                abi_path.display(),
+                ABIS_VAR,
+                ABI_DIR_VAR,
+            ));
+        }
+
+        println!("cargo:rerun-if-changed={}", abi_path.display());
+
         let mut out_path = out_dir.join(name);
         out_path.set_extension("rs");
 
-        Abigen::new(name, abi_path.to_str().unwrap())
+        let abi_str = match abi_path.to_str() {
+            Some(s) => s,
+            None => fail(format!("{} isn't valid utf-8", abi_path.display())),
+        };
+
+        Abigen::new(name, abi_str)
             .expect("unable to load abi")
             .generate()
             .expect("unable to generate rust")
@@ -35,5 +111,8 @@ fn generate_abi(names: &[&str]) {
 }
 
 fn main() {
-    generate_abi(&["Utxo", "Dropsafe"]);
+    println!("cargo:rerun-if-env-changed={}", ABIS_VAR);
+    println!("cargo:rerun-if-env-changed={}", ABI_DIR_VAR);
+
+    generate_abi(&abi_dir(), &abi_names());
 }
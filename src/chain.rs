@@ -0,0 +1,376 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::contracts::Bundle;
+
+use ethers::types::{H256, U64};
+
+use std::collections::BTreeMap;
+
+/// A block on the canonical chain, along with the bundles mined in it.
+#[derive(Debug, Clone)]
+pub struct Seen {
+    pub hash: H256,
+    pub parent_hash: H256,
+    pub bundles: Vec<Bundle>,
+
+    /// Whether the block is deep enough that its bundles have been removed
+    /// from the pools.
+    pub confirmed: bool,
+}
+
+impl Seen {
+    pub fn new(hash: H256, parent_hash: H256) -> Self {
+        Self {
+            hash,
+            parent_hash,
+            bundles: Vec::new(),
+            confirmed: false,
+        }
+    }
+}
+
+/// Tracks the most recent blocks of the canonical chain, so that bundles mined
+/// in blocks later orphaned by a reorg can be recovered.
+#[derive(Debug)]
+pub struct Chain {
+    max_depth: u64,
+    blocks: BTreeMap<U64, Seen>,
+}
+
+impl Default for Chain {
+    fn default() -> Self {
+        Self {
+            max_depth: Self::DEFAULT_MAX_DEPTH,
+            blocks: BTreeMap::new(),
+        }
+    }
+}
+
+impl Chain {
+    pub const DEFAULT_MAX_DEPTH: u64 = 64;
+
+    /// Records `seen` as the canonical block at `number`, returning every
+    /// previously recorded block that is no longer part of the chain.
+    pub fn insert(&mut self, number: U64, seen: Seen) -> Vec<Seen> {
+        if let Some(existing) = self.blocks.get(&number) {
+            if existing.hash == seen.hash {
+                return Vec::new();
+            }
+        }
+
+        let mut orphaned = Vec::new();
+
+        // Blocks above `number` survive only if they descend from `seen`.
+        let later = self.blocks.split_off(&number);
+        let mut expected = (number, seen.hash);
+        let mut intact = true;
+
+        for (height, block) in later.into_iter() {
+            let child = height == expected.0 + U64::one()
+                && block.parent_hash == expected.1;
+
+            if intact && child {
+                expected = (height, block.hash);
+                self.blocks.insert(height, block);
+            } else {
+                intact = false;
+                orphaned.push(block);
+            }
+        }
+
+        // The block we previously recorded as the parent may have been
+        // replaced as well.
+        if number > U64::zero() {
+            let parent = number - U64::one();
+            let replaced = match self.blocks.get(&parent) {
+                Some(p) => p.hash != seen.parent_hash,
+                None => false,
+            };
+
+            if replaced {
+                orphaned.extend(self.blocks.remove(&parent));
+            }
+        }
+
+        self.blocks.insert(number, seen);
+        self.prune();
+
+        orphaned
+    }
+
+    /// The height and hash of the parent of the block recorded at `number`,
+    /// if that parent isn't recorded but older blocks are, as when a reorg
+    /// replaced it. Recording the parent in turn checks the block below it,
+    /// so walking back this way finds every block a deep reorg orphans.
+    pub fn missing_parent(&self, number: U64) -> Option<(U64, H256)> {
+        let seen = self.blocks.get(&number)?;
+
+        if number == U64::zero() {
+            return None;
+        }
+
+        let parent = number - U64::one();
+
+        if self.blocks.contains_key(&parent) {
+            return None;
+        }
+
+        self.blocks.range(..parent).next_back()?;
+        Some((parent, seen.parent_hash))
+    }
+
+    /// Attaches the bundles mined in the block `hash` at `number`. Returns
+    /// `false` if that block is no longer part of the chain.
+    pub fn record(
+        &mut self,
+        number: U64,
+        hash: H256,
+        bundles: Vec<Bundle>,
+    ) -> bool {
+        match self.blocks.get_mut(&number) {
+            Some(seen) if seen.hash == hash => {
+                seen.bundles = bundles;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Marks every block at least `confirmations` below `tip` as confirmed,
+    /// returning the bundles from those that weren't already, oldest first.
+    pub fn confirm(&mut self, tip: U64, confirmations: u64) -> Vec<Bundle> {
+        let confirmations = U64::from(confirmations);
+
+        if tip < confirmations {
+            return Vec::new();
+        }
+
+        let mut bundles = Vec::new();
+
+        for seen in self.blocks.range_mut(..=tip - confirmations) {
+            let seen = seen.1;
+
+            if !seen.confirmed {
+                seen.confirmed = true;
+                bundles.extend(seen.bundles.iter().cloned());
+            }
+        }
+
+        bundles
+    }
+
+    fn prune(&mut self) {
+        let highest = match self.blocks.keys().next_back() {
+            Some(h) => *h,
+            None => return,
+        };
+
+        if highest <= U64::from(self.max_depth) {
+            return;
+        }
+
+        let keep = self
+            .blocks
+            .split_off(&(highest - U64::from(self.max_depth)));
+        self.blocks = keep;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(x: u8) -> H256 {
+        H256([x; 32])
+    }
+
+    #[test]
+    fn insert_linear() {
+        let mut chain = Chain::default();
+
+        assert!(chain
+            .insert(1.into(), Seen::new(hash(1), hash(0)))
+            .is_empty());
+        assert!(chain
+            .insert(2.into(), Seen::new(hash(2), hash(1)))
+            .is_empty());
+        assert!(chain
+            .insert(3.into(), Seen::new(hash(3), hash(2)))
+            .is_empty());
+
+        assert_eq!(chain.blocks.len(), 3);
+    }
+
+    #[test]
+    fn insert_duplicate() {
+        let mut chain = Chain::default();
+
+        chain.insert(1.into(), Seen::new(hash(1), hash(0)));
+        assert!(chain
+            .insert(1.into(), Seen::new(hash(1), hash(0)))
+            .is_empty());
+
+        assert_eq!(chain.blocks.len(), 1);
+    }
+
+    #[test]
+    fn insert_sibling_orphans_descendants() {
+        let mut chain = Chain::default();
+
+        chain.insert(1.into(), Seen::new(hash(1), hash(0)));
+        chain.insert(2.into(), Seen::new(hash(2), hash(1)));
+        chain.insert(3.into(), Seen::new(hash(3), hash(2)));
+
+        let orphaned = chain.insert(2.into(), Seen::new(hash(12), hash(1)));
+        let hashes: Vec<_> = orphaned.iter().map(|s| s.hash).collect();
+
+        assert_eq!(hashes, vec![hash(2), hash(3)]);
+        assert_eq!(chain.blocks.len(), 2);
+        assert_eq!(chain.blocks[&U64::from(2)].hash, hash(12));
+    }
+
+    #[test]
+    fn insert_parent_mismatch() {
+        let mut chain = Chain::default();
+
+        chain.insert(1.into(), Seen::new(hash(1), hash(0)));
+        chain.insert(2.into(), Seen::new(hash(2), hash(1)));
+
+        let orphaned = chain.insert(3.into(), Seen::new(hash(13), hash(12)));
+        let hashes: Vec<_> = orphaned.iter().map(|s| s.hash).collect();
+
+        assert_eq!(hashes, vec![hash(2)]);
+        assert!(!chain.blocks.contains_key(&U64::from(2)));
+    }
+
+    #[test]
+    fn insert_parent_after_child() {
+        let mut chain = Chain::default();
+
+        chain.insert(1.into(), Seen::new(hash(1), hash(0)));
+        chain.insert(2.into(), Seen::new(hash(2), hash(1)));
+        chain.insert(3.into(), Seen::new(hash(13), hash(12)));
+
+        let orphaned = chain.insert(2.into(), Seen::new(hash(12), hash(1)));
+
+        assert!(orphaned.is_empty());
+        assert_eq!(chain.blocks[&U64::from(3)].hash, hash(13));
+    }
+
+    #[test]
+    fn insert_two_deep_reorg() {
+        let mut chain = Chain::default();
+
+        chain.insert(1.into(), Seen::new(hash(1), hash(0)));
+        chain.insert(2.into(), Seen::new(hash(2), hash(1)));
+        chain.insert(3.into(), Seen::new(hash(3), hash(2)));
+        assert_eq!(chain.missing_parent(3.into()), None);
+
+        // The new tip only says its parent isn't block 3.
+        let orphaned = chain.insert(4.into(), Seen::new(hash(14), hash(13)));
+        let hashes: Vec<_> = orphaned.iter().map(|s| s.hash).collect();
+        assert_eq!(hashes, vec![hash(3)]);
+
+        // Walking back through the new parents finds block 2 replaced too.
+        let missing = chain.missing_parent(4.into());
+        assert_eq!(missing, Some((3.into(), hash(13))));
+
+        let orphaned = chain.insert(3.into(), Seen::new(hash(13), hash(12)));
+        let hashes: Vec<_> = orphaned.iter().map(|s| s.hash).collect();
+        assert_eq!(hashes, vec![hash(2)]);
+
+        let missing = chain.missing_parent(3.into());
+        assert_eq!(missing, Some((2.into(), hash(12))));
+
+        // Block 1 is shared, which ends the walk.
+        let orphaned = chain.insert(2.into(), Seen::new(hash(12), hash(1)));
+        assert!(orphaned.is_empty());
+        assert_eq!(chain.missing_parent(2.into()), None);
+
+        let hashes: Vec<_> = chain.blocks.values().map(|s| s.hash).collect();
+        assert_eq!(hashes, vec![hash(1), hash(12), hash(13), hash(14)]);
+    }
+
+    #[test]
+    fn missing_parent_of_oldest() {
+        let mut chain = Chain::default();
+        chain.insert(5.into(), Seen::new(hash(5), hash(4)));
+
+        // Nothing older was ever recorded, so there's nothing to walk to.
+        assert_eq!(chain.missing_parent(5.into()), None);
+        assert_eq!(chain.missing_parent(6.into()), None);
+    }
+
+    #[test]
+    fn record_orphaned() {
+        let mut chain = Chain::default();
+
+        chain.insert(1.into(), Seen::new(hash(1), hash(0)));
+        chain.insert(1.into(), Seen::new(hash(11), hash(0)));
+
+        assert!(!chain.record(1.into(), hash(1), vec![Bundle::new()]));
+        assert!(chain.record(1.into(), hash(11), vec![Bundle::new()]));
+    }
+
+    #[test]
+    fn confirm_immediately() {
+        let mut chain = Chain::default();
+
+        chain.insert(1.into(), Seen::new(hash(1), hash(0)));
+        chain.record(1.into(), hash(1), vec![Bundle::new()]);
+
+        assert_eq!(chain.confirm(1.into(), 0).len(), 1);
+        assert!(chain.confirm(1.into(), 0).is_empty());
+    }
+
+    #[test]
+    fn confirm_waits_for_depth() {
+        let mut chain = Chain::default();
+
+        chain.insert(1.into(), Seen::new(hash(1), hash(0)));
+        chain.record(1.into(), hash(1), vec![Bundle::new()]);
+        assert!(chain.confirm(1.into(), 2).is_empty());
+
+        chain.insert(2.into(), Seen::new(hash(2), hash(1)));
+        assert!(chain.confirm(2.into(), 2).is_empty());
+
+        chain.insert(3.into(), Seen::new(hash(3), hash(2)));
+        assert_eq!(chain.confirm(3.into(), 2).len(), 1);
+        assert!(chain.blocks[&U64::from(1)].confirmed);
+        assert!(!chain.blocks[&U64::from(2)].confirmed);
+    }
+
+    #[test]
+    fn confirm_skips_orphaned() {
+        let mut chain = Chain::default();
+
+        chain.insert(1.into(), Seen::new(hash(1), hash(0)));
+        chain.record(1.into(), hash(1), vec![Bundle::new()]);
+
+        let orphaned = chain.insert(1.into(), Seen::new(hash(11), hash(0)));
+        assert_eq!(orphaned.len(), 1);
+        assert!(!orphaned[0].confirmed);
+
+        chain.insert(2.into(), Seen::new(hash(2), hash(11)));
+        assert!(chain.confirm(2.into(), 1).is_empty());
+    }
+
+    #[test]
+    fn prune() {
+        let mut chain = Chain::default();
+
+        for ii in 1..=(Chain::DEFAULT_MAX_DEPTH + 10) {
+            chain.insert(
+                ii.into(),
+                Seen::new(
+                    H256::from_low_u64_be(ii),
+                    H256::from_low_u64_be(ii - 1),
+                ),
+            );
+        }
+
+        assert_eq!(chain.blocks.len() as u64, Chain::DEFAULT_MAX_DEPTH + 1);
+    }
+}
@@ -2,29 +2,112 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use crate::pool::{Inputs, Transaction};
+use crate::pool::{Identified, Inputs, Transaction};
 
 use educe::Educe;
 
-use ethers::abi::Detokenize;
+use ethers::abi::{Abi, Detokenize, Function, ParamType, Token, Tokenize};
 use ethers::contract::builders::ContractCall;
 use ethers::providers::JsonRpcClient;
 use ethers::signers::Signer;
 use ethers::types::{
     Address, Signature, Transaction as EthTransaction, H256, U256,
 };
+use ethers::utils::keccak256;
 
 pub use self::dropsafe_mod::Dropsafe;
 pub use self::utxo_mod::{Utxo, UTXO_ABI};
 
-use snafu::{ResultExt, Snafu};
+use rustc_hex::ToHex;
 
-use std::cmp::Ordering;
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::HashMap;
 use std::fmt;
+use std::str::FromStr;
 
 include!(concat!(env!("OUT_DIR"), "/abi/Utxo.rs"));
 include!(concat!(env!("OUT_DIR"), "/abi/Dropsafe.rs"));
 
+/// Every version of the UTXO contract's ABI that bundles can be decoded from,
+/// newest first. To decode across an upgrade, generate the new ABI in
+/// `build.rs` and add it here. Its `transact` must still take a claim,
+/// transfers, and withdrawals, in that order.
+fn utxo_abis() -> [&'static Abi; 1] {
+    [&*UTXO_ABI]
+}
+
+/// Finds the `transact` function, from any known ABI version, with the given
+/// selector.
+fn transact_function(selector: &[u8]) -> Option<&'static Function> {
+    utxo_abis()
+        .iter()
+        .copied()
+        .filter_map(|abi| abi.functions.get("transact"))
+        .flatten()
+        .find(|f| f.short_signature() == selector)
+}
+
+/// Signature of the event the drop safe emits for each new deposit.
+pub const NEW_DEPOSIT_EVENT: &str =
+    "NewDeposit(uint256,uint256,uint256,address)";
+
+/// The type strings hashed into `TRANSFER_TYPEHASH` and `WITHDRAW_TYPEHASH` in
+/// `Utxo.sol`.
+const TRANSFER_TYPE: &str = concat!(
+    "Transfer(",
+    "uint256 input0,",
+    "uint256 input1,",
+    "address destination,",
+    "address change,",
+    "uint256 amount,",
+    "uint256 gasprice",
+    ")"
+);
+const WITHDRAW_TYPE: &str = "Withdraw(uint256 input,uint256 gasprice)";
+
+/// The type string of the domain `Utxo.sol` signs under.
+const DOMAIN_TYPE: &str = concat!(
+    "EIP712Domain(",
+    "string name,",
+    "string version,",
+    "uint256 chainId,",
+    "address verifyingContract",
+    ")"
+);
+
+/// Computes the `DOMAIN_SEPARATOR` of a UTXO contract deployed at `contract`
+/// on chain `chain_id`, without asking the contract for it.
+///
+/// The constructor reads the chain id into a `uint8`, so it's truncated the
+/// same way here.
+pub fn domain_separator(chain_id: u64, contract: Address) -> [u8; 32] {
+    let tokens = [
+        Token::FixedBytes(keccak256(DOMAIN_TYPE).to_vec()),
+        Token::FixedBytes(keccak256("utxo").to_vec()),
+        Token::FixedBytes(keccak256("1").to_vec()),
+        Token::Uint((chain_id as u8).into()),
+        Token::Address(contract),
+    ];
+
+    keccak256(ethers::abi::encode(&tokens))
+}
+
+/// Computes the EIP-712 digest the contract recovers a signer from, given the
+/// contract's `DOMAIN_SEPARATOR` and the encoded struct (including its type
+/// hash).
+fn typed_digest(domain: &[u8; 32], tokens: &[Token]) -> [u8; 32] {
+    let struct_hash = keccak256(ethers::abi::encode(tokens));
+
+    let mut packed = Vec::with_capacity(66);
+    packed.extend_from_slice(b"\x19\x01");
+    packed.extend_from_slice(domain);
+    packed.extend_from_slice(&struct_hash);
+
+    keccak256(packed)
+}
+
 pub type OutputTuple = (Address, U256);
 pub type WithdrawalTuple = (U256, U256, u8, [u8; 32], [u8; 32]);
 pub type ClaimTuple = (U256, U256, Vec<U256>, u8, [u8; 32], [u8; 32]);
@@ -85,6 +168,10 @@ impl fmt::Display for Withdrawal {
 }
 
 impl Transaction for Withdrawal {
+    type Kind = ();
+
+    fn kind(&self) {}
+
     fn gas_price(&self) -> &U256 {
         &self.gasprice
     }
@@ -92,6 +179,17 @@ impl Transaction for Withdrawal {
     fn inputs(&self) -> Inputs {
         Inputs::One(&self.input)
     }
+
+    fn signing_hash(&self, domain: &[u8; 32]) -> [u8; 32] {
+        typed_digest(
+            domain,
+            &[
+                Token::FixedBytes(keccak256(WITHDRAW_TYPE).to_vec()),
+                Token::Uint(self.input),
+                Token::Uint(self.gasprice),
+            ],
+        )
+    }
 }
 
 impl From<WithdrawalTuple> for Withdrawal {
@@ -145,6 +243,47 @@ impl Deposit {
         let gas = Self::GAS_CONSTANT + (Self::GAS_VARIABLE * count);
         gas * gasprice
     }
+
+    /// Fee for adding one more deposit to a claim at `gasprice`.
+    pub fn marginal_fee(gasprice: &U256) -> U256 {
+        Self::fees(1, gasprice) - Self::fees(0, gasprice)
+    }
+
+    /// Decodes the data of a `NewDeposit` event, returning the id of the
+    /// deposit along with the deposit itself.
+    pub fn decode_event(data: &[u8]) -> Result<(U256, Self), DecodeError> {
+        let kinds = [
+            ParamType::Uint(256),
+            ParamType::Uint(256),
+            ParamType::Uint(256),
+            ParamType::Address,
+        ];
+
+        let tokens = ethers::abi::decode(&kinds, data).context(Abi)?;
+
+        let fields = match tokens.as_slice() {
+            [id, amount, bounty, owner] => (id, amount, bounty, owner),
+            _ => return Err(DecodeError::Tokens),
+        };
+
+        match fields {
+            (
+                Token::Uint(id),
+                Token::Uint(amount),
+                Token::Uint(bounty),
+                Token::Address(owner),
+            ) => {
+                let deposit = Self {
+                    amount: *amount,
+                    bounty: *bounty,
+                    owner: *owner,
+                };
+
+                Ok((*id, deposit))
+            }
+            _ => Err(DecodeError::Tokens),
+        }
+    }
 }
 
 impl PartialOrd for Deposit {
@@ -172,6 +311,34 @@ pub struct Claim {
     pub signature: Signature,
 }
 
+impl Claim {
+    /// The output the claim's fees are paid from and its change goes back to,
+    /// or `None` for a bundle claiming no deposits, which has no claim.
+    pub fn sponsor(&self) -> Option<U256> {
+        if self.deposits.is_empty() {
+            None
+        } else {
+            Some(self.input)
+        }
+    }
+}
+
+impl fmt::Display for Claim {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "i={} gas={} deposits=[", self.input, self.gasprice)?;
+
+        for (index, id) in self.deposits.iter().enumerate() {
+            if index > 0 {
+                write!(f, ", ")?;
+            }
+
+            write!(f, "{}", id)?;
+        }
+
+        write!(f, "]")
+    }
+}
+
 impl From<ClaimTuple> for Claim {
     fn from(c: ClaimTuple) -> Self {
         Self {
@@ -211,6 +378,9 @@ pub struct Transfer {
     pub change: Address,
 
     pub amount: U256,
+
+    /// The contract has no separate relayer fee, so what the sender pays per
+    /// unit of gas is all a transfer is worth to us.
     pub gasprice: U256,
 
     #[educe(PartialEq(ignore), Hash(ignore))]
@@ -238,6 +408,10 @@ impl fmt::Display for Transfer {
 }
 
 impl Transaction for Transfer {
+    type Kind = ();
+
+    fn kind(&self) {}
+
     fn gas_price(&self) -> &U256 {
         &self.gasprice
     }
@@ -250,6 +424,21 @@ impl Transaction for Transfer {
             (true, true) => Inputs::None,
         }
     }
+
+    fn signing_hash(&self, domain: &[u8; 32]) -> [u8; 32] {
+        typed_digest(
+            domain,
+            &[
+                Token::FixedBytes(keccak256(TRANSFER_TYPE).to_vec()),
+                Token::Uint(self.input0),
+                Token::Uint(self.input1),
+                Token::Address(self.destination),
+                Token::Address(self.change),
+                Token::Uint(self.amount),
+                Token::Uint(self.gasprice),
+            ],
+        )
+    }
 }
 
 impl From<TransferTuple> for Transfer {
@@ -288,7 +477,28 @@ impl From<Transfer> for TransferTuple {
 
 #[derive(Debug, Snafu)]
 pub enum DecodeError {
-    Abi { source: ethers::abi::Error },
+    Abi {
+        source: ethers::abi::Error,
+    },
+    Tokens,
+
+    #[snafu(display("calldata too short for a function selector"))]
+    Truncated,
+
+    #[snafu(display(
+        "no known `transact` has the selector 0x{}",
+        selector.to_hex::<String>()
+    ))]
+    UnknownSelector {
+        selector: [u8; 4],
+    },
+}
+
+/// A bundle that only partially decoded, with the error that stopped it.
+#[derive(Debug)]
+pub struct PartialDecode {
+    pub bundle: Bundle,
+    pub error: DecodeError,
 }
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
@@ -297,11 +507,27 @@ pub enum Txn<T = Transfer, W = Withdrawal> {
     Withdrawal(W),
 }
 
+/// Which variant of `Txn` a transaction is, for `Pool::iter_kind`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
+pub enum TxnKind {
+    Transfer,
+    Withdrawal,
+}
+
 impl<T, W> Transaction for Txn<T, W>
 where
     T: Transaction,
     W: Transaction,
 {
+    type Kind = TxnKind;
+
+    fn kind(&self) -> TxnKind {
+        match self {
+            Txn::Transfer(_) => TxnKind::Transfer,
+            Txn::Withdrawal(_) => TxnKind::Withdrawal,
+        }
+    }
+
     fn inputs(&self) -> Inputs {
         match self {
             Txn::Transfer(t) => t.inputs(),
@@ -315,6 +541,26 @@ where
             Txn::Withdrawal(w) => w.gas_price(),
         }
     }
+
+    fn signing_hash(&self, domain: &[u8; 32]) -> [u8; 32] {
+        match self {
+            Txn::Transfer(t) => t.signing_hash(domain),
+            Txn::Withdrawal(w) => w.signing_hash(domain),
+        }
+    }
+}
+
+impl<T, W> fmt::Display for Txn<T, W>
+where
+    T: fmt::Display,
+    W: fmt::Display,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Txn::Transfer(t) => write!(f, "xfr {}", t),
+            Txn::Withdrawal(w) => write!(f, "wdr {}", w),
+        }
+    }
 }
 
 pub type TxnRef<'a> = Txn<&'a Transfer, &'a Withdrawal>;
@@ -352,6 +598,183 @@ impl From<Transfer> for Txn<Transfer, Withdrawal> {
     }
 }
 
+/// Anything a bundle consumes: a claimed deposit, a transfer, or a withdrawal.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Entry<'a> {
+    Deposit(&'a U256),
+    Transfer(&'a Transfer),
+    Withdrawal(&'a Withdrawal),
+}
+
+/// Slots kept free for each kind of transaction, so a bundle keeps a mix even
+/// when one kind pays more.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Reservations {
+    pub transfers: usize,
+    pub withdrawals: usize,
+}
+
+impl Reservations {
+    /// Whether `txn` can go into `bundle` without taking a slot reserved for
+    /// the other kind of transaction.
+    pub fn admits(&self, bundle: &Bundle, txn: &Txn) -> bool {
+        let (slots, reserved) = match txn {
+            Txn::Transfer(_) => (
+                Bundle::SLOTS_PER_TRANSFER,
+                self.withdrawals.saturating_sub(bundle.withdrawals.len())
+                    * Bundle::SLOTS_PER_WITHDRAWAL,
+            ),
+            Txn::Withdrawal(_) => (
+                Bundle::SLOTS_PER_WITHDRAWAL,
+                self.transfers.saturating_sub(bundle.transfers.len())
+                    * Bundle::SLOTS_PER_TRANSFER,
+            ),
+        };
+
+        bundle.free_slots() >= slots + reserved
+    }
+}
+
+/// Most of each kind of transaction one bundle may hold, whatever slots are
+/// free, from `--max-bundle-transfers` and `--max-bundle-withdrawals`.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Caps {
+    pub transfers: Option<usize>,
+    pub withdrawals: Option<usize>,
+}
+
+impl Caps {
+    /// Whether `bundle` has room under the cap for another `txn`.
+    pub fn admits(&self, bundle: &Bundle, txn: &Txn) -> bool {
+        let (cap, count) = match txn {
+            Txn::Transfer(_) => (self.transfers, bundle.transfers.len()),
+            Txn::Withdrawal(_) => (self.withdrawals, bundle.withdrawals.len()),
+        };
+
+        cap.map_or(true, |c| count < c)
+    }
+}
+
+/// Which kind of transaction wins when a transfer and a withdrawal pay the
+/// same gas price, as given to `--prefer`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Preference {
+    Transfers,
+    Withdrawals,
+}
+
+impl Preference {
+    /// Ranks `txn` for sorting, lowest (preferred) first.
+    pub fn rank<T, W>(self, txn: &Txn<T, W>) -> u8 {
+        match (self, txn) {
+            (Preference::Transfers, Txn::Transfer(_)) => 0,
+            (Preference::Withdrawals, Txn::Withdrawal(_)) => 0,
+            _ => 1,
+        }
+    }
+}
+
+impl FromStr for Preference {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "transfers" => Ok(Preference::Transfers),
+            "withdrawals" => Ok(Preference::Withdrawals),
+            _ => Err(format!(
+                "expected `transfers` or `withdrawals`, not `{}`",
+                s
+            )),
+        }
+    }
+}
+
+/// How much to favour each owner's deposits over others paying the same
+/// bounty, from `--owner-weights`. Only breaks ties, so never changes how many
+/// deposits a claim holds or what it earns. Unlisted owners weigh zero.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Weights {
+    by_owner: HashMap<Address, u64>,
+}
+
+impl Weights {
+    pub fn weight(&self, owner: &Address) -> u64 {
+        self.by_owner.get(owner).copied().unwrap_or(0)
+    }
+
+    /// Takes the ids of the first `count` of `deposits`, which must be in
+    /// descending bounty order (ex. from `DepositPool::iter_by_profit`), but
+    /// among those with the same bounty as the last one taken, heavier owners
+    /// go first. Without weights, that's just the first `count`.
+    pub fn pick<'a, I>(&self, deposits: I, count: usize) -> Vec<U256>
+    where
+        I: IntoIterator<Item = &'a Identified>,
+    {
+        let mut deposits = deposits.into_iter().peekable();
+        let mut picked: Vec<&Identified> =
+            deposits.by_ref().take(count).collect();
+
+        if !self.by_owner.is_empty() {
+            // Deposits tied with the cheapest one picked could take its place.
+            if let Some(cutoff) = picked.last().map(|d| d.bounty) {
+                while let Some(d) = deposits.peek() {
+                    if d.bounty != cutoff {
+                        break;
+                    }
+
+                    picked.extend(deposits.next());
+                }
+            }
+
+            // Stable, so equal weights keep the pool's order.
+            picked.sort_by_key(|d| {
+                (Reverse(d.bounty), Reverse(self.weight(&d.owner)))
+            });
+            picked.truncate(count);
+        }
+
+        picked.into_iter().map(|d| *d.id()).collect()
+    }
+}
+
+/// Parses one `owner weight` pair per line, separated by whitespace or a
+/// comma. Blank lines and lines starting with `#` are skipped.
+impl FromStr for Weights {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut by_owner = HashMap::new();
+
+        for (index, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields: Vec<_> = line
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|f| !f.is_empty())
+                .collect();
+
+            let invalid =
+                || format!("line {}: expected `owner weight`", index + 1);
+
+            let (owner, weight) = match fields.as_slice() {
+                [owner, weight] => (*owner, *weight),
+                _ => return Err(invalid()),
+            };
+
+            let owner = owner.strip_prefix("0x").unwrap_or(owner);
+            let owner = Address::from_str(owner).map_err(|_| invalid())?;
+            let weight = weight.parse().map_err(|_| invalid())?;
+
+            by_owner.insert(owner, weight);
+        }
+
+        Ok(Self { by_owner })
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Bundle {
     pub claim: Claim,
@@ -366,9 +789,13 @@ impl Default for Bundle {
 }
 
 impl Bundle {
+    // The same as `MAX_SLOTS`, `SLOTS_DEPOSIT`, `SLOTS_TRANSFER`, and
+    // `SLOTS_WITHDRAWAL` in `Utxo.sol`, which refuses fuller bundles.
     pub const MAX_SLOTS: usize = 10;
-    pub const SLOTS_PER_CLAIM: usize = 1;
-    pub const SLOTS_PER_TRANSFER: usize = 1;
+
+    /// Slots each deposit in the claim fills.
+    pub const SLOTS_PER_CLAIM: usize = 3;
+    pub const SLOTS_PER_TRANSFER: usize = 2;
     pub const SLOTS_PER_WITHDRAWAL: usize = 1;
 
     pub fn new() -> Self {
@@ -388,6 +815,29 @@ impl Bundle {
         }
     }
 
+    /// Builds a bundle from a claim and a set of transactions, filing each
+    /// transaction under `transfers` or `withdrawals`.
+    ///
+    /// The claim's deposits take their slots first. Transactions that don't
+    /// fit in the remaining slots are returned in their original order.
+    pub fn from_transactions<I>(claim: Claim, txns: I) -> (Self, Vec<Txn>)
+    where
+        I: IntoIterator<Item = Txn>,
+    {
+        let mut bundle = Self {
+            claim,
+            transfers: vec![],
+            withdrawals: vec![],
+        };
+
+        let overflow = txns
+            .into_iter()
+            .filter_map(|txn| bundle.insert(txn))
+            .collect();
+
+        (bundle, overflow)
+    }
+
     pub fn transactions(&self) -> impl Iterator<Item = TxnRef> {
         self.transfers
             .iter()
@@ -395,6 +845,28 @@ impl Bundle {
             .chain(self.withdrawals.iter().map(Txn::from))
     }
 
+    /// The sum of the amounts of every transfer in the bundle. Saturates
+    /// instead of overflowing, since amounts aren't checked before mining.
+    pub fn total_transfer_amount(&self) -> U256 {
+        self.transfers
+            .iter()
+            .fold(U256::zero(), |sum, t| sum.saturating_add(t.amount))
+    }
+
+    /// Iterates over the ids of the deposits claimed by this bundle.
+    pub fn deposits(&self) -> impl Iterator<Item = &U256> {
+        self.claim.deposits.iter()
+    }
+
+    /// Iterates over everything this bundle consumes, starting with the
+    /// claimed deposits, then the transfers, and finally the withdrawals.
+    pub fn entries(&self) -> impl Iterator<Item = Entry> {
+        self.deposits()
+            .map(Entry::Deposit)
+            .chain(self.transfers.iter().map(Entry::Transfer))
+            .chain(self.withdrawals.iter().map(Entry::Withdrawal))
+    }
+
     pub fn insert(&mut self, txn: Txn) -> Option<Txn> {
         match txn {
             Txn::Withdrawal(w) => self.insert_withdrawal(w).map(Txn::from),
@@ -429,6 +901,14 @@ impl Bundle {
         }
     }
 
+    /// Slots `txn` fills once inserted.
+    pub fn slots_of(txn: &Txn) -> usize {
+        match txn {
+            Txn::Transfer(_) => Self::SLOTS_PER_TRANSFER,
+            Txn::Withdrawal(_) => Self::SLOTS_PER_WITHDRAWAL,
+        }
+    }
+
     pub fn full_slots(&self) -> usize {
         (self.claim.deposits.len() * Self::SLOTS_PER_CLAIM)
             + (self.transfers.len() * Self::SLOTS_PER_TRANSFER)
@@ -452,18 +932,82 @@ impl Bundle {
             .min()
     }
 
+    /// Estimates the gas price the contract will pay for this bundle, given
+    /// the current fee base.
+    ///
+    /// Follows `compute_gasprice` and `transact` in `Utxo.sol`: the lowest gas
+    /// price of the transfers and withdrawals, plus a share of how far it is
+    /// above the base for each full slot, rounded toward zero, and then no
+    /// more than the claim's gas price.
     pub fn estimate_price(&self, base: U256) -> U256 {
-        let min_gp = match self.minimum_gas_price() {
-            Some(m) if m > base => m,
-            Some(m) => return m,
-            None => return U256::zero(),
+        let txn_min = self
+            .transfers
+            .iter()
+            .map(|t| t.gasprice)
+            .chain(self.withdrawals.iter().map(|w| w.gasprice))
+            .min();
+
+        let claim = if self.claim.deposits.is_empty() {
+            None
+        } else {
+            Some(self.claim.gasprice)
+        };
+
+        Self::price_of(txn_min, claim, self.full_slots(), base)
+    }
+
+    /// `estimate_price` for a bundle that fills `full_slots`, whose lowest
+    /// transfer or withdrawal gas price is `txn_min`, and whose claim, if it
+    /// has any deposits, pays `claim`, without needing the bundle itself.
+    pub fn price_of(
+        txn_min: Option<U256>,
+        claim: Option<U256>,
+        full_slots: usize,
+        base: U256,
+    ) -> U256 {
+        let min_gp = match (txn_min, claim) {
+            (Some(m), _) => m,
+
+            // The contract starts from the largest gas price, even when there
+            // are no transfers or withdrawals to lower it.
+            (None, Some(_)) => U256::max_value(),
+
+            // An empty bundle pays nothing.
+            (None, None) => return U256::zero(),
+        };
+
+        let price = if min_gp <= base {
+            min_gp
+        } else {
+            // Solidity 0.6 doesn't check for overflow, so wrap the same way.
+            let (bonus, _) = (min_gp - base).overflowing_mul(full_slots.into());
+            let (price, _) = base.overflowing_add(bonus / Self::MAX_SLOTS);
+            price
         };
 
-        let full_slots = self.full_slots();
-        let mut bribe = (min_gp - base) * full_slots; // TODO: Overflows?
-        bribe /= Self::MAX_SLOTS;
+        match claim {
+            Some(c) => price.min(c),
+            None => price,
+        }
+    }
 
-        base + bribe
+    /// The bribe this bundle pays: its estimated price above `base`, times the
+    /// slots it fills.
+    pub fn bribe(&self, base: U256) -> U256 {
+        let above = self.estimate_price(base).saturating_sub(base);
+        above.saturating_mul(self.full_slots().into())
+    }
+
+    /// Returns how far the estimated price of this bundle falls below `base`,
+    /// or `None` if it pays at least `base`.
+    pub fn shortfall(&self, base: U256) -> Option<U256> {
+        let estimate = self.estimate_price(base);
+
+        if estimate < base {
+            Some(base - estimate)
+        } else {
+            None
+        }
     }
 
     pub fn decode(transaction: &EthTransaction) -> Result<Self, DecodeError> {
@@ -471,38 +1015,107 @@ impl Bundle {
     }
 
     pub fn decode_slice(input: &[u8]) -> Result<Self, DecodeError> {
-        // TODO: Check solidity function hash (ie. input[..4])
-        let transact_abi = &UTXO_ABI.functions["transact"][0];
-        let mut decoded = transact_abi
-            .decode_input(&input[4..])
-            .context(Abi)?
-            .into_iter();
-
-        let claim_tokens = vec![decoded.next().unwrap()];
-        let claim_tuple = ClaimTuple::from_tokens(claim_tokens).unwrap();
-        let claim = Claim::from(claim_tuple);
-
-        let transfers_tokens = vec![decoded.next().unwrap()];
-        let transfers: Vec<_> =
-            Vec::<TransferTuple>::from_tokens(transfers_tokens)
-                .unwrap()
+        Self::decode_partial(input).map_err(|p| p.error)
+    }
+
+    /// Finds a call to `transact` in `input`: either `input` itself, or one
+    /// passed as a `bytes` argument (ex. to a router or proxy.)
+    pub fn find_transact(input: &[u8]) -> Option<&[u8]> {
+        // A `bytes` argument's contents start after the outer selector, at
+        // least one head word, and a length word, and are 32 byte aligned.
+        std::iter::once(0)
+            .chain((68..input.len()).step_by(32))
+            .map(|start| &input[start..])
+            .filter(|call| call.len() >= 4)
+            .find(|call| transact_function(&call[..4]).is_some())
+    }
+
+    /// Like `decode_slice`, but when decoding fails, also returns whatever
+    /// decoded before the failure.
+    ///
+    /// Recovery works a whole parameter at a time, in the order claim,
+    /// transfers, withdrawals. For example, malformed withdrawals still leave
+    /// the claim and transfers, but one bad transfer loses all of them.
+    pub fn decode_partial(input: &[u8]) -> Result<Self, PartialDecode> {
+        let mut bundle = Self::new();
+
+        match bundle.decode_into(input) {
+            Ok(()) => Ok(bundle),
+            Err(error) => Err(PartialDecode { bundle, error }),
+        }
+    }
+
+    fn decode_into(&mut self, input: &[u8]) -> Result<(), DecodeError> {
+        ensure!(input.len() >= 4, Truncated);
+
+        let (selector, data) = input.split_at(4);
+        let function =
+            transact_function(selector).context(UnknownSelector {
+                selector: [selector[0], selector[1], selector[2], selector[3]],
+            })?;
+
+        let kinds: Vec<ParamType> =
+            function.inputs.iter().map(|p| p.kind.clone()).collect();
+
+        match ethers::abi::decode(&kinds, data) {
+            Ok(tokens) => self.fill(tokens),
+            Err(source) => {
+                // Find the longest run of leading parameters that decodes.
+                let prefix = (1..kinds.len())
+                    .rev()
+                    .find_map(|n| ethers::abi::decode(&kinds[..n], data).ok())
+                    .unwrap_or_default();
+
+                self.fill(prefix)?;
+                Err(DecodeError::Abi { source })
+            }
+        }
+    }
+
+    /// Sets the claim, transfers, and withdrawals from the decoded parameters
+    /// of `transact`, stopping early if `tokens` runs out.
+    fn fill(&mut self, tokens: Vec<Token>) -> Result<(), DecodeError> {
+        let mut tokens = tokens.into_iter().map(|t| vec![t]);
+
+        if let Some(claim) = tokens.next() {
+            self.claim = ClaimTuple::from_tokens(claim)
+                .map_err(|_| DecodeError::Tokens)?
+                .into();
+        }
+
+        if let Some(transfers) = tokens.next() {
+            self.transfers = Vec::<TransferTuple>::from_tokens(transfers)
+                .map_err(|_| DecodeError::Tokens)?
                 .into_iter()
                 .map(Transfer::from)
                 .collect();
+        }
 
-        let withdrawals_tokens = vec![decoded.next().unwrap()];
-        let withdrawals: Vec<_> =
-            Vec::<WithdrawalTuple>::from_tokens(withdrawals_tokens)
-                .unwrap()
+        if let Some(withdrawals) = tokens.next() {
+            self.withdrawals = Vec::<WithdrawalTuple>::from_tokens(withdrawals)
+                .map_err(|_| DecodeError::Tokens)?
                 .into_iter()
                 .map(Withdrawal::from)
                 .collect();
+        }
 
-        Ok(Self {
-            claim,
-            transfers,
-            withdrawals,
-        })
+        Ok(())
+    }
+
+    /// Encodes the bundle as calldata for `transact`, the inverse of
+    /// `decode_slice`.
+    pub fn encode_calldata(self) -> Vec<u8> {
+        let claim: ClaimTuple = self.claim.into();
+        let transfers: Vec<TransferTuple> =
+            self.transfers.into_iter().map(Into::into).collect();
+        let withdrawals: Vec<WithdrawalTuple> =
+            self.withdrawals.into_iter().map(Into::into).collect();
+
+        let tokens = (claim, transfers, withdrawals).into_tokens();
+
+        UTXO_ABI.functions["transact"][0]
+            .encode_input(&tokens)
+            .expect("bundle tokens should match transact")
     }
 
     pub fn encode<P, S>(self, utxo: &Utxo<P, S>) -> ContractCall<P, S, ()>
@@ -522,6 +1135,13 @@ impl Bundle {
 mod tests {
     use ethers::types::H256;
 
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use crate::pool::{Insertion, Pool};
+
+    use rustc_hex::FromHex;
+
     use super::*;
 
     fn sig() -> Signature {
@@ -532,7 +1152,384 @@ mod tests {
         }
     }
 
-    // TODO: Add tests with base < min_gas_price
+    fn transfer(gasprice: u64, input: u64) -> Transfer {
+        Transfer {
+            gasprice: gasprice.into(),
+            input0: input.into(),
+            input1: (input + 1).into(),
+            signature: sig(),
+            amount: U256::zero(),
+            change: Address::zero(),
+            destination: Address::zero(),
+        }
+    }
+
+    #[test]
+    fn txn_pool_iter_kind() {
+        let mut pool: Pool<Txn> = Pool::default();
+
+        let wdw = Withdrawal {
+            gasprice: 20.into(),
+            input: 9.into(),
+            signature: sig(),
+        };
+
+        pool.insert(transfer(10, 1));
+        pool.insert(wdw.clone());
+        pool.insert(transfer(30, 3));
+
+        let transfers: Vec<_> = pool
+            .iter_kind(TxnKind::Transfer)
+            .map(|t| *t.gas_price())
+            .collect();
+        assert_eq!(transfers, vec![30.into(), 10.into()]);
+
+        let withdrawals: Vec<_> =
+            pool.iter_kind(TxnKind::Withdrawal).cloned().collect();
+        assert_eq!(withdrawals, vec![Txn::Withdrawal(wdw)]);
+    }
+
+    #[test]
+    fn txn_pool_withdrawal_replaces_transfer() {
+        let mut pool: Pool<Txn> = Pool::default();
+
+        let xfr = transfer(10, 1);
+        let wdw = Withdrawal {
+            gasprice: 20.into(),
+            input: xfr.input0,
+            signature: sig(),
+        };
+
+        assert_eq!(pool.insert(xfr.clone()), Insertion::Added { replaced: 0 });
+        assert_eq!(pool.insert(wdw.clone()), Insertion::Added { replaced: 1 });
+
+        // Both of the transfer's inputs are freed, not just the shared one.
+        let pooled: Vec<_> = pool.iter().cloned().collect();
+        assert_eq!(pooled, vec![Txn::Withdrawal(wdw)]);
+    }
+
+    #[test]
+    fn txn_pool_transfer_replaces_withdrawal() {
+        let mut pool: Pool<Txn> = Pool::default();
+
+        let xfr = transfer(20, 1);
+        let wdw = Withdrawal {
+            gasprice: 10.into(),
+            input: xfr.input0,
+            signature: sig(),
+        };
+
+        assert_eq!(pool.insert(wdw.clone()), Insertion::Added { replaced: 0 });
+        assert_eq!(pool.insert(xfr.clone()), Insertion::Added { replaced: 1 });
+
+        let pooled: Vec<_> = pool.iter().cloned().collect();
+        assert_eq!(pooled, vec![Txn::Transfer(xfr)]);
+    }
+
+    #[test]
+    fn txn_pool_cheaper_withdrawal_outbid_by_transfer() {
+        let mut pool: Pool<Txn> = Pool::default();
+
+        let xfr = transfer(20, 1);
+        let wdw = Withdrawal {
+            gasprice: 10.into(),
+            input: xfr.input1,
+            signature: sig(),
+        };
+
+        assert_eq!(pool.insert(xfr.clone()), Insertion::Added { replaced: 0 });
+        assert_eq!(
+            pool.insert(wdw.clone()),
+            Insertion::Outbid {
+                input: xfr.input1,
+                gasprice: 20.into(),
+            }
+        );
+
+        let pooled: Vec<_> = pool.iter().cloned().collect();
+        assert_eq!(pooled, vec![Txn::Transfer(xfr)]);
+    }
+
+    #[test]
+    fn caps_admit() {
+        let mut bundle = Bundle::new();
+        bundle.insert_transfer(transfer(10, 1));
+
+        let wdw = Txn::Withdrawal(Withdrawal {
+            gasprice: 10.into(),
+            input: 7.into(),
+            signature: sig(),
+        });
+        let xfr = Txn::Transfer(transfer(10, 3));
+
+        assert!(Caps::default().admits(&bundle, &xfr));
+
+        let caps = Caps {
+            transfers: Some(1),
+            withdrawals: Some(0),
+        };
+        assert!(!caps.admits(&bundle, &xfr));
+        assert!(!caps.admits(&bundle, &wdw));
+
+        let caps = Caps {
+            transfers: Some(2),
+            withdrawals: None,
+        };
+        assert!(caps.admits(&bundle, &xfr));
+        assert!(caps.admits(&bundle, &wdw));
+    }
+
+    #[test]
+    fn weights_from_str() {
+        let text = concat!(
+            "# trusted\n",
+            "\n",
+            "0x0101010101010101010101010101010101010101 5\n",
+            "0202020202020202020202020202020202020202,2\n",
+        );
+        let weights: Weights = text.parse().unwrap();
+
+        assert_eq!(weights.weight(&Address::repeat_byte(1)), 5);
+        assert_eq!(weights.weight(&Address::repeat_byte(2)), 2);
+        assert_eq!(weights.weight(&Address::repeat_byte(3)), 0);
+
+        assert!("0x01 5".parse::<Weights>().is_err());
+        assert!("0x0101010101010101010101010101010101010101"
+            .parse::<Weights>()
+            .is_err());
+    }
+
+    fn weighted(id: u64, bounty: u64, owner: u8) -> Identified {
+        Identified::new(
+            Deposit {
+                amount: 10.into(),
+                bounty: bounty.into(),
+                owner: Address::repeat_byte(owner),
+            },
+            id.into(),
+        )
+    }
+
+    #[test]
+    fn weights_pick_breaks_ties() {
+        let deposits = vec![
+            weighted(1, 30, 1),
+            weighted(2, 20, 1),
+            weighted(3, 20, 2),
+            weighted(4, 20, 3),
+            weighted(5, 10, 3),
+        ];
+
+        let ids = |picked: Vec<U256>| -> Vec<u64> {
+            picked.into_iter().map(|id| id.as_u64()).collect()
+        };
+
+        let none = Weights::default();
+        assert_eq!(ids(none.pick(&deposits, 2)), vec![1, 2]);
+
+        let weights = Weights {
+            by_owner: vec![
+                (Address::repeat_byte(3), 2),
+                (Address::repeat_byte(2), 1),
+            ]
+            .into_iter()
+            .collect(),
+        };
+
+        // Owner 3 wins the tie at 20, but never beats a bigger bounty.
+        assert_eq!(ids(weights.pick(&deposits, 2)), vec![1, 4]);
+        assert_eq!(ids(weights.pick(&deposits, 3)), vec![1, 4, 3]);
+        assert_eq!(ids(weights.pick(&deposits, 4)), vec![1, 4, 3, 2]);
+        assert_eq!(ids(weights.pick(&deposits, 5)), vec![1, 4, 3, 2, 5]);
+        assert_eq!(ids(weights.pick(&deposits, 0)), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn total_transfer_amount() {
+        let mut bundle = Bundle::new();
+        assert_eq!(bundle.total_transfer_amount(), U256::zero());
+
+        bundle.transfers.push(Transfer {
+            amount: 5.into(),
+            ..transfer(1, 1)
+        });
+        bundle.transfers.push(Transfer {
+            amount: 7.into(),
+            ..transfer(1, 3)
+        });
+
+        assert_eq!(bundle.total_transfer_amount(), 12.into());
+
+        bundle.transfers.push(Transfer {
+            amount: U256::max_value(),
+            ..transfer(1, 5)
+        });
+
+        assert_eq!(bundle.total_transfer_amount(), U256::max_value());
+    }
+
+    #[test]
+    fn bundle_from_transactions_sorts() {
+        let claim = Claim {
+            deposits: vec![U256::one()],
+            ..Bundle::new().claim
+        };
+
+        let withdrawal = Withdrawal {
+            gasprice: 113.into(),
+            input: 7.into(),
+            signature: sig(),
+        };
+
+        let txns = vec![
+            Txn::from(transfer(117, 0)),
+            Txn::from(withdrawal),
+            Txn::from(transfer(101, 2)),
+        ];
+
+        let (bundle, overflow) = Bundle::from_transactions(claim, txns);
+
+        assert!(overflow.is_empty());
+        assert_eq!(bundle.full_slots(), 8);
+        assert_eq!(bundle.claim.deposits, vec![U256::one()]);
+        assert_eq!(bundle.transfers.len(), 2);
+        assert_eq!(bundle.transfers[0].gasprice, 117.into());
+        assert_eq!(bundle.transfers[1].gasprice, 101.into());
+        assert_eq!(bundle.withdrawals.len(), 1);
+        assert_eq!(bundle.withdrawals[0].input, 7.into());
+    }
+
+    #[test]
+    fn bundle_from_transactions_overflow() {
+        let claim = Claim {
+            deposits: vec![U256::one(), 2.into()],
+            ..Bundle::new().claim
+        };
+
+        let txns = (0..Bundle::MAX_SLOTS as u64)
+            .map(|ii| Txn::from(transfer(100 + ii, ii * 2)));
+
+        let (bundle, overflow) = Bundle::from_transactions(claim, txns);
+
+        // Two deposits fill six slots, leaving four for two transfers.
+        assert_eq!(bundle.free_slots(), 0);
+        assert_eq!(bundle.transfers.len(), 2);
+
+        let gasprices: Vec<_> =
+            overflow.iter().map(|txn| *txn.gas_price()).collect();
+        let expected: Vec<_> = (102..110).map(U256::from).collect();
+        assert_eq!(gasprices, expected);
+    }
+
+    #[test]
+    fn bundle_estimate_price_truncates_remainder() {
+        let mut bundle = Bundle::new();
+        for ii in 0..3 {
+            bundle.insert_transfer(transfer(117, ii * 2));
+        }
+
+        // (117 - 100) * 6 / 10 = 10.2, which truncates to 10.
+        assert_eq!(U256::from(110), bundle.estimate_price(100.into()));
+    }
+
+    #[test]
+    fn bundle_estimate_price_nearly_full_truncates() {
+        let mut bundle = Bundle::new();
+        for ii in 0..4 {
+            bundle.insert_transfer(transfer(101, ii * 2));
+        }
+        bundle.insert_withdrawal(Withdrawal {
+            gasprice: 101.into(),
+            input: 100.into(),
+            signature: sig(),
+        });
+
+        // (101 - 100) * 9 / 10 = 0.9, which truncates to 0.
+        assert_eq!(U256::from(100), bundle.estimate_price(100.into()));
+    }
+
+    #[test]
+    fn bundle_bribe() {
+        let mut bundle = Bundle::new();
+        for ii in 0..4 {
+            bundle.insert_transfer(transfer(120, ii * 2));
+        }
+
+        // (120 - 100) * 8 / 10 = 16 above the base, for each of 8 slots.
+        assert_eq!(bundle.bribe(100.into()), 128.into());
+
+        // Paying no more than the base is no bribe at all.
+        assert_eq!(bundle.bribe(120.into()), U256::zero());
+        assert_eq!(bundle.bribe(200.into()), U256::zero());
+    }
+
+    #[test]
+    fn bundle_shortfall() {
+        let mut bundle = Bundle::new();
+        bundle.insert_transfer(transfer(90, 0));
+
+        assert_eq!(bundle.shortfall(100.into()), Some(10.into()));
+        assert_eq!(bundle.shortfall(90.into()), None);
+        assert_eq!(bundle.shortfall(50.into()), None);
+    }
+
+    #[test]
+    fn bundle_estimate_price_full_exact() {
+        let mut bundle = Bundle::new();
+        for ii in 0..5 {
+            bundle.insert_transfer(transfer(101, ii * 2));
+        }
+
+        assert_eq!(bundle.free_slots(), 0);
+        assert_eq!(U256::from(101), bundle.estimate_price(100.into()));
+    }
+
+    #[test]
+    fn bundle_estimate_price_weighs_slots() {
+        let mut bundle = Bundle::new();
+        bundle.insert_transfer(transfer(150, 0));
+        bundle.insert_withdrawal(Withdrawal {
+            gasprice: 130.into(),
+            input: 7.into(),
+            signature: sig(),
+        });
+
+        // A transfer fills 2 slots and a withdrawal 1, so
+        // 100 + (130 - 100) * 3 / 10.
+        assert_eq!(U256::from(109), bundle.estimate_price(100.into()));
+
+        // With a deposit filling 3 more, 100 + (130 - 100) * 6 / 10.
+        bundle.claim.gasprice = 200.into();
+        assert!(bundle.insert_deposit(U256::one()).is_none());
+        assert_eq!(U256::from(118), bundle.estimate_price(100.into()));
+    }
+
+    #[test]
+    fn bundle_estimate_price_claim_caps_price() {
+        let mut bundle = Bundle::new();
+        bundle.insert_transfer(transfer(150, 0));
+        bundle.insert_transfer(transfer(150, 2));
+        bundle.insert_deposit(U256::one());
+
+        // 100 + (150 - 100) * 7 / 10 = 135, which the claim only pays 120 of.
+        bundle.claim.gasprice = 120.into();
+        assert_eq!(U256::from(120), bundle.estimate_price(100.into()));
+
+        // The claim's gas price doesn't lower the minimum the bonus is of.
+        bundle.claim.gasprice = 140.into();
+        assert_eq!(U256::from(135), bundle.estimate_price(100.into()));
+    }
+
+    #[test]
+    fn bundle_estimate_price_claim_without_transactions() {
+        let mut bundle = Bundle::new();
+        bundle.insert_deposit(U256::one());
+        bundle.claim.gasprice = 120.into();
+
+        // The contract's bonus starts from the largest gas price, so only the
+        // claim's gas price bounds it.
+        assert_eq!(U256::from(120), bundle.estimate_price(100.into()));
+    }
 
     #[test]
     fn bundle_estimate_price_max_base() {
@@ -644,134 +1641,190 @@ mod tests {
     }
 
     #[test]
-    fn bundle_decode_slice() {
-        let input = [
-            0xe2, 0x3c, 0x9c, 0x75, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x60, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x01, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03,
-            0xe0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x27,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x3b, 0x9a, 0xca, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xc0, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xaa, 0xaa, 0xaa,
-            0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa,
-            0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa,
-            0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xbb, 0xbb, 0xbb, 0xbb,
-            0xbb, 0xbb, 0xbb, 0xbb, 0xbb, 0xbb, 0xbb, 0xbb, 0xbb, 0xbb, 0xbb,
-            0xbb, 0xbb, 0xbb, 0xbb, 0xbb, 0xbb, 0xbb, 0xbb, 0xbb, 0xbb, 0xbb,
-            0xbb, 0xbb, 0xbb, 0xbb, 0xbb, 0xbb, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x0c, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc,
-            0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0xdd, 0xdd, 0xdd, 0xdd, 0xdd, 0xdd, 0xdd, 0xdd, 0xdd, 0xdd, 0xdd,
-            0xdd, 0xdd, 0xdd, 0xdd, 0xdd, 0xdd, 0xdd, 0xdd, 0xdd, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xee, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x44, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x09, 0x11, 0x11, 0x11, 0x11, 0x11,
-            0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11,
-            0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11,
-            0x11, 0x11, 0x11, 0x11, 0x11, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22,
-            0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22,
-            0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22,
-            0x22, 0x22, 0x22, 0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x77, 0x77, 0x77, 0x77, 0x77, 0x77, 0x77, 0x77,
-            0x77, 0x77, 0x77, 0x77, 0x77, 0x77, 0x77, 0x77, 0x77, 0x77, 0x77,
-            0x77, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55,
-            0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xee, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x44, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09, 0x11, 0x11, 0x11,
-            0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11,
-            0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11,
-            0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x22, 0x22, 0x22, 0x22,
-            0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22,
-            0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22,
-            0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0xde, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x09, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11,
-            0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11,
-            0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11,
-            0x11, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22,
-            0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22,
-            0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x06, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xfe, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x19, 0x13, 0x11, 0x11,
-            0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11,
-            0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11,
-            0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x23, 0x22, 0x22, 0x22,
-            0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22,
-            0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22,
-            0x22, 0x22, 0x22, 0x22, 0x22, 0x22,
-        ];
+    fn bundle_entries() {
+        let transfer = Transfer {
+            gasprice: 117.into(),
+            input0: U256::one(),
+            input1: 2.into(),
+            signature: sig(),
+            amount: U256::zero(),
+            change: Address::zero(),
+            destination: Address::zero(),
+        };
 
-        let bundle = Bundle::decode_slice(&input).unwrap();
+        let withdrawal = Withdrawal {
+            gasprice: 113.into(),
+            input: 3.into(),
+            signature: sig(),
+        };
+
+        let bundle = Bundle {
+            claim: Claim {
+                deposits: vec![5.into(), 6.into()],
+                gasprice: 77.into(),
+                input: 4.into(),
+                signature: sig(),
+            },
+            transfers: vec![transfer.clone()],
+            withdrawals: vec![withdrawal.clone()],
+        };
+
+        let deposits: Vec<_> = bundle.deposits().cloned().collect();
+        assert_eq!(deposits, vec![U256::from(5), U256::from(6)]);
+
+        let entries: Vec<_> = bundle.entries().collect();
+        assert_eq!(
+            entries,
+            vec![
+                Entry::Deposit(&5.into()),
+                Entry::Deposit(&6.into()),
+                Entry::Transfer(&transfer),
+                Entry::Withdrawal(&withdrawal),
+            ]
+        );
+    }
+
+    #[test]
+    fn deposit_decode_event() {
+        let data = ethers::abi::encode(&[
+            Token::Uint(7.into()),
+            Token::Uint(100.into()),
+            Token::Uint(3.into()),
+            Token::Address([0x11; 20].into()),
+        ]);
+
+        let (id, deposit) = Deposit::decode_event(&data).unwrap();
+
+        assert_eq!(id, 7.into());
+        assert_eq!(deposit.amount, 100.into());
+        assert_eq!(deposit.bounty, 3.into());
+        assert_eq!(deposit.owner, [0x11; 20].into());
+    }
+
+    #[test]
+    fn deposit_decode_event_truncated() {
+        assert!(Deposit::decode_event(&[0u8; 40]).is_err());
+    }
+
+    const BUNDLE_CALLDATA: [u8; 1348] = [
+        0xe2, 0x3c, 0x9c, 0x75, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x60,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x80, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x03, 0xe0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x27,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x3b, 0x9a, 0xca, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0xc0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa,
+        0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa,
+        0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xbb, 0xbb, 0xbb, 0xbb,
+        0xbb, 0xbb, 0xbb, 0xbb, 0xbb, 0xbb, 0xbb, 0xbb, 0xbb, 0xbb, 0xbb, 0xbb,
+        0xbb, 0xbb, 0xbb, 0xbb, 0xbb, 0xbb, 0xbb, 0xbb, 0xbb, 0xbb, 0xbb, 0xbb,
+        0xbb, 0xbb, 0xbb, 0xbb, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x0c, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc,
+        0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0xdd, 0xdd, 0xdd, 0xdd, 0xdd, 0xdd, 0xdd, 0xdd, 0xdd, 0xdd, 0xdd, 0xdd,
+        0xdd, 0xdd, 0xdd, 0xdd, 0xdd, 0xdd, 0xdd, 0xdd, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0xee, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x44,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09, 0x11, 0x11, 0x11, 0x11,
+        0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11,
+        0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11,
+        0x11, 0x11, 0x11, 0x11, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22,
+        0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22,
+        0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x77, 0x77, 0x77, 0x77, 0x77, 0x77, 0x77, 0x77,
+        0x77, 0x77, 0x77, 0x77, 0x77, 0x77, 0x77, 0x77, 0x77, 0x77, 0x77, 0x77,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55,
+        0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0xee, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x44,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09, 0x11, 0x11, 0x11, 0x11,
+        0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11,
+        0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11,
+        0x11, 0x11, 0x11, 0x11, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22,
+        0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22,
+        0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xde,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09, 0x11, 0x11, 0x11, 0x11,
+        0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11,
+        0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11,
+        0x11, 0x11, 0x11, 0x11, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22,
+        0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22,
+        0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x06, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0xfe, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x19,
+        0x13, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11,
+        0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11,
+        0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x23, 0x22, 0x22, 0x22,
+        0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22,
+        0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22,
+        0x22, 0x22, 0x22, 0x22,
+    ];
+
+    #[test]
+    fn bundle_decode_slice() {
+        let bundle = Bundle::decode_slice(&BUNDLE_CALLDATA).unwrap();
 
         // TODO: Check all the things.
 
@@ -792,4 +1845,377 @@ mod tests {
         assert_eq!(withdrawals[0].gasprice, 0xde.into());
         assert_eq!(withdrawals[1].signature.v, 0x19);
     }
+
+    #[test]
+    fn bundle_decode_unknown_selector() {
+        let mut input = BUNDLE_CALLDATA.to_vec();
+        input[..4].copy_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+
+        match Bundle::decode_slice(&input) {
+            Err(DecodeError::UnknownSelector { selector }) => {
+                assert_eq!(selector, [0xde, 0xad, 0xbe, 0xef]);
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn transact_function_by_selector() {
+        let function = transact_function(&BUNDLE_CALLDATA[..4]).unwrap();
+        assert_eq!(function.name, "transact");
+
+        assert!(transact_function(&[0xde, 0xad, 0xbe, 0xef]).is_none());
+    }
+
+    #[test]
+    fn transact_takes_one_claim() {
+        // `Bundle` holds a single claim. If an ABI version ever batches
+        // several, `fill` would need a `Vec<Claim>` instead of failing to
+        // decode them, so catch that here first.
+        for abi in utxo_abis().iter() {
+            for function in &abi.functions["transact"] {
+                let claim = &function.inputs[0].kind;
+
+                assert!(matches!(claim, ParamType::Tuple(_)), "{:?}", claim);
+                assert_eq!(function.inputs.len(), 3);
+            }
+        }
+    }
+
+    #[test]
+    fn find_transact_direct() {
+        let found = Bundle::find_transact(&BUNDLE_CALLDATA).unwrap();
+        assert_eq!(found, &BUNDLE_CALLDATA[..]);
+    }
+
+    #[test]
+    fn find_transact_nested() {
+        // forward(bytes) with the bundle as its only argument.
+        let mut input = vec![0xde, 0xad, 0xbe, 0xef];
+        input.extend_from_slice(&[0; 31]);
+        input.push(32);
+        input.extend_from_slice(&[0; 30]);
+        input.extend_from_slice(&(BUNDLE_CALLDATA.len() as u16).to_be_bytes());
+        input.extend_from_slice(&BUNDLE_CALLDATA);
+        input.extend_from_slice(&[0; 28]);
+
+        let found = Bundle::find_transact(&input).unwrap();
+        assert_eq!(&found[..BUNDLE_CALLDATA.len()], &BUNDLE_CALLDATA[..]);
+        assert!(Bundle::decode_slice(found).is_ok());
+    }
+
+    #[test]
+    fn find_transact_missing() {
+        assert!(Bundle::find_transact(&[0xde, 0xad, 0xbe, 0xef]).is_none());
+        assert!(Bundle::find_transact(&[]).is_none());
+    }
+
+    #[test]
+    fn bundle_decode_partial_keeps_prefix() {
+        let mut input = BUNDLE_CALLDATA.to_vec();
+
+        // Point the withdrawals far past the end of the calldata.
+        input[4 + 2 * 32] = 0xff;
+
+        assert!(Bundle::decode_slice(&input).is_err());
+
+        let partial = Bundle::decode_partial(&input).unwrap_err();
+        assert_eq!(partial.bundle.claim.deposits.len(), 2);
+        assert_eq!(partial.bundle.transfers.len(), 2);
+        assert!(partial.bundle.withdrawals.is_empty());
+    }
+
+    #[test]
+    fn bundle_decode_partial_complete() {
+        let bundle = Bundle::decode_partial(&BUNDLE_CALLDATA).unwrap();
+        assert_eq!(bundle.withdrawals.len(), 2);
+    }
+
+    /// Number of random inputs each randomized test tries.
+    const CASES: usize = 256;
+
+    /// A fixed seed, so failures can be reproduced.
+    fn rng() -> StdRng {
+        StdRng::seed_from_u64(0x5554_584f)
+    }
+
+    fn random_u256(rng: &mut StdRng) -> U256 {
+        U256(rng.gen())
+    }
+
+    fn random_address(rng: &mut StdRng) -> Address {
+        Address::from(rng.gen::<[u8; 20]>())
+    }
+
+    fn random_signature(rng: &mut StdRng) -> Signature {
+        // The contract only has room for a `u8` v.
+        Signature {
+            v: rng.gen::<u8>().into(),
+            r: H256(rng.gen()),
+            s: H256(rng.gen()),
+        }
+    }
+
+    fn random_withdrawal(rng: &mut StdRng) -> Withdrawal {
+        Withdrawal {
+            input: random_u256(rng),
+            gasprice: random_u256(rng),
+            signature: random_signature(rng),
+        }
+    }
+
+    fn random_transfer(rng: &mut StdRng) -> Transfer {
+        Transfer {
+            input0: random_u256(rng),
+            input1: random_u256(rng),
+            destination: random_address(rng),
+            change: random_address(rng),
+            amount: random_u256(rng),
+            gasprice: random_u256(rng),
+            signature: random_signature(rng),
+        }
+    }
+
+    fn random_claim(rng: &mut StdRng) -> Claim {
+        let deposits = rng.gen_range(0, Bundle::MAX_SLOTS);
+
+        Claim {
+            input: random_u256(rng),
+            gasprice: random_u256(rng),
+            deposits: (0..deposits).map(|_| random_u256(rng)).collect(),
+            signature: random_signature(rng),
+        }
+    }
+
+    fn random_bundle(rng: &mut StdRng) -> Bundle {
+        let claim = random_claim(rng);
+        let transfers = rng.gen_range(0, Bundle::MAX_SLOTS);
+        let withdrawals = rng.gen_range(0, Bundle::MAX_SLOTS);
+
+        Bundle {
+            claim,
+            transfers: (0..transfers).map(|_| random_transfer(rng)).collect(),
+            withdrawals: (0..withdrawals)
+                .map(|_| random_withdrawal(rng))
+                .collect(),
+        }
+    }
+
+    fn assert_claim_eq(left: &Claim, right: &Claim) {
+        assert_eq!(left.input, right.input);
+        assert_eq!(left.gasprice, right.gasprice);
+        assert_eq!(left.deposits, right.deposits);
+        assert_eq!(left.signature, right.signature);
+    }
+
+    #[test]
+    fn signing_hash_covers_every_field() {
+        let domain = [0x42; 32];
+
+        let xfr = transfer(100, 1);
+        let mut other = xfr.clone();
+        other.amount = 1.into();
+
+        assert_ne!(xfr.signing_hash(&domain), other.signing_hash(&domain));
+        assert_ne!(xfr.signing_hash(&domain), xfr.signing_hash(&[0; 32]));
+
+        let wdr = Withdrawal {
+            input: 1.into(),
+            gasprice: 100.into(),
+            signature: sig(),
+        };
+
+        let mut other = wdr.clone();
+        other.gasprice = 101.into();
+
+        assert_ne!(wdr.signing_hash(&domain), other.signing_hash(&domain));
+    }
+
+    fn hex32(s: &str) -> [u8; 32] {
+        let mut out = [0; 32];
+        out.copy_from_slice(&s.from_hex::<Vec<u8>>().unwrap());
+        out
+    }
+
+    fn known_domain() -> [u8; 32] {
+        let contract: Address =
+            "5fbdb2315678afecb367f032d93f642f64180aa3".parse().unwrap();
+        domain_separator(1, contract)
+    }
+
+    #[test]
+    fn domain_separator_matches_contract() {
+        let expected = hex32(
+            "2e5efe18d2ad3e52e78bd85905e8b6c789c4f4044d97b831b6b1dd530dc83efe",
+        );
+
+        assert_eq!(known_domain(), expected);
+    }
+
+    #[test]
+    fn domain_separator_binds_contract() {
+        let a = Address::repeat_byte(0xaa);
+        let b = Address::repeat_byte(0xbb);
+
+        assert_ne!(domain_separator(1, a), domain_separator(1, b));
+
+        let xfr = transfer(10, 1);
+        assert_ne!(
+            xfr.signing_hash(&domain_separator(1, a)),
+            xfr.signing_hash(&domain_separator(1, b)),
+        );
+    }
+
+    #[test]
+    fn domain_separator_truncates_chain_id() {
+        let contract: Address =
+            "5fbdb2315678afecb367f032d93f642f64180aa3".parse().unwrap();
+
+        let expected = hex32(
+            "e88aea2b50d64ae595933c488f60f51030014db99e1cba895a548b6ac22ad97a",
+        );
+
+        assert_eq!(domain_separator(300, contract), expected);
+        assert_eq!(
+            domain_separator(300, contract),
+            domain_separator(44, contract)
+        );
+    }
+
+    #[test]
+    fn signing_hash_matches_contract() {
+        let domain = known_domain();
+
+        let xfr = Transfer {
+            input0: 1.into(),
+            input1: 2.into(),
+            destination: Address::repeat_byte(0x11),
+            change: Address::repeat_byte(0x22),
+            amount: 100.into(),
+            gasprice: 7.into(),
+            signature: sig(),
+        };
+
+        let wdr = Withdrawal {
+            input: 3.into(),
+            gasprice: 5.into(),
+            signature: sig(),
+        };
+
+        let xfr_hash = hex32(
+            "61c4647626beda7ed0cde3b07dc99eb5154a27f5f0e488e6d65797fadd03334f",
+        );
+        let wdr_hash = hex32(
+            "0cd0b4aadd5ce84fc16cafd7831e489c72185061f66b0680059cca7b5857b7bb",
+        );
+
+        assert_eq!(xfr.signing_hash(&domain), xfr_hash);
+        assert_eq!(wdr.signing_hash(&domain), wdr_hash);
+
+        let txn: Txn = xfr.into();
+        assert_eq!(txn.signing_hash(&domain), xfr_hash);
+
+        let txn: Txn = wdr.into();
+        assert_eq!(txn.signing_hash(&domain), wdr_hash);
+    }
+
+    #[test]
+    fn withdrawal_tuple_round_trip() {
+        let mut rng = rng();
+
+        for _ in 0..CASES {
+            let expected = random_withdrawal(&mut rng);
+            let tuple: WithdrawalTuple = expected.clone().into();
+            let actual = Withdrawal::from(tuple);
+
+            assert_eq!(actual, expected);
+            assert_eq!(actual.signature, expected.signature);
+        }
+    }
+
+    #[test]
+    fn transfer_tuple_round_trip() {
+        let mut rng = rng();
+
+        for _ in 0..CASES {
+            let expected = random_transfer(&mut rng);
+            let tuple: TransferTuple = expected.clone().into();
+            let actual = Transfer::from(tuple);
+
+            assert_eq!(actual, expected);
+            assert_eq!(actual.signature, expected.signature);
+        }
+    }
+
+    #[test]
+    fn claim_tuple_round_trip() {
+        let mut rng = rng();
+
+        for _ in 0..CASES {
+            let expected = random_claim(&mut rng);
+            let tuple: ClaimTuple = expected.clone().into();
+            let actual = Claim::from(tuple);
+
+            assert_claim_eq(&actual, &expected);
+        }
+    }
+
+    #[test]
+    fn bundle_calldata_round_trip() {
+        let signatures = |b: &Bundle| -> Vec<Signature> {
+            b.transfers
+                .iter()
+                .map(|t| t.signature)
+                .chain(b.withdrawals.iter().map(|w| w.signature))
+                .collect()
+        };
+
+        let mut rng = rng();
+
+        for _ in 0..CASES {
+            let expected = random_bundle(&mut rng);
+            let calldata = expected.clone().encode_calldata();
+            let actual = Bundle::decode_slice(&calldata).unwrap();
+
+            assert_claim_eq(&actual.claim, &expected.claim);
+            assert_eq!(actual.transfers, expected.transfers);
+            assert_eq!(actual.withdrawals, expected.withdrawals);
+            assert_eq!(signatures(&actual), signatures(&expected));
+        }
+    }
+
+    #[test]
+    fn bundle_decode_slice_arbitrary_no_panic() {
+        let mut rng = rng();
+
+        for _ in 0..CASES {
+            let len = rng.gen_range(0, 2048);
+            let input: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+
+            let _ = Bundle::decode_slice(&input);
+        }
+    }
+
+    #[test]
+    fn bundle_decode_slice_mutated_no_panic() {
+        let mut rng = rng();
+
+        for _ in 0..CASES {
+            let mut input = BUNDLE_CALLDATA.to_vec();
+
+            for _ in 0..rng.gen_range(1, 16) {
+                let index = rng.gen_range(0, input.len());
+                input[index] = rng.gen();
+            }
+
+            let _ = Bundle::decode_slice(&input);
+        }
+    }
+
+    #[test]
+    fn bundle_decode_slice_truncated_no_panic() {
+        for len in 0..BUNDLE_CALLDATA.len() {
+            let _ = Bundle::decode_slice(&BUNDLE_CALLDATA[..len]);
+        }
+    }
 }
@@ -0,0 +1,69 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::contracts::DecodeError;
+use crate::pool::PoolError;
+
+use ethers::contract::ContractError;
+use ethers::providers::ProviderError;
+use ethers::signers::ClientError;
+use ethers::types::U256;
+
+use snafu::Snafu;
+
+/// Errors from the relayer's own operations.
+///
+/// These still convert into the boxed `crate::Error` with `?`, so code can be
+/// migrated piecemeal. Whoever receives a boxed error can recover the variant
+/// with `downcast_ref::<RelayerError>()`.
+#[derive(Debug, Snafu)]
+#[snafu(visibility = "pub(crate)")]
+pub enum RelayerError {
+    #[snafu(display("RPC request failed: {}", source))]
+    Rpc { source: ProviderError },
+
+    #[snafu(display("contract call failed: {}", source))]
+    Contract { source: ContractError },
+
+    #[snafu(display("unable to sign transaction: {}", source))]
+    Sign { source: ClientError },
+
+    #[snafu(display("unable to send transaction: {}", source))]
+    SendTransaction { source: ClientError },
+
+    #[snafu(display("unable to decode bundle: {}", source))]
+    Decode { source: DecodeError },
+
+    #[snafu(display("transaction pool is inconsistent: {}", source))]
+    Pool { source: PoolError },
+
+    #[snafu(display(
+        "configured for chain id {}, but the node is on chain {}",
+        expected,
+        actual
+    ))]
+    ChainMismatch { expected: u64, actual: U256 },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::contracts::Bundle;
+
+    use snafu::ResultExt;
+
+    #[test]
+    fn downcast_from_boxed() {
+        let input = [0xe2, 0x3c, 0x9c, 0x75, 0x00];
+        let result = Bundle::decode_slice(&input).context(Decode);
+
+        let boxed: crate::Error = result.unwrap_err().into();
+
+        match boxed.downcast_ref::<RelayerError>() {
+            Some(RelayerError::Decode { .. }) => (),
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+}
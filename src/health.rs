@@ -0,0 +1,129 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::future::Future;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+
+/// Most of a request read before answering. Nothing in it matters, so this
+/// only has to be enough to get past the request line.
+const REQUEST_LIMIT: usize = 1024;
+
+/// How long a client gets to send its request before it's answered anyway,
+/// so one that never does can't hold its connection open.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Answers every connection on `listener`, whatever it asks for, with 200 if
+/// `check` finds no problems, or 503 listing them. Just enough HTTP for an
+/// orchestrator's probe (ex. Kubernetes), without a server to depend on.
+pub async fn serve<F, Fut>(mut listener: TcpListener, check: F)
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Vec<String>> + Send + 'static,
+{
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(answer(stream, check()));
+            }
+            Err(e) => eprintln!("\nHealth endpoint error: {}", e),
+        }
+    }
+}
+
+async fn answer<Fut>(mut stream: TcpStream, problems: Fut)
+where
+    Fut: Future<Output = Vec<String>>,
+{
+    let mut request = [0u8; REQUEST_LIMIT];
+    let mut read = 0;
+
+    // Wait for the end of the headers, so the client isn't reset before it
+    // finishes sending them.
+    let headers = async {
+        while read < request.len() {
+            match stream.read(&mut request[read..]).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => read += n,
+            }
+
+            if request[..read].windows(4).any(|w| w == b"\r\n\r\n") {
+                break;
+            }
+        }
+    };
+
+    let _ = time::timeout(REQUEST_TIMEOUT, headers).await;
+
+    let reply = response(&problems.await);
+
+    // The client may have gone away, which is fine.
+    let _ = stream.write_all(reply.as_bytes()).await;
+    let _ = stream.shutdown(std::net::Shutdown::Write);
+}
+
+/// The whole HTTP response for `problems`.
+fn response(problems: &[String]) -> String {
+    let (status, body) = if problems.is_empty() {
+        ("200 OK", "ok\n".to_string())
+    } else {
+        let lines: Vec<_> =
+            problems.iter().map(|p| format!("{}\n", p)).collect();
+        ("503 Service Unavailable", lines.concat())
+    };
+
+    format!(
+        concat!(
+            "HTTP/1.1 {}\r\n",
+            "Content-Type: text/plain\r\n",
+            "Content-Length: {}\r\n",
+            "Connection: close\r\n",
+            "\r\n",
+            "{}"
+        ),
+        status,
+        body.len(),
+        body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn response_healthy() {
+        let expected = concat!(
+            "HTTP/1.1 200 OK\r\n",
+            "Content-Type: text/plain\r\n",
+            "Content-Length: 3\r\n",
+            "Connection: close\r\n",
+            "\r\n",
+            "ok\n"
+        );
+
+        assert_eq!(response(&[]), expected);
+    }
+
+    #[test]
+    fn response_lists_problems() {
+        let problems =
+            vec!["no block seen yet".to_string(), "low balance".to_string()];
+
+        let expected = concat!(
+            "HTTP/1.1 503 Service Unavailable\r\n",
+            "Content-Type: text/plain\r\n",
+            "Content-Length: 30\r\n",
+            "Connection: close\r\n",
+            "\r\n",
+            "no block seen yet\n",
+            "low balance\n"
+        );
+
+        assert_eq!(response(&problems), expected);
+    }
+}
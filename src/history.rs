@@ -0,0 +1,220 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::contracts::Bundle;
+
+use ethers::types::{Address, TxHash, U256};
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// What became of a broadcast bundle, as far as we've seen.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Outcome {
+    /// Not seen in a block yet.
+    Pending,
+    Mined,
+    Reverted,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Outcome::Pending => write!(f, "pending"),
+            Outcome::Mined => write!(f, "mined"),
+            Outcome::Reverted => write!(f, "reverted"),
+        }
+    }
+}
+
+/// One bundle we broadcast.
+#[derive(Debug, Clone)]
+pub struct Broadcast {
+    pub contract: Address,
+    pub bundle: Bundle,
+    pub nonce: U256,
+    pub gas: Option<U256>,
+    pub hash: TxHash,
+    pub sent: SystemTime,
+    pub outcome: Outcome,
+}
+
+impl fmt::Display for Broadcast {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:?} nonce {} {}: {} deposit(s), {} transfer(s), {} withdrawal(s)",
+            self.hash,
+            self.nonce,
+            self.outcome,
+            self.bundle.claim.deposits.len(),
+            self.bundle.transfers.len(),
+            self.bundle.withdrawals.len(),
+        )?;
+
+        if let Some(gas) = self.gas {
+            write!(f, ", estimated {} gas", gas)?;
+        }
+
+        if let Ok(age) = self.sent.elapsed() {
+            write!(f, ", sent {}s ago", age.as_secs())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The last few bundles we broadcast, oldest first, for looking into what
+/// went wrong after the fact.
+#[derive(Debug)]
+pub struct History {
+    capacity: usize,
+    entries: Mutex<VecDeque<Broadcast>>,
+}
+
+impl History {
+    /// Keeps at most `capacity` broadcasts, or none at all if zero.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Records a broadcast, forgetting the oldest one if full.
+    pub fn record(&self, broadcast: Broadcast) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+
+        while entries.len() >= self.capacity {
+            entries.pop_front();
+        }
+
+        entries.push_back(broadcast);
+    }
+
+    /// Sets the outcome of the broadcast with transaction `hash`, returning
+    /// false if it isn't one we remember.
+    pub fn mark(&self, hash: &TxHash, outcome: Outcome) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+
+        match entries.iter_mut().find(|b| &b.hash == hash) {
+            Some(broadcast) => {
+                broadcast.outcome = outcome;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Whether `hash` is a remembered broadcast to `contract`.
+    pub fn contains(&self, contract: Address, hash: &TxHash) -> bool {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|b| b.contract == contract && &b.hash == hash)
+    }
+
+    /// Copies out the remembered broadcasts to `contract`, oldest first.
+    pub fn to_contract(&self, contract: Address) -> Vec<Broadcast> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|b| b.contract == contract)
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn broadcast(nonce: u64) -> Broadcast {
+        Broadcast {
+            contract: Address::zero(),
+            bundle: Bundle::new(),
+            nonce: nonce.into(),
+            gas: None,
+            hash: TxHash::from_low_u64_be(nonce),
+            sent: SystemTime::now(),
+            outcome: Outcome::Pending,
+        }
+    }
+
+    fn nonces(history: &History) -> Vec<U256> {
+        history
+            .to_contract(Address::zero())
+            .iter()
+            .map(|b| b.nonce)
+            .collect()
+    }
+
+    #[test]
+    fn record_forgets_oldest() {
+        let history = History::new(2);
+
+        for nonce in 1..=3 {
+            history.record(broadcast(nonce));
+        }
+
+        assert_eq!(nonces(&history), vec![2.into(), 3.into()]);
+    }
+
+    #[test]
+    fn record_nothing_when_zero() {
+        let history = History::new(0);
+        history.record(broadcast(1));
+
+        assert!(nonces(&history).is_empty());
+    }
+
+    #[test]
+    fn mark_by_hash() {
+        let history = History::new(4);
+        history.record(broadcast(1));
+        history.record(broadcast(2));
+
+        let hash = TxHash::from_low_u64_be(2);
+        assert!(history.mark(&hash, Outcome::Reverted));
+        assert!(!history.mark(&TxHash::from_low_u64_be(9), Outcome::Mined));
+
+        let outcomes: Vec<_> = history
+            .to_contract(Address::zero())
+            .iter()
+            .map(|b| b.outcome)
+            .collect();
+        assert_eq!(outcomes, vec![Outcome::Pending, Outcome::Reverted]);
+    }
+
+    #[test]
+    fn to_contract_filters() {
+        let history = History::new(4);
+        history.record(broadcast(1));
+
+        let mut other = broadcast(2);
+        other.contract = Address::repeat_byte(1);
+        history.record(other);
+
+        assert_eq!(nonces(&history), vec![1.into()]);
+    }
+
+    #[test]
+    fn contains_by_contract_and_hash() {
+        let history = History::new(4);
+        history.record(broadcast(1));
+
+        let hash = TxHash::from_low_u64_be(1);
+        assert!(history.contains(Address::zero(), &hash));
+        assert!(!history.contains(Address::repeat_byte(1), &hash));
+        assert!(!history.contains(Address::zero(), &TxHash::zero()));
+    }
+}
@@ -0,0 +1,130 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::contracts::{Deposit, Transfer};
+use crate::pool::Identified;
+
+use ethers::types::{Address, Signature, H256, U256};
+
+/// One gwei, the unit gas prices are spread over.
+const GWEI: u64 = 1_000_000_000;
+
+/// Synthesizes transfers and deposits to fill the pools with (ex. for the
+/// `generate-load` command.) Nothing it makes has a valid signature.
+///
+/// Uses a small xorshift generator, so the same seed always makes the same
+/// load, and no extra dependency is needed for it.
+#[derive(Debug, Clone)]
+pub struct LoadGenerator {
+    state: u64,
+
+    /// Inputs are picked from `1..=inputs`, so some transfers conflict.
+    inputs: u64,
+}
+
+impl LoadGenerator {
+    pub fn new(seed: u64, inputs: u64) -> Self {
+        Self {
+            // Xorshift gets stuck at zero.
+            state: seed.max(1),
+            inputs: inputs.max(1),
+        }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    /// Returns a number in `low..=high`.
+    fn between(&mut self, low: u64, high: u64) -> u64 {
+        low + self.next() % (high - low + 1)
+    }
+
+    fn address(&mut self) -> Address {
+        let mut bytes = [0; 20];
+        bytes[..8].copy_from_slice(&self.next().to_be_bytes());
+        bytes[8..16].copy_from_slice(&self.next().to_be_bytes());
+        Address::from(bytes)
+    }
+
+    /// A transfer spending one or two inputs, at 1 to 100 gwei for gas.
+    pub fn transfer(&mut self) -> Transfer {
+        let input0 = self.between(1, self.inputs);
+
+        // About half spend a second input.
+        let input1 = match self.between(0, 1) {
+            0 => 0,
+            _ => self.between(1, self.inputs),
+        };
+
+        let input1 = if input1 == input0 { 0 } else { input1 };
+
+        Transfer {
+            input0: input0.into(),
+            input1: input1.into(),
+            destination: self.address(),
+            change: self.address(),
+            amount: self.between(1, 1_000_000).into(),
+            gasprice: U256::from(self.between(1, 100)) * GWEI,
+            signature: Signature {
+                v: 0,
+                r: H256::zero(),
+                s: H256::zero(),
+            },
+        }
+    }
+
+    /// A deposit whose bounty pays for 100k gas at 0 to 10 gwei.
+    pub fn deposit(&mut self) -> Identified {
+        let deposit = Deposit {
+            amount: self.between(1, 1_000_000).into(),
+            bounty: U256::from(self.between(0, 10)) * GWEI * 100_000,
+            owner: self.address(),
+        };
+
+        Identified::new(deposit, self.next().into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::pool::Transaction;
+
+    #[test]
+    fn same_seed_same_load() {
+        let mut first = LoadGenerator::new(7, 100);
+        let mut second = LoadGenerator::new(7, 100);
+
+        for _ in 0..10 {
+            assert_eq!(first.transfer(), second.transfer());
+            assert_eq!(first.deposit(), second.deposit());
+        }
+    }
+
+    #[test]
+    fn zero_seed() {
+        let mut gen = LoadGenerator::new(0, 100);
+        assert_ne!(gen.next(), 0);
+    }
+
+    #[test]
+    fn transfers_in_range() {
+        let mut gen = LoadGenerator::new(3, 4);
+
+        for _ in 0..100 {
+            let transfer = gen.transfer();
+
+            assert!(transfer.input0 >= 1.into());
+            assert!(transfer.input0 <= 4.into());
+            assert!(transfer.input1 <= 4.into());
+            assert_ne!(transfer.input0, transfer.input1);
+            assert!(!transfer.gas_price().is_zero());
+        }
+    }
+}
@@ -3,34 +3,77 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 #![feature(map_first_last)]
+#![cfg_attr(test, feature(test))]
 
+mod chain;
 mod contracts;
+mod error;
+mod health;
+mod history;
+mod load;
 mod pool;
+mod relay;
 mod signer;
+mod timed;
+mod trace;
 mod ui;
+mod watchdog;
 
+use crate::chain::{Chain, Seen};
 use crate::contracts::{
-    Bundle, Deposit, Output, Transfer, Txn, Utxo, Withdrawal,
+    Bundle, Caps, Deposit, Output, PartialDecode, Preference, Reservations,
+    Transfer, Txn, TxnKind, TxnRef, Utxo, Weights, Withdrawal,
+    NEW_DEPOSIT_EVENT,
 };
-use crate::pool::{DepositPool, Pool, Transaction as _};
-use crate::signer::AbstractSigner;
+use crate::error::{
+    ChainMismatch, Contract, Decode, Pool as PoolInconsistent, RelayerError,
+    Rpc, SendTransaction, Sign,
+};
+use crate::history::{Broadcast, History, Outcome};
+use crate::load::LoadGenerator;
+use crate::pool::{DepositPool, Identified, Insertion, Pool, Transaction as _};
+use crate::relay::{BuilderPayment, PrivateRelay};
+use crate::signer::{recover_signer, LocalKey, RelayerSigner, SignerKind};
+use crate::timed::{TimedMutex, TimedRwLock};
+use crate::trace::{Correlation, Stage, Trace};
 use crate::ui::{
-    Command, CommandKind, EventKind, Events, GetType, GetUtxo, PoolType,
+    Bid, CancelTransfer, ClearType, Command, CommandKind,
+    Decode as DecodeCalldata, EventKind, Events, Explain, ExportType,
+    GasFilter, GenerateLoad, GetDeposit, GetType, GetUtxo,
+    History as ShowHistory, Limit, LoadDeposits, LoadType, PoolSize, PoolType,
+    Reserve, SetType, SimulateClaim, SimulateType, Stats, Trace as ShowTrace,
+    WhatIfBase, WhatIfType,
 };
+use crate::watchdog::{Activity, Lag};
 
-use ethers::providers::{JsonRpcClient, Provider};
-use ethers::signers::Client;
+use ethers::providers::{Http, JsonRpcClient, Provider, ProviderError};
+use ethers::signers::{Client, ClientError, Signer as _};
 use ethers::types::{
-    Address, BlockNumber, Transaction as EthTransaction, H160, H256, U256, U64,
+    Address, BlockNumber, Filter, Log, Transaction as EthTransaction,
+    TransactionRequest, TxHash, H160, H256, U256, U64,
 };
 
-use std::convert::TryFrom;
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashSet};
+use std::convert::{TryFrom, TryInto};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use rustc_hex::{FromHex, ToHex};
+
+use snafu::{ensure, ResultExt};
 
 use structopt::StructOpt;
 
+use tokio::net::TcpListener;
 use tokio::stream::StreamExt;
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, Mutex, RwLockReadGuard, Semaphore};
 
 type Error = Box<dyn std::error::Error + Sync + Send>;
 
@@ -39,6 +82,8 @@ const ENTRY_POINT: Address = H160([
     0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
 ]);
 
+const NODE_URL: &str = "http://localhost:8545";
+
 const UTXO: Address = H160([
     0x7C, 0x25, 0xD8, 0xB4, 0x98, 0x82, 0xd4, 0x9b, 0x95, 0x01, 0xDF, 0xD8,
     0x05, 0x9D, 0xA8, 0x8a, 0xB2, 0xeB, 0x3B, 0xfD,
@@ -48,527 +93,5398 @@ const UTXO: Address = H160([
 pub struct Opts {
     #[structopt(long = "oob")]
     oob: bool,
+
+    /// Don't read commands from the terminal (ex. when running as a service).
+    #[structopt(long = "headless")]
+    headless: bool,
+
+    /// UTXO contract to relay bundles for. May be given more than once.
+    /// Defaults to the well-known deployment.
+    #[structopt(long = "utxo", number_of_values = 1)]
+    utxo: Vec<Address>,
+
+    /// Also accept commands, one per line, from a Unix socket at this path.
+    #[structopt(long = "control-socket", parse(from_os_str))]
+    control_socket: Option<PathBuf>,
+
+    /// Run the commands in this file, one per line, before reading any from
+    /// the terminal (ex. to seed deposits and settings reproducibly.) A bad
+    /// line is reported and skipped.
+    #[structopt(long = "script", parse(from_os_str))]
+    script: Option<PathBuf>,
+
+    /// Answer HTTP requests on this address (ex. 127.0.0.1:8080) with 200
+    /// while the node answers, a block was seen within `--max-block-age`
+    /// (default 300s), and the signer has `--min-balance`, or 503 otherwise.
+    #[structopt(long = "health-addr")]
+    health_addr: Option<SocketAddr>,
+
+    /// Events queued for printing before whatever sent them has to wait.
+    /// Heartbeats (ex. new blocks) are dropped instead of waiting.
+    #[structopt(long = "event-capacity", default_value = "256")]
+    event_capacity: usize,
+
+    /// When a competing bundle pays more for gas than ours, broadcast one
+    /// paying this multiple (ex. 1.1) of the competitor's, if the pools can
+    /// make one.
+    #[structopt(long = "rebump-factor")]
+    rebump_factor: Option<f64>,
+
+    /// When the node refuses a broadcast as underpriced (ex. it replaces one
+    /// of ours at the same nonce), raise the gas price by a tenth and retry,
+    /// at most this many times. Never with `--signer abstract`, since
+    /// transactions from the entry point must have a gas price of zero.
+    #[structopt(long = "underpriced-retries", default_value = "3")]
+    underpriced_retries: u32,
+
+    /// Refuse to broadcast while the newest block seen was mined more than
+    /// this many seconds ago (ex. the node stopped syncing), since the pools
+    /// could be far out of date.
+    #[structopt(long = "max-block-age")]
+    max_block_age: Option<u64>,
+
+    /// Don't absorb pending transactions whose nonce has already been mined,
+    /// since they likely spend UTXOs that are already gone.
+    #[structopt(long = "skip-stale-pending")]
+    skip_stale_pending: bool,
+
+    /// Chain id to sign broadcasts for (EIP-155). The node must report the
+    /// same id, or the relayer refuses to start.
+    #[structopt(long = "chain-id", default_value = "12345")]
+    chain_id: u64,
+
+    /// How to sign broadcasts: `abstract` (unsigned, from the entry point),
+    /// `wallet:<key file>`, or `external:<socket>` for a separate signing
+    /// service that keeps the key out of the relayer.
+    #[structopt(long = "signer", default_value = "abstract")]
+    signer: SignerKind,
+
+    /// Sign transfers and withdrawals entered as commands with this private
+    /// key (hex). Only meant for testing, since the key ends up in the process
+    /// list and shell history.
+    #[structopt(long = "sign-with")]
+    sign_with: Option<LocalKey>,
+
+    /// Submit bundles to this private relay with `eth_sendBundle`, instead of
+    /// to the public mempool. Needs a `--signer` that can really sign.
+    #[structopt(long = "private-relay-url")]
+    private_relay_url: Option<Http>,
+
+    /// With `--private-relay-url`, follow each bundle with a transfer of
+    /// `<wei>` to `<address>`, for builders paid directly.
+    #[structopt(long = "builder-payment")]
+    builder_payment: Option<BuilderPayment>,
+
+    /// Don't broadcast bundles whose bribe, the gas price paid above the fee
+    /// base times the slots filled, is less than this (in wei).
+    #[structopt(long = "min-bribe", parse(try_from_str = U256::from_dec_str))]
+    min_bribe: Option<U256>,
+
+    /// Don't broadcast bundles paying more than this many wei for gas, either
+    /// through `paygas` or as signed transactions, even when outbid.
+    #[structopt(
+        long = "max-gas-price",
+        parse(try_from_str = U256::from_dec_str)
+    )]
+    max_gas_price: Option<U256>,
+
+    /// Gas limit for broadcasts, instead of the latest block's. Bundles
+    /// estimated to need more aren't broadcast.
+    #[structopt(long = "gas-limit", parse(try_from_str = U256::from_dec_str))]
+    gas_limit: Option<U256>,
+
+    /// Warn, and stop broadcasting, while the signer's balance is below this
+    /// many wei. Needs a wallet or external signer, since the entry point has
+    /// no balance to speak of.
+    #[structopt(
+        long = "min-balance",
+        parse(try_from_str = U256::from_dec_str)
+    )]
+    min_balance: Option<U256>,
+
+    /// Return the transfers and withdrawals from reverted bundles to the pool.
+    /// Off by default, since they may be invalid or even malicious.
+    #[structopt(long = "absorb-reverted")]
+    absorb_reverted: bool,
+
+    /// Restart the block or pending transaction watcher if it goes this many
+    /// seconds without seeing anything. Should comfortably exceed the block
+    /// time.
+    #[structopt(long = "watchdog")]
+    watchdog: Option<u64>,
+
+    /// Every this many seconds, re-send the best bundle in case the node
+    /// dropped it, until it's mined.
+    #[structopt(long = "rebroadcast-interval")]
+    rebroadcast_interval: Option<u64>,
+
+    /// How many of the last bundles broadcast `history` remembers.
+    #[structopt(long = "history-size", default_value = "32")]
+    history_size: usize,
+
+    /// How many steps (ex. pooled, broadcast, mined) of recent transactions
+    /// `trace` remembers.
+    #[structopt(long = "trace-size", default_value = "1024")]
+    trace_size: usize,
+
+    /// When a nonce we've broadcast past never reaches the node, send a
+    /// transfer to ourselves at that nonce so later broadcasts can be mined.
+    #[structopt(long = "fill-nonce-gaps")]
+    fill_nonce_gaps: bool,
+
+    /// Keep the next nonce to broadcast with in this file, so after a restart
+    /// broadcasts still in flight are accounted for.
+    #[structopt(long = "nonce-file", parse(from_os_str))]
+    nonce_file: Option<PathBuf>,
+
+    /// Also watch for bundles sent through this proxy or router contract. May
+    /// be given more than once, but only when relaying for one contract.
+    #[structopt(long = "proxy-address", number_of_values = 1)]
+    proxy_address: Vec<Address>,
+
+    /// Only pool transfers and withdrawals signed by this address. May be
+    /// given more than once.
+    #[structopt(long = "allow-sender", number_of_values = 1)]
+    allow_sender: Vec<Address>,
+
+    /// Never pool transfers and withdrawals signed by this address. May be
+    /// given more than once.
+    #[structopt(long = "deny-sender", number_of_values = 1)]
+    deny_sender: Vec<Address>,
+
+    /// Maximum number of blocks and pending transactions processed at once.
+    /// At least one, or nothing would ever be processed.
+    #[structopt(long = "max-concurrent-tasks", default_value = "64")]
+    max_concurrent_tasks: NonZeroUsize,
+
+    /// When a transfer and a withdrawal pay the same gas price but only one
+    /// fits, include the `transfers` or the `withdrawals`.
+    #[structopt(long = "prefer")]
+    prefer: Option<Preference>,
+
+    /// Most transfers to put into one bundle, even with slots to spare, to
+    /// limit how much one revert takes down with it.
+    #[structopt(long = "max-bundle-transfers")]
+    max_bundle_transfers: Option<usize>,
+
+    /// Most withdrawals to put into one bundle, even with slots to spare.
+    #[structopt(long = "max-bundle-withdrawals")]
+    max_bundle_withdrawals: Option<usize>,
+
+    /// File of `owner weight` lines. Among deposits paying the same bounty,
+    /// those from heavier owners are claimed first.
+    #[structopt(long = "owner-weights", parse(from_os_str))]
+    owner_weights: Option<PathBuf>,
+
+    /// Only remove what a bundle mined from the pools once its block is this
+    /// many blocks deep, so a shallow reorg doesn't undo it. Must be less
+    /// than 64.
+    #[structopt(long = "confirmations", default_value = "0")]
+    confirmations: u64,
+
+    /// Enable commands only meant for testing (ex. `generate-load`.)
+    #[structopt(long = "testing")]
+    testing: bool,
+
+    /// Process the next block, broadcasting at most one bundle, then exit.
+    #[structopt(long = "once")]
+    once: bool,
+
+    /// Replay recorded `transact` calldata (hex, one per line) offline,
+    /// printing each bundle that would be broadcast, then exit.
+    #[structopt(long = "replay", parse(from_os_str))]
+    replay: Option<PathBuf>,
+
+    /// Fee base (in wei) assumed while replaying.
+    #[structopt(
+        long = "replay-fee-base",
+        default_value = "0",
+        parse(try_from_str = U256::from_dec_str)
+    )]
+    replay_fee_base: U256,
 }
 
-#[derive(Debug)]
+/// Pools owned outright, for when nothing else needs them at the same time
+/// (ex. replaying recorded calldata.) A `Market` locks each one separately.
+#[derive(Debug, Default)]
 pub struct Pending {
     deposits: DepositPool,
     transactions: Pool<Txn>,
+    reservations: Reservations,
+    caps: Caps,
+    prefer: Option<Preference>,
+    weights: Weights,
 
     best_bundle: Option<Bundle>,
 }
 
 impl Pending {
-    pub fn generate(&mut self, base: U256) -> Option<&Bundle> {
-        self.best_bundle = None;
-        self.regenerate(base)
+    fn pools(&self) -> Pools {
+        Pools {
+            deposits: &self.deposits,
+            transactions: &self.transactions,
+            reservations: &self.reservations,
+            caps: self.caps,
+            prefer: self.prefer,
+            weights: &self.weights,
+        }
+    }
+
+    /// Adds the transfers and withdrawals from `bundle` to the pool.
+    pub fn absorb(&mut self, bundle: Bundle) {
+        absorb(&mut self.transactions, bundle);
     }
 
     pub fn regenerate(&mut self, base: U256) -> Option<&Bundle> {
-        let mut bundle = Bundle::new();
+        self.publish(base, false)
+    }
 
-        // TODO: Handle the case where there are only deposits and no
-        //       other transactions.
+    fn publish(&mut self, base: U256, reset: bool) -> Option<&Bundle> {
+        let pools = self.pools();
 
-        for txn in self.transactions.iter() {
-            let gp = txn.gas_price();
+        if !reset && pools.is_idle(base) {
+            return None;
+        }
 
-            // Create a new bundle, copying the transfers and withdrawals.
-            let mut new_bundle = Bundle::new();
-            new_bundle.transfers = bundle.transfers.clone();
-            new_bundle.withdrawals = bundle.withdrawals.clone();
+        let bundle = pools.select(base);
 
-            // Insert the next best transaction.
-            new_bundle.insert(txn.clone());
+        if reset || improves(self.best_bundle.as_ref(), &bundle, base) {
+            self.best_bundle = Some(bundle);
+            self.best_bundle.as_ref()
+        } else {
+            None
+        }
+    }
+}
 
-            // NB: There an attack where a malicious bundler Alice could
-            //     repackage Bob's claim with more transactions than Bob
-            //     anticipated. This isn't an issue here, since this assumes
-            //     each claim pays the full gas price, but something to be
-            //     aware of.
+/// Shortcuts for tests, which don't need to go through a `Market`'s locks.
+#[cfg(test)]
+impl Pending {
+    pub fn generate(&mut self, base: U256) -> Option<&Bundle> {
+        self.publish(base, true)
+    }
 
-            // Collect deposits that break even at txn's gas price.
-            let deposits = &new_bundle.claim.deposits;
-            new_bundle.claim.gasprice = *gp;
+    pub fn rebroadcastable(&self) -> Option<&Bundle> {
+        self.pools().rebroadcastable(self.best_bundle.as_ref())
+    }
 
-            for candidate in self.deposits.iter() {
-                // TODO: This is likely too conservative. It misses cases where
-                //       multiple deposits together would be profitable if the
-                //       first deposit isn't profitable on its own.
-                let previous_fees = Deposit::fees(deposits.len(), gp);
-                let fees = Deposit::fees(deposits.len() + 1, gp);
-                let my_fees = fees - previous_fees;
+    pub fn explain(&self, input: &U256, base: U256) -> Selection {
+        self.pools().explain(self.best_bundle.as_ref(), input, base)
+    }
 
-                if candidate.bounty < my_fees {
-                    break;
-                }
+    pub fn simulate_claim(
+        &self,
+        id: &U256,
+        base: U256,
+    ) -> Result<ClaimSimulation, Error> {
+        self.pools()
+            .simulate_claim(self.best_bundle.as_ref(), id, base)
+    }
 
-                if bundle.insert_deposit(*candidate.id()).is_some() {
-                    break;
-                }
-            }
+    pub fn export(&self) -> Vec<Bundle> {
+        self.pools().export()
+    }
 
-            if bundle.estimate_price(base) >= new_bundle.estimate_price(base) {
-                break;
-            } else {
-                bundle = new_bundle;
-            }
-        }
+    pub fn what_if_base(&self, bases: &[U256]) -> Vec<BaseFeeRow> {
+        self.pools().what_if_base(bases)
+    }
+}
 
-        let mut replace = true;
-        if let Some(ref best_bundle) = self.best_bundle {
-            if best_bundle.estimate_price(base) >= bundle.estimate_price(base) {
-                replace = false;
-            }
-        }
+/// Pools the transfers and withdrawals in `bundle`, returning the id and gas
+/// price of each one added.
+fn absorb(
+    transactions: &mut Pool<Txn>,
+    bundle: Bundle,
+) -> Vec<(Correlation, U256)> {
+    let withdrawals = bundle.withdrawals.into_iter().map(Txn::from);
+    let transfers = bundle.transfers.into_iter().map(Txn::from);
 
-        if replace {
-            self.best_bundle = Some(bundle);
-            self.best_bundle.as_ref()
-        } else {
-            None
+    let mut added = Vec::new();
+
+    for txn in withdrawals.chain(transfers) {
+        let correlation = Correlation::of(&txn);
+        let gasprice = *txn.gas_price();
+
+        if let Insertion::Added { .. } = transactions.insert(txn) {
+            added.push((correlation, gasprice));
         }
     }
-}
 
-pub struct State<T> {
-    events: Events,
-    provider: Provider<T>,
-    utxo: Utxo<T, AbstractSigner>,
-    pending: Mutex<Pending>,
+    added
 }
 
-pub type SharedState<T> = Arc<State<T>>;
+/// Whose transfers and withdrawals may be pooled for one contract, from
+/// `--allow-sender` and `--deny-sender`.
+#[derive(Debug, Clone, Copy)]
+struct SenderPolicy<'a> {
+    allow: &'a [Address],
+    deny: &'a [Address],
+    domain: [u8; 32],
+}
 
-#[tokio::main]
-async fn main() -> Result<(), Error> {
-    let opts = Opts::from_args();
+impl<'a> SenderPolicy<'a> {
+    fn new(opts: &'a Opts, contract: Address) -> Self {
+        Self {
+            allow: &opts.allow_sender,
+            deny: &opts.deny_sender,
+            domain: contracts::domain_separator(opts.chain_id, contract),
+        }
+    }
 
-    let handle = tokio::runtime::Handle::current();
-    let ui = ui::Ui::start(handle, opts.oob)?;
+    fn is_open(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
 
-    let provider = Provider::try_from("http://localhost:8545")?;
-    let signer = AbstractSigner::new(Some(12345));
-    let client = Client::new(provider.clone(), signer);
-    let utxo = Utxo::new(UTXO, client);
+    fn admits(&self, signer: Address) -> bool {
+        !self.deny.contains(&signer)
+            && (self.allow.is_empty() || self.allow.contains(&signer))
+    }
 
-    let state = Arc::new(State {
-        utxo,
-        provider,
-        events: ui.events(),
-        pending: Mutex::new(Pending {
-            deposits: Default::default(),
-            transactions: Pool::default(),
+    /// Recovers who signed `txn`, failing if they aren't admitted. Nothing is
+    /// recovered when every sender is admitted.
+    fn check(&self, txn: TxnRef) -> Result<(), Error> {
+        if self.is_open() {
+            return Ok(());
+        }
 
-            best_bundle: None,
-        }),
-    });
+        let signature = match txn {
+            Txn::Transfer(t) => &t.signature,
+            Txn::Withdrawal(w) => &w.signature,
+        };
 
-    let cmd_watcher = tokio::spawn(execute_commands(state.clone(), ui));
+        let signer =
+            recover_signer(&txn.signing_hash(&self.domain), signature)?;
 
-    let block_watcher =
-        async { tokio::spawn(process_blocks(state.clone())).await? };
+        if self.admits(signer) {
+            Ok(())
+        } else {
+            Err(format!("transactions from {:?} aren't accepted", signer)
+                .into())
+        }
+    }
 
-    let bundle_watcher =
-        async { tokio::spawn(process_transactions(state.clone())).await? };
+    /// Drops the transfers and withdrawals from `bundle` that `check` fails,
+    /// returning how many.
+    fn retain(&self, bundle: &mut Bundle) -> usize {
+        if self.is_open() {
+            return 0;
+        }
 
-    tokio::try_join!(block_watcher, bundle_watcher)?;
+        let before = bundle.transfers.len() + bundle.withdrawals.len();
 
-    cmd_watcher.await?;
+        bundle
+            .transfers
+            .retain(|t| self.check(Txn::Transfer(t)).is_ok());
+        bundle
+            .withdrawals
+            .retain(|w| self.check(Txn::Withdrawal(w)).is_ok());
 
-    Ok(())
+        before - bundle.transfers.len() - bundle.withdrawals.len()
+    }
 }
 
-async fn execute_commands<T>(state: SharedState<T>, mut ui: ui::Ui)
-where
-    T: JsonRpcClient,
-{
-    let mut events = state.events.clone();
-
-    while let Some(cmd) = ui.recv_command().await {
-        if let Err(e) = try_execute_command(&state, &cmd).await {
-            events.reply(&cmd, EventKind::CommandError(e)).await;
+/// Whether `candidate` should replace `best` as the bundle to broadcast.
+fn improves(best: Option<&Bundle>, candidate: &Bundle, base: U256) -> bool {
+    match best {
+        Some(best) => {
+            best.estimate_price(base) < candidate.estimate_price(base)
         }
+        None => true,
     }
 }
 
-async fn try_execute_command<T>(
-    state: &SharedState<T>,
-    cmd: &Command,
-) -> Result<(), Error>
-where
-    T: JsonRpcClient,
-{
-    let mut events = state.events.clone();
+/// The pools bundles are built from, borrowed from either a `Pending` or a
+/// `Market`'s locks.
+#[derive(Debug, Clone, Copy)]
+struct Pools<'a> {
+    deposits: &'a DepositPool,
+    transactions: &'a Pool<Txn>,
+    reservations: &'a Reservations,
+    caps: Caps,
 
-    match cmd.kind() {
-        CommandKind::Transfer(xfr) => {
-            insert_transfer(state, cmd, xfr.clone().into()).await?;
+    /// Breaks ties between transfers and withdrawals paying the same gas.
+    prefer: Option<Preference>,
+
+    /// Breaks ties between deposits paying the same bounty.
+    weights: &'a Weights,
+}
+
+impl<'a> Pools<'a> {
+    /// Whether there's nothing to bundle at `base`: no transfers or
+    /// withdrawals, and no deposits worth claiming.
+    fn is_idle(self, base: U256) -> bool {
+        self.transactions.is_empty()
+            && self.deposits.iter_by_profit(&base).next().is_none()
+    }
+
+    /// The best bundle, unless it's empty or something in it has left the
+    /// pools (ex. because it was mined.)
+    fn rebroadcastable<'b>(
+        self,
+        best: Option<&'b Bundle>,
+    ) -> Option<&'b Bundle> {
+        let bundle = best?;
+
+        if bundle.transactions().next().is_none() {
+            return None;
         }
-        CommandKind::Withdraw(wdw) => {
-            insert_withdrawal(state, cmd, wdw.clone().into()).await?;
+
+        let deposits =
+            bundle.deposits().all(|id| self.deposits.get(id).is_some());
+
+        let transactions = bundle.transactions().all(|txn| {
+            txn.inputs()
+                .next()
+                .and_then(|input| self.transactions.get(input))
+                .map_or(false, |pooled| pooled.as_ref() == txn)
+        });
+
+        if deposits && transactions {
+            Some(bundle)
+        } else {
+            None
         }
-        CommandKind::Show(show) => match show.what {
-            PoolType::Transfers => show_transfers(state, cmd).await,
-            PoolType::Withdrawals => show_withdrawals(state, cmd).await,
-            PoolType::Deposits => show_deposits(state, cmd).await,
-        },
-        CommandKind::Get(get) => match get {
-            GetType::FeeBase => get_fee_base(state, cmd).await?,
-            GetType::UtxoCount => get_utxo_count(state, cmd).await?,
-            GetType::Utxo(GetUtxo { id }) => get_utxo(state, cmd, *id).await?,
-        },
-        _ => events.reply(cmd, format!("{:?}", cmd)).await,
     }
 
-    Ok(())
-}
+    /// Explains why the transaction spending `input` is or isn't in the best
+    /// bundle, following the same rules as `select`.
+    fn explain(
+        self,
+        best: Option<&Bundle>,
+        input: &U256,
+        base: U256,
+    ) -> Selection {
+        let txn = match self.transactions.get(input) {
+            Some(t) => t,
+            None => return Selection::NotPending,
+        };
 
-async fn get_utxo<T>(
-    state: &SharedState<T>,
-    cmd: &Command,
-    id: U256,
-) -> Result<(), Error>
-where
-    T: JsonRpcClient,
-{
-    let output_tuple = state.utxo.get_utxo(id).from(ENTRY_POINT).call().await?;
-    let output = Output::from(output_tuple);
+        let bundle = match best {
+            Some(b) => b,
+            None => return Selection::NoBundle,
+        };
 
-    let name = format!("utxo[{}]", id);
-    state.events.clone().get(cmd, name, output).await;
-    Ok(())
-}
+        if bundle.transactions().any(|t| t == txn.as_ref()) {
+            return Selection::Selected;
+        }
 
-async fn get_utxo_count<T>(
-    state: &SharedState<T>,
-    cmd: &Command,
-) -> Result<(), Error>
-where
-    T: JsonRpcClient,
-{
-    let count = state.utxo.get_utxo_count().from(ENTRY_POINT).call().await?;
-    state.events.clone().get(cmd, "utxo_count", count).await;
-    Ok(())
-}
+        if bundle.free_slots() == 0 {
+            let ahead =
+                self.transactions.iter().take_while(|t| *t != txn).count();
 
-async fn get_fee_base<T>(
-    state: &SharedState<T>,
-    cmd: &Command,
-) -> Result<(), Error>
-where
-    T: JsonRpcClient,
-{
-    let base = fetch_base(state).await?;
-    state.events.clone().get(cmd, "fee_base", base).await;
-    Ok(())
-}
+            return Selection::BundleFull { ahead };
+        }
 
-async fn show_transfers<T>(state: &SharedState<T>, cmd: &Command)
-where
-    T: JsonRpcClient,
-{
-    let mut events = state.events.clone();
+        if !self.reservations.admits(bundle, txn) {
+            return Selection::Reserved;
+        }
 
-    let pending = state.pending.lock().await;
-    for txn in pending.transactions.iter() {
-        if let Txn::Transfer(t) = txn {
-            events.reply(cmd, t.to_string()).await;
+        if !self.caps.admits(bundle, txn) {
+            return Selection::Capped;
+        }
+
+        let estimate = bundle.estimate_price(base);
+
+        let mut with_txn = bundle.clone();
+        with_txn.insert(txn.clone());
+
+        if with_txn.estimate_price(base) <= estimate {
+            Selection::TooCheap {
+                gasprice: *txn.gas_price(),
+                estimate,
+            }
+        } else {
+            Selection::Deferred
         }
     }
-}
 
-async fn show_withdrawals<T>(state: &SharedState<T>, cmd: &Command)
-where
-    T: JsonRpcClient,
-{
-    let mut events = state.events.clone();
+    /// Works out what forcing deposit `id` into the best bundle would do,
+    /// without changing anything.
+    fn simulate_claim(
+        self,
+        best: Option<&Bundle>,
+        id: &U256,
+        base: U256,
+    ) -> Result<ClaimSimulation, Error> {
+        let deposit = match self.deposits.get(id) {
+            Some(d) => d,
+            None => return Err(format!("no pending deposit {}", id).into()),
+        };
+
+        let mut bundle = best.cloned().unwrap_or_default();
+        let price_before = bundle.estimate_price(base);
+        let fee = Deposit::marginal_fee(&bundle.claim.gasprice);
+
+        let outcome = if bundle.deposits().any(|d| d == id) {
+            ClaimOutcome::AlreadyIncluded
+        } else if bundle.insert_deposit(*id).is_some() {
+            ClaimOutcome::NoFreeSlot
+        } else if deposit.bounty < fee {
+            ClaimOutcome::Unprofitable
+        } else {
+            ClaimOutcome::Included
+        };
 
-    let pending = state.pending.lock().await;
-    for txn in pending.transactions.iter() {
-        if let Txn::Withdrawal(w) = txn {
-            events.reply(cmd, w.to_string()).await;
+        Ok(ClaimSimulation {
+            id: *id,
+            bounty: deposit.bounty,
+            fee,
+            price_before,
+            price_after: bundle.estimate_price(base),
+            outcome,
+        })
+    }
+
+    /// Packs every pending transaction, highest gas price first, into as
+    /// many bundles as it takes. Unlike `select`, no deposits are claimed
+    /// and reservations and caps are ignored.
+    fn export(self) -> Vec<Bundle> {
+        let mut bundles = vec![];
+        let mut remaining: Vec<Txn> =
+            self.transactions.iter().cloned().collect();
+
+        while !remaining.is_empty() {
+            let (bundle, overflow) =
+                Bundle::from_transactions(Bundle::new().claim, remaining);
+
+            bundles.push(bundle);
+            remaining = overflow;
         }
+
+        bundles
     }
-}
 
-async fn show_deposits<T>(state: &SharedState<T>, cmd: &Command)
-where
-    T: JsonRpcClient,
-{
-    let mut events = state.events.clone();
+    /// Picks a bundle at each of `bases`, without touching the best bundle.
+    fn what_if_base(self, bases: &[U256]) -> Vec<BaseFeeRow> {
+        bases
+            .iter()
+            .map(|base| {
+                let bundle = self.select(*base);
 
-    let pending = state.pending.lock().await;
-    for deposit in pending.deposits.iter() {
-        events.reply(cmd, deposit.to_string()).await;
+                BaseFeeRow {
+                    base: *base,
+                    estimate: bundle.estimate_price(*base),
+                    slots: bundle.full_slots(),
+                }
+            })
+            .collect()
     }
-}
 
-async fn insert_txn<T>(
-    state: &SharedState<T>,
-    cmd: &Command,
-    txn: Txn,
-) -> Result<(), Error>
-where
-    T: JsonRpcClient,
-{
-    let mut events = state.events.clone();
-    let mut pending = state.pending.lock().await;
+    /// Picks the most valuable bundle from the pools.
+    fn select(self, base: U256) -> Bundle {
+        self.select_with_floor(base).0
+    }
+
+    /// Like `select`, but also returns the gas price of the transaction that
+    /// ended the search, or `None` if every transaction was looked at. Adding
+    /// or removing a transaction paying less than that can't change the pick.
+    fn select_with_floor(self, base: U256) -> (Bundle, Option<U256>) {
+        let mut bundle = Bundle::new();
+        let mut price = bundle.estimate_price(base);
+
+        // The lowest gas price and the slots of just the transfers and
+        // withdrawals in `bundle`. Each step keeps those and rebuilds the
+        // claim, so that's all a candidate needs to know about `bundle`.
+        let mut txn_min: Option<U256> = None;
+        let mut txn_slots = 0;
+
+        // TODO: Handle the case where there are only deposits and no
+        //       other transactions.
+
+        for txn in self.ordered().into_iter() {
+            if !self.reservations.admits(&bundle, txn)
+                || !self.caps.admits(&bundle, txn)
+            {
+                continue;
+            }
+
+            let gp = *txn.gas_price();
+
+            // Price the bundle with the next best transaction added, without
+            // building it: copying every transfer and withdrawal only to
+            // throw the copy away is most of the work on a losing step.
+            let slots = Bundle::slots_of(txn);
+            let fits = Bundle::MAX_SLOTS - txn_slots >= slots;
+
+            let (new_min, new_slots) = if fits {
+                (Some(txn_min.map_or(gp, |m| m.min(gp))), txn_slots + slots)
+            } else {
+                (txn_min, txn_slots)
+            };
+
+            // NB: There an attack where a malicious bundler Alice could
+            //     repackage Bob's claim with more transactions than Bob
+            //     anticipated. This isn't an issue here, since this assumes
+            //     each claim pays the full gas price, but something to be
+            //     aware of.
+
+            // Count deposits that break even at txn's gas price.
+            //
+            // TODO: This is likely too conservative. It misses cases where
+            //       multiple deposits together would be profitable if the
+            //       first deposit isn't profitable on its own.
+            let room =
+                (Bundle::MAX_SLOTS - new_slots) / Bundle::SLOTS_PER_CLAIM;
+            let deposits = self.deposits.iter_by_profit(&gp).take(room).count();
+
+            let claim = if deposits == 0 { None } else { Some(gp) };
+            let claim_slots = deposits * Bundle::SLOTS_PER_CLAIM;
+            let new_price =
+                Bundle::price_of(new_min, claim, new_slots + claim_slots, base);
+
+            if price >= new_price {
+                return (bundle, Some(gp));
+            }
+
+            // Only now that it's better, build it. Empty the claim first, so
+            // `insert` sees the free slots the priced bundle had.
+            bundle.claim.deposits.clear();
+
+            if fits {
+                bundle.insert(txn.clone());
+                txn_min = new_min;
+                txn_slots = new_slots;
+            }
+
+            let profitable = self.deposits.iter_by_profit(&gp).map(|(d, _)| d);
+            bundle.claim.gasprice = gp;
+            bundle.claim.deposits = self.weights.pick(profitable, deposits);
+
+            price = new_price;
+        }
+
+        (bundle, None)
+    }
+
+    /// Looks past where `select` stops for a bundle paying at least `target`
+    /// for gas, adding transactions in the same order even while the price
+    /// drops, in case the extra slots make up for a lower minimum gas price.
+    /// Returns the first bundle reaching `target`, so no more is bid than
+    /// needed, or `None` if none does.
+    fn select_to_outbid(self, base: U256, target: U256) -> Option<Bundle> {
+        let mut bundle = Bundle::new();
+
+        for txn in self.ordered().into_iter() {
+            if !self.reservations.admits(&bundle, txn)
+                || !self.caps.admits(&bundle, txn)
+            {
+                continue;
+            }
+
+            // The claim is rebuilt for each step, so it mustn't take up slots
+            // the transaction needs.
+            bundle.claim.deposits.clear();
+
+            if bundle.insert(txn.clone()).is_some() {
+                break;
+            }
+
+            let gp = *txn.gas_price();
+            let room = bundle.free_slots() / Bundle::SLOTS_PER_CLAIM;
+            let deposits = self.deposits.iter_by_profit(&gp).take(room).count();
+            let profitable = self.deposits.iter_by_profit(&gp).map(|(d, _)| d);
+
+            bundle.claim.gasprice = gp;
+            bundle.claim.deposits = self.weights.pick(profitable, deposits);
+
+            if bundle.estimate_price(base) >= target {
+                return Some(bundle);
+            }
+        }
+
+        None
+    }
+
+    /// Pending transactions in the order they're considered for a bundle.
+    fn ordered(self) -> Vec<&'a Txn> {
+        let mut ordered: Vec<&Txn> = self.transactions.iter().collect();
+
+        // Already sorted by gas price, and the sort is stable, so this only
+        // reorders transactions paying the same gas.
+        if let Some(prefer) = self.prefer {
+            ordered.sort_by_key(|t| (Reverse(*t.gas_price()), prefer.rank(t)));
+        }
+
+        ordered
+    }
+}
+
+/// The bundle that would be picked at one fee base.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct BaseFeeRow {
+    base: U256,
+    estimate: U256,
+    slots: usize,
+}
+
+impl std::fmt::Display for BaseFeeRow {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "base {}: price {} with {} slot(s) filled",
+            self.base, self.estimate, self.slots,
+        )?;
+
+        if self.slots == 0 {
+            write!(f, ", nothing worth bundling")
+        } else if self.estimate < self.base {
+            write!(f, ", below the fee base")
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// The most fee bases `whatif base` reports on at once.
+const MAX_WHAT_IF_ROWS: usize = 100;
+
+/// Every fee base from `low` to `high`, `step` apart, or an error if there
+/// would be none or too many.
+fn what_if_bases(
+    low: U256,
+    high: U256,
+    step: U256,
+) -> Result<Vec<U256>, Error> {
+    if step.is_zero() {
+        return Err("step must be greater than zero".into());
+    }
+
+    if high < low {
+        return Err("high must be at least low".into());
+    }
+
+    let too_many = || {
+        let msg = format!("at most {} fee bases at once", MAX_WHAT_IF_ROWS);
+        Error::from(msg)
+    };
+
+    let rows = ((high - low) / step)
+        .checked_add(U256::one())
+        .ok_or_else(too_many)?;
+
+    if rows > MAX_WHAT_IF_ROWS.into() {
+        return Err(too_many());
+    }
+
+    Ok((0..rows.as_usize()).map(|ii| low + step * ii).collect())
+}
+
+/// Would-be effect of adding one deposit to the current best bundle.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ClaimSimulation {
+    id: U256,
+    bounty: U256,
+    fee: U256,
+    price_before: U256,
+    price_after: U256,
+    outcome: ClaimOutcome,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ClaimOutcome {
+    /// The best bundle already claims the deposit.
+    AlreadyIncluded,
+
+    /// The deposit pays for itself and fits in the best bundle.
+    Included,
+
+    /// The bounty doesn't cover the fee for claiming the deposit.
+    Unprofitable,
+
+    /// The best bundle has no slot left for the claim.
+    NoFreeSlot,
+}
+
+impl std::fmt::Display for ClaimSimulation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let outcome = match self.outcome {
+            ClaimOutcome::AlreadyIncluded => "already in the best bundle",
+            ClaimOutcome::Included => "would be included",
+            ClaimOutcome::Unprofitable => "not worth claiming",
+            ClaimOutcome::NoFreeSlot => "no free slot in the best bundle",
+        };
+
+        write!(
+            f,
+            "deposit {}: {} (bounty={} fee={} bundle price {} -> {})",
+            self.id,
+            outcome,
+            self.bounty,
+            self.fee,
+            self.price_before,
+            self.price_after,
+        )
+    }
+}
+
+/// Why a pending transaction is or isn't in the best bundle.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Selection {
+    /// No pending transaction spends the input. It may never have been
+    /// submitted, or lost to a conflicting transaction paying more gas.
+    NotPending,
+
+    /// Nothing has been worth bundling yet.
+    NoBundle,
+
+    /// The transaction is in the best bundle.
+    Selected,
+
+    /// The best bundle has no free slots, and `ahead` pending transactions
+    /// pay at least as much gas.
+    BundleFull { ahead: usize },
+
+    /// Adding the transaction wouldn't raise the best bundle's price.
+    TooCheap { gasprice: U256, estimate: U256 },
+
+    /// Adding the transaction would take a slot reserved for the other kind.
+    Reserved,
+
+    /// The best bundle already holds as many of the transaction's kind as
+    /// `--max-bundle-transfers` or `--max-bundle-withdrawals` allow.
+    Capped,
+
+    /// The transaction would improve the best bundle, which will happen the
+    /// next time it is regenerated.
+    Deferred,
+}
+
+impl std::fmt::Display for Selection {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Selection::NotPending => write!(f, "no pending transaction"),
+            Selection::NoBundle => write!(f, "no bundle generated yet"),
+            Selection::Selected => write!(f, "in the best bundle"),
+            Selection::BundleFull { ahead } => write!(
+                f,
+                "bundle full, {} transaction(s) pay at least as much gas",
+                ahead
+            ),
+            Selection::TooCheap { gasprice, estimate } => write!(
+                f,
+                "gasprice {} wouldn't raise the bundle price of {}",
+                gasprice, estimate
+            ),
+            Selection::Reserved => {
+                write!(f, "remaining slots are reserved for the other kind")
+            }
+            Selection::Capped => {
+                write!(f, "bundle already holds the most allowed of its kind")
+            }
+            Selection::Deferred => {
+                write!(f, "would improve the bundle on the next regenerate")
+            }
+        }
+    }
+}
+
+/// A deployment of the UTXO contract, and everything tracked for it.
+pub struct Market<T> {
+    address: Address,
+    utxo: Utxo<T, RelayerSigner>,
+    dropsafe: Address,
+
+    // Locked separately, so working out a bundle only needs to share the
+    // pools, and nothing waits on it to publish the result. When taking more
+    // than one, take them in this order.
+    deposits: TimedRwLock<DepositPool>,
+    transactions: TimedRwLock<Pool<Txn>>,
+    reservations: TimedRwLock<Reservations>,
+    best_bundle: TimedMutex<Option<Bundle>>,
+
+    /// How the best bundle was picked. Only locked while holding
+    /// `best_bundle`.
+    frontier: Mutex<Option<Frontier>>,
+
+    /// From `--prefer`, which never changes.
+    prefer: Option<Preference>,
+
+    /// From `--max-bundle-transfers` and `--max-bundle-withdrawals`.
+    caps: Caps,
+
+    /// From `--owner-weights`, which is only read at startup.
+    weights: Weights,
+
+    chain: Mutex<Chain>,
+
+    /// What was last sent, so `rebroadcast` can leave it be while the node
+    /// still has it.
+    last_sent: Mutex<Option<Sent>>,
+
+    /// Least gas price signed bundles are broadcast with (see `gas_price_for`),
+    /// reset with each new block. Only raised when the node refuses a signed
+    /// broadcast as underpriced.
+    gas_price: Mutex<U256>,
+}
+
+impl<T> Market<T> {
+    fn new(
+        address: Address,
+        utxo: Utxo<T, RelayerSigner>,
+        dropsafe: Address,
+        prefer: Option<Preference>,
+        caps: Caps,
+        weights: Weights,
+    ) -> Self {
+        Self {
+            address,
+            utxo,
+            dropsafe,
+            deposits: TimedRwLock::new(DepositPool::default()),
+            transactions: TimedRwLock::new(Pool::default()),
+            reservations: TimedRwLock::new(Reservations::default()),
+            best_bundle: TimedMutex::new(None),
+            frontier: Mutex::new(None),
+            prefer,
+            caps,
+            weights,
+            chain: Mutex::new(Chain::default()),
+            last_sent: Mutex::new(None),
+            gas_price: Mutex::new(U256::zero()),
+        }
+    }
+
+    /// Takes read locks on every pool.
+    async fn read_pools(&self) -> PoolsGuard<'_> {
+        PoolsGuard {
+            deposits: self.deposits.read().await,
+            transactions: self.transactions.read().await,
+            reservations: self.reservations.read().await,
+            caps: self.caps,
+            prefer: self.prefer,
+            weights: &self.weights,
+        }
+    }
+
+    /// Empty pools, as big as this market's and selecting the way it does,
+    /// for work that must never reach a real bundle.
+    async fn scratch(&self) -> Pending {
+        let mut pending = Pending {
+            caps: self.caps,
+            prefer: self.prefer,
+            weights: self.weights.clone(),
+            ..Pending::default()
+        };
+
+        let deposits = self.deposits.read().await.max_len();
+        pending.deposits.set_max_len(deposits);
+
+        let transactions = self.transactions.read().await.max_len();
+        pending.transactions.set_max_len(transactions);
+
+        pending
+    }
+
+    /// Changes whenever any of the pools is written.
+    fn pools_version(&self) -> u64 {
+        self.deposits.writes()
+            + self.transactions.writes()
+            + self.reservations.writes()
+    }
+
+    /// Adds the transfers and withdrawals from `bundle` to the pool,
+    /// returning the id and gas price of each one added.
+    async fn absorb(&self, bundle: Bundle) -> Vec<(Correlation, U256)> {
+        absorb(&mut *self.transactions.write().await, bundle)
+    }
+
+    /// Replaces the best bundle with one paying at least `target` for gas, if
+    /// the pools can make one, to outbid a competitor.
+    async fn outbid(&self, base: U256, target: U256) -> Option<Bundle> {
+        loop {
+            let (bundle, version) = {
+                let pools = self.read_pools().await;
+                let bundle = pools.pools().select_to_outbid(base, target)?;
+                (bundle, self.pools_version())
+            };
+
+            let mut best = self.best_bundle.lock().await;
+
+            // As in `publish`, the bundle may be out of date already.
+            if self.pools_version() != version {
+                continue;
+            }
+
+            // Not what `select` picks, so nothing can be kept on its account.
+            *self.frontier.lock().await = None;
+
+            *best = Some(bundle.clone());
+            return Some(bundle);
+        }
+    }
+
+    /// Replaces the best bundle, even with a worse one.
+    async fn generate(&self, base: U256) -> Option<Bundle> {
+        self.publish(base, true).await
+    }
+
+    /// Replaces the best bundle if a better one can be made from the pools.
+    async fn regenerate(&self, base: U256) -> Option<Bundle> {
+        self.publish(base, false).await
+    }
+
+    /// Keeps the best bundle after the pools lost transactions paying
+    /// `removed`, instead of regenerating it, if that can't change the pick.
+    ///
+    /// That's when the bundle was picked at the same `base`, the only writes
+    /// since then took the pools from version `before` to `after`, and each
+    /// removed transaction pays less than the floor. Otherwise returns `None`,
+    /// and the bundle has to be regenerated.
+    async fn keep_best(
+        &self,
+        base: U256,
+        before: u64,
+        after: u64,
+        removed: &[U256],
+    ) -> Option<Bundle> {
+        let best = self.best_bundle.lock().await;
+        let mut frontier = self.frontier.lock().await;
+
+        let frontier = frontier.as_mut()?;
+        let floor = frontier.floor?;
+
+        if frontier.version != before
+            || frontier.base != base
+            || self.pools_version() != after
+        {
+            return None;
+        }
+
+        if removed.iter().any(|gp| *gp >= floor) {
+            return None;
+        }
+
+        frontier.version = after;
+        best.clone()
+    }
+
+    async fn publish(&self, base: U256, reset: bool) -> Option<Bundle> {
+        loop {
+            let (candidate, floor, version) = {
+                let pools = self.read_pools().await;
+
+                // Not worth building an empty bundle just to compare it.
+                if !reset && pools.pools().is_idle(base) {
+                    return None;
+                }
+
+                let (candidate, floor) = pools.pools().select_with_floor(base);
+                (candidate, floor, self.pools_version())
+            };
+
+            let mut best = self.best_bundle.lock().await;
+
+            // The pools changed while the bundle was being picked, so it may
+            // hold something that's no longer pending. Pick again.
+            if self.pools_version() != version {
+                continue;
+            }
+
+            if !reset && !improves(best.as_ref(), &candidate, base) {
+                return None;
+            }
+
+            *self.frontier.lock().await = Some(Frontier {
+                version,
+                base,
+                floor,
+            });
+
+            *best = Some(candidate.clone());
+            return Some(candidate);
+        }
+    }
+}
+
+/// A bundle as it was last sent for one market.
+#[derive(Debug, Clone)]
+struct Sent {
+    calldata: Vec<u8>,
+    gas_price: U256,
+    hash: TxHash,
+}
+
+/// What picking the best bundle depended on, so it can be kept when the
+/// pools change in ways that don't matter.
+#[derive(Debug, Clone, Copy)]
+struct Frontier {
+    /// `Market::pools_version` when the bundle was picked.
+    version: u64,
+
+    /// Fee base the bundle was picked at.
+    base: U256,
+
+    /// From `Pools::select_with_floor`.
+    floor: Option<U256>,
+}
+
+/// Read locks on all of a market's pools.
+struct PoolsGuard<'a> {
+    deposits: RwLockReadGuard<'a, DepositPool>,
+    transactions: RwLockReadGuard<'a, Pool<Txn>>,
+    reservations: RwLockReadGuard<'a, Reservations>,
+    caps: Caps,
+    prefer: Option<Preference>,
+    weights: &'a Weights,
+}
+
+impl<'a> PoolsGuard<'a> {
+    fn pools(&self) -> Pools {
+        Pools {
+            deposits: &self.deposits,
+            transactions: &self.transactions,
+            reservations: &self.reservations,
+            caps: self.caps,
+            prefer: self.prefer,
+            weights: self.weights,
+        }
+    }
+}
+
+pub struct State<T> {
+    opts: Opts,
+    events: Events,
+    provider: Provider<T>,
+
+    /// The same node as `provider`, for methods it doesn't wrap.
+    node: T,
+
+    markets: BTreeMap<Address, Market<T>>,
+    tasks: Semaphore,
+    broadcasted: AtomicBool,
+
+    /// Set by the `pause` command, so `broadcast` sends nothing.
+    paused: AtomicBool,
+
+    /// Set while the node reports a chain other than `--chain-id`, which
+    /// also stops `broadcast`, whatever `resume` says.
+    wrong_chain: AtomicBool,
+
+    /// Address our broadcasts come from.
+    sender: Address,
+
+    /// Signs transactions from `sender` for the public mempool.
+    signer: RelayerSigner,
+
+    /// One more than the highest nonce broadcast to the public mempool, to
+    /// notice when one never made it there.
+    next_nonce: Mutex<U256>,
+
+    /// Where to submit bundles, if not the public mempool.
+    relay: Option<PrivateRelay>,
+
+    block_activity: Activity,
+    transaction_activity: Activity,
+
+    /// Time from a block being mined until it's processed.
+    block_lag: Lag,
+
+    /// Time from a pending transaction being first seen until it's processed.
+    pending_lag: Lag,
+
+    /// The last `--history-size` bundles broadcast.
+    history: History,
+
+    /// The last `--trace-size` steps taken by transactions.
+    trace: Trace,
+}
+
+impl<T> State<T> {
+    /// Traces each of the transactions in `added` as pooled for `contract`,
+    /// returning their ids for `PoolAdd`.
+    fn trace_pooled(
+        &self,
+        contract: Address,
+        added: Vec<(Correlation, U256)>,
+    ) -> Vec<Correlation> {
+        added
+            .into_iter()
+            .map(|(correlation, gasprice)| {
+                let stage = Stage::Pooled { gasprice };
+                self.trace.record(contract, correlation.clone(), stage);
+                correlation
+            })
+            .collect()
+    }
+
+    /// Whether the transaction `hash` is a bundle we broadcast to `contract`,
+    /// as far as the history or trace remember. Under the abstract signer,
+    /// every bundle is sent from the entry point, so the sender can't tell.
+    fn broadcast_by_us(&self, contract: Address, hash: &TxHash) -> bool {
+        self.history.contains(contract, hash)
+            || self.trace.broadcast_in(contract, hash)
+    }
+
+    /// Finds the market a command applies to, which only needs to be named
+    /// when there's more than one.
+    fn market(&self, contract: Option<Address>) -> Result<&Market<T>, Error> {
+        if let Some(address) = contract {
+            return match self.markets.get(&address) {
+                Some(m) => Ok(m),
+                None => Err(format!("not relaying for {:?}", address).into()),
+            };
+        }
+
+        let mut markets = self.markets.values();
+
+        match (markets.next(), markets.next()) {
+            (Some(m), None) => Ok(m),
+            _ => Err("relaying for several contracts, use --contract".into()),
+        }
+    }
+
+    /// Finds the market `tx` calls, either directly or through one of the
+    /// `--proxy-address` contracts. Through a proxy, the returned transaction's
+    /// input is narrowed down to the `transact` call inside it.
+    fn market_for<'a>(
+        &self,
+        tx: &'a EthTransaction,
+    ) -> Option<(&Market<T>, Cow<'a, EthTransaction>)> {
+        let to = tx.to?;
+
+        if let Some(market) = self.markets.get(&to) {
+            return Some((market, Cow::Borrowed(tx)));
+        }
+
+        if !self.opts.proxy_address.contains(&to) {
+            return None;
+        }
+
+        let market = self.market(None).ok()?;
+        let inner = Bundle::find_transact(&tx.input.0)?;
+
+        let mut unwrapped = tx.clone();
+        unwrapped.input = inner.to_vec().into();
+
+        Some((market, Cow::Owned(unwrapped)))
+    }
+
+    /// Every address transactions carrying bundles are sent to: the UTXO
+    /// contracts, and any `--proxy-address`.
+    fn watched_addresses(&self) -> Vec<Address> {
+        self.markets
+            .keys()
+            .chain(self.opts.proxy_address.iter())
+            .copied()
+            .collect()
+    }
+
+    /// Finds the market whose drop safe is at `address`.
+    fn market_by_dropsafe(&self, address: Address) -> Option<&Market<T>> {
+        self.markets.values().find(|m| m.dropsafe == address)
+    }
+}
+
+pub type SharedState<T> = Arc<State<T>>;
+
+/// One of `State::tasks`'s permits, returned when dropped.
+struct TaskPermit<T>(SharedState<T>);
+
+impl<T> TaskPermit<T> {
+    async fn acquire(state: &SharedState<T>) -> Self {
+        let permit = match state.tasks.try_acquire() {
+            Ok(p) => p,
+            Err(_) => {
+                let limit = state.opts.max_concurrent_tasks.get();
+                state.events.clone().saturated(limit).await;
+                state.tasks.acquire().await
+            }
+        };
+
+        permit.forget();
+        TaskPermit(state.clone())
+    }
+}
+
+impl<T> Drop for TaskPermit<T> {
+    fn drop(&mut self) {
+        self.0.tasks.add_permits(1);
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    let opts = Opts::from_args();
+
+    let caps = Caps {
+        transfers: opts.max_bundle_transfers,
+        withdrawals: opts.max_bundle_withdrawals,
+    };
+
+    if let Some(ref path) = opts.replay {
+        return replay(path, opts.replay_fee_base, opts.prefer, caps);
+    }
+
+    let weights = match opts.owner_weights {
+        Some(ref path) => std::fs::read_to_string(path)?
+            .parse::<Weights>()
+            .map_err(|e| format!("{}: {}", path.display(), e))?,
+        None => Weights::default(),
+    };
+
+    let handle = tokio::runtime::Handle::current();
+    let mut ui = ui::Ui::start(
+        handle,
+        opts.oob,
+        opts.headless,
+        opts.control_socket.as_deref(),
+        opts.script.as_deref(),
+        opts.event_capacity,
+    )?;
+
+    let provider = Provider::try_from(NODE_URL)?;
+    let node: Http = NODE_URL.parse()?;
+    let chain_id = provider.get_chainid().await.context(Rpc)?;
+    ensure!(
+        chain_id == opts.chain_id.into(),
+        ChainMismatch {
+            expected: opts.chain_id,
+            actual: chain_id,
+        }
+    );
+
+    if opts.min_balance.is_some() && opts.signer == SignerKind::Abstract {
+        return Err("--min-balance needs a wallet or external signer".into());
+    }
+
+    let signer = RelayerSigner::new(&opts.signer, opts.chain_id)?;
+    let sender = signer.address();
+
+    let relay = match opts.private_relay_url {
+        Some(_) if opts.signer == SignerKind::Abstract => {
+            let msg = "--private-relay-url needs a wallet or external signer";
+            return Err(msg.into());
+        }
+        Some(ref http) => Some(PrivateRelay::new(
+            http.clone(),
+            signer.clone(),
+            opts.builder_payment,
+        )),
+        None if opts.builder_payment.is_some() => {
+            let msg = "--builder-payment needs --private-relay-url";
+            return Err(msg.into());
+        }
+        None => None,
+    };
+
+    let client = Client::new(provider.clone(), signer.clone());
+
+    let addresses = if opts.utxo.is_empty() {
+        vec![UTXO]
+    } else {
+        opts.utxo.clone()
+    };
+
+    // Blocks deeper than the chain tracks are forgotten before confirming.
+    if opts.confirmations >= Chain::DEFAULT_MAX_DEPTH {
+        let msg = format!(
+            "--confirmations must be less than {}",
+            Chain::DEFAULT_MAX_DEPTH
+        );
+        return Err(msg.into());
+    }
+
+    if !opts.proxy_address.is_empty() && addresses.len() > 1 {
+        return Err("--proxy-address needs exactly one --utxo".into());
+    }
+
+    // The node may have dropped broadcasts from before a restart, which
+    // `watch_nonces` can only notice if it knows about them.
+    let next_nonce = match opts.nonce_file {
+        Some(ref path) => {
+            let pending = provider
+                .get_transaction_count(sender, Some(BlockNumber::Pending))
+                .await
+                .context(Rpc)?;
+
+            match read_nonce_file(path)? {
+                Some(saved) => saved.max(pending),
+                None => pending,
+            }
+        }
+        None => U256::zero(),
+    };
+
+    let mut markets = BTreeMap::new();
+
+    for address in addresses.into_iter() {
+        let utxo = Utxo::new(address, client.clone());
+        let dropsafe = utxo.dropsafe().from(ENTRY_POINT).call().await?;
+        let market = Market::new(
+            address,
+            utxo,
+            dropsafe,
+            opts.prefer,
+            caps,
+            weights.clone(),
+        );
+        markets.insert(address, market);
+    }
+
+    let state = Arc::new(State {
+        provider,
+        node,
+        markets,
+        events: ui.events(),
+        tasks: Semaphore::new(opts.max_concurrent_tasks.get()),
+        broadcasted: AtomicBool::new(false),
+        paused: AtomicBool::new(false),
+        wrong_chain: AtomicBool::new(false),
+        sender,
+        signer,
+        next_nonce: Mutex::new(next_nonce),
+        relay,
+        block_activity: Activity::default(),
+        transaction_activity: Activity::default(),
+        block_lag: Lag::default(),
+        pending_lag: Lag::default(),
+        history: History::new(opts.history_size),
+        trace: Trace::new(opts.trace_size),
+        opts,
+    });
+
+    let cmd_watcher =
+        tokio::spawn(execute_commands(state.clone(), ui.commands()));
+
+    if state.opts.min_balance.is_some() {
+        tokio::spawn(watch_balance(state.clone()));
+    }
+
+    tokio::spawn(watch_chain(state.clone()));
+
+    if let Some(addr) = state.opts.health_addr {
+        let listener = TcpListener::bind(addr).await?;
+        let state = state.clone();
+
+        tokio::spawn(health::serve(listener, move || {
+            health_problems(state.clone())
+        }));
+    }
+
+    // Abstract broadcasts aren't really signed, so their nonces don't matter.
+    if state.opts.signer != SignerKind::Abstract {
+        tokio::spawn(watch_nonces(state.clone()));
+    }
+
+    if let Some(secs) = state.opts.rebroadcast_interval {
+        let period = Duration::from_secs(secs);
+        tokio::spawn(rebroadcast(state.clone(), period));
+    }
+
+    let block_watcher = async {
+        let watcher = supervise(
+            state.clone(),
+            "block watcher",
+            |s| &s.block_activity,
+            process_blocks,
+        );
+
+        tokio::spawn(watcher).await?
+    };
+
+    let bundle_watcher = async {
+        let watcher = supervise(
+            state.clone(),
+            "transaction watcher",
+            |s| &s.transaction_activity,
+            process_transactions,
+        );
+
+        tokio::spawn(watcher).await?
+    };
+
+    let deposit_watcher =
+        async { tokio::spawn(process_deposits(state.clone())).await? };
+
+    let watchers = async {
+        if state.opts.once {
+            // Only the block watcher finishes on its own, so stop everything
+            // once it has processed its block.
+            let others = async {
+                tokio::try_join!(bundle_watcher, deposit_watcher).map(|_| ())
+            };
+
+            tokio::select! {
+                r = block_watcher => r?,
+                r = others => r?,
+            }
+
+            return Ok(());
+        }
+
+        tokio::try_join!(block_watcher, bundle_watcher, deposit_watcher)?;
+
+        cmd_watcher.await?;
+
+        Ok::<(), Error>(())
+    };
+
+    let result = tokio::select! {
+        r = watchers => r,
+
+        // The reader finishes when the user asks to exit (ex. with ctrl-d.)
+        _ = ui.closed() => Ok(()),
+    };
+
+    ui.shutdown();
+
+    result
+}
+
+fn replay(
+    path: &Path,
+    base: U256,
+    prefer: Option<Preference>,
+    caps: Caps,
+) -> Result<(), Error> {
+    let contents = std::fs::read_to_string(path)?;
+    let mut pending = Pending {
+        prefer,
+        caps,
+        ..Pending::default()
+    };
+
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        match replay_line(&mut pending, line, base) {
+            Ok(Some(bundle)) => {
+                let event = EventKind::Broadcast(bundle, None);
+                println!("{}: {}", index + 1, event)
+            }
+            Ok(None) => println!("{}: no new bundle", index + 1),
+            Err(e) => println!("{}: skipped: {}", index + 1, e),
+        }
+    }
+
+    Ok(())
+}
+
+/// Feeds one line of recorded calldata through the same path as a pending
+/// transaction, returning the bundle that would be broadcast, if any.
+fn replay_line(
+    pending: &mut Pending,
+    line: &str,
+    base: U256,
+) -> Result<Option<Bundle>, Error> {
+    let input: Vec<u8> = line.trim_start_matches("0x").from_hex()?;
+
+    if input.len() < 4 {
+        return Err("calldata too short".into());
+    }
+
+    let bundle = Bundle::decode_slice(&input).context(Decode)?;
+    pending.absorb(bundle);
+
+    Ok(pending.regenerate(base).cloned())
+}
+
+/// Decodes hex `transact` calldata into one line for the claim, then one for
+/// each transfer and withdrawal.
+fn describe_calldata(calldata: &str) -> Result<Vec<String>, Error> {
+    let input: Vec<u8> = calldata.trim_start_matches("0x").from_hex()?;
+    let bundle = Bundle::decode_slice(&input).context(Decode)?;
+
+    let claim = format!("claim {}", bundle.claim);
+    let txns = bundle.transactions().map(|t| t.to_string());
+
+    Ok(std::iter::once(claim).chain(txns).collect())
+}
+
+async fn execute_commands<T>(
+    state: SharedState<T>,
+    mut commands: mpsc::Receiver<Command>,
+) where
+    T: JsonRpcClient,
+{
+    let mut events = state.events.clone();
+
+    while let Some(cmd) = commands.recv().await {
+        if let Err(e) = try_execute_command(&state, &cmd).await {
+            events.reply(&cmd, EventKind::CommandError(e)).await;
+        }
+    }
+
+    // The watchers keep running without commands, so this isn't fatal.
+    events.oob("No longer accepting commands").await;
+}
+
+async fn try_execute_command<T>(
+    state: &SharedState<T>,
+    cmd: &Command,
+) -> Result<(), Error>
+where
+    T: JsonRpcClient,
+{
+    let mut events = state.events.clone();
+
+    // These act on every contract, or none, so don't need one picked out.
+    match cmd.kind() {
+        CommandKind::Pause => {
+            let msg = if state.paused.swap(true, Ordering::SeqCst) {
+                "broadcasting was already paused"
+            } else {
+                "broadcasting paused"
+            };
+
+            events.reply(cmd, msg).await;
+            return Ok(());
+        }
+        CommandKind::Resume => return resume(state, cmd).await,
+        CommandKind::Decode(DecodeCalldata { calldata }) => {
+            for line in describe_calldata(calldata)?.into_iter() {
+                events.reply(cmd, line).await;
+            }
+
+            return Ok(());
+        }
+        _ => (),
+    }
+
+    let market = state.market(cmd.contract())?;
+
+    match cmd.kind() {
+        CommandKind::Transfer(xfr) => {
+            insert_transfer(state, market, cmd, xfr.clone().try_into()?)
+                .await?;
+        }
+        CommandKind::Withdraw(wdw) => {
+            insert_withdrawal(state, market, cmd, wdw.clone().try_into()?)
+                .await?;
+        }
+        CommandKind::CancelTransfer(cancel) => {
+            cancel_transfer(state, market, cmd, cancel).await?
+        }
+        CommandKind::Show(show) => match show.what {
+            PoolType::Transfers(filter) => {
+                show_transfers(state, market, cmd, filter).await
+            }
+            PoolType::Withdrawals(filter) => {
+                show_withdrawals(state, market, cmd, filter).await
+            }
+            PoolType::Transactions(filter) => {
+                show_transactions(state, market, cmd, filter).await
+            }
+            PoolType::Deposits(limit) => {
+                show_deposits(state, market, cmd, limit).await?
+            }
+            PoolType::Dropsafe => show_dropsafe(state, market, cmd).await?,
+        },
+        CommandKind::Get(get) => match get {
+            GetType::FeeBase => get_fee_base(state, market, cmd).await?,
+            GetType::UtxoCount => get_utxo_count(state, market, cmd).await?,
+            GetType::Utxo(GetUtxo { id }) => {
+                get_utxo(state, market, cmd, *id).await?
+            }
+            GetType::Deposit(GetDeposit { id }) => {
+                get_deposit(state, market, cmd, *id).await?
+            }
+            GetType::MaxPoolSize => {
+                let size = market.transactions.read().await.max_len();
+                events.get(cmd, "max_pool_size", size).await;
+            }
+            GetType::MaxDepositPoolSize => {
+                let size = market.deposits.read().await.max_len();
+                events.get(cmd, "max_deposit_pool_size", size).await;
+            }
+        },
+        CommandKind::Stats(Stats { buckets }) => {
+            show_stats(state, market, cmd, *buckets).await
+        }
+        CommandKind::Load(LoadType::Deposits(LoadDeposits { path })) => {
+            load_deposits(state, market, cmd, path).await?
+        }
+        CommandKind::Simulate(SimulateType::Claim(SimulateClaim { id })) => {
+            simulate_claim(state, market, cmd, *id).await?
+        }
+        CommandKind::WhatIf(WhatIfType::Base(what_if)) => {
+            what_if_base(state, market, cmd, what_if).await?
+        }
+        CommandKind::Validate => {
+            market.transactions.read().await.validate_indexes()?;
+
+            events.reply(cmd, "transaction pool is consistent").await;
+        }
+        CommandKind::History(ShowHistory { full }) => {
+            show_history(state, market, cmd, *full).await
+        }
+        CommandKind::Trace(ShowTrace { input }) => {
+            show_trace(state, market, cmd, input).await
+        }
+        CommandKind::Explain(Explain { input }) => {
+            explain(state, market, cmd, input).await?
+        }
+        CommandKind::Reserve(reserve) => {
+            reserve_slots(state, market, cmd, reserve).await?
+        }
+        CommandKind::Set(set) => set_pool_size(state, market, cmd, set).await,
+        CommandKind::Export(ExportType::Bundles) => {
+            export_bundles(state, market, cmd).await
+        }
+        CommandKind::Clear(what) => clear_pools(state, market, cmd, what).await,
+        CommandKind::GenerateLoad(load) => {
+            generate_load(state, market, cmd, load).await?
+        }
+        _ => events.reply(cmd, format!("{:?}", cmd)).await,
+    }
+
+    Ok(())
+}
+
+async fn get_utxo<T>(
+    state: &SharedState<T>,
+    market: &Market<T>,
+    cmd: &Command,
+    id: U256,
+) -> Result<(), Error>
+where
+    T: JsonRpcClient,
+{
+    let call = market.utxo.get_utxo(id).from(ENTRY_POINT);
+    let output_tuple = call.call().await.context(Contract)?;
+    let output = Output::from(output_tuple);
+
+    let transactions = market.transactions.read().await;
+    let value = describe_utxo(&output, transactions.get(&id));
+    drop(transactions);
+
+    let name = format!("utxo[{}]", id);
+    state.events.clone().get(cmd, name, value).await;
+    Ok(())
+}
+
+/// Describes the on-chain `output`, along with `pooled`, the pooled
+/// transaction spending it, if any.
+///
+/// `Utxo.sol` deletes outputs as they're spent, so a zero owner means the
+/// output was spent or never existed, and a pooled transaction spending it
+/// can't succeed.
+fn describe_utxo(output: &Output, pooled: Option<&Txn>) -> String {
+    let exists = output.owner != Address::zero();
+
+    match (exists, pooled) {
+        (true, None) => output.to_string(),
+        (true, Some(txn)) => format!("{}; spent by pooled {}", output, txn),
+        (false, None) => "spent or nonexistent".to_string(),
+        (false, Some(txn)) => {
+            format!("spent or nonexistent, yet pooled {} spends it", txn)
+        }
+    }
+}
+
+async fn get_utxo_count<T>(
+    state: &SharedState<T>,
+    market: &Market<T>,
+    cmd: &Command,
+) -> Result<(), Error>
+where
+    T: JsonRpcClient,
+{
+    let call = market.utxo.get_utxo_count().from(ENTRY_POINT);
+    let count = call.call().await.context(Contract)?;
+    state.events.clone().get(cmd, "utxo_count", count).await;
+    Ok(())
+}
+
+async fn simulate_claim<T>(
+    state: &SharedState<T>,
+    market: &Market<T>,
+    cmd: &Command,
+    id: U256,
+) -> Result<(), Error>
+where
+    T: JsonRpcClient,
+{
+    let base = fetch_base(market).await?;
+    let pools = market.read_pools().await;
+    let best = market.best_bundle.lock().await;
+
+    let simulation = pools.pools().simulate_claim(best.as_ref(), &id, base)?;
+    drop((pools, best));
+
+    state
+        .events
+        .clone()
+        .reply(cmd, simulation.to_string())
+        .await;
+
+    Ok(())
+}
+
+async fn what_if_base<T>(
+    state: &SharedState<T>,
+    market: &Market<T>,
+    cmd: &Command,
+    what_if: &WhatIfBase,
+) -> Result<(), Error>
+where
+    T: JsonRpcClient,
+{
+    let bases = what_if_bases(what_if.low, what_if.high, what_if.step)?;
+    let rows = market.read_pools().await.pools().what_if_base(&bases);
+
+    let mut events = state.events.clone();
+
+    for row in rows.into_iter() {
+        events.reply(cmd, row.to_string()).await;
+    }
+
+    Ok(())
+}
+
+async fn reserve_slots<T>(
+    state: &SharedState<T>,
+    market: &Market<T>,
+    cmd: &Command,
+    reserve: &Reserve,
+) -> Result<(), Error>
+where
+    T: JsonRpcClient,
+{
+    let reservations = Reservations {
+        transfers: reserve.transfers,
+        withdrawals: reserve.withdrawals,
+    };
+
+    let slots = reservations.transfers * Bundle::SLOTS_PER_TRANSFER
+        + reservations.withdrawals * Bundle::SLOTS_PER_WITHDRAWAL;
+
+    if slots > Bundle::MAX_SLOTS {
+        let msg =
+            format!("can't reserve more than {} slots", Bundle::MAX_SLOTS);
+        return Err(msg.into());
+    }
+
+    let base = fetch_base(market).await?;
+    *market.reservations.write().await = reservations;
+
+    let mut events = state.events.clone();
+    events
+        .reply(cmd, format!("reserved {:?}", reservations))
+        .await;
+
+    if let Some(new_bundle) = market.generate(base).await {
+        broadcast(state, market, Some(cmd), new_bundle).await?;
+    }
+
+    Ok(())
+}
+
+/// Handles `set max-pool-size` and `set max-deposit-pool-size`.
+async fn set_pool_size<T>(
+    state: &SharedState<T>,
+    market: &Market<T>,
+    cmd: &Command,
+    set: &SetType,
+) where
+    T: JsonRpcClient,
+{
+    let mut events = state.events.clone();
+
+    let (what, size, evicted) = match set {
+        SetType::MaxPoolSize(PoolSize { size }) => {
+            let mut transactions = market.transactions.write().await;
+            ("transaction(s)", *size, transactions.set_max_len(*size))
+        }
+        SetType::MaxDepositPoolSize(PoolSize { size }) => {
+            let mut deposits = market.deposits.write().await;
+            ("deposit(s)", *size, deposits.set_max_len(*size))
+        }
+    };
+
+    // As with `clear`, the best bundle may hold what was just evicted.
+    if evicted > 0 {
+        *market.best_bundle.lock().await = None;
+    }
+
+    let msg = format!("pooling at most {} {}, evicted {}", size, what, evicted);
+    events.reply(cmd, msg).await;
+}
+
+async fn clear_pools<T>(
+    state: &SharedState<T>,
+    market: &Market<T>,
+    cmd: &Command,
+    what: &ClearType,
+) where
+    T: JsonRpcClient,
+{
+    let mut events = state.events.clone();
+
+    let (transactions, deposits) = {
+        let mut deposits = market.deposits.write().await;
+        let mut transactions = market.transactions.write().await;
+
+        let before = transactions.len();
+
+        match what {
+            ClearType::Transfers => {
+                transactions.retain(|t| matches!(t, Txn::Withdrawal(_)));
+                (before - transactions.len(), 0)
+            }
+            ClearType::Withdrawals => {
+                transactions.retain(|t| matches!(t, Txn::Transfer(_)));
+                (before - transactions.len(), 0)
+            }
+            ClearType::Deposits => (0, deposits.clear()),
+            ClearType::All => (transactions.clear(), deposits.clear()),
+        }
+    };
+
+    // Whatever's generated next shouldn't have to beat a bundle made from
+    // what was just cleared.
+    *market.best_bundle.lock().await = None;
+
+    if transactions > 0 {
+        events.reply(cmd, EventKind::PoolDrop(transactions)).await;
+    }
+
+    if deposits > 0 {
+        let msg = format!("Cleared {} deposit(s) from pool", deposits);
+        events.reply(cmd, msg).await;
+    }
+
+    if transactions == 0 && deposits == 0 {
+        events.reply(cmd, "nothing to clear").await;
+    }
+}
+
+/// Fills the pools with synthetic transfers and deposits, then reports how
+/// long regenerating the best bundle takes. The bundle isn't broadcast, since
+/// nothing in it is really signed.
+async fn generate_load<T>(
+    state: &SharedState<T>,
+    market: &Market<T>,
+    cmd: &Command,
+    load: &GenerateLoad,
+) -> Result<(), Error>
+where
+    T: JsonRpcClient,
+{
+    if !state.opts.testing {
+        return Err("generate-load needs --testing".into());
+    }
+
+    let mut events = state.events.clone();
+
+    let seed = match load.seed {
+        Some(s) => s,
+        None => SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos() as u64,
+    };
+
+    let (mut scratch, generated) = generate_scratch(market, seed, load).await;
+
+    let msg = format!(
+        concat!(
+            "Generated with seed {}: {} transfer(s) added ({} replaced, ",
+            "{} outbid, {} evicted) and {} deposit(s) added"
+        ),
+        seed,
+        generated.added,
+        generated.replaced,
+        generated.outbid,
+        generated.evicted,
+        generated.deposits,
+    );
+    events.reply(cmd, msg).await;
+
+    let base = fetch_base(market).await?;
+
+    let start = Instant::now();
+    let slots = scratch.regenerate(base).map(Bundle::full_slots);
+    let elapsed = start.elapsed();
+
+    let msg = match slots {
+        Some(s) => {
+            format!("Regenerated in {:?}, filling {} slot(s)", elapsed, s)
+        }
+        None => format!("Regenerated in {:?}, no bundle", elapsed),
+    };
+    events.reply(cmd, msg).await;
+
+    Ok(())
+}
+
+/// What `generate_scratch` put in the pools.
+#[derive(Debug, Default)]
+struct Generated {
+    added: usize,
+    replaced: usize,
+    outbid: usize,
+    evicted: usize,
+    deposits: usize,
+}
+
+/// Fills scratch pools like `market`'s with `load`. Never the market's own,
+/// since the generated transactions aren't signed, and would otherwise end up
+/// in the best bundle, to be broadcast on `resume`.
+async fn generate_scratch<T>(
+    market: &Market<T>,
+    seed: u64,
+    load: &GenerateLoad,
+) -> (Pending, Generated) {
+    let mut scratch = market.scratch().await;
+    let mut generated = Generated::default();
+
+    // Enough inputs that most transfers fit, but some still conflict.
+    let mut gen = LoadGenerator::new(seed, load.transfers.saturating_mul(2));
+
+    let deposits: Vec<_> = (0..load.deposits).map(|_| gen.deposit()).collect();
+    generated.deposits = scratch.deposits.extend(deposits);
+
+    for _ in 0..load.transfers {
+        match scratch.transactions.insert(gen.transfer()) {
+            Insertion::Added { replaced: r } => {
+                generated.added += 1;
+                generated.replaced += r;
+            }
+            Insertion::Outbid { .. } => generated.outbid += 1,
+            Insertion::Evicted => generated.evicted += 1,
+        }
+    }
+
+    (scratch, generated)
+}
+
+async fn export_bundles<T>(
+    state: &SharedState<T>,
+    market: &Market<T>,
+    cmd: &Command,
+) where
+    T: JsonRpcClient,
+{
+    let mut events = state.events.clone();
+
+    let bundles = market.read_pools().await.pools().export();
+
+    for bundle in bundles.into_iter() {
+        let calldata = bundle.encode_calldata();
+        events
+            .reply(cmd, format!("0x{}", calldata.to_hex::<String>()))
+            .await;
+    }
+}
+
+async fn explain<T>(
+    state: &SharedState<T>,
+    market: &Market<T>,
+    cmd: &Command,
+    input: &U256,
+) -> Result<(), Error>
+where
+    T: JsonRpcClient,
+{
+    let base = fetch_base(market).await?;
+    let pools = market.read_pools().await;
+    let best = market.best_bundle.lock().await;
+
+    let selection = pools.pools().explain(best.as_ref(), input, base);
+    let reply = format!("input {}: {}", input, selection);
+    drop((pools, best));
+
+    state.events.clone().reply(cmd, reply).await;
+
+    Ok(())
+}
+
+async fn get_deposit<T>(
+    state: &SharedState<T>,
+    market: &Market<T>,
+    cmd: &Command,
+    id: U256,
+) -> Result<(), Error>
+where
+    T: JsonRpcClient,
+{
+    let mut events = state.events.clone();
+
+    let base = fetch_base(market).await?;
+    let deposits = market.deposits.read().await;
+
+    match deposits.get(&id) {
+        Some(deposit) => {
+            let name = format!("deposit[{}]", id);
+            let value = describe_deposit(deposit, base);
+            events.get(cmd, name, value).await;
+        }
+        None => events.reply(cmd, "not found").await,
+    }
+
+    Ok(())
+}
+
+/// Describes `deposit`, along with what adding it to a claim earns (or
+/// costs) at the fee base `base`.
+fn describe_deposit(deposit: &Deposit, base: U256) -> String {
+    let fee = Deposit::marginal_fee(&base);
+
+    let profit = if deposit.bounty >= fee {
+        format!("earns {} wei", deposit.bounty - fee)
+    } else {
+        format!("loses {} wei", fee - deposit.bounty)
+    };
+
+    format!("{}; {} at the fee base of {} wei", deposit, profit, base)
+}
+
+async fn get_fee_base<T>(
+    state: &SharedState<T>,
+    market: &Market<T>,
+    cmd: &Command,
+) -> Result<(), Error>
+where
+    T: JsonRpcClient,
+{
+    let base = fetch_base(market).await?;
+    state.events.clone().get(cmd, "fee_base", base).await;
+    Ok(())
+}
+
+async fn show_stats<T>(
+    state: &SharedState<T>,
+    market: &Market<T>,
+    cmd: &Command,
+    buckets: usize,
+) where
+    T: JsonRpcClient,
+{
+    let mut events = state.events.clone();
+
+    let transactions = market.transactions.read().await;
+    let count = transactions.len();
+
+    match transactions.gas_stats(buckets) {
+        Some(stats) => {
+            events.reply(cmd, format!("{} transaction(s)", count)).await;
+            events.reply(cmd, stats.to_string()).await;
+        }
+        None => events.reply(cmd, "Pool is empty").await,
+    }
+
+    drop(transactions);
+
+    if let Some(ref best) = *market.best_bundle.lock().await {
+        let msg = format!(
+            "Best bundle: {} transfer(s) moving {} wei, {} withdrawal(s)",
+            best.transfers.len(),
+            best.total_transfer_amount(),
+            best.withdrawals.len(),
+        );
+        events.reply(cmd, msg).await;
+    }
+
+    let waits = [
+        ("deposits", market.deposits.waits()),
+        ("transactions", market.transactions.waits()),
+        ("reservations", market.reservations.waits()),
+        ("best bundle", market.best_bundle.waits()),
+    ];
+
+    for (name, waits) in waits.iter() {
+        events.reply(cmd, format!("{} lock: {}", name, waits)).await;
+    }
+
+    let lags = [
+        ("block", state.block_lag.stats()),
+        ("pending transaction", state.pending_lag.stats()),
+    ];
+
+    for (name, lag) in lags.iter() {
+        events.reply(cmd, format!("{} lag: {}", name, lag)).await;
+    }
+
+    let dropped = events.dropped();
+    if dropped > 0 {
+        let msg = format!("{} heartbeat(s) dropped by a busy printer", dropped);
+        events.reply(cmd, msg).await;
+    }
+}
+
+async fn show_transfers<T>(
+    state: &SharedState<T>,
+    market: &Market<T>,
+    cmd: &Command,
+    filter: &GasFilter,
+) where
+    T: JsonRpcClient,
+{
+    let mut events = state.events.clone();
+
+    let transactions = market.transactions.read().await;
+    let transfers = transactions
+        .iter_kind(TxnKind::Transfer)
+        .take_while(|txn| filter.admits(txn.gas_price()))
+        .filter_map(|txn| match txn {
+            Txn::Transfer(t) => Some(t),
+            Txn::Withdrawal(_) => None,
+        })
+        .take(filter.limit.max_entries());
+
+    for t in transfers {
+        events.reply(cmd, t.to_string()).await;
+    }
+}
+
+async fn show_transactions<T>(
+    state: &SharedState<T>,
+    market: &Market<T>,
+    cmd: &Command,
+    filter: &GasFilter,
+) where
+    T: JsonRpcClient,
+{
+    let mut events = state.events.clone();
+
+    let transactions = market.transactions.read().await;
+    let top = transactions.peek_n(filter.limit.max_entries());
+
+    for txn in top.into_iter() {
+        // Sorted by gas price, so nothing after this one is admitted either.
+        if !filter.admits(txn.gas_price()) {
+            break;
+        }
+
+        events.reply(cmd, txn.to_string()).await;
+    }
+}
+
+async fn show_withdrawals<T>(
+    state: &SharedState<T>,
+    market: &Market<T>,
+    cmd: &Command,
+    filter: &GasFilter,
+) where
+    T: JsonRpcClient,
+{
+    let mut events = state.events.clone();
+
+    let transactions = market.transactions.read().await;
+    let withdrawals = transactions
+        .iter_kind(TxnKind::Withdrawal)
+        .take_while(|txn| filter.admits(txn.gas_price()))
+        .filter_map(|txn| match txn {
+            Txn::Withdrawal(w) => Some(w),
+            Txn::Transfer(_) => None,
+        })
+        .take(filter.limit.max_entries());
+
+    for w in withdrawals {
+        events.reply(cmd, w.to_string()).await;
+    }
+}
+
+/// Dropsafe has no view methods, so its balance is all there is to check
+/// against the pool. It holds the ether (amount and bounty) of deposits not
+/// yet claimed, but also the bounty of every claimed one, since claiming only
+/// sends the amount on to the UTXO contract.
+async fn show_dropsafe<T>(
+    state: &SharedState<T>,
+    market: &Market<T>,
+    cmd: &Command,
+) -> Result<(), Error>
+where
+    T: JsonRpcClient,
+{
+    let balance = state
+        .provider
+        .get_balance(market.dropsafe, None)
+        .await
+        .context(Rpc)?;
+
+    let pooled = market
+        .deposits
+        .read()
+        .await
+        .iter()
+        .fold(U256::zero(), |total, d| {
+            total.saturating_add(d.amount).saturating_add(d.bounty)
+        });
+
+    let mut events = state.events.clone();
+    events
+        .reply(cmd, describe_dropsafe(market.dropsafe, balance, pooled))
+        .await;
+
+    Ok(())
+}
+
+fn describe_dropsafe(address: Address, balance: U256, pooled: U256) -> String {
+    let mut text = format!(
+        "dropsafe {:?} holds {} wei, {} wei of it in pooled deposits",
+        address, balance, pooled,
+    );
+
+    if balance < pooled {
+        // Every pooled deposit should still be in escrow, so some of them
+        // must have been claimed without the pool noticing.
+        text.push_str(&format!(
+            "; {} wei short, so some pooled deposits are already claimed",
+            pooled - balance,
+        ));
+    } else if balance > pooled {
+        // Bounties of claimed deposits can't be told apart from deposits the
+        // pool missed, so there's nothing to diagnose.
+        text.push_str(&format!(
+            concat!(
+                "; {} wei more, ",
+                "from claimed deposits' bounties or unpooled deposits"
+            ),
+            balance - pooled,
+        ));
+    }
+
+    text
+}
+
+async fn show_deposits<T>(
+    state: &SharedState<T>,
+    market: &Market<T>,
+    cmd: &Command,
+    limit: &Limit,
+) -> Result<(), Error>
+where
+    T: JsonRpcClient,
+{
+    let mut events = state.events.clone();
+
+    let base = fetch_base(market).await?;
+    let deposits = market.deposits.read().await;
+
+    let mut count = 0;
+    let mut amount = U256::zero();
+    let mut bounty = U256::zero();
+
+    for deposit in deposits.iter() {
+        if count < limit.max_entries() {
+            events.reply(cmd, deposit.to_string()).await;
+        }
+
+        count += 1;
+        amount = amount.saturating_add(deposit.amount);
+        bounty = bounty.saturating_add(deposit.bounty);
+    }
+
+    let mut profitable = 0;
+    let mut profit = U256::zero();
+
+    for (_, p) in deposits.iter_by_profit(&base) {
+        profitable += 1;
+        profit = profit.saturating_add(p);
+    }
+
+    let summary = format!(
+        concat!(
+            "{} deposit(s) totalling {} wei with {} wei in bounties; ",
+            "{} profitable at the fee base of {} wei, earning {} wei"
+        ),
+        count, amount, bounty, profitable, base, profit,
+    );
+
+    events.reply(cmd, summary).await;
+
+    Ok(())
+}
+
+async fn load_deposits<T>(
+    state: &SharedState<T>,
+    market: &Market<T>,
+    cmd: &Command,
+    path: &Path,
+) -> Result<(), Error>
+where
+    T: JsonRpcClient,
+{
+    let mut events = state.events.clone();
+
+    let contents = std::fs::read_to_string(path)?;
+    let mut parsed = Vec::new();
+
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        match line.parse::<Identified>() {
+            Ok(deposit) => parsed.push(deposit),
+            Err(e) => {
+                let msg = format!("skipping line {}: {}", index + 1, e);
+                events.reply(cmd, EventKind::Warning(msg)).await;
+            }
+        }
+    }
+
+    let total = parsed.len();
+
+    let base = fetch_base(market).await?;
+    let loaded = market.deposits.write().await.extend(parsed);
+    let msg = format!("Loaded {} of {} deposit(s)", loaded, total);
+    events.reply(cmd, msg).await;
+
+    if let Some(new_bundle) = market.regenerate(base).await {
+        broadcast(state, market, Some(cmd), new_bundle).await?;
+    }
+
+    Ok(())
+}
+
+async fn insert_txn<T>(
+    state: &SharedState<T>,
+    market: &Market<T>,
+    cmd: &Command,
+    txn: Txn,
+    force: bool,
+) -> Result<(), Error>
+where
+    T: JsonRpcClient,
+{
+    let mut events = state.events.clone();
+
+    SenderPolicy::new(&state.opts, market.address).check(txn.as_ref())?;
+
+    let offered = *txn.gas_price();
+    let correlation = Correlation::of(&txn);
+
+    let insertion = {
+        let mut transactions = market.transactions.write().await;
+
+        if force {
+            transactions.replace(txn)
+        } else {
+            transactions.insert(txn)
+        }
+    };
+
+    match insertion {
+        Insertion::Added { replaced } => {
+            if replaced > 0 {
+                events.reply(cmd, EventKind::PoolDrop(replaced)).await;
+            }
+
+            let added = vec![(correlation, offered)];
+            let added = state.trace_pooled(market.address, added);
+            events.reply(cmd, EventKind::PoolAdd(added)).await;
+        }
+        Insertion::Outbid { input, gasprice } => {
+            let event = EventKind::PoolReject {
+                input,
+                pooled: gasprice,
+                offered,
+            };
+            events.reply(cmd, event).await;
+            return Ok(());
+        }
+        Insertion::Evicted => {
+            let msg = "pool is full of better paying transactions, not added";
+            events.reply(cmd, EventKind::Warning(msg.into())).await;
+            return Ok(());
+        }
+    }
+
+    let base = fetch_base(market).await?;
+    if let Some(new_bundle) = market.regenerate(base).await {
+        broadcast(state, market, Some(cmd), new_bundle).await?;
+    }
+
+    Ok(())
+}
+
+async fn insert_withdrawal<T>(
+    state: &SharedState<T>,
+    market: &Market<T>,
+    cmd: &Command,
+    mut withdrawal: Withdrawal,
+) -> Result<(), Error>
+where
+    T: JsonRpcClient,
+{
+    if let Some(ref key) = state.opts.sign_with {
+        let domain = domain_separator(market).await?;
+        withdrawal.signature = key.sign_hash(&withdrawal.signing_hash(&domain));
+    }
+
+    insert_txn(state, market, cmd, withdrawal.into(), false).await
+}
+
+async fn insert_transfer<T>(
+    state: &SharedState<T>,
+    market: &Market<T>,
+    cmd: &Command,
+    mut xfr: Transfer,
+) -> Result<(), Error>
+where
+    T: JsonRpcClient,
+{
+    if let Some(ref key) = state.opts.sign_with {
+        let domain = domain_separator(market).await?;
+        xfr.signature = key.sign_hash(&xfr.signing_hash(&domain));
+    }
+
+    insert_txn(state, market, cmd, xfr.into(), false).await
+}
+
+/// Inserts a transfer spending `cancel.input0` back to its owner, evicting
+/// whatever pooled transaction spends it.
+///
+/// The eviction relies on `Pool::replace` ignoring gas prices, so it works
+/// even at a lower gas price than the original. For the cancellation to
+/// stick on chain, though, it has to be mined first, so it should pay more.
+async fn cancel_transfer<T>(
+    state: &SharedState<T>,
+    market: &Market<T>,
+    cmd: &Command,
+    cancel: &CancelTransfer,
+) -> Result<(), Error>
+where
+    T: JsonRpcClient,
+{
+    let owner = cancel
+        .change
+        .or_else(|| state.opts.sign_with.as_ref().map(LocalKey::address));
+
+    let owner = match owner {
+        Some(o) => o,
+        None => {
+            return Err("cancel-transfer needs --change or --sign-with".into())
+        }
+    };
+
+    let mut xfr = cancel.clone().into_transfer(owner)?;
+
+    if let Some(ref key) = state.opts.sign_with {
+        let domain = domain_separator(market).await?;
+        xfr.signature = key.sign_hash(&xfr.signing_hash(&domain));
+    }
+
+    insert_txn(state, market, cmd, xfr.into(), true).await
+}
+
+async fn domain_separator<T>(
+    market: &Market<T>,
+) -> Result<[u8; 32], RelayerError>
+where
+    T: JsonRpcClient,
+{
+    let call = market.utxo.domain_separator().from(ENTRY_POINT);
+    call.call().await.context(Contract)
+}
+
+/// Runs the watcher returned by `watch`, starting a fresh one whenever the
+/// `activity` it touches goes quiet for longer than `--watchdog` allows.
+async fn supervise<T, A, W, F>(
+    state: SharedState<T>,
+    name: &'static str,
+    activity: A,
+    watch: W,
+) -> Result<(), Error>
+where
+    A: Fn(&State<T>) -> &Activity,
+    W: Fn(SharedState<T>) -> F,
+    F: Future<Output = Result<(), Error>>,
+{
+    let window = match state.opts.watchdog {
+        Some(secs) => Duration::from_secs(secs),
+        None => return watch(state).await,
+    };
+
+    let mut events = state.events.clone();
+
+    loop {
+        activity(&state).touch();
+
+        tokio::select! {
+            r = watch(state.clone()) => return r,
+            _ = activity(&state).stalled(window) => {
+                events.stalled(name, activity(&state).idle()).await;
+            }
+        }
+    }
+}
+
+async fn process_blocks<T>(state: SharedState<T>) -> Result<(), Error>
+where
+    T: 'static + JsonRpcClient,
+{
+    let mut stream = state.provider.watch_blocks().await?;
+
+    let mut events = state.events.clone();
+    events.oob("Watching for new blocks...").await;
+
+    while let Some(bkhash) = stream.next().await {
+        state.block_activity.touch();
+        events.new_block(bkhash).await;
+
+        let permit = TaskPermit::acquire(&state).await;
+
+        if state.opts.once {
+            process_block(permit, bkhash).await;
+            break;
+        }
+
+        tokio::spawn(process_block(permit, bkhash));
+    }
+
+    Ok(())
+}
+
+async fn process_block<T>(permit: TaskPermit<T>, bkhash: H256)
+where
+    T: JsonRpcClient,
+{
+    let state = permit.0.clone();
+    let mut events = state.events.clone();
+
+    if let Err(e) = try_process_block(state, bkhash).await {
+        events.bad_block(bkhash, e).await;
+    }
+}
+
+async fn try_process_block<T>(
+    state: SharedState<T>,
+    bkhash: H256,
+) -> Result<(), Error>
+where
+    T: JsonRpcClient,
+{
+    let (number, parent_hash, timestamp, body) =
+        match state.provider.get_block_with_txs(bkhash).await {
+            // An empty block has nothing to fetch one by one either.
+            Ok(block) => (
+                block.number,
+                block.parent_hash,
+                block.timestamp,
+                BlockBody::Full(block.transactions),
+            ),
+            Err(e) => {
+                // Some nodes only return transaction hashes, even when asked
+                // for full transactions, so fall back to fetching each one.
+                let msg = format!(
+                    concat!(
+                        "unable to fetch block {:?} in full, ",
+                        "fetching its transactions one by one: {}"
+                    ),
+                    bkhash, e
+                );
+                state.events.clone().oob(EventKind::Warning(msg)).await;
+
+                let block =
+                    state.provider.get_block(bkhash).await.context(Rpc)?;
+                (
+                    block.number,
+                    block.parent_hash,
+                    block.timestamp,
+                    BlockBody::Hashes(block.transactions),
+                )
+            }
+        };
+
+    let lag = state.block_lag.record_block(timestamp);
+
+    if lag > BLOCK_LAG_WARNING {
+        let msg = format!(
+            "block {:?} was mined {:?} before it was processed",
+            bkhash, lag
+        );
+        state.events.clone().oob(EventKind::Warning(msg)).await;
+    }
+
+    let number = match number {
+        Some(n) => n,
+        None => return Ok(()),
+    };
+
+    // Restore bundles from blocks this one orphans before processing its
+    // transactions, so anything mined again here is removed afterwards.
+    for market in state.markets.values() {
+        let seen = Seen::new(bkhash, parent_hash);
+        let mut orphaned = market.chain.lock().await.insert(number, seen);
+
+        // A reorg more than one block deep replaced older blocks too, which
+        // only turn up by walking back through the new block's ancestors.
+        let mut height = number;
+        while !orphaned.is_empty() {
+            let missing = market.chain.lock().await.missing_parent(height);
+
+            let (parent, hash) = match missing {
+                Some(m) => m,
+                None => break,
+            };
+
+            let block = state.provider.get_block(hash).await.context(Rpc)?;
+            let seen = Seen::new(hash, block.parent_hash);
+            orphaned.extend(market.chain.lock().await.insert(parent, seen));
+
+            height = parent;
+        }
+
+        if !orphaned.is_empty() {
+            restore_orphaned(&state, market, orphaned).await?;
+        }
+
+        *market.gas_price.lock().await = U256::zero();
+    }
+
+    let transactions = body
+        .resolve(|txhash| fetch_transaction(&state, txhash))
+        .await?;
+
+    let mut mined: BTreeMap<Address, Vec<Bundle>> = BTreeMap::new();
+
+    for tx in transactions.iter() {
+        let (market, tx) = match state.market_for(tx) {
+            Some(m) => m,
+            None => continue,
+        };
+
+        if let Some(bundle) =
+            process_block_transaction(&state, market, &tx).await?
+        {
+            mined.entry(market.address).or_default().push(bundle);
+        }
+    }
+
+    for (address, market) in state.markets.iter() {
+        let bundles = mined.remove(address).unwrap_or_default();
+
+        let confirmed = {
+            let mut chain = market.chain.lock().await;
+            chain.record(number, bkhash, bundles);
+            chain.confirm(number, state.opts.confirmations)
+        };
+
+        if !confirmed.is_empty() {
+            remove_mined(&state, market, confirmed).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Blocks processed this long after being mined are warned about, since the
+/// bundles built on them are likely stale.
+const BLOCK_LAG_WARNING: Duration = Duration::from_secs(30);
+
+/// The transactions included in a block, either in full or as hashes.
+#[derive(Debug)]
+enum BlockBody {
+    Full(Vec<EthTransaction>),
+    Hashes(Vec<H256>),
+}
+
+impl BlockBody {
+    async fn resolve<F, Fut>(
+        self,
+        mut fetch: F,
+    ) -> Result<Vec<EthTransaction>, Error>
+    where
+        F: FnMut(H256) -> Fut,
+        Fut: Future<Output = Result<EthTransaction, Error>>,
+    {
+        let hashes = match self {
+            BlockBody::Full(txs) => return Ok(txs),
+            BlockBody::Hashes(h) => h,
+        };
+
+        let mut txs = Vec::with_capacity(hashes.len());
+
+        for txhash in hashes.into_iter() {
+            txs.push(fetch(txhash).await?);
+        }
+
+        Ok(txs)
+    }
+}
+
+async fn fetch_transaction<T>(
+    state: &SharedState<T>,
+    txhash: H256,
+) -> Result<EthTransaction, Error>
+where
+    T: JsonRpcClient,
+{
+    Ok(state.provider.get_transaction(txhash).await.context(Rpc)?)
+}
+
+async fn restore_orphaned<T>(
+    state: &SharedState<T>,
+    market: &Market<T>,
+    orphaned: Vec<Seen>,
+) -> Result<(), Error>
+where
+    T: JsonRpcClient,
+{
+    let mut events = state.events.clone();
+
+    let base = fetch_base(market).await?;
+
+    let mut added = Vec::new();
+
+    for seen in orphaned.into_iter() {
+        events.reorg(seen.hash, seen.bundles.len()).await;
+
+        // Bundles from unconfirmed blocks were never removed from the pools.
+        if !seen.confirmed {
+            continue;
+        }
+
+        for bundle in seen.bundles.into_iter() {
+            added.extend(market.absorb(bundle).await);
+        }
+    }
+
+    if !added.is_empty() {
+        let added = state.trace_pooled(market.address, added);
+        events.oob(EventKind::PoolAdd(added)).await;
+    }
+
+    if let Some(new_bundle) = market.generate(base).await {
+        broadcast(state, market, None, new_bundle).await?;
+    }
+
+    Ok(())
+}
+
+async fn process_block_transaction<T>(
+    state: &SharedState<T>,
+    market: &Market<T>,
+    tx: &EthTransaction,
+) -> Result<Option<Bundle>, Error>
+where
+    T: JsonRpcClient,
+{
+    let receipt = state
+        .provider
+        .get_transaction_receipt(tx.hash)
+        .await
+        .context(Rpc)?;
+
+    let mut events = state.events.clone();
+
+    if receipt.status != Some(U64::one()) {
+        events.bad_bundle(tx.clone()).await;
+
+        if state.history.mark(&tx.hash, Outcome::Reverted) {
+            let msg = format!("{:?} was ours, see `history`", tx.hash);
+            events.oob(EventKind::Warning(msg)).await;
+        }
+
+        let absorb = state.opts.absorb_reverted;
+        if let Some(reverted) = reverted_bundle(receipt.status, tx, absorb) {
+            let base = fetch_base(market).await?;
+            let added = market.absorb(reverted).await;
+
+            if !added.is_empty() {
+                let added = state.trace_pooled(market.address, added);
+                events.oob(EventKind::PoolAdd(added)).await;
+            }
+
+            if let Some(new_bundle) = market.regenerate(base).await {
+                broadcast(state, market, None, new_bundle).await?;
+            }
+        }
+
+        return Ok(None);
+    }
+
+    // Whatever did decode was still mined, so prune it from the pool anyway.
+    let bundle = match Bundle::decode_partial(&tx.input.0) {
+        Ok(b) => b,
+        Err(PartialDecode { bundle, error }) => {
+            events.decode_error(tx.clone(), error).await;
+            bundle
+        }
+    };
+
+    // Only what went through our pool has anything to trace.
+    let ours = state.broadcast_by_us(market.address, &tx.hash);
+    let traced: Vec<Correlation> = Correlation::all_in(&bundle)
+        .into_iter()
+        .filter(|c| state.trace.knows(market.address, c))
+        .collect();
+
+    for correlation in traced.iter() {
+        let stage = Stage::Mined {
+            hash: tx.hash,
+            ours,
+        };
+        state
+            .trace
+            .record(market.address, correlation.clone(), stage);
+    }
+
+    events
+        .good_bundle(tx.clone(), bundle.claim.clone(), traced)
+        .await;
+
+    state.history.mark(&tx.hash, Outcome::Mined);
+
+    // Our own bundles only include what we pooled, so there's nothing to lose.
+    if !ours {
+        let lost = pooled_in(&*market.transactions.read().await, &bundle);
+
+        if !lost.is_empty() {
+            events.oob(EventKind::MinedElsewhere(tx.hash, lost)).await;
+        }
+    }
+
+    Ok(Some(bundle))
+}
+
+/// Removes everything the successfully mined `bundles` spent or claimed from
+/// the pools, once their blocks have enough confirmations.
+async fn remove_mined<T>(
+    state: &SharedState<T>,
+    market: &Market<T>,
+    bundles: Vec<Bundle>,
+) -> Result<(), Error>
+where
+    T: JsonRpcClient,
+{
+    let mut events = state.events.clone();
+
+    let base = fetch_base(market).await?;
+
+    let version = market.pools_version();
+    let mut transactions = market.transactions.write().await;
+
+    let before_count = transactions.len();
+
+    // Gas prices of the pooled transactions about to be removed.
+    let removed_gas: Vec<U256> = bundles
+        .iter()
+        .flat_map(Bundle::transactions)
+        .flat_map(|txn| {
+            txn.inputs()
+                .into_iter()
+                .filter_map(|i| transactions.get(i))
+                .map(|pooled| *pooled.gas_price())
+                .collect::<Vec<_>>()
+        })
+        .collect();
+
+    for txn in bundles.iter().flat_map(Bundle::transactions) {
+        transactions
+            .remove_conflicting(&txn)
+            .context(PoolInconsistent)?;
+    }
+
+    let removed = before_count - transactions.len();
+    drop(transactions);
+
+    if removed > 0 {
+        events.oob(EventKind::PoolDrop(removed)).await;
+    }
+
+    // The drop safe doesn't emit an event when deposits are claimed, so the
+    // claim in each successful bundle (ours or a competitor's) is the only
+    // record of which deposits are gone.
+    let claimed = market
+        .deposits
+        .write()
+        .await
+        .remove_claimed(bundles.iter().flat_map(Bundle::deposits));
+
+    if claimed > 0 {
+        events.oob(EventKind::DepositDrop(claimed)).await;
+    }
+
+    // Unless something else wrote the pools meanwhile, the two writes above
+    // are the only changes since the best bundle was picked. Removing cheap
+    // transactions leaves it as is, but claimed deposits may not.
+    let kept = if claimed == 0 {
+        market
+            .keep_best(base, version, version + 2, &removed_gas)
+            .await
+    } else {
+        None
+    };
+
+    let best = match kept {
+        Some(b) => Some(b),
+        None => market.generate(base).await,
+    };
+
+    if let Some(new_bundle) = best {
+        broadcast(state, market, None, new_bundle).await?;
+    }
+
+    Ok(())
+}
+
+/// Decodes the bundle from a mined transaction whose transfers and withdrawals
+/// should go back into the pool, which is only when it reverted and
+/// `--absorb-reverted` is set.
+fn reverted_bundle(
+    status: Option<U64>,
+    tx: &EthTransaction,
+    absorb_reverted: bool,
+) -> Option<Bundle> {
+    if !absorb_reverted || status == Some(U64::one()) {
+        return None;
+    }
+
+    let bundle =
+        Bundle::decode_partial(&tx.input.0).unwrap_or_else(|p| p.bundle);
+    Some(bundle)
+}
+
+async fn fetch_base<T>(market: &Market<T>) -> Result<U256, RelayerError>
+where
+    T: JsonRpcClient,
+{
+    let call = market.utxo.get_fee_base().from(ENTRY_POINT);
+    call.call().await.context(Contract)
+}
+
+async fn process_deposits<T>(state: SharedState<T>) -> Result<(), Error>
+where
+    T: 'static + JsonRpcClient,
+{
+    let dropsafes: Vec<_> =
+        state.markets.values().map(|m| m.dropsafe).collect();
+
+    let filter = Filter::new().address(dropsafes).event(NEW_DEPOSIT_EVENT);
+
+    let mut stream = state.provider.watch(&filter).await?;
+
+    let mut events = state.events.clone();
+
+    events.oob("Watching for new deposits...").await;
+
+    while let Some(log) = stream.next().await {
+        let permit = TaskPermit::acquire(&state).await;
+        tokio::spawn(process_deposit(permit, log));
+    }
+
+    Ok(())
+}
+
+async fn process_deposit<T>(permit: TaskPermit<T>, log: Log)
+where
+    T: JsonRpcClient,
+{
+    let state = permit.0.clone();
+    let mut events = state.events.clone();
+    let txhash = log.transaction_hash;
+
+    // Most likely the node hiccuped, which shouldn't take the task down.
+    if let Err(e) = try_process_deposit(state, log).await {
+        let msg = format!("unable to process deposit in {:?}: {}", txhash, e);
+        events.oob(EventKind::Warning(msg)).await;
+    }
+}
+
+async fn try_process_deposit<T>(
+    state: SharedState<T>,
+    log: Log,
+) -> Result<(), Error>
+where
+    T: JsonRpcClient,
+{
+    let mut events = state.events.clone();
+
+    let market = match state.market_by_dropsafe(log.address) {
+        Some(m) => m,
+        None => return Ok(()),
+    };
+
+    let (id, deposit) = match Deposit::decode_event(&log.data.0) {
+        Ok(d) => d,
+        Err(e) => {
+            let msg = format!(
+                "unable to decode deposit in {:?}: {}",
+                log.transaction_hash, e
+            );
+            events.oob(EventKind::Warning(msg)).await;
+            return Ok(());
+        }
+    };
+
+    events.new_deposit(id, deposit.clone()).await;
+
+    let base = fetch_base(market).await?;
+
+    // Deposits already in the pool (ex. loaded from a file) are skipped, and
+    // a full pool evicts the deposit straight away if its bounty is smallest.
+    let deposit = Identified::new(deposit, id);
+    if market.deposits.write().await.extend(Some(deposit)) == 0 {
+        return Ok(());
+    }
+
+    if let Some(new_bundle) = market.regenerate(base).await {
+        broadcast(&state, market, None, new_bundle).await?;
+    }
+
+    Ok(())
+}
+
+async fn process_transactions<T>(state: SharedState<T>) -> Result<(), Error>
+where
+    T: 'static + JsonRpcClient,
+{
+    let mut events = state.events.clone();
+
+    // Getting whole transactions saves fetching each one by hash, and having
+    // the node filter them by recipient saves getting the rest, but only some
+    // nodes can do either.
+    let bodies = match pending_bodies(&state, true).await {
+        Ok(txs) => Ok((true, txs)),
+        Err(_) => pending_bodies(&state, false).await.map(|txs| (false, txs)),
+    };
+
+    match bodies {
+        Ok((filtered, txs)) => {
+            let msg = if filtered {
+                "Watching for pending transactions (in full, filtered)..."
+            } else {
+                "Watching for pending transactions (in full)..."
+            };
+
+            events.oob(msg).await;
+            return process_pending_bodies(state, filtered, txs).await;
+        }
+        Err(e) => {
+            let msg = format!(
+                "unable to get whole pending transactions, fetching each: {}",
+                e
+            );
+            events.oob(EventKind::Warning(msg)).await;
+        }
+    }
+
+    let mut stream = state.provider.watch_pending_transactions().await?;
+
+    events.oob("Watching for pending transactions...").await;
+
+    while let Some(txhash) = stream.next().await {
+        let seen = Instant::now();
+        state.transaction_activity.touch();
+        events.pending_tx(txhash).await;
+
+        let permit = TaskPermit::acquire(&state).await;
+        let pending = PendingTx::Hash(txhash);
+        tokio::spawn(process_transaction(permit, pending, seen));
+    }
+
+    Ok(())
+}
+
+/// How often `process_pending_bodies` asks the node for its pending
+/// transactions.
+const PENDING_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Gets the transactions in the node's pool with
+/// `parity_pendingTransactions`: only those sent to a contract we watch if
+/// `filtered`, or else all of them.
+async fn pending_bodies<T>(
+    state: &SharedState<T>,
+    filtered: bool,
+) -> Result<Vec<EthTransaction>, ProviderError>
+where
+    T: JsonRpcClient,
+{
+    const METHOD: &str = "parity_pendingTransactions";
+
+    if !filtered {
+        return state
+            .node
+            .request(METHOD, None::<()>)
+            .await
+            .map_err(Into::into);
+    }
+
+    let mut txs = Vec::new();
+
+    // Filters can only match one recipient each.
+    for address in state.watched_addresses() {
+        let found: Vec<EthTransaction> = state
+            .node
+            .request(METHOD, Some(pending_filter(address)))
+            .await
+            .map_err(Into::into)?;
+
+        txs.extend(found);
+    }
+
+    Ok(txs)
+}
+
+/// Parameters for `parity_pendingTransactions` matching transactions sent to
+/// `to`, with no limit on how many.
+fn pending_filter(to: Address) -> serde_json::Value {
+    serde_json::json!([null, { "to": { "eq": to } }])
+}
+
+/// Polls the node for whole pending transactions, starting with `txs`, and
+/// processes each one not seen in the previous poll.
+async fn process_pending_bodies<T>(
+    state: SharedState<T>,
+    filtered: bool,
+    mut txs: Vec<EthTransaction>,
+) -> Result<(), Error>
+where
+    T: 'static + JsonRpcClient,
+{
+    let mut events = state.events.clone();
+    let mut interval = tokio::time::interval(PENDING_POLL_INTERVAL);
+    let mut seen = HashSet::new();
+
+    // The first tick completes immediately, but `txs` is already fresh.
+    interval.tick().await;
+
+    loop {
+        let previous = std::mem::take(&mut seen);
+
+        for tx in txs.into_iter() {
+            seen.insert(tx.hash);
+
+            if previous.contains(&tx.hash) {
+                continue;
+            }
+
+            let seen = Instant::now();
+            state.transaction_activity.touch();
+            events.pending_tx(tx.hash).await;
+
+            let permit = TaskPermit::acquire(&state).await;
+            let pending = PendingTx::Full(tx);
+            tokio::spawn(process_transaction(permit, pending, seen));
+        }
+
+        // A poll failing now and then shouldn't stop the watching for good,
+        // so try again on the next tick.
+        txs = loop {
+            interval.tick().await;
+
+            match pending_bodies(&state, filtered).await {
+                Ok(txs) => break txs,
+                Err(e) => {
+                    let msg =
+                        format!("unable to poll pending transactions: {}", e);
+                    events.oob(EventKind::Warning(msg)).await;
+                }
+            }
+        };
+    }
+}
+
+/// A pending transaction, either in full or as its hash.
+#[derive(Debug)]
+enum PendingTx {
+    Full(EthTransaction),
+    Hash(H256),
+}
+
+/// Processes a pending transaction first seen at `seen`.
+async fn process_transaction<T>(
+    permit: TaskPermit<T>,
+    pending: PendingTx,
+    seen: Instant,
+) where
+    T: JsonRpcClient,
+{
+    let state = permit.0.clone();
+    let mut events = state.events.clone();
+
+    let txhash = match pending {
+        PendingTx::Full(ref tx) => tx.hash,
+        PendingTx::Hash(h) => h,
+    };
+
+    // As with deposits, a node hiccup shouldn't take the task down.
+    if let Err(e) = try_process_transaction(state, pending, seen).await {
+        let msg = format!("unable to process transaction {:?}: {}", txhash, e);
+        events.oob(EventKind::Warning(msg)).await;
+    }
+}
+
+async fn try_process_transaction<T>(
+    state: SharedState<T>,
+    pending: PendingTx,
+    seen: Instant,
+) -> Result<(), Error>
+where
+    T: JsonRpcClient,
+{
+    let tx = match pending {
+        PendingTx::Full(tx) => tx,
+        PendingTx::Hash(txhash) => {
+            state.provider.get_transaction(txhash).await.context(Rpc)?
+        }
+    };
+
+    if tx.block_hash.is_some() {
+        return Ok(());
+    }
+
+    let (market, tx) = match state.market_for(&tx) {
+        Some(m) => m,
+        None => return Ok(()),
+    };
+
+    let mut events = state.events.clone();
+
+    if state.opts.skip_stale_pending {
+        // Transactions from the entry point use the nonce of the contract
+        // they call, not of the sender.
+        let account = match tx.to {
+            Some(to) if tx.from == ENTRY_POINT => to,
+            _ => tx.from,
+        };
+
+        let mined = state
+            .provider
+            .get_transaction_count(account, Some(BlockNumber::Latest))
+            .await;
+
+        match mined {
+            Ok(mined) => {
+                if let Some(stale) = staleness(tx.nonce, mined) {
+                    let msg = format!("not absorbing {:?}, {}", tx.hash, stale);
+                    events.oob(msg).await;
+                    return Ok(());
+                }
+            }
+            Err(e) => {
+                let msg = format!(
+                    "unable to check the nonce of {:?}, absorbing anyway: {}",
+                    tx.hash, e
+                );
+                events.oob(EventKind::Warning(msg)).await;
+            }
+        }
+    }
+
+    let mut bundle = match Bundle::decode_slice(&tx.input.0) {
+        Ok(b) => b,
+        Err(e) => {
+            events.decode_error(tx.into_owned(), e).await;
+            return Ok(());
+        }
+    };
+
+    let base = fetch_base(market).await?;
+    let competing = bundle.estimate_price(base);
+
+    if !state.broadcast_by_us(market.address, &tx.hash) {
+        let bid = Bid {
+            tx: tx.hash,
+            sponsor: bundle.claim.sponsor(),
+            price: competing,
+            base,
+        };
+
+        events.competing_bid(bid).await;
+    }
+
+    SenderPolicy::new(&state.opts, market.address).retain(&mut bundle);
+    let added = market.absorb(bundle).await;
+    state.trace_pooled(market.address, added);
+    state.pending_lag.record(seen.elapsed());
+
+    if let Some(new_bundle) = market.regenerate(base).await {
+        broadcast(&state, market, None, new_bundle).await?;
+        return Ok(());
+    }
+
+    // Never outbid our own broadcasts.
+    let factor = match state.opts.rebump_factor {
+        Some(f) if !state.broadcast_by_us(market.address, &tx.hash) => f,
+        _ => return Ok(()),
+    };
+
+    let ours = match *market.best_bundle.lock().await {
+        Some(ref b) => b.estimate_price(base),
+        None => return Ok(()),
+    };
+
+    let target = match outbid(competing, ours, factor) {
+        Some(t) => t,
+        None => return Ok(()),
+    };
+
+    let bundle = match market.outbid(base, target).await {
+        Some(b) => b,
+        None => {
+            let msg = format!(
+                concat!(
+                    "competing bundle pays {} wei for gas, ",
+                    "and no bundle from the pools pays {} wei"
+                ),
+                competing, target
+            );
+            events.oob(EventKind::Warning(msg)).await;
+            return Ok(());
+        }
+    };
+
+    let bumped = bundle.estimate_price(base);
+    events.oob(EventKind::Outbid(competing, bumped)).await;
+    broadcast(&state, market, None, bundle).await?;
+
+    Ok(())
+}
+
+/// The pooled transactions that `bundle` includes as is, rather than just
+/// conflicting with.
+fn pooled_in(transactions: &Pool<Txn>, bundle: &Bundle) -> Vec<Txn> {
+    bundle
+        .transactions()
+        .filter_map(|txn| {
+            let input = txn.inputs().into_iter().next()?;
+            let pooled = transactions.get(input)?;
+
+            if pooled.as_ref() == txn {
+                Some(pooled.clone())
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Why a pending transaction is too stale to absorb: its account has already
+/// mined `mined` transactions, so one at `nonce` never can be.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+struct Stale {
+    nonce: U256,
+    mined: U256,
+}
+
+impl std::fmt::Display for Stale {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "its nonce {} is below the account's next nonce {}",
+            self.nonce, self.mined
+        )
+    }
+}
+
+/// Whether a pending transaction at `nonce`, from an account that has mined
+/// `mined` transactions, can't be mined anymore.
+fn staleness(nonce: U256, mined: U256) -> Option<Stale> {
+    if nonce < mined {
+        Some(Stale { nonce, mined })
+    } else {
+        None
+    }
+}
+
+/// Returns the gas price our bundle has to pay, `factor` times the
+/// competitor's, if a competing bundle paying `competing` beats ours paying
+/// `ours`. Never less than what beats the competitor, whatever `factor` is.
+fn outbid(competing: U256, ours: U256, factor: f64) -> Option<U256> {
+    if competing <= ours {
+        return None;
+    }
+
+    // Factors are applied with a precision of one thousandth.
+    let permille = U256::from((factor * 1000.0).round() as u64);
+    let bumped = competing.saturating_mul(permille) / 1000;
+
+    Some(bumped.max(competing.saturating_add(U256::one())))
+}
+
+/// How often `watch_balance` checks the signer's balance.
+const BALANCE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Warns whenever the signer's balance drops below `--min-balance`.
+async fn watch_balance<T>(state: SharedState<T>)
+where
+    T: JsonRpcClient,
+{
+    let mut events = state.events.clone();
+    let mut interval = tokio::time::interval(BALANCE_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        if let Err(e) = low_balance(&state).await {
+            let msg = format!("unable to check signer balance: {}", e);
+            events.oob(EventKind::Warning(msg)).await;
+        }
+    }
+}
+
+/// How often `watch_chain` asks the node which chain it's on.
+const CHAIN_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Halts broadcasting while the node reports a different chain than it did at
+/// startup (ex. a load balancer failed over to another network), and starts
+/// again once it's back.
+async fn watch_chain<T>(state: SharedState<T>)
+where
+    T: JsonRpcClient,
+{
+    let mut events = state.events.clone();
+    let mut interval = tokio::time::interval(CHAIN_INTERVAL);
+
+    // The first tick completes immediately, and startup just checked.
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+
+        let actual = match state.provider.get_chainid().await {
+            Ok(c) => c,
+            Err(e) => {
+                let msg = format!("unable to check chain id: {}", e);
+                events.oob(EventKind::Warning(msg)).await;
+                continue;
+            }
+        };
+
+        let expected = state.opts.chain_id;
+        let wrong = actual != expected.into();
+
+        match (state.wrong_chain.swap(wrong, Ordering::SeqCst), wrong) {
+            (false, true) => {
+                events
+                    .oob(EventKind::ChainChanged { expected, actual })
+                    .await;
+            }
+            (true, false) => {
+                let msg = format!(
+                    "node is back on chain {}, broadcasting again",
+                    expected
+                );
+                events.oob(msg).await;
+            }
+            _ => (),
+        }
+    }
+}
+
+/// The market's best bundle, if it's worth sending again.
+async fn rebroadcastable<T>(market: &Market<T>) -> Option<Bundle>
+where
+    T: JsonRpcClient,
+{
+    let pools = market.read_pools().await;
+    let best = market.best_bundle.lock().await;
+
+    pools.pools().rebroadcastable(best.as_ref()).cloned()
+}
+
+/// Whether `bundle` is what was last sent for `market`, at the gas price it
+/// would be sent with now, and the node still has it, so sending it again
+/// would only be refused as already known.
+async fn still_pending<T>(
+    state: &SharedState<T>,
+    market: &Market<T>,
+    bundle: &Bundle,
+) -> bool
+where
+    T: JsonRpcClient,
+{
+    let gas_price = match fetch_base(market).await {
+        Ok(base) => gas_price_for(state, market, bundle, base).await,
+        Err(_) => return false,
+    };
+
+    let hash = match *market.last_sent.lock().await {
+        Some(ref sent)
+            if sent.gas_price == gas_price
+                && sent.calldata == bundle.clone().encode_calldata() =>
+        {
+            sent.hash
+        }
+        _ => return false,
+    };
+
+    // The node can't find a transaction it dropped.
+    state.provider.get_transaction(hash).await.is_ok()
+}
+
+/// Re-sends each market's best bundle every `period`, so one the node dropped
+/// from its mempool still gets retried.
+async fn rebroadcast<T>(state: SharedState<T>, period: Duration)
+where
+    T: JsonRpcClient,
+{
+    let mut events = state.events.clone();
+    let mut interval = tokio::time::interval(period);
+
+    // The first tick completes immediately, before there's anything to retry.
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+
+        for market in state.markets.values() {
+            let bundle = match rebroadcastable(market).await {
+                Some(b) => b,
+                None => continue,
+            };
+
+            if still_pending(&state, market, &bundle).await {
+                continue;
+            }
+
+            if let Err(e) = broadcast(&state, market, None, bundle).await {
+                let msg = format!("unable to rebroadcast bundle: {}", e);
+                events.oob(EventKind::Warning(msg)).await;
+            }
+        }
+    }
+}
+
+/// How often `watch_nonces` compares the node's nonces with ours.
+const NONCE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Warns whenever a nonce we've broadcast past never reached the node.
+async fn watch_nonces<T>(state: SharedState<T>)
+where
+    T: JsonRpcClient,
+{
+    let mut events = state.events.clone();
+    let mut interval = tokio::time::interval(NONCE_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        if let Err(e) = fill_nonce_gap(&state).await {
+            let msg = format!("unable to check for nonce gaps: {}", e);
+            events.oob(EventKind::Warning(msg)).await;
+        }
+    }
+}
+
+/// Emits `NonceGap` if the node hasn't seen a nonce below one we've broadcast
+/// with, and with `--fill-nonce-gaps`, sends a transfer to ourselves there.
+async fn fill_nonce_gap<T>(state: &SharedState<T>) -> Result<(), RelayerError>
+where
+    T: JsonRpcClient,
+{
+    let confirmed = state
+        .provider
+        .get_transaction_count(state.sender, Some(BlockNumber::Latest))
+        .await
+        .context(Rpc)?;
+
+    let pending = state
+        .provider
+        .get_transaction_count(state.sender, Some(BlockNumber::Pending))
+        .await
+        .context(Rpc)?;
+
+    let next = *state.next_nonce.lock().await;
+
+    let missing = match nonce_gap(confirmed, pending, next) {
+        Some(m) => m,
+        None => return Ok(()),
+    };
+
+    let mut events = state.events.clone();
+    events.oob(EventKind::NonceGap { missing, next }).await;
+
+    if !state.opts.fill_nonce_gaps {
+        return Ok(());
+    }
+
+    let gas_price = state.provider.get_gas_price().await.context(Rpc)?;
+
+    // Signed for `--chain-id`, so it can't be replayed on another chain.
+    let filler = TransactionRequest::new()
+        .from(state.sender)
+        .to(state.sender)
+        .value(0)
+        .gas(21_000)
+        .gas_price(gas_price)
+        .nonce(missing);
+
+    let hash = send_signed(state, filler).await?;
+
+    let msg = format!("Sent {:?} to fill nonce {}", hash, missing);
+    events.oob(msg).await;
+
+    Ok(())
+}
+
+/// Returns the first nonce the node hasn't seen, if we've broadcast with a
+/// higher one. Nonces already `confirmed` or `pending` need no filling.
+fn nonce_gap(confirmed: U256, pending: U256, next: U256) -> Option<U256> {
+    let seen = confirmed.max(pending);
+
+    if next > seen {
+        Some(seen)
+    } else {
+        None
+    }
+}
+
+/// Emits `LowBalance` and returns `true` if the signer has less than
+/// `--min-balance`.
+async fn low_balance<T>(state: &SharedState<T>) -> Result<bool, RelayerError>
+where
+    T: JsonRpcClient,
+{
+    let event = match balance_shortfall(state).await? {
+        Some((balance, minimum)) => EventKind::LowBalance { balance, minimum },
+        None => return Ok(false),
+    };
+
+    state.events.clone().oob(event).await;
+
+    Ok(true)
+}
+
+/// The signer's balance and `--min-balance`, if the balance is lower.
+async fn balance_shortfall<T>(
+    state: &SharedState<T>,
+) -> Result<Option<(U256, U256)>, RelayerError>
+where
+    T: JsonRpcClient,
+{
+    let minimum = match state.opts.min_balance {
+        Some(m) => m,
+        None => return Ok(None),
+    };
+
+    let balance = state
+        .provider
+        .get_balance(state.sender, None)
+        .await
+        .context(Rpc)?;
+
+    if balance >= minimum {
+        Ok(None)
+    } else {
+        Ok(Some((balance, minimum)))
+    }
+}
+
+/// How old the newest block may get before `--health-addr` reports a problem,
+/// unless `--max-block-age` says otherwise.
+const HEALTH_BLOCK_AGE: Duration = Duration::from_secs(300);
+
+/// How long `--health-addr` waits for the node before reporting it unreachable.
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Everything `--health-addr` reports as wrong, or nothing if healthy. Never
+/// emits events, since orchestrators may ask every few seconds.
+async fn health_problems<T>(state: SharedState<T>) -> Vec<String>
+where
+    T: JsonRpcClient,
+{
+    let mut problems = Vec::new();
+
+    if state.wrong_chain.load(Ordering::SeqCst) {
+        problems.push("node is on the wrong chain".to_string());
+    }
+
+    let number = state.provider.get_block_number();
+
+    match tokio::time::timeout(HEALTH_TIMEOUT, number).await {
+        Ok(Ok(_)) => (),
+        Ok(Err(e)) => problems.push(format!("node unreachable: {}", e)),
+        Err(_) => problems.push("node didn't answer in time".to_string()),
+    }
+
+    let maximum = state
+        .opts
+        .max_block_age
+        .map_or(HEALTH_BLOCK_AGE, Duration::from_secs);
+
+    match state.block_lag.block_age() {
+        Some(age) if age > maximum => problems.push(format!(
+            "newest block is {}s old, above {}s",
+            age.as_secs(),
+            maximum.as_secs()
+        )),
+        Some(_) => (),
+        None => problems.push("no block seen yet".to_string()),
+    }
+
+    match balance_shortfall(&state).await {
+        Ok(Some((balance, minimum))) => problems.push(format!(
+            "signer balance of {} wei is below --min-balance {}",
+            balance, minimum
+        )),
+        Ok(None) => (),
+        Err(e) => problems.push(format!("unable to check balance: {}", e)),
+    }
+
+    problems
+}
+
+/// Handles the `resume` command, broadcasting each market's best bundle,
+/// since any improvements while paused weren't sent.
+async fn resume<T>(state: &SharedState<T>, cmd: &Command) -> Result<(), Error>
+where
+    T: JsonRpcClient,
+{
+    let mut events = state.events.clone();
+
+    if !state.paused.swap(false, Ordering::SeqCst) {
+        events.reply(cmd, "broadcasting wasn't paused").await;
+        return Ok(());
+    }
+
+    events.reply(cmd, "broadcasting resumed").await;
+
+    for market in state.markets.values() {
+        if let Some(bundle) = rebroadcastable(market).await {
+            broadcast(state, market, Some(cmd), bundle).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Sends `bundle`, announcing it in reply to `cmd` if a command caused it.
+async fn broadcast<T>(
+    state: &SharedState<T>,
+    market: &Market<T>,
+    cmd: Option<&Command>,
+    bundle: Bundle,
+) -> Result<(), Error>
+where
+    T: JsonRpcClient,
+{
+    let mut events = state.events.clone();
+
+    // `watch_chain` already warned about this, loudly.
+    if state.wrong_chain.load(Ordering::SeqCst) {
+        let msg = "node is on the wrong chain, not sending bundle";
+
+        match cmd {
+            Some(cmd) => events.reply(cmd, msg).await,
+            None => events.oob(msg).await,
+        }
+
+        return Ok(());
+    }
+
+    // The bundle is still the best one, and is sent on `resume` if it's still
+    // worth it then.
+    if state.paused.load(Ordering::SeqCst) {
+        let msg = "broadcasting is paused, not sending bundle";
+
+        match cmd {
+            Some(cmd) => events.reply(cmd, msg).await,
+            None => events.oob(msg).await,
+        }
+
+        return Ok(());
+    }
+
+    if let Some(secs) = state.opts.max_block_age {
+        let maximum = Duration::from_secs(secs);
+
+        match state.block_lag.block_age() {
+            Some(age) if age > maximum => {
+                events.oob(EventKind::StaleBlock { age, maximum }).await;
+                return Ok(());
+            }
+            Some(_) => (),
+            None => {
+                let msg = "no block seen yet, not sending bundle";
+                events.oob(EventKind::Warning(msg.into())).await;
+                return Ok(());
+            }
+        }
+    }
+
+    // The base may have changed since the bundle was generated, and a bundle
+    // paying less than it can't be included, so check against the live value.
+    let base = fetch_base(market).await?;
+
+    if bundle.shortfall(base).is_some() {
+        let estimate = bundle.estimate_price(base);
+        events.oob(EventKind::Underpriced { estimate, base }).await;
+        return Ok(());
+    }
+
+    if let Some(minimum) = state.opts.min_bribe {
+        let bribe = bundle.bribe(base);
+
+        if bribe < minimum {
+            events
+                .oob(EventKind::BelowMinBribe { bribe, minimum })
+                .await;
+            return Ok(());
+        }
+    }
+
+    if low_balance(state).await? {
+        return Ok(());
+    }
+
+    let gas_price = gas_price_for(state, market, &bundle, base).await;
+
+    if let Some(maximum) = state.opts.max_gas_price {
+        let paid = effective_gas_price(&bundle, base, gas_price);
+
+        if paid > maximum {
+            events
+                .oob(EventKind::AboveMaxGasPrice {
+                    gas_price: paid,
+                    maximum,
+                })
+                .await;
+            return Ok(());
+        }
+    }
+
+    let block = state
+        .provider
+        .get_block(BlockNumber::Latest)
+        .await
+        .context(Rpc)?;
+
+    let gas_limit = state.opts.gas_limit.unwrap_or(block.gas_limit);
+
+    let call = bundle
+        .clone()
+        .encode(&market.utxo)
+        .gas_price(gas_price)
+        .gas(gas_limit)
+        .from(state.sender);
+
+    // The estimate is only informational, so broadcast without it if needed.
+    let gas = call.estimate_gas().await.ok();
+
+    match (gas, state.opts.gas_limit) {
+        (Some(estimate), Some(limit)) if estimate > limit => {
+            events
+                .oob(EventKind::AboveGasLimit { estimate, limit })
+                .await;
+            return Ok(());
+        }
+        (None, Some(limit)) => {
+            let msg = format!(
+                concat!(
+                    "unable to estimate gas, ",
+                    "sending without checking --gas-limit {}"
+                ),
+                limit
+            );
+            events.oob(EventKind::Warning(msg)).await;
+        }
+        _ => (),
+    }
+
+    if state.opts.once && state.broadcasted.swap(true, Ordering::SeqCst) {
+        let msg = "already broadcast a bundle, skipping (--once)";
+        events.oob(EventKind::Warning(msg.into())).await;
+        return Ok(());
+    }
+
+    let event = EventKind::Broadcast(bundle.clone(), gas);
+
+    match cmd {
+        Some(cmd) => events.reply(cmd, event).await,
+        None => events.oob(event).await,
+    }
+
+    call.call().await.context(Contract)?;
+
+    let pending = state
+        .provider
+        .get_transaction_count(state.sender, Some(BlockNumber::Pending))
+        .await
+        .context(Rpc)?;
+
+    // The node forgets broadcasts it drops, including any from before a
+    // restart that only `--nonce-file` remembers, so don't reuse their nonces.
+    let nonce = pending.max(*state.next_nonce.lock().await);
+
+    let calldata = bundle.clone().encode_calldata();
+
+    let tx = TransactionRequest::new()
+        .from(state.sender)
+        .to(market.address)
+        .data(calldata.clone())
+        .gas(gas_limit)
+        .gas_price(gas_price)
+        .nonce(nonce);
+
+    let (hash, gas_price) = match state.relay {
+        Some(ref relay) => {
+            // Relays only try to include a bundle in the block it targets.
+            let target = block.number.unwrap_or_default() + U64::one();
+            (relay.send(tx, target).await?, gas_price)
+        }
+        None => {
+            let sent = match send_direct(state, market, tx).await? {
+                Some(s) => s,
+                None => return Ok(()),
+            };
+
+            let mut next = state.next_nonce.lock().await;
+            *next = (*next).max(nonce + 1);
+
+            // Still holding `next_nonce`, so writes can't land out of order.
+            if let Some(ref path) = state.opts.nonce_file {
+                let path = path.clone();
+                let nonce = *next;
+                let written = tokio::task::spawn_blocking(move || {
+                    write_nonce_file(&path, nonce)
+                });
+
+                let written = match written.await {
+                    Ok(w) => w,
+                    Err(e) => Err(e.into()),
+                };
+
+                // The bundle is already out, so it still has to be recorded.
+                if let Err(e) = written {
+                    let msg = format!("unable to save the nonce: {}", e);
+                    events.oob(EventKind::Warning(msg)).await;
+                }
+            }
+
+            sent
+        }
+    };
+
+    *market.last_sent.lock().await = Some(Sent {
+        calldata,
+        gas_price,
+        hash,
+    });
+
+    for correlation in Correlation::all_in(&bundle) {
+        let stage = Stage::Broadcast { hash };
+        state.trace.record(market.address, correlation, stage);
+    }
+
+    state.history.record(Broadcast {
+        contract: market.address,
+        bundle,
+        nonce,
+        gas,
+        hash,
+        sent: SystemTime::now(),
+        outcome: Outcome::Pending,
+    });
+
+    Ok(())
+}
+
+/// Ways the node refuses a transaction that `send_direct` handles, instead of
+/// failing the broadcast.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Refusal {
+    /// The node already has this exact transaction, ex. from an earlier
+    /// rebroadcast.
+    AlreadyKnown,
+
+    /// The gas price is too low to replace the transaction at the same nonce,
+    /// or to get into the mempool at all.
+    Underpriced,
+}
+
+impl Refusal {
+    /// Recognizes a refusal from the error messages of geth and OpenEthereum,
+    /// since neither has an error code for these.
+    fn from_message(message: &str) -> Option<Self> {
+        let message = message.to_lowercase();
+
+        if message.contains("already known")
+            || message.contains("known transaction")
+            || message.contains("already imported")
+        {
+            Some(Refusal::AlreadyKnown)
+        } else if message.contains("underpriced")
+            || message.contains("gas price is too low")
+        {
+            Some(Refusal::Underpriced)
+        } else {
+            None
+        }
+    }
+}
+
+/// The gas price to retry an underpriced transaction with: a tenth more, which
+/// is the least geth accepts as a replacement, plus one wei to round up.
+fn bump_gas_price(gas_price: U256) -> U256 {
+    gas_price
+        .saturating_add(gas_price / 10)
+        .saturating_add(U256::one())
+}
+
+/// Signs `tx`, which must already have its nonce, gas, and gas price, and
+/// sends it to the node.
+async fn send_signed<T>(
+    state: &SharedState<T>,
+    tx: TransactionRequest,
+) -> Result<TxHash, RelayerError>
+where
+    T: JsonRpcClient,
+{
+    let signed = state
+        .signer
+        .sign_transaction_async(tx)
+        .await
+        .context(Sign)?;
+
+    let hash = state
+        .provider
+        .send_raw_transaction(&signed)
+        .await
+        .map_err(ClientError::from)
+        .context(SendTransaction)?;
+
+    Ok(hash)
+}
+
+/// The gas price to broadcast `bundle` with at fee base `base`.
+///
+/// The entry point can't pay for gas itself, so its bundles go out at zero,
+/// and pay through `paygas`. Signed bundles pay for their own gas, at what
+/// the bundle is estimated to pay back, or more if the node has refused that
+/// as underpriced since the last block.
+async fn gas_price_for<T>(
+    state: &SharedState<T>,
+    market: &Market<T>,
+    bundle: &Bundle,
+    base: U256,
+) -> U256 {
+    let raised = *market.gas_price.lock().await;
+    outer_gas_price(&state.opts.signer, bundle.estimate_price(base), raised)
+}
+
+/// The gas price `bundle` really pays when sent at `outer`: through `paygas`
+/// for the entry point, whose `outer` is zero, or `outer` for signed bundles.
+fn effective_gas_price(bundle: &Bundle, base: U256, outer: U256) -> U256 {
+    outer.max(bundle.estimate_price(base))
+}
+
+/// `gas_price_for`, given the bundle's `estimate` and the `raised` price.
+fn outer_gas_price(signer: &SignerKind, estimate: U256, raised: U256) -> U256 {
+    match signer {
+        SignerKind::Abstract => U256::zero(),
+        _ => estimate.max(raised),
+    }
+}
+
+/// Sends `tx` to the node, retrying with a higher gas price while it's
+/// refused as underpriced, up to `--underpriced-retries` times, and keeping
+/// the price that was accepted for `market`'s next broadcast. Returns the
+/// hash and gas price it went out with, or `None` if nothing new was sent,
+/// either because the node already has `tx`, or because retrying would go
+/// over `--max-gas-price`.
+async fn send_direct<T>(
+    state: &SharedState<T>,
+    market: &Market<T>,
+    mut tx: TransactionRequest,
+) -> Result<Option<(TxHash, U256)>, Error>
+where
+    T: JsonRpcClient,
+{
+    let mut events = state.events.clone();
+    let mut retries = 0;
+
+    // The entry point's transactions can't pay for gas themselves.
+    let retry = if state.opts.signer == SignerKind::Abstract {
+        0
+    } else {
+        state.opts.underpriced_retries
+    };
+
+    loop {
+        let error = match send_signed(state, tx.clone()).await {
+            Ok(hash) => {
+                let gas_price = tx.gas_price.unwrap_or_default();
+
+                // Otherwise the next broadcast is refused all over again.
+                if retries > 0 {
+                    *market.gas_price.lock().await = gas_price;
+                }
+
+                return Ok(Some((hash, gas_price)));
+            }
+            Err(e) => e,
+        };
+
+        match Refusal::from_message(&error.to_string()) {
+            Some(Refusal::AlreadyKnown) => {
+                events.oob("node already has this bundle").await;
+                return Ok(None);
+            }
+            Some(Refusal::Underpriced) if retries < retry => {
+                let gas_price =
+                    bump_gas_price(tx.gas_price.unwrap_or_default());
+
+                if let Some(maximum) = state.opts.max_gas_price {
+                    if gas_price > maximum {
+                        events
+                            .oob(EventKind::AboveMaxGasPrice {
+                                gas_price,
+                                maximum,
+                            })
+                            .await;
+                        return Ok(None);
+                    }
+                }
+
+                let msg = format!(
+                    "node refused bundle as underpriced, retrying at {} wei",
+                    gas_price
+                );
+                events.oob(EventKind::Warning(msg)).await;
+
+                retries += 1;
+                tx = tx.gas_price(gas_price);
+            }
+            _ => return Err(error.into()),
+        }
+    }
+}
+
+async fn show_trace<T>(
+    state: &SharedState<T>,
+    market: &Market<T>,
+    cmd: &Command,
+    input: &U256,
+) {
+    let mut events = state.events.clone();
+    let steps = state.trace.spending(market.address, input);
+
+    if steps.is_empty() {
+        let msg = format!("nothing traced spending input {}", input);
+        events.reply(cmd, msg).await;
+        return;
+    }
+
+    for step in steps.iter() {
+        events.reply(cmd, step.to_string()).await;
+    }
+}
+
+async fn show_history<T>(
+    state: &SharedState<T>,
+    market: &Market<T>,
+    cmd: &Command,
+    full: bool,
+) {
+    let mut events = state.events.clone();
+    let broadcasts = state.history.to_contract(market.address);
+
+    if broadcasts.is_empty() {
+        events.reply(cmd, "no bundles broadcast").await;
+        return;
+    }
+
+    for broadcast in broadcasts.iter() {
+        events.reply(cmd, broadcast.to_string()).await;
+
+        if !full {
+            continue;
+        }
+
+        for id in broadcast.bundle.deposits() {
+            events.reply(cmd, format!("  claims deposit {}", id)).await;
+        }
+
+        for txn in broadcast.bundle.transactions() {
+            events.reply(cmd, format!("  {}", txn)).await;
+        }
+    }
+}
+
+/// Reads the nonce saved by `write_nonce_file`, or `None` if there isn't one
+/// yet.
+fn read_nonce_file(path: &Path) -> Result<Option<U256>, Error> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let nonce = U256::from_dec_str(contents.trim())
+        .map_err(|_| format!("{} doesn't hold a nonce", path.display()))?;
+
+    Ok(Some(nonce))
+}
+
+/// Saves `nonce` to `path`, replacing the old file only once the new one is
+/// written, so a crash never leaves half a nonce behind.
+fn write_nonce_file(path: &Path, nonce: U256) -> Result<(), Error> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+
+    std::fs::write(&temporary, format!("{}\n", nonce))?;
+    std::fs::rename(&temporary, path)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate test;
+
+    use crate::contracts::UTXO_ABI;
+
+    use ethers::abi::Token;
+    use ethers::types::{Bytes, Signature};
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use self::test::Bencher;
+
+    use super::*;
+
+    fn withdrawal_calldata(input: u64, gasprice: u64) -> String {
+        let empty = Token::FixedBytes(vec![0; 32]);
+
+        let claim = Token::Tuple(vec![
+            Token::Uint(U256::zero()),
+            Token::Uint(U256::zero()),
+            Token::Array(vec![]),
+            Token::Uint(U256::zero()),
+            empty.clone(),
+            empty.clone(),
+        ]);
+
+        let withdrawal = Token::Tuple(vec![
+            Token::Uint(input.into()),
+            Token::Uint(gasprice.into()),
+            Token::Uint(U256::zero()),
+            empty.clone(),
+            empty,
+        ]);
+
+        let calldata = UTXO_ABI.functions["transact"][0]
+            .encode_input(&[
+                claim,
+                Token::Array(vec![]),
+                Token::Array(vec![withdrawal]),
+            ])
+            .unwrap();
+
+        format!("0x{}", calldata.to_hex::<String>())
+    }
+
+    #[test]
+    fn replay_line_improves_bundle() {
+        let mut pending = Pending::default();
+
+        let first =
+            replay_line(&mut pending, &withdrawal_calldata(1, 50), 0.into())
+                .unwrap()
+                .expect("first withdrawal should produce a bundle");
+        assert_eq!(first.withdrawals.len(), 1);
+
+        let second =
+            replay_line(&mut pending, &withdrawal_calldata(2, 60), 0.into())
+                .unwrap()
+                .expect("second withdrawal should improve the bundle");
+        assert_eq!(second.withdrawals.len(), 2);
+
+        // Replaying the same withdrawal again doesn't change anything.
+        let again =
+            replay_line(&mut pending, &withdrawal_calldata(2, 60), 0.into())
+                .unwrap();
+        assert!(again.is_none());
+    }
+
+    fn reverted_transaction() -> EthTransaction {
+        let mut bundle = Bundle::new();
+        bundle.insert_withdrawal(Withdrawal {
+            input: 1.into(),
+            gasprice: 50.into(),
+            signature: Signature {
+                v: 0,
+                r: H256::zero(),
+                s: H256::zero(),
+            },
+        });
+
+        let mut tx = eth_transaction(H256::zero());
+        tx.input = bundle.encode_calldata().into();
+        tx
+    }
+
+    #[test]
+    fn reverted_bundle_absorbed_when_enabled() {
+        let tx = reverted_transaction();
+
+        let bundle = reverted_bundle(Some(U64::zero()), &tx, true)
+            .expect("reverted bundle should be absorbed");
+        assert_eq!(bundle.withdrawals.len(), 1);
+
+        let mut pending = Pending::default();
+        pending.absorb(bundle);
+        assert_eq!(pending.transactions.len(), 1);
+    }
+
+    #[test]
+    fn reverted_bundle_ignored_by_default() {
+        let tx = reverted_transaction();
+        assert!(reverted_bundle(Some(U64::zero()), &tx, false).is_none());
+    }
+
+    #[test]
+    fn reverted_bundle_ignores_success() {
+        let tx = reverted_transaction();
+        assert!(reverted_bundle(Some(U64::one()), &tx, true).is_none());
+    }
+
+    #[test]
+    fn replay_line_rejects_garbage() {
+        let mut pending = Pending::default();
+
+        assert!(replay_line(&mut pending, "0xzz", 0.into()).is_err());
+        assert!(replay_line(&mut pending, "0x1234", 0.into()).is_err());
+    }
+
+    fn eth_transaction(hash: H256) -> EthTransaction {
+        EthTransaction {
+            hash,
+            nonce: U256::zero(),
+            block_hash: None,
+            block_number: None,
+            transaction_index: None,
+            from: Address::zero(),
+            to: Some(UTXO),
+            value: U256::zero(),
+            gas_price: U256::zero(),
+            gas: U256::zero(),
+            input: Bytes::default(),
+            v: U64::zero(),
+            r: U256::zero(),
+            s: U256::zero(),
+        }
+    }
+
+    #[tokio::test]
+    async fn block_body_full() {
+        let txs = vec![eth_transaction(H256([1; 32]))];
+
+        let resolved = BlockBody::Full(txs)
+            .resolve(|_| async {
+                Err::<EthTransaction, Error>("fetched".into())
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(resolved.len(), 1);
+        assert_eq!(resolved[0].hash, H256([1; 32]));
+    }
+
+    #[tokio::test]
+    async fn block_body_hashes_only() {
+        let hashes = vec![H256([1; 32]), H256([2; 32])];
+
+        let resolved = BlockBody::Hashes(hashes.clone())
+            .resolve(
+                |txhash| async move { Ok::<_, Error>(eth_transaction(txhash)) },
+            )
+            .await
+            .unwrap();
+
+        let actual: Vec<_> = resolved.iter().map(|tx| tx.hash).collect();
+        assert_eq!(actual, hashes);
+    }
+
+    #[tokio::test]
+    async fn block_body_empty() {
+        let resolved = BlockBody::Hashes(vec![])
+            .resolve(|_| async {
+                Err::<EthTransaction, Error>("fetched".into())
+            })
+            .await
+            .unwrap();
+
+        assert!(resolved.is_empty());
+    }
+
+    #[test]
+    fn nonce_gap_none_when_caught_up() {
+        assert_eq!(nonce_gap(5.into(), 5.into(), 5.into()), None);
+        assert_eq!(nonce_gap(5.into(), 7.into(), 7.into()), None);
+        assert_eq!(nonce_gap(0.into(), 0.into(), 0.into()), None);
+    }
+
+    #[test]
+    fn nonce_gap_stale_counter() {
+        // Someone else used the key, so our counter is behind.
+        assert_eq!(nonce_gap(9.into(), 9.into(), 5.into()), None);
+    }
+
+    #[test]
+    fn nonce_gap_missing() {
+        assert_eq!(nonce_gap(5.into(), 6.into(), 8.into()), Some(6.into()));
+        assert_eq!(nonce_gap(5.into(), 5.into(), 6.into()), Some(5.into()));
+    }
+
+    /// A private key, and the address it signs for.
+    const SIGNING_KEY: &str =
+        "4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318";
+    const SIGNING_ADDRESS: &str = "2c7536e3605d9c16a7a3d7b1898e529396a65c23";
+
+    fn signed_transfer(key: &LocalKey, domain: &[u8; 32]) -> Transfer {
+        let mut xfr = Transfer {
+            input0: 1.into(),
+            input1: U256::zero(),
+            destination: Address::zero(),
+            change: Address::zero(),
+            amount: 10.into(),
+            gasprice: 100.into(),
+            signature: Signature {
+                v: 0,
+                r: H256::zero(),
+                s: H256::zero(),
+            },
+        };
+
+        xfr.signature = key.sign_hash(&xfr.signing_hash(domain));
+        xfr
+    }
+
+    #[test]
+    fn sender_policy_allow_and_deny() {
+        let key: LocalKey = SIGNING_KEY.parse().unwrap();
+        let signer: Address = SIGNING_ADDRESS.parse().unwrap();
+        let other = Address::from([0x11; 20]);
+
+        let open = SenderPolicy {
+            allow: &[],
+            deny: &[],
+            domain: [0x42; 32],
+        };
+
+        let xfr = signed_transfer(&key, &open.domain);
+        assert!(open.check(Txn::Transfer(&xfr)).is_ok());
+
+        let allowed = [signer];
+        let others = [other];
+
+        let allow = SenderPolicy {
+            allow: &allowed,
+            ..open
+        };
+        assert!(allow.check(Txn::Transfer(&xfr)).is_ok());
+
+        let allow_other = SenderPolicy {
+            allow: &others,
+            ..open
+        };
+        assert!(allow_other.check(Txn::Transfer(&xfr)).is_err());
+
+        let deny = SenderPolicy {
+            deny: &allowed,
+            ..open
+        };
+        assert!(deny.check(Txn::Transfer(&xfr)).is_err());
+
+        let deny_other = SenderPolicy {
+            deny: &others,
+            ..open
+        };
+        assert!(deny_other.check(Txn::Transfer(&xfr)).is_ok());
+    }
+
+    #[test]
+    fn sender_policy_retain() {
+        let key: LocalKey = SIGNING_KEY.parse().unwrap();
+        let signer: Address = SIGNING_ADDRESS.parse().unwrap();
+        let denied = [signer];
+
+        let policy = SenderPolicy {
+            allow: &[],
+            deny: &denied,
+            domain: [0x42; 32],
+        };
+
+        let signed = signed_transfer(&key, &policy.domain);
+
+        // Signed over a different domain, so some other address recovers.
+        let mut stranger = signed_transfer(&key, &[0; 32]);
+        stranger.input0 = 2.into();
+
+        let mut bundle = Bundle::default();
+        bundle.transfers.push(signed);
+        bundle.transfers.push(stranger.clone());
+
+        assert_eq!(policy.retain(&mut bundle), 1);
+        assert_eq!(bundle.transfers, vec![stranger]);
+    }
+
+    #[test]
+    fn sender_policy_binds_contract() {
+        let key: LocalKey = SIGNING_KEY.parse().unwrap();
+        let signer: Address = SIGNING_ADDRESS.parse().unwrap();
+        let allowed = [signer];
+
+        let contract_a = Address::repeat_byte(0xaa);
+        let contract_b = Address::repeat_byte(0xbb);
+
+        let policy_a = SenderPolicy {
+            allow: &allowed,
+            deny: &[],
+            domain: contracts::domain_separator(1, contract_a),
+        };
+
+        let policy_b = SenderPolicy {
+            domain: contracts::domain_separator(1, contract_b),
+            ..policy_a
+        };
+
+        // Replayed against B, the signature recovers to someone else.
+        let xfr = signed_transfer(&key, &policy_a.domain);
+        assert!(policy_a.check(Txn::Transfer(&xfr)).is_ok());
+        assert!(policy_b.check(Txn::Transfer(&xfr)).is_err());
+    }
+
+    #[test]
+    fn describe_utxo_spent() {
+        let spent = Output {
+            owner: Address::zero(),
+            amount: U256::zero(),
+        };
+        assert_eq!(describe_utxo(&spent, None), "spent or nonexistent");
+
+        let txn = Txn::from(explain_transfer(100, 1));
+        assert_eq!(
+            describe_utxo(&spent, Some(&txn)),
+            format!("spent or nonexistent, yet pooled {} spends it", txn)
+        );
+    }
+
+    #[test]
+    fn describe_utxo_unspent() {
+        let output = Output {
+            owner: Address::repeat_byte(1),
+            amount: 10.into(),
+        };
+        assert_eq!(describe_utxo(&output, None), output.to_string());
+
+        let txn = Txn::from(explain_transfer(100, 1));
+        assert_eq!(
+            describe_utxo(&output, Some(&txn)),
+            format!("{}; spent by pooled {}", output, txn)
+        );
+    }
+
+    #[test]
+    fn describe_deposit_profit() {
+        let deposit = Deposit {
+            amount: 5.into(),
+            bounty: 100.into(),
+            owner: Address::zero(),
+        };
+
+        let base = U256::from(7);
+        let fee = Deposit::marginal_fee(&base);
+
+        let expected = format!(
+            "{}; earns {} wei at the fee base of 7 wei",
+            deposit,
+            U256::from(100) - fee
+        );
+
+        assert_eq!(describe_deposit(&deposit, base), expected);
+    }
+
+    #[test]
+    fn describe_dropsafe_balance() {
+        let address = Address::repeat_byte(0xd5);
+
+        let even = describe_dropsafe(address, 50.into(), 50.into());
+        assert!(even.ends_with("50 wei of it in pooled deposits"));
+
+        let short = describe_dropsafe(address, 30.into(), 50.into());
+        assert!(short.contains("20 wei short"));
+
+        let extra = describe_dropsafe(address, 80.into(), 50.into());
+        assert!(extra.contains("30 wei more, from claimed deposits' bounties"));
+    }
+
+    #[test]
+    fn nonce_file_round_trip() {
+        let path = std::env::temp_dir()
+            .join(format!("relayer-nonce-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(read_nonce_file(&path).unwrap(), None);
+
+        write_nonce_file(&path, 41.into()).unwrap();
+        write_nonce_file(&path, 42.into()).unwrap();
+        assert_eq!(read_nonce_file(&path).unwrap(), Some(42.into()));
+
+        std::fs::write(&path, "forty-two").unwrap();
+        assert!(read_nonce_file(&path).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn pending_filter_shape() {
+        let to = Address::from([0x11; 20]);
+
+        let expected = serde_json::json!([
+            null,
+            { "to": { "eq": "0x1111111111111111111111111111111111111111" } },
+        ]);
+
+        assert_eq!(pending_filter(to), expected);
+    }
+
+    #[test]
+    fn what_if_bases_range() {
+        let bases = what_if_bases(10.into(), 30.into(), 10.into()).unwrap();
+        assert_eq!(bases, vec![10.into(), 20.into(), 30.into()]);
+
+        let bases = what_if_bases(10.into(), 25.into(), 10.into()).unwrap();
+        assert_eq!(bases, vec![10.into(), 20.into()]);
+    }
+
+    #[test]
+    fn what_if_bases_invalid() {
+        assert!(what_if_bases(0.into(), 10.into(), 0.into()).is_err());
+        assert!(what_if_bases(10.into(), 0.into(), 1.into()).is_err());
+        assert!(what_if_bases(0.into(), 1000.into(), 1.into()).is_err());
+        assert!(what_if_bases(0.into(), U256::max_value(), 1.into()).is_err());
+    }
+
+    #[test]
+    fn what_if_base_drops_cheap_transactions() {
+        let mut pending = Pending::default();
+        pending.transactions.insert(explain_transfer(100, 1));
+        pending.transactions.insert(explain_transfer(60, 2));
+
+        let rows = pending.what_if_base(&[0.into(), 200.into()]);
+
+        // Two transfers fill four slots: 60 * 4 / 10 = 24.
+        assert_eq!(rows[0].slots, 4);
+        assert_eq!(rows[0].estimate, 24.into());
+
+        // Below the base, only the most expensive transaction is worth it.
+        assert_eq!(rows[1].slots, 2);
+        assert!(rows[1].to_string().ends_with("below the fee base"));
+        assert!(pending.best_bundle.is_none());
+    }
+
+    #[test]
+    fn staleness_fresh() {
+        assert_eq!(staleness(5.into(), 5.into()), None);
+        assert_eq!(staleness(6.into(), 5.into()), None);
+    }
+
+    #[test]
+    fn staleness_nonce_mined() {
+        assert_eq!(
+            staleness(4.into(), 5.into()),
+            Some(Stale {
+                nonce: 4.into(),
+                mined: 5.into()
+            })
+        );
+    }
+
+    #[test]
+    fn refusal_from_message() {
+        let cases = [
+            ("already known", Some(Refusal::AlreadyKnown)),
+            ("known transaction: 0xabcd", Some(Refusal::AlreadyKnown)),
+            (
+                "Transaction with the same hash was already imported.",
+                Some(Refusal::AlreadyKnown),
+            ),
+            (
+                "replacement transaction underpriced",
+                Some(Refusal::Underpriced),
+            ),
+            ("transaction underpriced", Some(Refusal::Underpriced)),
+            (
+                "Transaction gas price is too low. There is another \
+                 transaction with same nonce in the queue.",
+                Some(Refusal::Underpriced),
+            ),
+            ("nonce too low", None),
+            ("insufficient funds for gas * price + value", None),
+        ];
+
+        for (message, expected) in cases.iter() {
+            assert_eq!(
+                Refusal::from_message(message),
+                *expected,
+                "{}",
+                message
+            );
+        }
+    }
+
+    #[test]
+    fn effective_gas_price_over_cap() {
+        let mut bundle = Bundle::new();
+        bundle.insert_transfer(explain_transfer(300, 1));
+
+        // Sent at zero by the entry point, but pays over a cap of 200.
+        let paid = effective_gas_price(&bundle, 250.into(), U256::zero());
+        assert!(paid > 200.into());
+        assert_eq!(paid, bundle.estimate_price(250.into()));
+
+        // A raised outer price is what a signed bundle pays.
+        let paid = effective_gas_price(&bundle, 250.into(), 500.into());
+        assert_eq!(paid, 500.into());
+    }
+
+    #[test]
+    fn outer_gas_price_by_signer() {
+        let wallet = SignerKind::Wallet("/tmp/key".into());
+
+        // Signed bundles pay for their own gas, at least what they pay back.
+        assert_eq!(outer_gas_price(&wallet, 120.into(), 0.into()), 120.into());
+        assert_eq!(
+            outer_gas_price(&wallet, 120.into(), 133.into()),
+            133.into()
+        );
+
+        // The entry point pays through `paygas` instead.
+        let price =
+            outer_gas_price(&SignerKind::Abstract, 120.into(), 0.into());
+        assert_eq!(price, U256::zero());
+    }
+
+    #[test]
+    fn bump_gas_price_by_a_tenth() {
+        assert_eq!(bump_gas_price(100.into()), 111.into());
+        assert_eq!(bump_gas_price(0.into()), 1.into());
+        assert_eq!(bump_gas_price(U256::max_value()), U256::max_value());
+    }
+
+    #[test]
+    fn outbid_below_ours() {
+        assert_eq!(outbid(90.into(), 100.into(), 1.1), None);
+        assert_eq!(outbid(100.into(), 100.into(), 1.1), None);
+    }
+
+    #[test]
+    fn outbid_bumps_competitor() {
+        let bumped = outbid(200.into(), 100.into(), 1.1);
+        assert_eq!(bumped, Some(220.into()));
+    }
+
+    #[test]
+    fn outbid_always_beats_competitor() {
+        assert_eq!(outbid(200.into(), 100.into(), 0.9), Some(201.into()));
+        assert_eq!(outbid(200.into(), 100.into(), 1.0), Some(201.into()));
+    }
+
+    fn explain_transfer(gasprice: u64, input: u64) -> Transfer {
+        Transfer {
+            amount: 10.into(),
+            gasprice: gasprice.into(),
+            change: Address::zero(),
+            destination: Address::zero(),
+            input0: input.into(),
+            input1: U256::zero(),
+            signature: Signature {
+                v: 0,
+                r: H256::zero(),
+                s: H256::zero(),
+            },
+        }
+    }
+
+    #[test]
+    fn explain_selected_and_too_cheap() {
+        let mut pending = Pending::default();
+        pending.transactions.insert(explain_transfer(100, 1));
+        pending.transactions.insert(explain_transfer(60, 2));
+        pending.regenerate(50.into());
+
+        assert_eq!(pending.explain(&1.into(), 50.into()), Selection::Selected);
+        assert_eq!(
+            pending.explain(&2.into(), 50.into()),
+            Selection::TooCheap {
+                gasprice: 60.into(),
+                estimate: 60.into(),
+            }
+        );
+        assert_eq!(
+            pending.explain(&3.into(), 50.into()),
+            Selection::NotPending
+        );
+    }
+
+    #[test]
+    fn select_to_outbid_looks_past_select() {
+        let mut pending = Pending::default();
+        pending.transactions.insert(explain_transfer(100, 1));
+        for input in 2..=6 {
+            pending.transactions.insert(explain_transfer(30, input));
+        }
+
+        let base = U256::zero();
+        let picked = pending.pools().select(base);
+        assert_eq!(picked.estimate_price(base), 20.into());
+
+        // 30 * 8 / 10, with three of the cheaper transfers added.
+        let outbid = pending.pools().select_to_outbid(base, 24.into()).unwrap();
+        assert_eq!(outbid.transfers.len(), 4);
+        assert_eq!(outbid.estimate_price(base), 24.into());
+
+        // Even a full bundle only pays 30.
+        let unreachable = pending.pools().select_to_outbid(base, 31.into());
+        assert!(unreachable.is_none());
+    }
+
+    #[test]
+    fn explain_bundle_full() {
+        let mut pending = Pending::default();
+        for ii in 0..=(Bundle::MAX_SLOTS as u64) {
+            pending
+                .transactions
+                .insert(explain_transfer(100 - ii, ii + 1));
+        }
+        pending.regenerate(0.into());
+
+        let last = Bundle::MAX_SLOTS as u64 + 1;
+        assert_eq!(
+            pending.explain(&last.into(), 0.into()),
+            Selection::BundleFull {
+                ahead: Bundle::MAX_SLOTS
+            }
+        );
+    }
+
+    #[test]
+    fn explain_no_bundle() {
+        let mut pending = Pending::default();
+        pending.transactions.insert(explain_transfer(100, 1));
+
+        assert_eq!(pending.explain(&1.into(), 0.into()), Selection::NoBundle);
+    }
+
+    #[test]
+    fn owner_weights_break_deposit_ties() {
+        let mut pending = Pending::default();
+        pending.transactions.insert(explain_transfer(100, 1));
+
+        let deposit = |owner: u8, id: u64| {
+            let deposit = Deposit {
+                amount: 10.into(),
+                bounty: 5.into(),
+                owner: Address::repeat_byte(owner),
+            };
+            Identified::new(deposit, id.into())
+        };
+
+        // More tied deposits than fit, with owner 1's last in pool order.
+        for id in 1..=11 {
+            pending.deposits.insert(deposit(2, id));
+        }
+        pending.deposits.insert(deposit(1, 12));
+
+        let room = (Bundle::MAX_SLOTS - Bundle::SLOTS_PER_TRANSFER)
+            / Bundle::SLOTS_PER_CLAIM;
+
+        let unweighted = pending.generate(0.into()).unwrap().clone();
+        assert_eq!(unweighted.claim.deposits.len(), room);
+        assert!(!unweighted.claim.deposits.contains(&12.into()));
+
+        pending.weights = "0x0101010101010101010101010101010101010101 1"
+            .parse()
+            .unwrap();
+
+        let weighted = pending.generate(0.into()).unwrap().clone();
+        assert_eq!(weighted.claim.deposits.len(), room);
+        assert!(weighted.claim.deposits.contains(&12.into()));
+        assert_eq!(
+            weighted.estimate_price(0.into()),
+            unweighted.estimate_price(0.into())
+        );
+    }
+
+    fn simulation_pending() -> Pending {
+        let mut pending = Pending::default();
+
+        pending.deposits.insert(Identified::new(
+            Deposit {
+                amount: 10.into(),
+                bounty: 5.into(),
+                owner: Address::zero(),
+            },
+            7.into(),
+        ));
+
+        pending
+    }
+
+    #[test]
+    fn simulate_claim_included() {
+        let mut pending = simulation_pending();
+        pending.best_bundle = Some(Bundle::new());
+
+        let simulation = pending.simulate_claim(&7.into(), 0.into()).unwrap();
+
+        assert_eq!(simulation.outcome, ClaimOutcome::Included);
+        assert_eq!(simulation.bounty, 5.into());
+        assert!(pending.best_bundle.unwrap().claim.deposits.is_empty());
+    }
+
+    #[test]
+    fn simulate_claim_already_included() {
+        let mut pending = simulation_pending();
+
+        let mut bundle = Bundle::new();
+        bundle.insert_deposit(7.into());
+        pending.best_bundle = Some(bundle);
+
+        let simulation = pending.simulate_claim(&7.into(), 0.into()).unwrap();
+        assert_eq!(simulation.outcome, ClaimOutcome::AlreadyIncluded);
+    }
+
+    #[test]
+    fn simulate_claim_no_free_slot() {
+        let mut pending = simulation_pending();
+
+        let mut bundle = Bundle::new();
+        for ii in 0..(Bundle::MAX_SLOTS / Bundle::SLOTS_PER_CLAIM) {
+            assert!(bundle.insert_deposit(U256::from(100 + ii)).is_none());
+        }
+        pending.best_bundle = Some(bundle);
+
+        let simulation = pending.simulate_claim(&7.into(), 0.into()).unwrap();
+        assert_eq!(simulation.outcome, ClaimOutcome::NoFreeSlot);
+    }
+
+    #[test]
+    fn simulate_claim_unknown_deposit() {
+        let pending = simulation_pending();
+        assert!(pending.simulate_claim(&8.into(), 0.into()).is_err());
+    }
+
+    #[test]
+    fn export_packs_whole_pool() {
+        let mut pending = Pending::default();
+        pending.reservations.withdrawals = 2;
+
+        let per_bundle = Bundle::MAX_SLOTS / Bundle::SLOTS_PER_TRANSFER;
+        let count = 2 * per_bundle as u64 + 3;
+        for ii in 0..count {
+            pending
+                .transactions
+                .insert(explain_transfer(100 + ii, ii + 1));
+        }
 
-    let before_count = pending.transactions.len();
-    pending.transactions.remove_conflicting(&txn);
-    let after_count = pending.transactions.len();
+        let bundles = pending.export();
+        assert_eq!(bundles.len(), 3);
 
-    let removed = before_count - after_count;
-    if removed > 0 {
-        events.reply(cmd, EventKind::PoolDrop(removed)).await;
-    }
+        let exported: Vec<_> =
+            bundles.iter().flat_map(|b| b.transfers.iter()).collect();
+        assert_eq!(exported.len(), count as usize);
 
-    pending.transactions.insert(txn);
-    let added = pending.transactions.len() - after_count;
-    events.reply(cmd, EventKind::PoolAdd(added)).await;
+        let prices: Vec<_> = exported.iter().map(|t| t.gasprice).collect();
+        let mut sorted = prices.clone();
+        sorted.sort_by(|a, b| b.cmp(a));
+        assert_eq!(prices, sorted);
 
-    let base = fetch_base(state).await?;
-    if let Some(new_bundle) = pending.regenerate(base) {
-        events
-            .reply(cmd, EventKind::Broadcast(new_bundle.clone()))
-            .await;
-        broadcast(&state, new_bundle.clone()).await?;
+        assert!(bundles.iter().all(|b| b.claim.deposits.is_empty()));
     }
 
-    Ok(())
-}
-
-async fn insert_withdrawal<T>(
-    state: &SharedState<T>,
-    cmd: &Command,
-    withdrawal: Withdrawal,
-) -> Result<(), Error>
-where
-    T: JsonRpcClient,
-{
-    insert_txn(state, cmd, withdrawal.into()).await
-}
+    #[test]
+    fn rebroadcastable_while_pooled() {
+        let mut pending = Pending::default();
+        assert!(pending.rebroadcastable().is_none());
 
-async fn insert_transfer<T>(
-    state: &SharedState<T>,
-    cmd: &Command,
-    xfr: Transfer,
-) -> Result<(), Error>
-where
-    T: JsonRpcClient,
-{
-    insert_txn(state, cmd, xfr.into()).await
-}
+        pending.transactions.insert(explain_transfer(100, 1));
+        pending.transactions.insert(explain_transfer(90, 2));
+        pending.generate(0.into());
 
-async fn process_blocks<T>(state: SharedState<T>) -> Result<(), Error>
-where
-    T: 'static + JsonRpcClient,
-{
-    let mut stream = state.provider.watch_blocks().await?;
+        assert!(pending.rebroadcastable().is_some());
 
-    let mut events = state.events.clone();
-    events.oob("Watching for new blocks...").await;
+        // Mined, so its conflicts are removed from the pool.
+        pending
+            .transactions
+            .remove_conflicting(&explain_transfer(100, 1))
+            .unwrap();
 
-    while let Some(bkhash) = stream.next().await {
-        events.new_block(bkhash).await;
-        tokio::spawn(process_block(state.clone(), bkhash));
+        assert!(pending.rebroadcastable().is_none());
     }
 
-    Ok(())
-}
+    #[test]
+    fn cancel_transfer_evicts_original() {
+        let mut original = explain_transfer(100, 1);
+        original.input1 = 2.into();
 
-async fn process_block<T>(state: SharedState<T>, bkhash: H256)
-where
-    T: JsonRpcClient,
-{
-    let mut events = state.events.clone();
+        let mut pending = Pending::default();
+        pending.transactions.insert(original);
 
-    if let Err(e) = try_process_block(state, bkhash).await {
-        events.bad_block(bkhash, e).await;
-    }
-}
+        // Cheaper than the original, which `replace` doesn't care about.
+        let cancel = CancelTransfer {
+            input0: 1.into(),
+            gasprice: 50.into(),
+            change: None,
+        };
 
-async fn try_process_block<T>(
-    state: SharedState<T>,
-    bkhash: H256,
-) -> Result<(), Error>
-where
-    T: JsonRpcClient,
-{
-    let block = state.provider.get_block_with_txs(bkhash).await?;
+        let xfr = cancel.into_transfer(Address::from([0x11; 20])).unwrap();
+        let insertion = pending.transactions.replace(xfr.clone().into());
 
-    for tx in block.transactions.iter() {
-        process_block_transaction(&state, tx).await?;
+        assert_eq!(insertion, Insertion::Added { replaced: 1 });
+        assert_eq!(pending.transactions.len(), 1);
+        assert_eq!(
+            pending.transactions.get(&1.into()),
+            Some(&Txn::Transfer(xfr))
+        );
+        assert!(pending.transactions.get(&2.into()).is_none());
     }
 
-    Ok(())
-}
+    #[test]
+    fn regenerate_idle() {
+        let mut pending = Pending::default();
+        assert!(pending.regenerate(0.into()).is_none());
+        assert!(pending.best_bundle.is_none());
 
-async fn process_block_transaction<T>(
-    state: &SharedState<T>,
-    tx: &EthTransaction,
-) -> Result<(), Error>
-where
-    T: JsonRpcClient,
-{
-    if tx.to.as_ref() != Some(&UTXO) {
-        return Ok(());
+        // Left over from before the pools emptied.
+        let mut best = Bundle::new();
+        best.transfers.push(explain_transfer(100, 1));
+        pending.best_bundle = Some(best);
+
+        assert!(pending.regenerate(0.into()).is_none());
+        assert_eq!(
+            pending.best_bundle.unwrap().transfers,
+            vec![explain_transfer(100, 1)]
+        );
     }
 
-    let receipt = state.provider.get_transaction_receipt(tx.hash).await?;
+    #[test]
+    fn rebroadcastable_not_empty() {
+        let mut pending = Pending::default();
+        pending.generate(0.into());
 
-    let mut events = state.events.clone();
+        assert!(pending.best_bundle.is_some());
+        assert!(pending.rebroadcastable().is_none());
+    }
 
-    if receipt.status != Some(U64::one()) {
-        events.bad_bundle(tx.clone()).await;
+    #[test]
+    fn describe_calldata_lines() {
+        let lines = describe_calldata(&withdrawal_calldata(7, 3)).unwrap();
 
-        // TODO: There might be valid transactions in the bundle that can be
-        //       added to the pool.
+        let expected = vec![
+            "claim i=0 gas=0 deposits=[]".to_owned(),
+            "wdr i=7 gas=3".to_owned(),
+        ];
 
-        return Ok(());
+        assert_eq!(lines, expected);
     }
 
-    events.good_bundle(tx.clone()).await;
+    #[test]
+    fn describe_calldata_invalid() {
+        assert!(describe_calldata("0xe23c9c7500").is_err());
+        assert!(describe_calldata("not hex").is_err());
+    }
 
-    let bundle = match Bundle::decode_slice(&tx.input.0) {
-        Ok(b) => b,
-        Err(e) => {
-            events.decode_error(tx.clone(), e).await;
-            return Ok(());
-        }
-    };
+    #[test]
+    fn pooled_in_exact_matches_only() {
+        let mut pending = Pending::default();
+        pending.transactions.insert(explain_transfer(100, 1));
+        pending.transactions.insert(explain_transfer(100, 2));
 
-    let base = fetch_base(state).await?;
-    let mut shared = state.pending.lock().await;
+        let mut bundle = Bundle::new();
+        bundle.transfers.push(explain_transfer(100, 1));
+        bundle.transfers.push(explain_transfer(90, 2));
+        bundle.transfers.push(explain_transfer(100, 3));
 
-    let before_count = shared.transactions.len();
+        let lost = pooled_in(&pending.transactions, &bundle);
 
-    for txn in bundle.transactions() {
-        shared.transactions.remove_conflicting(&txn);
+        assert_eq!(lost, vec![Txn::Transfer(explain_transfer(100, 1))]);
     }
 
-    let removed = before_count - shared.transactions.len();
+    #[test]
+    fn improves_only_when_better() {
+        let mut cheap = Bundle::new();
+        cheap.insert(explain_transfer(10, 1).into());
 
-    if removed > 0 {
-        events.oob(EventKind::PoolDrop(removed)).await;
+        let mut dear = Bundle::new();
+        dear.insert(explain_transfer(20, 2).into());
+
+        assert!(improves(None, &cheap, 0.into()));
+        assert!(improves(Some(&cheap), &dear, 0.into()));
+        assert!(!improves(Some(&dear), &cheap, 0.into()));
+        assert!(!improves(Some(&dear), &dear, 0.into()));
     }
 
-    // TODO: Only regenerate the bundle if the pool actually changed.
-    if let Some(new_bundle) = shared.generate(base) {
-        events.oob(EventKind::Broadcast(new_bundle.clone())).await;
-        broadcast(&state, new_bundle.clone()).await?;
+    #[test]
+    fn export_empty_pool() {
+        assert!(Pending::default().export().is_empty());
     }
 
-    Ok(())
-}
+    #[test]
+    fn select_floor_stops_at_first_rejected() {
+        let mut pending = Pending::default();
 
-async fn fetch_base<T>(state: &SharedState<T>) -> Result<U256, Error>
-where
-    T: JsonRpcClient,
-{
-    let call = state.utxo.get_fee_base().from(ENTRY_POINT);
-    Ok(call.call().await?)
-}
+        let per_bundle = Bundle::MAX_SLOTS / Bundle::SLOTS_PER_TRANSFER;
+        for ii in 0..(per_bundle as u64) {
+            pending.transactions.insert(explain_transfer(100, ii + 1));
+        }
 
-async fn process_transactions<T>(state: SharedState<T>) -> Result<(), Error>
-where
-    T: 'static + JsonRpcClient,
-{
-    let mut stream = state.provider.watch_pending_transactions().await?;
+        pending.transactions.insert(explain_transfer(50, 20));
+        pending.transactions.insert(explain_transfer(10, 21));
 
-    let mut events = state.events.clone();
+        let (bundle, floor) = pending.pools().select_with_floor(0.into());
 
-    events.oob("Watching for pending transactions...").await;
+        assert_eq!(bundle.transfers.len(), per_bundle);
+        assert_eq!(floor, Some(50.into()));
+    }
 
-    while let Some(txhash) = stream.next().await {
-        events.pending_tx(txhash).await;
-        tokio::spawn(process_transaction(state.clone(), txhash));
+    #[test]
+    fn select_floor_none_when_exhausted() {
+        let mut pending = Pending::default();
+        pending.transactions.insert(explain_transfer(100, 1));
+
+        let (bundle, floor) = pending.pools().select_with_floor(0.into());
+
+        assert_eq!(bundle.transfers.len(), 1);
+        assert_eq!(floor, None);
     }
 
-    Ok(())
-}
+    #[test]
+    fn select_unchanged_by_removal_below_floor() {
+        let mut pending = Pending::default();
 
-async fn process_transaction<T>(state: SharedState<T>, txhash: H256)
-where
-    T: JsonRpcClient,
-{
-    try_process_transaction(state, txhash).await.unwrap();
-}
+        let per_bundle = Bundle::MAX_SLOTS / Bundle::SLOTS_PER_TRANSFER;
+        for ii in 0..(per_bundle as u64) {
+            pending
+                .transactions
+                .insert(explain_transfer(100 + ii, ii + 1));
+        }
 
-async fn try_process_transaction<T>(
-    state: SharedState<T>,
-    txhash: H256,
-) -> Result<(), Error>
-where
-    T: JsonRpcClient,
-{
-    let tx = state.provider.get_transaction(txhash).await?;
-    if tx.to != Some(UTXO) || tx.block_hash.is_some() {
-        return Ok(());
+        pending.transactions.insert(explain_transfer(50, 20));
+        pending.transactions.insert(explain_transfer(10, 21));
+
+        let (before, floor) = pending.pools().select_with_floor(0.into());
+        assert_eq!(floor, Some(50.into()));
+
+        let cheap = explain_transfer(10, 21);
+        pending.transactions.remove_conflicting(&cheap).unwrap();
+
+        let after = pending.pools().select(0.into());
+
+        assert_eq!(after.transfers, before.transfers);
+        assert_eq!(after.claim.deposits, before.claim.deposits);
+        assert_eq!(
+            after.estimate_price(0.into()),
+            before.estimate_price(0.into())
+        );
     }
 
-    let mut events = state.events.clone();
+    /// Fills all but a transfer's worth of slots with transfers, then offers
+    /// another transfer and a withdrawal at the same gas price, pooling the
+    /// preferred kind last. Only one of the two fits.
+    fn tied_for_last_slot(prefer: Preference) -> Bundle {
+        let mut pending = Pending {
+            prefer: Some(prefer),
+            ..Pending::default()
+        };
 
-    let bundle = match Bundle::decode_slice(&tx.input.0) {
-        Ok(b) => b,
-        Err(e) => {
-            events.decode_error(tx.clone(), e).await;
-            return Ok(());
+        let withdrawal = Withdrawal {
+            input: 100.into(),
+            gasprice: 100.into(),
+            signature: Signature {
+                v: 0,
+                r: H256::zero(),
+                s: H256::zero(),
+            },
+        };
+
+        if prefer == Preference::Transfers {
+            pending.transactions.insert(withdrawal.clone());
+        }
+
+        let per_bundle = Bundle::MAX_SLOTS / Bundle::SLOTS_PER_TRANSFER;
+        for ii in 0..(per_bundle as u64) {
+            pending.transactions.insert(explain_transfer(100, ii + 1));
         }
-    };
 
-    let base = fetch_base(&state).await?;
-    let mut pending = state.pending.lock().await;
+        if prefer == Preference::Withdrawals {
+            pending.transactions.insert(withdrawal);
+        }
 
-    for withdrawal in bundle.withdrawals.into_iter() {
-        pending.transactions.insert(withdrawal);
+        pending.regenerate(0.into()).unwrap().clone()
     }
 
-    for transfer in bundle.transfers.into_iter() {
-        pending.transactions.insert(transfer);
+    #[test]
+    fn prefer_withdrawals_at_equal_gas() {
+        let bundle = tied_for_last_slot(Preference::Withdrawals);
+
+        let per_bundle = Bundle::MAX_SLOTS / Bundle::SLOTS_PER_TRANSFER;
+        assert_eq!(bundle.withdrawals.len(), 1);
+        assert_eq!(bundle.transfers.len(), per_bundle - 1);
     }
 
-    if let Some(new_bundle) = pending.regenerate(base) {
-        events.oob(EventKind::Broadcast(new_bundle.clone())).await;
-        broadcast(&state, new_bundle.clone()).await?;
+    #[test]
+    fn prefer_transfers_at_equal_gas() {
+        let bundle = tied_for_last_slot(Preference::Transfers);
+
+        let per_bundle = Bundle::MAX_SLOTS / Bundle::SLOTS_PER_TRANSFER;
+        assert!(bundle.withdrawals.is_empty());
+        assert_eq!(bundle.transfers.len(), per_bundle);
     }
 
-    Ok(())
-}
+    #[test]
+    fn reserved_withdrawals_not_crowded_out() {
+        let mut pending = Pending::default();
+        pending.reservations.withdrawals = 2;
 
-async fn broadcast<T>(
-    state: &SharedState<T>,
-    bundle: Bundle,
-) -> Result<(), Error>
-where
-    T: JsonRpcClient,
-{
-    let block = state.provider.get_block(BlockNumber::Latest).await?;
+        for ii in 0..(Bundle::MAX_SLOTS as u64) {
+            pending.transactions.insert(explain_transfer(100, ii + 1));
+        }
 
-    let call = bundle
-        .encode(&state.utxo)
-        .gas_price(0)
-        .gas(block.gas_limit)
-        .from(ENTRY_POINT);
+        for ii in 0..2 {
+            pending.transactions.insert(Withdrawal {
+                input: (100 + ii).into(),
+                gasprice: 90.into(),
+                signature: Signature {
+                    v: 0,
+                    r: H256::zero(),
+                    s: H256::zero(),
+                },
+            });
+        }
 
-    call.call().await?;
-    call.send().await?;
+        let bundle = pending.regenerate(0.into()).unwrap();
 
-    Ok(())
-}
+        let unreserved = Bundle::MAX_SLOTS - 2 * Bundle::SLOTS_PER_WITHDRAWAL;
+        assert_eq!(
+            bundle.transfers.len(),
+            unreserved / Bundle::SLOTS_PER_TRANSFER
+        );
+        assert_eq!(bundle.withdrawals.len(), 2);
+    }
 
-#[cfg(test)]
-mod tests {
-    use ethers::types::Signature;
+    #[test]
+    fn transfer_cap_leaves_rest_pooled() {
+        let mut pending = Pending::default();
+        pending.caps.transfers = Some(2);
 
-    use super::*;
+        for ii in 0..5 {
+            pending
+                .transactions
+                .insert(explain_transfer(100 + ii, ii + 1));
+        }
+
+        let bundle = pending.regenerate(0.into()).unwrap().clone();
+
+        let gasprices: Vec<_> =
+            bundle.transfers.iter().map(|t| t.gasprice).collect();
+        assert_eq!(gasprices, vec![104.into(), 103.into()]);
+        assert_eq!(pending.transactions.len(), 5);
+
+        let capped = pending.explain(&3.into(), 0.into());
+        assert_eq!(capped, Selection::Capped);
+    }
 
     #[test]
     fn bundle_two_transfers_take_one() {
         let mut pending = Pending {
             deposits: DepositPool::default(),
             transactions: Pool::default(),
+            reservations: Reservations::default(),
+            caps: Caps::default(),
+            prefer: None,
+            weights: Weights::default(),
             best_bundle: None,
         };
 
@@ -613,6 +5529,10 @@ mod tests {
         let mut pending = Pending {
             deposits: DepositPool::default(),
             transactions: Pool::default(),
+            reservations: Reservations::default(),
+            caps: Caps::default(),
+            prefer: None,
+            weights: Weights::default(),
             best_bundle: None,
         };
 
@@ -659,6 +5579,10 @@ mod tests {
         let mut pending = Pending {
             deposits: DepositPool::default(),
             transactions: Pool::default(),
+            reservations: Reservations::default(),
+            caps: Caps::default(),
+            prefer: None,
+            weights: Weights::default(),
             best_bundle: None,
         };
 
@@ -687,4 +5611,206 @@ mod tests {
         let actual = pending.regenerate(U256::zero()).unwrap();
         assert_eq!(actual.transfers, &xfrs[..xfrs.len() - 5]);
     }
+
+    /// `select_with_floor` as it was before it priced candidates without
+    /// building them, kept to check the two pick the same bundle.
+    fn select_by_cloning(pools: Pools, base: U256) -> (Bundle, Option<U256>) {
+        let mut bundle = Bundle::new();
+        let mut ordered: Vec<&Txn> = pools.transactions.iter().collect();
+
+        if let Some(prefer) = pools.prefer {
+            ordered.sort_by_key(|t| (Reverse(*t.gas_price()), prefer.rank(t)));
+        }
+
+        for txn in ordered.into_iter() {
+            if !pools.reservations.admits(&bundle, txn)
+                || !pools.caps.admits(&bundle, txn)
+            {
+                continue;
+            }
+
+            let gp = txn.gas_price();
+
+            let mut new_bundle = Bundle::new();
+            new_bundle.transfers = bundle.transfers.clone();
+            new_bundle.withdrawals = bundle.withdrawals.clone();
+            new_bundle.insert(txn.clone());
+            new_bundle.claim.gasprice = *gp;
+
+            for (candidate, _) in pools.deposits.iter_by_profit(gp) {
+                if new_bundle.insert_deposit(*candidate.id()).is_some() {
+                    break;
+                }
+            }
+
+            if bundle.estimate_price(base) >= new_bundle.estimate_price(base) {
+                return (bundle, Some(*gp));
+            } else {
+                bundle = new_bundle;
+            }
+        }
+
+        (bundle, None)
+    }
+
+    /// A small pending pool with random prices, bounties, reservations, caps
+    /// and preference, to compare selection strategies on.
+    fn random_pending(rng: &mut StdRng, size: usize) -> Pending {
+        let mut pending = Pending::default();
+
+        for _ in 0..rng.gen_range(0, size) {
+            let txn =
+                explain_transfer(rng.gen_range(1, 80), rng.gen_range(1, 40));
+            pending.transactions.insert(txn);
+        }
+
+        for _ in 0..rng.gen_range(0, size) {
+            pending.transactions.insert(Withdrawal {
+                input: rng.gen_range(1u64, 40).into(),
+                gasprice: rng.gen_range(1u64, 80).into(),
+                signature: Signature {
+                    v: 0,
+                    r: H256::zero(),
+                    s: H256::zero(),
+                },
+            });
+        }
+
+        for id in 0..rng.gen_range(0u64, size as u64) {
+            pending.deposits.insert(Identified::new(
+                Deposit {
+                    amount: 10.into(),
+                    bounty: rng.gen_range(0u64, 100).into(),
+                    owner: Address::zero(),
+                },
+                id.into(),
+            ));
+        }
+
+        let cap = |rng: &mut StdRng| {
+            if rng.gen() {
+                Some(rng.gen_range(0, 6))
+            } else {
+                None
+            }
+        };
+
+        pending.reservations = Reservations {
+            transfers: rng.gen_range(0, 4),
+            withdrawals: rng.gen_range(0, 4),
+        };
+        pending.caps = Caps {
+            transfers: cap(rng),
+            withdrawals: cap(rng),
+        };
+        pending.prefer = match rng.gen_range(0, 3) {
+            0 => Some(Preference::Transfers),
+            1 => Some(Preference::Withdrawals),
+            _ => None,
+        };
+
+        pending
+    }
+
+    #[test]
+    fn select_matches_cloning() {
+        let mut rng = StdRng::seed_from_u64(0x5345_4c45);
+
+        for _ in 0..256 {
+            let pending = random_pending(&mut rng, 16);
+            let base = U256::from(rng.gen_range(0u64, 60));
+
+            let (actual, actual_floor) =
+                pending.pools().select_with_floor(base);
+            let (expected, expected_floor) =
+                select_by_cloning(pending.pools(), base);
+
+            assert_eq!(actual.transfers, expected.transfers);
+            assert_eq!(actual.withdrawals, expected.withdrawals);
+            assert_eq!(actual.claim.deposits, expected.claim.deposits);
+            assert_eq!(actual.claim.gasprice, expected.claim.gasprice);
+            assert_eq!(actual_floor, expected_floor);
+        }
+    }
+
+    /// Thousands of transactions and deposits at scattered prices, so the
+    /// benchmarks below spend their time selecting, not setting up.
+    fn large_pending() -> Pending {
+        let mut rng = StdRng::seed_from_u64(0x4c41_5247);
+        let mut pending = Pending::default();
+
+        for input in 0..2_000 {
+            let gasprice = rng.gen_range(1, 1_000);
+            pending
+                .transactions
+                .insert(explain_transfer(gasprice, input * 2 + 1));
+        }
+
+        for id in 0..2_000u64 {
+            pending.deposits.insert(Identified::new(
+                Deposit {
+                    amount: 10.into(),
+                    bounty: rng.gen_range(0u64, 1_000).into(),
+                    owner: Address::zero(),
+                },
+                id.into(),
+            ));
+        }
+
+        pending
+    }
+
+    // Compare with `cargo bench select_`: pricing candidates before building
+    // them should beat cloning a bundle for every candidate gas price.
+    #[bench]
+    fn select_with_floor_large(b: &mut Bencher) {
+        let pending = large_pending();
+        b.iter(|| pending.pools().select_with_floor(500.into()));
+    }
+
+    #[bench]
+    fn select_by_cloning_large(b: &mut Bencher) {
+        let pending = large_pending();
+        b.iter(|| select_by_cloning(pending.pools(), 500.into()));
+    }
+
+    /// A market for a node that's never contacted.
+    fn offline_market() -> Market<Http> {
+        let provider = Provider::try_from("http://localhost:8545").unwrap();
+        let signer = RelayerSigner::new(&SignerKind::Abstract, 1).unwrap();
+        let client = Client::new(provider, signer);
+
+        Market::new(
+            Address::zero(),
+            Utxo::new(Address::zero(), client),
+            Address::zero(),
+            None,
+            Caps::default(),
+            Weights::default(),
+        )
+    }
+
+    #[tokio::test]
+    async fn generate_load_leaves_market_alone() {
+        let market = offline_market();
+        let load = GenerateLoad {
+            transfers: 50,
+            deposits: 20,
+            seed: Some(7),
+        };
+
+        let (mut scratch, generated) =
+            generate_scratch(&market, 7, &load).await;
+        assert!(generated.added > 0);
+        assert_eq!(generated.deposits, 20);
+
+        // The scratch pools would broadcast the generated load...
+        scratch.generate(0.into());
+        assert!(scratch.rebroadcastable().is_some());
+
+        // ...but `resume` only rebroadcasts from the market, which is empty.
+        assert!(market.best_bundle.lock().await.is_none());
+        assert!(rebroadcastable(&market).await.is_none());
+        assert!(market.read_pools().await.pools().export().is_empty());
+    }
 }
@@ -4,23 +4,56 @@
 
 use crate::contracts::Deposit;
 
-use ethers::types::U256;
+use ethers::types::{Address, U256};
 
-use std::collections::btree_map::{BTreeMap, Entry};
+use snafu::{ensure, Snafu};
+
+use std::collections::btree_map::BTreeMap;
 use std::collections::btree_set::BTreeSet;
 use std::collections::HashMap;
 use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::iter::Iterator;
 use std::ops::{Deref, DerefMut};
+use std::str::FromStr;
 use std::sync::Arc;
 
-#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+/// The inputs a transaction spends.
+///
+/// Compared and hashed as a set, so `Two(a, b)` and `Two(b, a)` are the same.
+#[derive(Debug, Clone)]
 pub enum Inputs<'a> {
     None,
     One(&'a U256),
     Two(&'a U256, &'a U256),
 }
 
+impl<'a> Inputs<'a> {
+    /// The inputs in ascending order.
+    fn sorted(&self) -> (Option<&'a U256>, Option<&'a U256>) {
+        match *self {
+            Inputs::None => (None, None),
+            Inputs::One(one) => (Some(one), None),
+            Inputs::Two(one, two) if two < one => (Some(two), Some(one)),
+            Inputs::Two(one, two) => (Some(one), Some(two)),
+        }
+    }
+}
+
+impl<'a> PartialEq for Inputs<'a> {
+    fn eq(&self, other: &Self) -> bool {
+        self.sorted() == other.sorted()
+    }
+}
+
+impl<'a> Eq for Inputs<'a> {}
+
+impl<'a> Hash for Inputs<'a> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.sorted().hash(state);
+    }
+}
+
 impl<'a> Iterator for Inputs<'a> {
     type Item = &'a U256;
 
@@ -46,14 +79,29 @@ impl<'a> Iterator for Inputs<'a> {
 }
 
 pub trait Transaction: Eq {
+    /// Distinguishes transactions a pool can list separately (ex. transfers
+    /// and withdrawals) while still catching conflicts between them.
+    type Kind: Copy + Ord + fmt::Debug;
+
+    fn kind(&self) -> Self::Kind;
     fn gas_price(&self) -> &U256;
     fn inputs(&self) -> Inputs;
+
+    /// The EIP-712 digest the owner of the inputs signs, under the contract's
+    /// `DOMAIN_SEPARATOR`.
+    fn signing_hash(&self, domain: &[u8; 32]) -> [u8; 32];
 }
 
 impl<T> Transaction for &T
 where
     T: Transaction,
 {
+    type Kind = T::Kind;
+
+    fn kind(&self) -> Self::Kind {
+        T::kind(self)
+    }
+
     fn gas_price(&self) -> &U256 {
         T::gas_price(self)
     }
@@ -61,6 +109,74 @@ where
     fn inputs(&self) -> Inputs {
         T::inputs(self)
     }
+
+    fn signing_hash(&self, domain: &[u8; 32]) -> [u8; 32] {
+        T::signing_hash(self, domain)
+    }
+}
+
+/// A range of gas prices, and the number of transactions priced within it.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Bucket {
+    pub low: U256,
+    pub high: U256,
+    pub count: usize,
+}
+
+/// Summary of the distribution of gas prices in a pool.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct GasStats {
+    pub min: U256,
+    pub median: U256,
+    pub max: U256,
+    pub histogram: Vec<Bucket>,
+}
+
+impl fmt::Display for GasStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "min={} median={} max={}",
+            self.min, self.median, self.max
+        )?;
+
+        for bucket in self.histogram.iter() {
+            write!(
+                f,
+                "\n  [{}, {}]: {}",
+                bucket.low, bucket.high, bucket.count
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The pool's indexes disagreed about a transaction being removed.
+#[derive(Debug, Snafu)]
+pub enum PoolError {
+    #[snafu(display("transaction not found by input {}", input))]
+    MissingByInput { input: U256 },
+
+    #[snafu(display("input {} belongs to a different transaction", input))]
+    WrongByInput { input: U256 },
+
+    #[snafu(display("transaction not found by gas price {}", gasprice))]
+    MissingByGas { gasprice: U256 },
+}
+
+/// What inserting a transaction into a `Pool` did.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Insertion {
+    /// Added, replacing this many conflicting transactions.
+    Added { replaced: usize },
+
+    /// Not added, since the pooled transaction spending `input` pays a gas
+    /// price of `gasprice`, at least as much as the new one.
+    Outbid { input: U256, gasprice: U256 },
+
+    /// Added, then evicted straight away as the cheapest in a full pool.
+    Evicted,
 }
 
 #[derive(Debug)]
@@ -70,10 +186,63 @@ where
 {
     max_len: usize,
     len: usize,
+
+    /// Transactions paying the same gas price are kept in order of their
+    /// inputs (see `Inputs::sorted`), so the pool's order depends only on
+    /// what's in it, and not on the order it was inserted in.
     by_gas: BTreeMap<U256, Vec<Arc<T>>>,
+
+    /// The same as `by_gas`, split up by kind, so one kind can be listed
+    /// without walking past every other.
+    by_kind: BTreeMap<(T::Kind, U256), Vec<Arc<T>>>,
+
+    /// Shared by every kind, so a transaction conflicts with any other
+    /// spending the same input, whatever its kind.
     by_input: HashMap<U256, Arc<T>>,
 }
 
+/// Adds `item` to the entry for `key`, keeping the order `Pool::by_gas`
+/// describes.
+fn file<K, T>(index: &mut BTreeMap<K, Vec<Arc<T>>>, key: K, item: Arc<T>)
+where
+    K: Ord,
+    T: Transaction,
+{
+    let same = index.entry(key).or_default();
+    let sorted = item.inputs().sorted();
+    let at = match same.binary_search_by(|e| e.inputs().sorted().cmp(&sorted)) {
+        Ok(i) | Err(i) => i,
+    };
+    same.insert(at, item);
+}
+
+/// Removes every copy of `item` from the entry for `key`, returning how many
+/// there were.
+fn unfile<K, T>(
+    index: &mut BTreeMap<K, Vec<Arc<T>>>,
+    key: &K,
+    item: &T,
+) -> usize
+where
+    K: Ord,
+    T: Transaction,
+{
+    let same = match index.get_mut(key) {
+        Some(s) => s,
+        None => return 0,
+    };
+
+    let before = same.len();
+    same.retain(|e| e.as_ref() != item);
+    let removed = before - same.len();
+
+    if same.is_empty() {
+        index.remove(key);
+    }
+
+    removed
+}
+
 impl<T> Default for Pool<T>
 where
     T: Transaction,
@@ -83,6 +252,7 @@ where
             max_len: Self::DEFAULT_MAX_LEN,
             len: 0,
             by_gas: BTreeMap::new(),
+            by_kind: BTreeMap::new(),
             by_input: HashMap::new(),
         }
     }
@@ -103,11 +273,112 @@ where
             .map(Arc::as_ref)
     }
 
+    /// Returns up to `n` transactions with the highest gas prices, in the same
+    /// order as `iter`, without walking the rest of the pool.
+    pub fn peek_n(&self, n: usize) -> Vec<&T> {
+        let mut top = Vec::with_capacity(n.min(self.len));
+
+        for same_gas in self.by_gas.values().rev() {
+            for item in same_gas.iter() {
+                if top.len() == n {
+                    return top;
+                }
+
+                top.push(item.as_ref());
+            }
+        }
+
+        top
+    }
+
+    /// Returns the transaction spending `input`, if there is one.
+    pub fn get(&self, input: &U256) -> Option<&T> {
+        self.by_input.get(input).map(Arc::as_ref)
+    }
+
     /// The number of unique transactions in the pool.
     pub fn len(&self) -> usize {
         self.len
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The most transactions the pool holds before evicting the cheapest.
+    pub fn max_len(&self) -> usize {
+        self.max_len
+    }
+
+    /// Changes `max_len`, evicting the cheapest transactions straight away if
+    /// there are now too many. Returns how many were evicted.
+    pub fn set_max_len(&mut self, max_len: usize) -> usize {
+        self.max_len = max_len;
+
+        let mut evicted = 0;
+        while self.len > self.max_len {
+            self.evict_cheapest();
+            evicted += 1;
+        }
+
+        self.debug_validate();
+        evicted
+    }
+
+    /// Removes whatever `iter` would reach last, returning it.
+    fn evict_cheapest(&mut self) -> Arc<T> {
+        let (_, cheapest) = self.by_gas.first_key_value().unwrap();
+        let v = cheapest.last().unwrap().clone();
+        self.remove(&v).ok();
+        v
+    }
+
+    /// Computes the minimum, median, and maximum gas prices in the pool, along
+    /// with a histogram of `buckets` equally sized ranges between the minimum
+    /// and maximum. There's at least one bucket, and never more buckets than
+    /// transactions. Returns `None` if the pool is empty.
+    pub fn gas_stats(&self, buckets: usize) -> Option<GasStats> {
+        let min = *self.by_gas.first_key_value()?.0;
+        let max = *self.by_gas.last_key_value()?.0;
+
+        let buckets = buckets.min(self.len).max(1);
+        let width = ((max - min) / buckets).saturating_add(U256::one());
+
+        let mut histogram: Vec<_> = (0..buckets)
+            .map(|ii| {
+                let low = min.saturating_add(width.saturating_mul(ii.into()));
+                let high = low.saturating_add(width - U256::one()).min(max);
+                Bucket {
+                    low,
+                    high,
+                    count: 0,
+                }
+            })
+            .collect();
+
+        let middle = (self.len - 1) / 2;
+        let mut median = None;
+        let mut seen = 0;
+
+        for (gas, same_gas) in self.by_gas.iter() {
+            seen += same_gas.len();
+
+            if median.is_none() && seen > middle {
+                median = Some(*gas);
+            }
+
+            let index = ((*gas - min) / width).as_usize();
+            histogram[index].count += same_gas.len();
+        }
+
+        Some(GasStats {
+            min,
+            median: median.unwrap_or(max),
+            max,
+            histogram,
+        })
+    }
+
     /// Gets an iterator over the transactions, sorted by gas price in decending
     /// order.
     pub fn iter(&self) -> impl Iterator<Item = &T> {
@@ -118,59 +389,243 @@ where
             .map(Arc::as_ref)
     }
 
-    /// Removes a transaction from the pool. Panics if `item` is not in the
-    /// pool.
-    pub fn remove(&mut self, item: &T) {
-        for input in item.inputs() {
-            let removed = self
-                .by_input
-                .remove(input)
-                .expect("item to remove not found by input");
-            assert!(removed.as_ref() == item);
+    /// Like `iter`, but only over transactions of `kind`.
+    pub fn iter_kind(&self, kind: T::Kind) -> impl Iterator<Item = &T> {
+        self.by_kind
+            .range((kind, U256::zero())..=(kind, U256::max_value()))
+            .rev()
+            .flat_map(|(_, v)| v.iter())
+            .map(Arc::as_ref)
+    }
+
+    /// Checks that `by_gas`, `by_kind`, `by_input`, and `len` all agree with
+    /// each other, describing the first inconsistency found.
+    pub fn validate_indexes(&self) -> Result<(), String> {
+        let mut count = 0;
+
+        for (gas, same_gas) in self.by_gas.iter() {
+            if same_gas.is_empty() {
+                return Err(format!("empty entry by gas at {}", gas));
+            }
+
+            for item in same_gas.iter() {
+                count += 1;
+
+                if item.gas_price() != gas {
+                    return Err(format!(
+                        "transaction priced at {} filed by gas under {}",
+                        item.gas_price(),
+                        gas
+                    ));
+                }
+
+                for input in item.inputs() {
+                    match self.by_input.get(input) {
+                        Some(o) if Arc::ptr_eq(o, item) => (),
+                        Some(_) => {
+                            return Err(format!(
+                                "input {} maps to a different transaction",
+                                input
+                            ))
+                        }
+                        None => {
+                            return Err(format!(
+                                "input {} missing by input",
+                                input
+                            ))
+                        }
+                    }
+                }
+            }
         }
 
-        match self.by_gas.entry(*item.gas_price()) {
-            Entry::Vacant(_) => panic!("item to remove not found by gas"),
-            Entry::Occupied(mut o) => {
-                let empty = {
-                    let same_gas = o.get_mut();
-                    let before = same_gas.len();
+        if count != self.len {
+            return Err(format!(
+                "{} transactions by gas, but len is {}",
+                count, self.len
+            ));
+        }
 
-                    same_gas.retain(|e| e.as_ref() != item);
+        let mut count = 0;
 
-                    assert_eq!(
-                        before,
-                        same_gas.len() + 1,
-                        "incorrect number of items removed by gas"
-                    );
+        for ((kind, gas), same_kind) in self.by_kind.iter() {
+            if same_kind.is_empty() {
+                return Err(format!(
+                    "empty entry by kind at {:?} {}",
+                    kind, gas
+                ));
+            }
 
-                    same_gas.is_empty()
-                };
+            for item in same_kind.iter() {
+                count += 1;
+
+                if item.kind() != *kind || item.gas_price() != gas {
+                    return Err(format!(
+                        "{:?} transaction priced at {} filed under {:?} {}",
+                        item.kind(),
+                        item.gas_price(),
+                        kind,
+                        gas
+                    ));
+                }
 
-                if empty {
-                    o.remove();
+                let found = self
+                    .by_gas
+                    .get(gas)
+                    .map(|v| v.iter().any(|e| Arc::ptr_eq(e, item)))
+                    .unwrap_or(false);
+
+                if !found {
+                    return Err(format!(
+                        "{:?} transaction priced at {} missing by gas",
+                        kind, gas
+                    ));
                 }
             }
         }
 
-        self.len -= 1;
+        if count != self.len {
+            return Err(format!(
+                "{} transactions by kind, but len is {}",
+                count, self.len
+            ));
+        }
+
+        for (input, item) in self.by_input.iter() {
+            if !item.inputs().any(|i| i == input) {
+                return Err(format!(
+                    "input {} maps to a transaction not spending it",
+                    input
+                ));
+            }
+
+            let found = self
+                .by_gas
+                .get(item.gas_price())
+                .map(|v| v.iter().any(|e| Arc::ptr_eq(e, item)))
+                .unwrap_or(false);
+
+            if !found {
+                return Err(format!(
+                    "input {} maps to a transaction missing by gas",
+                    input
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Panics in debug builds if the indexes have become inconsistent. Every
+    /// index is walked, so benchmarks, built optimized, skip this.
+    fn debug_validate(&self) {
+        if cfg!(debug_assertions) {
+            assert_eq!(self.validate_indexes(), Ok(()));
+        }
+    }
+
+    /// Removes a transaction from the pool.
+    ///
+    /// If the indexes disagree about `item` (ex. it isn't in the pool at all),
+    /// whatever can be found is still removed, and the first disagreement is
+    /// returned as an error.
+    pub fn remove(&mut self, item: &T) -> Result<(), PoolError> {
+        let mut result = Ok(());
+
+        for input in item.inputs() {
+            let outcome = match self.by_input.get(input) {
+                Some(o) if o.as_ref() == item => {
+                    self.by_input.remove(input);
+                    Ok(())
+                }
+                Some(_) => WrongByInput { input: *input }.fail(),
+                None => MissingByInput { input: *input }.fail(),
+            };
+
+            result = result.and(outcome);
+        }
+
+        let gasprice = *item.gas_price();
+        let removed = unfile(&mut self.by_gas, &gasprice, item);
+        unfile(&mut self.by_kind, &(item.kind(), gasprice), item);
+
+        self.len -= removed;
+        self.debug_validate();
+
+        result?;
+        ensure!(removed > 0, MissingByGas { gasprice });
+        Ok(())
+    }
+
+    /// Removes and returns every transaction, in the same order as `iter`.
+    pub fn drain(&mut self) -> Vec<T>
+    where
+        T: Clone,
+    {
+        // With `by_input` gone, each `Arc` left in `by_gas` is usually the
+        // only one, so most transactions are unwrapped rather than cloned.
+        self.by_input.clear();
+        self.by_kind.clear();
+        self.len = 0;
+
+        let by_gas = std::mem::take(&mut self.by_gas);
+
+        by_gas
+            .into_iter()
+            .rev()
+            .flat_map(|(_, same_gas)| same_gas)
+            .map(|item| Arc::try_unwrap(item).unwrap_or_else(|a| (*a).clone()))
+            .collect()
+    }
+
+    /// Removes every transaction, returning how many there were.
+    pub fn clear(&mut self) -> usize {
+        let cleared = self.len;
+
+        self.by_gas.clear();
+        self.by_kind.clear();
+        self.by_input.clear();
+        self.len = 0;
+
+        cleared
+    }
+
+    /// Removes every transaction for which `f` returns `false`.
+    pub fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&T) -> bool,
+    {
+        let mut rejected = Vec::new();
+
+        for item in self.by_gas.values().flat_map(|v| v.iter()) {
+            if !f(item.as_ref()) {
+                rejected.push(item.clone());
+            }
+        }
+
+        // Every rejected item came from `by_gas`, so removing it can only fail
+        // if the indexes were already inconsistent, and `remove` cleans up
+        // what it can regardless.
+        for item in rejected.into_iter() {
+            self.remove(&item).ok();
+        }
     }
 
     /// Inserts a new transaction into the pool. If there are one or more
     /// conflicts with transactions already in the pool and the new transaction
     /// has a higher gas price, the new transaction replaces the existing ones.
-    pub fn insert<V: Into<T>>(&mut self, item: V) {
-        self.maybe_replace(item.into(), false);
+    pub fn insert<V: Into<T>>(&mut self, item: V) -> Insertion {
+        self.maybe_replace(item.into(), false)
     }
 
     /// Inserts a new transaction into the pool. If there are one or more
     /// conflicts with transactions already in the pool, the new transaction
     /// replaces the existing ones regardless of gas price.
-    pub fn replace(&mut self, item: T) {
-        self.maybe_replace(item, true);
+    pub fn replace(&mut self, item: T) -> Insertion {
+        self.maybe_replace(item, true)
     }
 
-    fn maybe_replace(&mut self, item: T, force: bool) {
+    fn maybe_replace(&mut self, item: T, force: bool) -> Insertion {
         let item = Arc::new(item);
         let inputs = item.inputs();
 
@@ -179,61 +634,67 @@ where
         for input in inputs.clone() {
             if let Some(conflict) = self.by_input.get(input) {
                 if !force && conflict.gas_price() >= item.gas_price() {
-                    return;
+                    return Insertion::Outbid {
+                        input: *input,
+                        gasprice: *conflict.gas_price(),
+                    };
                 } else {
                     replacees.push(conflict.clone());
                 }
             }
         }
 
-        // Remove replaced transactions.
+        // Remove replaced transactions. As in `retain`, these came from the
+        // pool, so an error here only means the indexes were already off.
+        let before = self.len;
         for replacee in replacees.into_iter() {
-            self.remove(&replacee);
+            self.remove(&replacee).ok();
         }
+        let replaced = before - self.len;
 
         // Insert the new transaction.
         for input in inputs {
             self.by_input.insert(*input, item.clone());
         }
 
-        self.by_gas.entry(*item.gas_price()).or_default().push(item);
+        let gasprice = *item.gas_price();
+        file(&mut self.by_gas, gasprice, item.clone());
+        file(&mut self.by_kind, (item.kind(), gasprice), item.clone());
 
         self.len += 1;
 
+        let mut result = Insertion::Added { replaced };
+
         if self.len > self.max_len {
-            let v = self.by_gas.first_key_value().unwrap().1[0].clone();
-            self.remove(&v);
+            let v = self.evict_cheapest();
+
+            if Arc::ptr_eq(&v, &item) {
+                result = Insertion::Evicted;
+            }
         }
+
+        self.debug_validate();
+        result
     }
 
     /// Removes all transactions from the pool that conflict with `other`.
-    pub fn remove_conflicting<U>(&mut self, other: &U)
+    pub fn remove_conflicting<U>(&mut self, other: &U) -> Result<(), PoolError>
     where
         U: Transaction,
     {
-        self.remove_conflicting_inputs(other.inputs());
-    }
+        let mut result = Ok(());
 
-    fn remove_conflicting_inputs(&mut self, inputs: Inputs) {
-        for input in inputs {
-            let old = match self.by_input.remove(input) {
-                Some(o) => o,
+        for input in other.inputs() {
+            let old = match self.by_input.get(input) {
+                Some(o) => o.clone(),
                 None => continue,
             };
 
-            let same_gas = match self.by_gas.get_mut(old.gas_price()) {
-                Some(s) => s,
-                None => panic!("transaction missing by gas"),
-            };
-
-            let before = same_gas.len();
-            same_gas.retain(|e| *e != old);
-
-            let removed = before - same_gas.len();
-            assert_eq!(1, removed, "too many transactions removed by gas");
-
-            self.len -= removed;
+            // Removes every input spent by `old`, not just the conflicting one.
+            result = result.and(self.remove(&old));
         }
+
+        result
     }
 }
 
@@ -261,6 +722,10 @@ impl DerefMut for Identified {
 }
 
 impl Identified {
+    pub fn new(deposit: Deposit, id: U256) -> Self {
+        Self(deposit, id)
+    }
+
     pub fn id(&self) -> &U256 {
         &self.1
     }
@@ -270,6 +735,70 @@ impl Identified {
     }
 }
 
+#[derive(Debug, Snafu)]
+pub enum ParseDepositError {
+    #[snafu(display("expected 4 fields, found {}", found))]
+    FieldCount { found: usize },
+
+    #[snafu(display("invalid {} `{}`", field, value))]
+    InvalidField { field: &'static str, value: String },
+}
+
+fn parse_u256(
+    field: &'static str,
+    value: &str,
+) -> Result<U256, ParseDepositError> {
+    let parsed = match value.strip_prefix("0x") {
+        Some(hex) => U256::from_str(hex).ok(),
+        None => U256::from_dec_str(value).ok(),
+    };
+
+    parsed.ok_or_else(|| ParseDepositError::InvalidField {
+        field,
+        value: value.to_owned(),
+    })
+}
+
+/// Parses a deposit from a line of the form `id amount bounty owner`, where
+/// the fields are separated by whitespace or commas. Numbers are decimal
+/// unless prefixed with `0x`.
+impl FromStr for Identified {
+    type Err = ParseDepositError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<_> = line
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|f| !f.is_empty())
+            .collect();
+
+        if fields.len() != 4 {
+            return Err(ParseDepositError::FieldCount {
+                found: fields.len(),
+            });
+        }
+
+        let id = parse_u256("id", fields[0])?;
+        let amount = parse_u256("amount", fields[1])?;
+        let bounty = parse_u256("bounty", fields[2])?;
+
+        let owner_hex = fields[3].strip_prefix("0x").unwrap_or(fields[3]);
+        let owner = Address::from_str(owner_hex).map_err(|_| {
+            ParseDepositError::InvalidField {
+                field: "owner",
+                value: fields[3].to_owned(),
+            }
+        })?;
+
+        let deposit = Deposit {
+            amount,
+            bounty,
+            owner,
+        };
+
+        Ok(Self::new(deposit, id))
+    }
+}
+
 #[derive(Debug)]
 pub struct DepositPool {
     max_len: usize,
@@ -290,11 +819,64 @@ impl Default for DepositPool {
 impl DepositPool {
     pub const DEFAULT_MAX_LEN: usize = 1024;
 
+    pub fn get(&self, id: &U256) -> Option<&Identified> {
+        self.by_id.get(id).map(Arc::as_ref)
+    }
+
     pub fn iter(&self) -> impl Iterator<Item = &Identified> {
         self.by_bounty.iter().map(Arc::as_ref).rev()
     }
 
-    pub fn insert(&mut self, item: Identified) {
+    pub fn max_len(&self) -> usize {
+        self.max_len
+    }
+
+    /// Changes `max_len`, evicting the deposits with the smallest bounties
+    /// straight away if there are now too many. Returns how many were evicted.
+    pub fn set_max_len(&mut self, max_len: usize) -> usize {
+        self.max_len = max_len;
+
+        let mut evicted = 0;
+        while self.by_id.len() > self.max_len {
+            self.evict_smallest();
+            evicted += 1;
+        }
+
+        evicted
+    }
+
+    fn evict_smallest(&mut self) -> Arc<Identified> {
+        let smallest = self.by_bounty.pop_first().unwrap();
+        self.by_id.remove(smallest.id());
+        smallest
+    }
+
+    /// Iterates over the deposits worth claiming at `gasprice`, most profitable
+    /// first, along with the profit (bounty less the marginal fee) of each.
+    ///
+    /// Every deposit adds the same gas to a claim, so bounty order is already
+    /// profit order. `iter` remains bounty ordered and unfiltered.
+    pub fn iter_by_profit(
+        &self,
+        gasprice: &U256,
+    ) -> impl Iterator<Item = (&Identified, U256)> {
+        self.iter_above(Deposit::marginal_fee(gasprice))
+    }
+
+    /// `iter_by_profit`, given the marginal fee instead of the gas price.
+    fn iter_above(
+        &self,
+        fee: U256,
+    ) -> impl Iterator<Item = (&Identified, U256)> {
+        self.iter()
+            .take_while(move |d| d.bounty >= fee)
+            .map(move |d| (d, d.bounty - fee))
+    }
+
+    /// Inserts `item`, then evicts the deposit with the smallest bounty if
+    /// that makes the pool longer than `max_len`. Returns whether `item` is
+    /// still pooled, which it isn't if it was the one evicted.
+    pub fn insert(&mut self, item: Identified) -> bool {
         let arc = Arc::new(item);
         let old = self.by_id.insert(*arc.id(), arc.clone());
 
@@ -303,7 +885,61 @@ impl DepositPool {
             self.by_bounty.remove(&old);
         }
 
-        self.by_bounty.insert(arc);
+        self.by_bounty.insert(arc.clone());
+
+        if self.by_id.len() > self.max_len {
+            let evicted = self.evict_smallest();
+            return !Arc::ptr_eq(&evicted, &arc);
+        }
+
+        true
+    }
+
+    /// Inserts every deposit from `items` whose id isn't already in the pool,
+    /// returning the number inserted without being evicted straight away.
+    pub fn extend<I>(&mut self, items: I) -> usize
+    where
+        I: IntoIterator<Item = Identified>,
+    {
+        let mut inserted = 0;
+
+        for item in items {
+            if self.by_id.contains_key(item.id()) {
+                continue;
+            }
+
+            if self.insert(item) {
+                inserted += 1;
+            }
+        }
+
+        inserted
+    }
+
+    /// Removes every deposit, returning how many there were.
+    pub fn clear(&mut self) -> usize {
+        let cleared = self.by_id.len();
+
+        self.by_id.clear();
+        self.by_bounty.clear();
+
+        cleared
+    }
+
+    /// Removes the deposit with the given id, if it is in the pool.
+    pub fn remove(&mut self, id: &U256) -> Option<Arc<Identified>> {
+        let removed = self.by_id.remove(id)?;
+        self.by_bounty.remove(&removed);
+        Some(removed)
+    }
+
+    /// Removes every deposit in `ids` (ex. those claimed on-chain), returning
+    /// the number actually removed.
+    pub fn remove_claimed<'a, I>(&mut self, ids: I) -> usize
+    where
+        I: IntoIterator<Item = &'a U256>,
+    {
+        ids.into_iter().filter_map(|id| self.remove(id)).count()
     }
 }
 
@@ -346,6 +982,13 @@ mod tests {
     }
 
     impl Transaction for MockTx {
+        /// Whether there's a second input, which is as good a kind as any.
+        type Kind = bool;
+
+        fn kind(&self) -> bool {
+            self.input1.is_some()
+        }
+
         fn gas_price(&self) -> &U256 {
             &self.gasprice
         }
@@ -357,12 +1000,35 @@ mod tests {
                 Inputs::One(&self.input0)
             }
         }
+
+        fn signing_hash(&self, _: &[u8; 32]) -> [u8; 32] {
+            // Pools never check signatures.
+            [0; 32]
+        }
+    }
+
+    #[test]
+    fn inputs_order_insensitive() {
+        use std::collections::HashSet;
+
+        let (a, b) = (U256::from(97), U256::from(103));
+
+        assert_eq!(Inputs::Two(&a, &b), Inputs::Two(&b, &a));
+        assert_ne!(Inputs::Two(&a, &b), Inputs::Two(&a, &a));
+        assert_ne!(Inputs::One(&a), Inputs::Two(&a, &b));
+
+        let set: HashSet<_> = vec![Inputs::Two(&a, &b), Inputs::Two(&b, &a)]
+            .into_iter()
+            .collect();
+
+        assert_eq!(set.len(), 1);
     }
 
     #[test]
     fn len_zero() {
         let pool = Pool::<MockTx>::default();
         assert_eq!(pool.len(), 0);
+        assert!(pool.is_empty());
     }
 
     #[test]
@@ -370,6 +1036,7 @@ mod tests {
         let mut pool = Pool::<MockTx>::default();
         pool.insert(MockTx::two(27, 97, 103));
         assert_eq!(pool.len(), 1);
+        assert!(!pool.is_empty());
 
         let peeked = pool.peek().expect("pool should contain a transaction");
         assert_eq!(peeked.gasprice, 27.into());
@@ -447,6 +1114,163 @@ mod tests {
         assert_eq!(pool.by_input[&97.into()], rc0[0]);
     }
 
+    #[test]
+    fn insert_outcome_added() {
+        let mut pool = Pool::<MockTx>::default();
+
+        let outcome = pool.insert(MockTx::one(27, 97));
+        assert_eq!(outcome, Insertion::Added { replaced: 0 });
+
+        // Conflicts with both of the existing transactions.
+        pool.insert(MockTx::one(27, 98));
+        let outcome = pool.insert(MockTx::two(28, 97, 98));
+        assert_eq!(outcome, Insertion::Added { replaced: 2 });
+    }
+
+    #[test]
+    fn insert_outcome_outbid() {
+        let mut pool = Pool::<MockTx>::default();
+        pool.insert(MockTx::one(27, 97));
+
+        let outcome = pool.insert(MockTx::two(27, 98, 97));
+        let expected = Insertion::Outbid {
+            input: 97.into(),
+            gasprice: 27.into(),
+        };
+
+        assert_eq!(outcome, expected);
+        assert_eq!(pool.len(), 1);
+    }
+
+    #[test]
+    fn equal_gas_ordered_by_input() {
+        let txs = [
+            MockTx::one(20, 9),
+            MockTx::two(20, 7, 3),
+            MockTx::one(20, 5),
+            MockTx::one(30, 8),
+        ];
+
+        let mut forward = Pool::<MockTx>::default();
+        let mut backward = Pool::<MockTx>::default();
+
+        for tx in txs.iter() {
+            forward.insert(tx.clone());
+        }
+
+        for tx in txs.iter().rev() {
+            backward.insert(tx.clone());
+        }
+
+        let expected = vec![
+            &txs[3], // gas 30
+            &txs[1], // inputs 3 and 7
+            &txs[2], // input 5
+            &txs[0], // input 9
+        ];
+
+        assert_eq!(forward.iter().collect::<Vec<_>>(), expected);
+        assert_eq!(backward.iter().collect::<Vec<_>>(), expected);
+        assert_eq!(forward.peek_n(2), &expected[..2]);
+    }
+
+    #[test]
+    fn iter_kind_by_gas() {
+        let mut pool = Pool::<MockTx>::default();
+        pool.insert(MockTx::one(27, 97));
+        pool.insert(MockTx::two(30, 98, 99));
+        pool.insert(MockTx::one(29, 100));
+        pool.insert(MockTx::two(28, 101, 102));
+        pool.insert(MockTx::two(28, 90, 91));
+
+        let two: Vec<_> = pool.iter_kind(true).map(|t| t.input0).collect();
+        assert_eq!(two, vec![98.into(), 90.into(), 101.into()]);
+
+        let one: Vec<_> = pool.iter_kind(false).map(|t| t.input0).collect();
+        assert_eq!(one, vec![100.into(), 97.into()]);
+    }
+
+    #[test]
+    fn iter_kind_after_cross_kind_replace() {
+        let mut pool = Pool::<MockTx>::default();
+        pool.insert(MockTx::two(27, 97, 98));
+
+        let outcome = pool.insert(MockTx::one(28, 98));
+        assert_eq!(outcome, Insertion::Added { replaced: 1 });
+        assert_eq!(pool.validate_indexes(), Ok(()));
+
+        assert_eq!(pool.iter_kind(true).count(), 0);
+        assert_eq!(pool.iter_kind(false).count(), 1);
+    }
+
+    #[test]
+    fn iter_kind_after_evict_and_clear() {
+        let mut pool = Pool::<MockTx>::default();
+        pool.max_len = 1;
+        pool.insert(MockTx::two(27, 97, 98));
+        pool.insert(MockTx::one(28, 99));
+
+        assert_eq!(pool.iter_kind(true).count(), 0);
+        assert_eq!(pool.validate_indexes(), Ok(()));
+
+        pool.clear();
+        assert_eq!(pool.iter_kind(false).count(), 0);
+    }
+
+    #[test]
+    fn evict_last_of_equal_gas() {
+        let mut pool = Pool::<MockTx>::default();
+        pool.max_len = 2;
+        pool.insert(MockTx::one(27, 97));
+        pool.insert(MockTx::one(27, 98));
+
+        let outcome = pool.insert(MockTx::one(27, 99));
+        assert_eq!(outcome, Insertion::Evicted);
+
+        let outcome = pool.insert(MockTx::one(27, 96));
+        assert_eq!(outcome, Insertion::Added { replaced: 0 });
+        assert!(pool.get(&98.into()).is_none());
+    }
+
+    #[test]
+    fn set_max_len_evicts_cheapest() {
+        let mut pool = Pool::<MockTx>::default();
+        pool.max_len = 4;
+        pool.insert(MockTx::one(30, 1));
+        pool.insert(MockTx::two(10, 2, 3));
+        pool.insert(MockTx::one(20, 4));
+        pool.insert(MockTx::one(20, 5));
+
+        assert_eq!(pool.set_max_len(2), 2);
+        assert_eq!(pool.max_len(), 2);
+        assert_eq!(pool.validate_indexes(), Ok(()));
+
+        let inputs: Vec<U256> = pool.iter().map(|t| t.input0).collect();
+        assert_eq!(inputs, vec![1.into(), 4.into()]);
+        assert!(pool.get(&3.into()).is_none());
+
+        // Growing the limit evicts nothing, and makes room again.
+        assert_eq!(pool.set_max_len(3), 0);
+        assert_eq!(
+            pool.insert(MockTx::one(5, 6)),
+            Insertion::Added { replaced: 0 }
+        );
+    }
+
+    #[test]
+    fn insert_outcome_evicted() {
+        let mut pool = Pool::<MockTx>::default();
+        pool.max_len = 1;
+        pool.insert(MockTx::one(27, 97));
+
+        assert_eq!(pool.insert(MockTx::one(26, 98)), Insertion::Evicted);
+        assert_eq!(
+            pool.insert(MockTx::one(28, 99)),
+            Insertion::Added { replaced: 0 }
+        );
+        assert_eq!(pool.len(), 1);
+    }
+
     #[test]
     fn peek_empty() {
         let pool = Pool::<MockTx>::default();
@@ -489,12 +1313,370 @@ mod tests {
         assert_eq!(pool.peek(), Some(&tx1));
     }
 
+    #[test]
+    fn gas_stats_empty() {
+        let pool = Pool::<MockTx>::default();
+        assert_eq!(pool.gas_stats(4), None);
+    }
+
+    #[test]
+    fn gas_stats() {
+        let mut pool = Pool::<MockTx>::default();
+
+        pool.insert(MockTx::one(10, 1));
+        pool.insert(MockTx::one(10, 2));
+        pool.insert(MockTx::one(15, 3));
+        pool.insert(MockTx::one(40, 4));
+        pool.insert(MockTx::one(49, 5));
+
+        let stats = pool.gas_stats(4).unwrap();
+
+        assert_eq!(stats.min, 10.into());
+        assert_eq!(stats.median, 15.into());
+        assert_eq!(stats.max, 49.into());
+
+        let counts: Vec<_> = stats.histogram.iter().map(|b| b.count).collect();
+        assert_eq!(counts, vec![3, 0, 0, 2]);
+
+        assert_eq!(stats.histogram[0].low, 10.into());
+        assert_eq!(stats.histogram[3].high, 49.into());
+    }
+
+    #[test]
+    fn gas_stats_full_range() {
+        let mut pool = Pool::<MockTx>::default();
+
+        pool.insert(MockTx::one(0, 1));
+        pool.insert(MockTx::one(U256::max_value(), 2));
+
+        let stats = pool.gas_stats(usize::max_value()).unwrap();
+        let counts: Vec<_> = stats.histogram.iter().map(|b| b.count).collect();
+        assert_eq!(counts, vec![1, 1]);
+        assert_eq!(stats.histogram[1].high, U256::max_value());
+
+        let stats = pool.gas_stats(0).unwrap();
+        assert_eq!(stats.histogram.len(), 1);
+        assert_eq!(stats.histogram[0].count, 2);
+    }
+
+    #[test]
+    fn parse_identified() {
+        let line = "7, 0x10 3 0x1111111111111111111111111111111111111111";
+        let parsed: Identified = line.parse().unwrap();
+
+        assert_eq!(*parsed.id(), 7.into());
+        assert_eq!(parsed.amount, 16.into());
+        assert_eq!(parsed.bounty, 3.into());
+        assert_eq!(parsed.owner, Address::from([0x11; 20]));
+    }
+
+    #[test]
+    fn parse_identified_malformed() {
+        assert!("1 2 3".parse::<Identified>().is_err());
+        assert!("1 2 three 0x00".parse::<Identified>().is_err());
+        assert!("1 2 3 notanaddress".parse::<Identified>().is_err());
+    }
+
+    #[test]
+    fn deposit_pool_extend_skips_existing() {
+        let deposit = |bounty: u64| Deposit {
+            amount: 100.into(),
+            bounty: bounty.into(),
+            owner: Address::zero(),
+        };
+
+        let mut pool = DepositPool::default();
+        pool.insert(Identified::new(deposit(1), 1.into()));
+
+        let inserted = pool.extend(vec![
+            Identified::new(deposit(2), 1.into()),
+            Identified::new(deposit(3), 2.into()),
+        ]);
+
+        assert_eq!(inserted, 1);
+        assert_eq!(pool.iter().count(), 2);
+    }
+
+    #[test]
+    fn deposit_pool_iter_by_profit() {
+        let deposit = |bounty: u64| Deposit {
+            amount: 100.into(),
+            bounty: bounty.into(),
+            owner: Address::zero(),
+        };
+
+        let mut pool = DepositPool::default();
+        pool.insert(Identified::new(deposit(5), 1.into()));
+        pool.insert(Identified::new(deposit(10), 2.into()));
+        pool.insert(Identified::new(deposit(1), 3.into()));
+
+        fn ids<'a, I>(iter: I) -> Vec<(u64, u64)>
+        where
+            I: Iterator<Item = (&'a Identified, U256)>,
+        {
+            iter.map(|(d, profit)| (d.id().low_u64(), profit.low_u64()))
+                .collect()
+        }
+
+        // Deposits cost no gas to claim yet, so nothing is left out here.
+        let gasprice = U256::from(100);
+        assert_eq!(Deposit::marginal_fee(&gasprice), U256::zero());
+        assert_eq!(
+            ids(pool.iter_by_profit(&gasprice)),
+            vec![(2, 10), (1, 5), (3, 1)],
+        );
+
+        // Deposit 3's bounty of 1 doesn't cover a fee of 4.
+        assert_eq!(ids(pool.iter_above(4.into())), vec![(2, 6), (1, 1)]);
+
+        // Exactly covering the fee is still worth it.
+        assert_eq!(ids(pool.iter_above(5.into())), vec![(2, 5), (1, 0)]);
+        assert!(ids(pool.iter_above(11.into())).is_empty());
+    }
+
+    #[test]
+    fn deposit_pool_remove_claimed() {
+        let deposit = |bounty: u64| Deposit {
+            amount: 100.into(),
+            bounty: bounty.into(),
+            owner: Address::zero(),
+        };
+
+        let mut pool = DepositPool::default();
+        pool.insert(Identified::new(deposit(1), 1.into()));
+        pool.insert(Identified::new(deposit(2), 2.into()));
+        pool.insert(Identified::new(deposit(3), 3.into()));
+
+        let claimed: Vec<U256> = vec![2.into(), 4.into()];
+        assert_eq!(pool.remove_claimed(&claimed), 1);
+
+        let ids: Vec<U256> = pool.iter().map(|d| *d.id()).collect();
+        assert_eq!(ids, vec![3.into(), 1.into()]);
+        assert!(pool.remove(&2.into()).is_none());
+    }
+
+    #[test]
+    fn deposit_pool_clear() {
+        let deposit = |bounty: u64| Deposit {
+            amount: 100.into(),
+            bounty: bounty.into(),
+            owner: Address::zero(),
+        };
+
+        let mut pool = DepositPool::default();
+        pool.insert(Identified::new(deposit(1), 1.into()));
+        pool.insert(Identified::new(deposit(2), 2.into()));
+
+        assert_eq!(pool.clear(), 2);
+        assert_eq!(pool.iter().count(), 0);
+        assert!(pool.get(&1.into()).is_none());
+    }
+
+    #[test]
+    fn deposit_pool_set_max_len() {
+        let deposit = |bounty: u64| Deposit {
+            amount: 100.into(),
+            bounty: bounty.into(),
+            owner: Address::zero(),
+        };
+
+        let mut pool = DepositPool::default();
+        pool.insert(Identified::new(deposit(1), 1.into()));
+        pool.insert(Identified::new(deposit(3), 2.into()));
+        pool.insert(Identified::new(deposit(2), 3.into()));
+
+        assert_eq!(pool.set_max_len(1), 2);
+        assert_eq!(pool.max_len(), 1);
+
+        let ids: Vec<U256> = pool.iter().map(|d| *d.id()).collect();
+        assert_eq!(ids, vec![2.into()]);
+        assert!(pool.get(&1.into()).is_none());
+        assert!(pool.get(&3.into()).is_none());
+    }
+
+    #[test]
+    fn deposit_pool_insert_evicts_smallest_bounty() {
+        let deposit = |bounty: u64| Deposit {
+            amount: 100.into(),
+            bounty: bounty.into(),
+            owner: Address::zero(),
+        };
+
+        let mut pool = DepositPool::default();
+        pool.set_max_len(2);
+
+        assert!(pool.insert(Identified::new(deposit(5), 1.into())));
+        assert!(pool.insert(Identified::new(deposit(2), 2.into())));
+
+        // A bigger bounty pushes out the smallest.
+        assert!(pool.insert(Identified::new(deposit(9), 3.into())));
+        let ids: Vec<U256> = pool.iter().map(|d| *d.id()).collect();
+        assert_eq!(ids, vec![3.into(), 1.into()]);
+
+        // A smaller one is evicted itself, and not counted as inserted.
+        assert!(!pool.insert(Identified::new(deposit(1), 4.into())));
+        let inserted = pool.extend(vec![
+            Identified::new(deposit(1), 5.into()),
+            Identified::new(deposit(7), 6.into()),
+        ]);
+
+        assert_eq!(inserted, 1);
+        let ids: Vec<U256> = pool.iter().map(|d| *d.id()).collect();
+        assert_eq!(ids, vec![3.into(), 6.into()]);
+    }
+
+    #[test]
+    fn peek_n() {
+        let mut pool = Pool::<MockTx>::default();
+        pool.insert(MockTx::one(10, 1));
+        pool.insert(MockTx::one(30, 2));
+        pool.insert(MockTx::one(20, 3));
+        pool.insert(MockTx::one(30, 4));
+
+        let gas = |txs: Vec<&MockTx>| -> Vec<U256> {
+            txs.into_iter().map(|t| t.gasprice).collect()
+        };
+
+        assert_eq!(gas(pool.peek_n(0)), Vec::<U256>::new());
+        assert_eq!(gas(pool.peek_n(3)), vec![30.into(), 30.into(), 20.into()]);
+        assert_eq!(gas(pool.peek_n(10)).len(), 4);
+
+        let expected: Vec<_> = pool.iter().take(3).collect();
+        assert_eq!(pool.peek_n(3), expected);
+    }
+
+    #[test]
+    fn retain_above_threshold() {
+        let mut pool = Pool::<MockTx>::default();
+        pool.insert(MockTx::two(10, 1, 2));
+        pool.insert(MockTx::one(20, 3));
+        pool.insert(MockTx::two(20, 4, 5));
+        pool.insert(MockTx::one(30, 6));
+
+        pool.retain(|tx| tx.gasprice >= 20.into());
+
+        assert_eq!(pool.len(), 3);
+        assert_eq!(pool.by_gas.len(), 2);
+        assert!(!pool.by_gas.contains_key(&10.into()));
+        assert_eq!(pool.by_input.len(), 4);
+        assert!(!pool.by_input.contains_key(&1.into()));
+        assert!(!pool.by_input.contains_key(&2.into()));
+
+        pool.retain(|_| false);
+
+        assert_eq!(pool.len(), 0);
+        assert!(pool.by_gas.is_empty());
+        assert!(pool.by_input.is_empty());
+    }
+
     #[test]
     fn remove() {
         let mut pool = Pool::default();
         let tx0 = MockTx::two(27, 100, 101);
         pool.insert(tx0.clone());
-        pool.remove(&tx0);
+        pool.remove(&tx0).unwrap();
+        assert_eq!(pool.len(), 0);
+    }
+
+    #[test]
+    fn drain() {
+        let mut pool = Pool::default();
+        pool.insert(MockTx::two(10, 1, 2));
+        pool.insert(MockTx::one(30, 3));
+        pool.insert(MockTx::one(20, 4));
+
+        let drained = pool.drain();
+
+        assert_eq!(
+            drained,
+            vec![
+                MockTx::one(30, 3),
+                MockTx::one(20, 4),
+                MockTx::two(10, 1, 2),
+            ]
+        );
+
         assert_eq!(pool.len(), 0);
+        assert!(pool.by_gas.is_empty());
+        assert!(pool.by_input.is_empty());
+        assert_eq!(pool.validate_indexes(), Ok(()));
+    }
+
+    #[test]
+    fn clear() {
+        let mut pool = Pool::default();
+        pool.insert(MockTx::two(10, 1, 2));
+        pool.insert(MockTx::one(30, 3));
+
+        assert_eq!(pool.clear(), 2);
+        assert_eq!(pool.len(), 0);
+        assert!(pool.peek().is_none());
+        assert_eq!(pool.validate_indexes(), Ok(()));
+
+        pool.insert(MockTx::one(20, 1));
+        assert_eq!(pool.len(), 1);
+    }
+
+    #[test]
+    fn remove_missing() {
+        let mut pool = Pool::default();
+        pool.insert(MockTx::one(27, 100));
+
+        let result = pool.remove(&MockTx::one(27, 101));
+
+        assert!(matches!(result, Err(PoolError::MissingByInput { .. })));
+        assert_eq!(pool.len(), 1);
+    }
+
+    #[test]
+    fn remove_conflicting_drops_every_input() {
+        let mut pool = Pool::default();
+        pool.insert(MockTx::two(27, 100, 101));
+        pool.insert(MockTx::one(30, 102));
+
+        pool.remove_conflicting(&MockTx::one(50, 100)).unwrap();
+
+        assert_eq!(pool.len(), 1);
+        assert_eq!(pool.by_gas.len(), 1);
+        assert!(!pool.by_input.contains_key(&101.into()));
+        assert_eq!(pool.validate_indexes(), Ok(()));
+    }
+
+    #[test]
+    fn validate_indexes_consistent() {
+        let mut pool = Pool::default();
+        pool.insert(MockTx::two(27, 100, 101));
+        pool.insert(MockTx::one(27, 102));
+        pool.insert(MockTx::one(30, 103));
+
+        assert_eq!(pool.validate_indexes(), Ok(()));
+    }
+
+    #[test]
+    fn validate_indexes_wrong_len() {
+        let mut pool = Pool::default();
+        pool.insert(MockTx::one(27, 100));
+        pool.len += 1;
+
+        assert!(pool.validate_indexes().is_err());
+    }
+
+    #[test]
+    fn validate_indexes_dangling_input() {
+        let mut pool = Pool::default();
+        pool.insert(MockTx::two(27, 100, 101));
+        pool.by_gas.clear();
+        pool.len = 0;
+
+        assert!(pool.validate_indexes().is_err());
+    }
+
+    #[test]
+    fn validate_indexes_missing_input() {
+        let mut pool = Pool::default();
+        pool.insert(MockTx::two(27, 100, 101));
+        pool.by_input.remove(&101.into());
+
+        assert!(pool.validate_indexes().is_err());
     }
 }
@@ -0,0 +1,199 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::signer::RelayerSigner;
+
+use ethers::providers::{Http, JsonRpcClient, ProviderError};
+use ethers::signers::ClientError;
+use ethers::types::{Address, Bytes, TransactionRequest, TxHash, U256, U64};
+
+use serde_json::{json, Value};
+
+use snafu::{ResultExt, Snafu};
+
+use std::str::FromStr;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility = "pub(crate)")]
+pub enum RelayError {
+    #[snafu(display("unable to sign transaction for relay: {}", source))]
+    Sign { source: ClientError },
+
+    #[snafu(display("bundle relay request failed: {}", source))]
+    Submit { source: ProviderError },
+}
+
+/// A transfer of ether sent along with each bundle, for builders paid
+/// directly instead of through the gas price.
+///
+/// Parsed from `<wei>:<address>`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct BuilderPayment {
+    pub amount: U256,
+    pub recipient: Address,
+}
+
+impl BuilderPayment {
+    /// Gas for a plain transfer of ether.
+    const GAS: u64 = 21_000;
+
+    /// The payment to send right after `tx`, from the same account and at
+    /// the same gas price.
+    fn after(&self, tx: &TransactionRequest) -> TransactionRequest {
+        let mut payment = TransactionRequest::new()
+            .to(self.recipient)
+            .value(self.amount)
+            .gas(Self::GAS);
+
+        payment.from = tx.from;
+        payment.gas_price = tx.gas_price;
+        payment.nonce = tx.nonce.map(|n| n + U256::one());
+
+        payment
+    }
+}
+
+impl FromStr for BuilderPayment {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let expected = || format!("expected `<wei>:<address>`, not `{}`", s);
+
+        let mut parts = s.splitn(2, ':');
+        let amount = parts.next().ok_or_else(expected)?;
+        let recipient = parts.next().ok_or_else(expected)?;
+
+        Ok(Self {
+            amount: U256::from_dec_str(amount).map_err(|_| expected())?,
+            recipient: recipient.parse().map_err(|_| expected())?,
+        })
+    }
+}
+
+/// Sends transactions to a private bundle relay with `eth_sendBundle`, instead
+/// of the public mempool where they can be seen and front-run.
+#[derive(Debug, Clone)]
+pub struct PrivateRelay {
+    http: Http,
+    signer: RelayerSigner,
+    payment: Option<BuilderPayment>,
+}
+
+impl PrivateRelay {
+    pub fn new(
+        http: Http,
+        signer: RelayerSigner,
+        payment: Option<BuilderPayment>,
+    ) -> Self {
+        Self {
+            http,
+            signer,
+            payment,
+        }
+    }
+
+    /// Signs `tx`, which must already have its nonce, gas, and gas price, and
+    /// asks the relay to include it in block `target`, followed by the
+    /// builder payment if there is one. Returns the hash of `tx`.
+    pub async fn send(
+        &self,
+        tx: TransactionRequest,
+        target: U64,
+    ) -> Result<TxHash, RelayError> {
+        let payment = self.payment.map(|p| p.after(&tx));
+
+        let mut raw = Vec::with_capacity(2);
+        let mut hashes = Vec::with_capacity(2);
+
+        for tx in std::iter::once(tx).chain(payment) {
+            let signed =
+                self.signer.sign_transaction_async(tx).await.context(Sign)?;
+            raw.push(signed.rlp());
+            hashes.push(signed.hash);
+        }
+
+        let params = bundle_params(&raw, target);
+
+        let _: Value = self
+            .http
+            .request("eth_sendBundle", Some(params))
+            .await
+            .map_err(Into::<ProviderError>::into)
+            .context(Submit)?;
+
+        Ok(hashes[0])
+    }
+}
+
+fn bundle_params(raw: &[Bytes], target: U64) -> Value {
+    json!([{
+        "txs": raw,
+        "blockNumber": target,
+    }])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bundle_params_shape() {
+        let params = bundle_params(&[Bytes(vec![0xab, 0xcd])], 16.into());
+
+        let expected = json!([{
+            "txs": ["0xabcd"],
+            "blockNumber": "0x10",
+        }]);
+
+        assert_eq!(params, expected);
+    }
+
+    #[test]
+    fn bundle_params_with_payment() {
+        let raw = [Bytes(vec![0xab]), Bytes(vec![0xcd])];
+        let params = bundle_params(&raw, 16.into());
+
+        let expected = json!([{
+            "txs": ["0xab", "0xcd"],
+            "blockNumber": "0x10",
+        }]);
+
+        assert_eq!(params, expected);
+    }
+
+    #[test]
+    fn parse_builder_payment() {
+        let payment: BuilderPayment =
+            "1000:0x1111111111111111111111111111111111111111"
+                .parse()
+                .unwrap();
+
+        assert_eq!(payment.amount, 1000.into());
+        assert_eq!(payment.recipient, Address::from([0x11; 20]));
+
+        assert!("1000".parse::<BuilderPayment>().is_err());
+        assert!("lots:0x11".parse::<BuilderPayment>().is_err());
+    }
+
+    #[test]
+    fn builder_payment_follows_transaction() {
+        let payment = BuilderPayment {
+            amount: 5.into(),
+            recipient: Address::from([0x22; 20]),
+        };
+
+        let tx = TransactionRequest::new()
+            .from(Address::from([0x11; 20]))
+            .gas_price(30)
+            .nonce(7);
+
+        let after = payment.after(&tx);
+
+        assert_eq!(after.from, tx.from);
+        assert_eq!(after.value, Some(5.into()));
+        assert_eq!(after.gas_price, Some(30.into()));
+        assert_eq!(after.nonce, Some(8.into()));
+        assert_eq!(after.gas, Some(21_000.into()));
+    }
+}
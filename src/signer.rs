@@ -2,11 +2,24 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use ethers::signers::{ClientError, Signer};
+use ethers::signers::{ClientError, Signer, Wallet};
 use ethers::types::{
-    Address, NameOrAddress, Signature, Transaction, TransactionRequest,
+    Address, NameOrAddress, Signature, Transaction, TransactionRequest, H256,
 };
-use ethers::utils::keccak256;
+use ethers::utils::{hash_message, keccak256};
+
+use rustc_hex::{FromHex, ToHex};
+
+use secp256k1::{Message, PublicKey, SecretKey};
+
+use snafu::{ensure, ResultExt, Snafu};
+
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
 
 #[derive(Debug, Clone, Copy)]
 pub struct AbstractSigner {
@@ -87,3 +100,555 @@ impl Signer for AbstractSigner {
         ])
     }
 }
+
+/// Which signer to broadcast bundles with, as given to `--signer`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum SignerKind {
+    /// `abstract`: unsigned transactions from the entry point.
+    Abstract,
+
+    /// `wallet:<path>`: a local private key, read from a file.
+    Wallet(PathBuf),
+
+    /// `external:<path>`: a signing service listening on a Unix socket.
+    External(PathBuf),
+}
+
+impl FromStr for SignerKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "abstract" {
+            return Ok(SignerKind::Abstract);
+        }
+
+        if let Some(path) = s.strip_prefix("wallet:") {
+            return Ok(SignerKind::Wallet(path.into()));
+        }
+
+        if let Some(path) = s.strip_prefix("external:") {
+            return Ok(SignerKind::External(path.into()));
+        }
+
+        Err(format!(
+            concat!(
+                "expected `abstract`, `wallet:<path>`, ",
+                "or `external:<path>`, not `{}`"
+            ),
+            s
+        ))
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum SignerError {
+    #[snafu(display("unable to read key from {}: {}", path.display(), source))]
+    ReadKey { path: PathBuf, source: io::Error },
+
+    #[snafu(display("invalid private key in {}", path.display()))]
+    WalletKey { path: PathBuf },
+
+    #[snafu(display("external signer unreachable: {}", source))]
+    ExternalIo { source: io::Error },
+
+    #[snafu(display("external signer replied with `{}`", reply))]
+    ExternalReply { reply: String },
+}
+
+/// Any of the signers the relayer can broadcast with.
+///
+/// `Signer` isn't object safe, so this stands in for a `Box<dyn Signer>`.
+#[derive(Debug, Clone)]
+pub enum RelayerSigner {
+    Abstract(AbstractSigner),
+    Wallet(Wallet),
+    External(ExternalSigner),
+}
+
+impl RelayerSigner {
+    pub fn new(kind: &SignerKind, chain_id: u64) -> Result<Self, SignerError> {
+        let signer = match kind {
+            SignerKind::Abstract => {
+                RelayerSigner::Abstract(AbstractSigner::new(Some(chain_id)))
+            }
+            SignerKind::Wallet(path) => {
+                let contents = std::fs::read_to_string(path)
+                    .context(ReadKey { path: path.clone() })?;
+
+                let wallet = Wallet::from_str(contents.trim())
+                    .map_err(|_| SignerError::WalletKey { path: path.clone() })?
+                    .set_chain_id(chain_id);
+
+                RelayerSigner::Wallet(wallet)
+            }
+            SignerKind::External(path) => RelayerSigner::External(
+                ExternalSigner::connect(path, chain_id)?,
+            ),
+        };
+
+        Ok(signer)
+    }
+
+    /// Signs the hash of `message` after prefixing it, like `sign_message`,
+    /// but returns an error if an external signer fails instead of a
+    /// signature nothing recovers from. Use this rather than `sign_message`.
+    pub fn try_sign_message<S: AsRef<[u8]>>(
+        &self,
+        message: S,
+    ) -> Result<Signature, SignerError> {
+        match self {
+            RelayerSigner::Abstract(s) => Ok(s.sign_message(message)),
+            RelayerSigner::Wallet(s) => Ok(s.sign_message(message)),
+            RelayerSigner::External(s) => s.try_sign_message(message),
+        }
+    }
+
+    /// Signs `tx` like `sign_transaction`, but off the runtime's threads for
+    /// an external signer, so a slow one doesn't hold up other tasks.
+    pub async fn sign_transaction_async(
+        &self,
+        tx: TransactionRequest,
+    ) -> Result<Transaction, ClientError> {
+        match self {
+            RelayerSigner::External(s) => {
+                let s = s.clone();
+                tokio::task::spawn_blocking(move || s.sign_transaction(tx))
+                    .await
+                    .map_err(|e| ClientError::SignerError(Box::new(e)))?
+            }
+            _ => self.sign_transaction(tx),
+        }
+    }
+}
+
+impl Signer for RelayerSigner {
+    type Error = ClientError;
+
+    fn sign_message<S: AsRef<[u8]>>(&self, message: S) -> Signature {
+        match self {
+            RelayerSigner::Abstract(s) => s.sign_message(message),
+            RelayerSigner::Wallet(s) => s.sign_message(message),
+            RelayerSigner::External(s) => s.sign_message(message),
+        }
+    }
+
+    fn sign_transaction(
+        &self,
+        tx: TransactionRequest,
+    ) -> Result<Transaction, Self::Error> {
+        match self {
+            RelayerSigner::Abstract(s) => s.sign_transaction(tx),
+            RelayerSigner::Wallet(s) => {
+                s.sign_transaction(tx).map_err(Into::into)
+            }
+            RelayerSigner::External(s) => s.sign_transaction(tx),
+        }
+    }
+
+    fn address(&self) -> Address {
+        match self {
+            RelayerSigner::Abstract(s) => s.address(),
+            RelayerSigner::Wallet(s) => s.address(),
+            RelayerSigner::External(s) => s.address(),
+        }
+    }
+}
+
+/// Asks a separate signing service, listening on a Unix socket, to sign, so
+/// the relayer never holds the key.
+///
+/// The service answers one request per line: `address` with the hex address
+/// it signs for, and `sign <hash>` with the 65 byte `r || s || v` signature of
+/// the 32 byte hash, both hex encoded. `v` is the bare recovery id (0 or 1).
+#[derive(Debug, Clone)]
+pub struct ExternalSigner {
+    socket: PathBuf,
+    address: Address,
+    chain_id: u64,
+}
+
+impl ExternalSigner {
+    pub fn connect(socket: &Path, chain_id: u64) -> Result<Self, SignerError> {
+        let reply = request(socket, "address")?;
+        let bytes: Vec<u8> =
+            reply.from_hex().map_err(|_| SignerError::ExternalReply {
+                reply: reply.clone(),
+            })?;
+
+        ensure!(bytes.len() == 20, ExternalReply { reply });
+
+        Ok(Self {
+            socket: socket.to_owned(),
+            address: Address::from_slice(&bytes),
+            chain_id,
+        })
+    }
+
+    /// Signs the hash of `message` after prefixing it, like `sign_message`,
+    /// but returns an error if the signing service fails.
+    pub fn try_sign_message<S: AsRef<[u8]>>(
+        &self,
+        message: S,
+    ) -> Result<Signature, SignerError> {
+        let mut signature = self.sign_hash(hash_message(message))?;
+        signature.v += 27;
+        Ok(signature)
+    }
+
+    /// Signs `hash`, returning the signature with a recovery id for `v`.
+    fn sign_hash(&self, hash: H256) -> Result<Signature, SignerError> {
+        let line = format!("sign {}", hash.as_bytes().to_hex::<String>());
+        let reply = request(&self.socket, &line)?;
+
+        let bytes: Vec<u8> =
+            reply.from_hex().map_err(|_| SignerError::ExternalReply {
+                reply: reply.clone(),
+            })?;
+
+        ensure!(bytes.len() == 65 && bytes[64] < 2, ExternalReply { reply });
+
+        Ok(Signature {
+            r: H256::from_slice(&bytes[..32]),
+            s: H256::from_slice(&bytes[32..64]),
+            v: bytes[64].into(),
+        })
+    }
+}
+
+/// How long the signing service gets to take each request and to reply.
+const EXTERNAL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Sends one request line to the signing service and reads back its reply.
+fn request(socket: &Path, line: &str) -> Result<String, SignerError> {
+    let mut stream = UnixStream::connect(socket).context(ExternalIo)?;
+    stream
+        .set_read_timeout(Some(EXTERNAL_TIMEOUT))
+        .context(ExternalIo)?;
+    stream
+        .set_write_timeout(Some(EXTERNAL_TIMEOUT))
+        .context(ExternalIo)?;
+
+    writeln!(stream, "{}", line).context(ExternalIo)?;
+
+    let mut reply = String::new();
+    BufReader::new(stream)
+        .read_line(&mut reply)
+        .context(ExternalIo)?;
+
+    Ok(reply.trim().trim_start_matches("0x").to_owned())
+}
+
+impl Signer for ExternalSigner {
+    type Error = ClientError;
+
+    /// Signs the hash of the provided message after prefixing it. This can't
+    /// return an error, so if the signing service fails the signature is all
+    /// zeros, which nothing recovers from. Only here because `Signer` needs
+    /// it: the relayer signs messages through `try_sign_message`, and never
+    /// through this.
+    fn sign_message<S: AsRef<[u8]>>(&self, message: S) -> Signature {
+        self.try_sign_message(message).unwrap_or_else(|e| {
+            eprintln!("\nUnable to sign message: {}", e);
+            Signature {
+                r: H256::zero(),
+                s: H256::zero(),
+                v: 0,
+            }
+        })
+    }
+
+    fn sign_transaction(
+        &self,
+        tx: TransactionRequest,
+    ) -> Result<Transaction, Self::Error> {
+        let mut signature = self
+            .sign_hash(tx.sighash(Some(self.chain_id)))
+            .map_err(|e| ClientError::SignerError(Box::new(e)))?;
+
+        // EIP-155
+        signature.v += self.chain_id * 2 + 35;
+
+        let rlp = tx.rlp_signed(&signature);
+        let hash = keccak256(&rlp.0);
+
+        let to = tx.to.map(|to| match to {
+            NameOrAddress::Address(inner) => inner,
+            NameOrAddress::Name(_) => panic!(
+                "Expected `to` to be an Ethereum Address, not an ENS name"
+            ),
+        });
+
+        Ok(Transaction {
+            hash: hash.into(),
+            nonce: tx.nonce.unwrap_or_default(),
+            from: self.address,
+            to,
+            value: tx.value.unwrap_or_default(),
+            gas_price: tx.gas_price.unwrap_or_default(),
+            gas: tx.gas.unwrap_or_default(),
+            input: tx.data.unwrap_or_default(),
+            v: signature.v.into(),
+            r: signature.r.0.into(),
+            s: signature.s.0.into(),
+
+            // Leave these empty as they're only used for included transactions
+            block_hash: None,
+            block_number: None,
+            transaction_index: None,
+        })
+    }
+
+    fn address(&self) -> Address {
+        self.address
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum ParseKeyError {
+    #[snafu(display("private key isn't hex: {}", source))]
+    Hex { source: rustc_hex::FromHexError },
+
+    #[snafu(display("invalid secp256k1 private key"))]
+    InvalidKey,
+}
+
+/// A private key for signing transfers and withdrawals entered at the
+/// command line, so they pass the contract's signature check.
+#[derive(Clone)]
+pub struct LocalKey(SecretKey);
+
+impl fmt::Debug for LocalKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Never print the key itself.
+        write!(f, "LocalKey(..)")
+    }
+}
+
+impl FromStr for LocalKey {
+    type Err = ParseKeyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = s.strip_prefix("0x").unwrap_or(s);
+        let bytes: Vec<u8> = hex
+            .from_hex()
+            .map_err(|source| ParseKeyError::Hex { source })?;
+
+        SecretKey::parse_slice(&bytes)
+            .map(LocalKey)
+            .map_err(|_| ParseKeyError::InvalidKey)
+    }
+}
+
+impl LocalKey {
+    /// Signs a digest directly, without any message prefix, the way
+    /// `ecrecover` in the contract expects.
+    pub fn sign_hash(&self, hash: &[u8; 32]) -> Signature {
+        let message = Message::parse(hash);
+        let (signature, recovery_id) = secp256k1::sign(&message, &self.0);
+        let rs = signature.serialize();
+
+        Signature {
+            v: 27 + u64::from(recovery_id.serialize()),
+            r: H256::from_slice(&rs[..32]),
+            s: H256::from_slice(&rs[32..]),
+        }
+    }
+
+    /// The address `ecrecover` returns for signatures from this key.
+    pub fn address(&self) -> Address {
+        public_address(&PublicKey::from_secret_key(&self.0))
+    }
+}
+
+fn public_address(public: &PublicKey) -> Address {
+    // Skip the leading tag byte of the uncompressed key.
+    let hashed = keccak256(&public.serialize()[1..]);
+    Address::from_slice(&hashed[12..])
+}
+
+#[derive(Debug, Snafu)]
+pub enum RecoverError {
+    #[snafu(display("signature v must be 27 or 28, not {}", v))]
+    InvalidV { v: u64 },
+
+    #[snafu(display("invalid secp256k1 signature"))]
+    InvalidSignature,
+}
+
+/// Recovers the address that signed `hash`, the way `ecrecover` in the
+/// contract does.
+pub fn recover_signer(
+    hash: &[u8; 32],
+    signature: &Signature,
+) -> Result<Address, RecoverError> {
+    let v = signature.v;
+    ensure!(v == 27 || v == 28, InvalidV { v });
+
+    let recovery_id = secp256k1::RecoveryId::parse((v - 27) as u8)
+        .map_err(|_| RecoverError::InvalidSignature)?;
+
+    let mut rs = [0u8; 64];
+    rs[..32].copy_from_slice(signature.r.as_bytes());
+    rs[32..].copy_from_slice(signature.s.as_bytes());
+
+    let public = secp256k1::recover(
+        &Message::parse(hash),
+        &secp256k1::Signature::parse(&rs),
+        &recovery_id,
+    )
+    .map_err(|_| RecoverError::InvalidSignature)?;
+
+    Ok(public_address(&public))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use secp256k1::RecoveryId;
+
+    const KEY: &str =
+        "0x4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318";
+
+    #[test]
+    fn parse_signer_kind() {
+        assert_eq!("abstract".parse(), Ok(SignerKind::Abstract));
+        assert_eq!(
+            "wallet:/tmp/key".parse(),
+            Ok(SignerKind::Wallet("/tmp/key".into()))
+        );
+        assert_eq!(
+            "external:/tmp/sock".parse(),
+            Ok(SignerKind::External("/tmp/sock".into()))
+        );
+        assert!("ledger".parse::<SignerKind>().is_err());
+    }
+
+    #[test]
+    fn external_signer_round_trip() {
+        use std::os::unix::net::UnixListener;
+
+        let dir = std::env::temp_dir()
+            .join(format!("relayer-signer-{}", std::process::id()));
+        let _ = std::fs::remove_file(&dir);
+        let listener = UnixListener::bind(&dir).unwrap();
+
+        let service = std::thread::spawn(move || {
+            let replies = [
+                "0x1111111111111111111111111111111111111111".to_owned(),
+                format!("{}{}01", "22".repeat(32), "33".repeat(32)),
+            ];
+
+            for reply in replies.iter() {
+                let (stream, _) = listener.accept().unwrap();
+                let mut line = String::new();
+                BufReader::new(&stream).read_line(&mut line).unwrap();
+                writeln!(&stream, "{}", reply).unwrap();
+            }
+        });
+
+        let signer = ExternalSigner::connect(&dir, 1).unwrap();
+        assert_eq!(signer.address(), Address::from([0x11; 20]));
+
+        let signature = signer.sign_hash(H256::zero()).unwrap();
+        assert_eq!(signature.r, H256([0x22; 32]));
+        assert_eq!(signature.s, H256([0x33; 32]));
+        assert_eq!(signature.v, 1);
+
+        service.join().unwrap();
+        std::fs::remove_file(&dir).unwrap();
+    }
+
+    #[test]
+    fn external_signer_bad_reply() {
+        use std::os::unix::net::UnixListener;
+
+        let dir = std::env::temp_dir()
+            .join(format!("relayer-signer-bad-{}", std::process::id()));
+        let _ = std::fs::remove_file(&dir);
+        let listener = UnixListener::bind(&dir).unwrap();
+
+        let service = std::thread::spawn(move || {
+            let replies = ["0x1111111111111111111111111111111111111111", "no"];
+
+            for reply in replies.iter() {
+                let (stream, _) = listener.accept().unwrap();
+                let mut line = String::new();
+                BufReader::new(&stream).read_line(&mut line).unwrap();
+                writeln!(&stream, "{}", reply).unwrap();
+            }
+        });
+
+        let signer = ExternalSigner::connect(&dir, 1).unwrap();
+        let signer = RelayerSigner::External(signer);
+
+        match signer.try_sign_message(b"utxo") {
+            Err(SignerError::ExternalReply { reply }) => {
+                assert_eq!(reply, "no")
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        service.join().unwrap();
+        std::fs::remove_file(&dir).unwrap();
+    }
+
+    #[test]
+    fn parse_key() {
+        assert!(LocalKey::from_str(KEY).is_ok());
+        assert!(LocalKey::from_str(&KEY[2..]).is_ok());
+        assert!(LocalKey::from_str("0xzz").is_err());
+        assert!(LocalKey::from_str("00").is_err());
+    }
+
+    #[test]
+    fn sign_hash_recovers() {
+        let key = LocalKey::from_str(KEY).unwrap();
+        let hash = keccak256(b"utxo");
+
+        let signature = key.sign_hash(&hash);
+
+        let mut rs = [0u8; 64];
+        rs[..32].copy_from_slice(signature.r.as_bytes());
+        rs[32..].copy_from_slice(signature.s.as_bytes());
+
+        let recovered = secp256k1::recover(
+            &Message::parse(&hash),
+            &secp256k1::Signature::parse(&rs),
+            &RecoveryId::parse((signature.v - 27) as u8).unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(recovered, PublicKey::from_secret_key(&key.0));
+    }
+
+    #[test]
+    fn recover_signer_address() {
+        let key = LocalKey::from_str(KEY).unwrap();
+        let hash = keccak256(b"utxo");
+
+        let signature = key.sign_hash(&hash);
+        let expected: Address =
+            "2c7536e3605d9c16a7a3d7b1898e529396a65c23".parse().unwrap();
+
+        assert_eq!(recover_signer(&hash, &signature).unwrap(), expected);
+        assert_eq!(key.address(), expected);
+
+        let other = keccak256(b"other");
+        assert_ne!(recover_signer(&other, &signature).unwrap(), expected);
+    }
+
+    #[test]
+    fn recover_signer_bad_v() {
+        let key = LocalKey::from_str(KEY).unwrap();
+        let hash = keccak256(b"utxo");
+
+        let mut signature = key.sign_hash(&hash);
+        signature.v -= 27;
+
+        match recover_signer(&hash, &signature) {
+            Err(RecoverError::InvalidV { v }) => assert!(v < 2),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,191 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use tokio::sync::{
+    Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard,
+};
+
+/// How long tasks have waited to acquire a `TimedMutex`.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct WaitStats {
+    pub count: u32,
+    pub total: Duration,
+    pub max: Duration,
+}
+
+impl WaitStats {
+    pub fn mean(&self) -> Duration {
+        if self.count == 0 {
+            Duration::default()
+        } else {
+            self.total / self.count
+        }
+    }
+
+    fn record(&mut self, wait: Duration) {
+        self.count = self.count.saturating_add(1);
+        self.total += wait;
+        self.max = self.max.max(wait);
+    }
+}
+
+impl fmt::Display for WaitStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} acquisition(s), waited {:?} on average and {:?} at most",
+            self.count,
+            self.mean(),
+            self.max
+        )
+    }
+}
+
+/// A `tokio::sync::Mutex` that keeps track of how long `lock` waits, to show
+/// when a lock is the bottleneck.
+#[derive(Debug)]
+pub struct TimedMutex<T> {
+    inner: Mutex<T>,
+    waits: std::sync::Mutex<WaitStats>,
+}
+
+impl<T> TimedMutex<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            inner: Mutex::new(value),
+            waits: Default::default(),
+        }
+    }
+
+    pub async fn lock(&self) -> MutexGuard<'_, T> {
+        let start = Instant::now();
+        let guard = self.inner.lock().await;
+
+        self.waits.lock().unwrap().record(start.elapsed());
+
+        guard
+    }
+
+    pub fn waits(&self) -> WaitStats {
+        *self.waits.lock().unwrap()
+    }
+}
+
+/// A `tokio::sync::RwLock` that keeps track of how long `read` and `write`
+/// wait, and of how many times it has been written.
+#[derive(Debug)]
+pub struct TimedRwLock<T> {
+    inner: RwLock<T>,
+    waits: std::sync::Mutex<WaitStats>,
+    writes: AtomicU64,
+}
+
+impl<T> TimedRwLock<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            inner: RwLock::new(value),
+            waits: Default::default(),
+            writes: AtomicU64::new(0),
+        }
+    }
+
+    pub async fn read(&self) -> RwLockReadGuard<'_, T> {
+        let start = Instant::now();
+        let guard = self.inner.read().await;
+
+        self.waits.lock().unwrap().record(start.elapsed());
+
+        guard
+    }
+
+    pub async fn write(&self) -> RwLockWriteGuard<'_, T> {
+        let start = Instant::now();
+        let guard = self.inner.write().await;
+
+        self.waits.lock().unwrap().record(start.elapsed());
+        self.writes.fetch_add(1, Ordering::SeqCst);
+
+        guard
+    }
+
+    pub fn waits(&self) -> WaitStats {
+        *self.waits.lock().unwrap()
+    }
+
+    /// How many times `write` has been called. Since it only changes while
+    /// the write lock is held, it can't change while a read lock is held.
+    pub fn writes(&self) -> u64 {
+        self.writes.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn uncontended() {
+        let mutex = TimedMutex::new(0);
+
+        *mutex.lock().await += 1;
+        *mutex.lock().await += 1;
+
+        assert_eq!(*mutex.lock().await, 2);
+        assert_eq!(mutex.waits().count, 3);
+    }
+
+    #[tokio::test]
+    async fn contended_wait_recorded() {
+        let mutex = TimedMutex::new(());
+        let hold = Duration::from_millis(20);
+
+        let holder = async {
+            let _guard = mutex.lock().await;
+            tokio::time::delay_for(hold).await;
+        };
+
+        let waiter = async {
+            // Let the holder take the lock first.
+            tokio::task::yield_now().await;
+            drop(mutex.lock().await);
+        };
+
+        tokio::join!(holder, waiter);
+
+        let waits = mutex.waits();
+        assert_eq!(waits.count, 2);
+        assert!(waits.max >= hold / 2);
+    }
+
+    #[tokio::test]
+    async fn rw_lock_counts_writes() {
+        let lock = TimedRwLock::new(0);
+
+        *lock.write().await += 1;
+        assert_eq!(*lock.read().await, 1);
+
+        assert_eq!(lock.writes(), 1);
+        assert_eq!(lock.waits().count, 2);
+    }
+
+    #[tokio::test]
+    async fn readers_share() {
+        let lock = TimedRwLock::new(());
+
+        let first = lock.read().await;
+        let second = lock.read().await;
+        drop((first, second));
+
+        assert_eq!(lock.writes(), 0);
+    }
+
+    #[test]
+    fn mean_of_nothing() {
+        assert_eq!(WaitStats::default().mean(), Duration::default());
+    }
+}
@@ -0,0 +1,282 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::contracts::Bundle;
+use crate::pool::Transaction;
+
+use ethers::types::{Address, TxHash, H256, U256};
+use ethers::utils::keccak256;
+
+use rustc_hex::ToHex;
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Follows one transaction from the pool, through broadcast, to a block.
+///
+/// Only depends on the inputs spent, so the same transaction gets the same id
+/// wherever it's seen, but a replacement spending the same inputs does too.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct Correlation {
+    /// Ascending.
+    inputs: Vec<U256>,
+}
+
+impl Correlation {
+    pub fn of<T>(txn: &T) -> Self
+    where
+        T: Transaction,
+    {
+        Self::from_inputs(txn.inputs().copied())
+    }
+
+    pub fn from_inputs<I>(inputs: I) -> Self
+    where
+        I: IntoIterator<Item = U256>,
+    {
+        let mut inputs: Vec<U256> = inputs.into_iter().collect();
+        inputs.sort();
+        Self { inputs }
+    }
+
+    /// The ids of every transfer and withdrawal in `bundle`.
+    pub fn all_in(bundle: &Bundle) -> Vec<Self> {
+        let transfers = bundle.transfers.iter().map(Self::of);
+        let withdrawals = bundle.withdrawals.iter().map(Self::of);
+        transfers.chain(withdrawals).collect()
+    }
+
+    pub fn spends(&self, input: &U256) -> bool {
+        self.inputs.contains(input)
+    }
+}
+
+impl fmt::Display for Correlation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut bytes = Vec::with_capacity(32 * self.inputs.len());
+
+        for input in self.inputs.iter() {
+            let mut word = [0u8; 32];
+            input.to_big_endian(&mut word);
+            bytes.extend_from_slice(&word);
+        }
+
+        let hash = keccak256(&bytes);
+        write!(f, "txn:{}", hash[..4].to_hex::<String>())
+    }
+}
+
+/// Where a traced transaction got to.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Stage {
+    Pooled { gasprice: U256 },
+    Broadcast { hash: TxHash },
+    Mined { hash: H256, ours: bool },
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stage::Pooled { gasprice } => {
+                write!(f, "pooled at {} wei for gas", gasprice)
+            }
+            Stage::Broadcast { hash } => write!(f, "broadcast in {:?}", hash),
+            Stage::Mined { hash, ours: true } => {
+                write!(f, "mined in our bundle {:?}", hash)
+            }
+            Stage::Mined { hash, ours: false } => {
+                write!(f, "mined in another bundler's {:?}", hash)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Step {
+    pub contract: Address,
+    pub correlation: Correlation,
+    pub stage: Stage,
+    pub at: SystemTime,
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.correlation, self.stage)?;
+
+        if let Ok(age) = self.at.elapsed() {
+            write!(f, ", {}s ago", age.as_secs())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The last few steps taken by any transaction, oldest first, for following
+/// one after the fact with `trace`.
+#[derive(Debug)]
+pub struct Trace {
+    capacity: usize,
+    steps: Mutex<VecDeque<Step>>,
+}
+
+impl Trace {
+    /// Keeps at most `capacity` steps, or none at all if zero.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            steps: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Records that `correlation` reached `stage`, forgetting the oldest step
+    /// if full.
+    pub fn record(
+        &self,
+        contract: Address,
+        correlation: Correlation,
+        stage: Stage,
+    ) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut steps = self.steps.lock().unwrap();
+
+        while steps.len() >= self.capacity {
+            steps.pop_front();
+        }
+
+        steps.push_back(Step {
+            contract,
+            correlation,
+            stage,
+            at: SystemTime::now(),
+        });
+    }
+
+    /// Whether any remembered step for `contract` is of `correlation`.
+    pub fn knows(&self, contract: Address, correlation: &Correlation) -> bool {
+        self.steps
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|s| s.contract == contract && &s.correlation == correlation)
+    }
+
+    /// Whether any remembered step for `contract` is a broadcast in `hash`.
+    pub fn broadcast_in(&self, contract: Address, hash: &TxHash) -> bool {
+        let broadcast = Stage::Broadcast { hash: *hash };
+
+        self.steps
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|s| s.contract == contract && s.stage == broadcast)
+    }
+
+    /// Copies out the remembered steps, oldest first, of every transaction to
+    /// `contract` spending `input`.
+    pub fn spending(&self, contract: Address, input: &U256) -> Vec<Step> {
+        self.steps
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|s| s.contract == contract && s.correlation.spends(input))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::contracts::Withdrawal;
+
+    use ethers::types::Signature;
+
+    fn withdrawal(input: u64, gasprice: u64) -> Withdrawal {
+        Withdrawal {
+            input: input.into(),
+            gasprice: gasprice.into(),
+            signature: Signature {
+                v: 0,
+                r: H256::zero(),
+                s: H256::zero(),
+            },
+        }
+    }
+
+    #[test]
+    fn correlation_depends_only_on_inputs() {
+        let first = Correlation::of(&withdrawal(1, 10));
+        let again = Correlation::of(&withdrawal(1, 20));
+        let other = Correlation::of(&withdrawal(2, 10));
+
+        assert_eq!(first, again);
+        assert_eq!(first.to_string(), again.to_string());
+        assert_ne!(first.to_string(), other.to_string());
+        assert!(first.to_string().starts_with("txn:"));
+    }
+
+    #[test]
+    fn spending_filters_by_input_and_contract() {
+        let trace = Trace::new(8);
+        let pooled = Stage::Pooled {
+            gasprice: 10.into(),
+        };
+
+        trace.record(
+            Address::zero(),
+            Correlation::of(&withdrawal(1, 10)),
+            pooled,
+        );
+        trace.record(
+            Address::zero(),
+            Correlation::of(&withdrawal(2, 10)),
+            pooled,
+        );
+        trace.record(
+            Address::repeat_byte(1),
+            Correlation::of(&withdrawal(1, 10)),
+            pooled,
+        );
+
+        let hash = TxHash::from_low_u64_be(7);
+        let broadcast = Stage::Broadcast { hash };
+        trace.record(
+            Address::zero(),
+            Correlation::of(&withdrawal(1, 10)),
+            broadcast,
+        );
+
+        let stages: Vec<Stage> = trace
+            .spending(Address::zero(), &1.into())
+            .into_iter()
+            .map(|s| s.stage)
+            .collect();
+        assert_eq!(stages, vec![pooled, broadcast]);
+
+        assert!(trace.broadcast_in(Address::zero(), &hash));
+        assert!(!trace.broadcast_in(Address::repeat_byte(1), &hash));
+    }
+
+    #[test]
+    fn record_forgets_oldest() {
+        let trace = Trace::new(1);
+        let first = Correlation::of(&withdrawal(1, 10));
+        let second = Correlation::of(&withdrawal(2, 10));
+        let stage = Stage::Pooled {
+            gasprice: 10.into(),
+        };
+
+        trace.record(Address::zero(), first.clone(), stage);
+        trace.record(Address::zero(), second.clone(), stage);
+
+        assert!(!trace.knows(Address::zero(), &first));
+        assert!(trace.knows(Address::zero(), &second));
+    }
+}
@@ -3,28 +3,81 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 mod commands;
+mod control;
+mod script;
 
-use crate::contracts::{Bundle, DecodeError};
+use crate::contracts::{Bundle, Claim, DecodeError, Deposit, Txn};
+use crate::trace::Correlation;
 
-use ethers::types::{Transaction as EthTransaction, H256};
+use ethers::types::{Address, Transaction as EthTransaction, H256, U256};
 
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 
-pub use self::commands::{CommandKind, GetType, GetUtxo, PoolType};
+use self::commands::CommandLine;
+
+pub use self::commands::{
+    CancelTransfer, ClearType, CommandKind, Decode, Explain, ExportType,
+    GasFilter, GenerateLoad, GetDeposit, GetType, GetUtxo, History, Limit,
+    LoadDeposits, LoadType, PoolSize, PoolType, Reserve, SetType,
+    SimulateClaim, SimulateType, Stats, Trace, WhatIfBase, WhatIfType,
+};
 
 use std::fmt;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use std::sync::Arc;
 use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
 use structopt::StructOpt;
 
 use tokio::runtime::Handle;
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::oneshot;
 
 #[derive(Debug, Clone)]
-pub struct Events(Sender<Event>);
+pub struct Events {
+    send: Sender<Event>,
+
+    /// Heartbeats dropped because the printer had fallen behind.
+    dropped: Arc<AtomicU64>,
+}
 
 impl Events {
+    fn new(send: Sender<Event>) -> Self {
+        Self {
+            send,
+            dropped: Arc::default(),
+        }
+    }
+
+    /// How many heartbeats (ex. new blocks) weren't printed so as not to hold
+    /// up processing.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Like `oob`, but for events only worth printing if the printer is
+    /// keeping up, so the caller never waits on it.
+    fn heartbeat(&mut self, kind: EventKind) {
+        let evt = Event {
+            reply_to: None,
+            kind,
+        };
+
+        match self.send.try_send(evt) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(_) => panic!("unable to send event"),
+        }
+    }
+
     pub async fn reply<E>(&mut self, cmd: &Command, kind: E)
     where
         E: Into<EventKind>,
@@ -34,7 +87,13 @@ impl Events {
             kind: kind.into(),
         };
 
-        self.0.send(evt).await.expect("unable to send event");
+        if let Some(ref client) = cmd.client {
+            // The client may have gone away, which is fine.
+            let _ = client.clone().send(format!("{}\n", evt)).await;
+            return;
+        }
+
+        self.send.send(evt).await.expect("unable to send event");
     }
 
     pub async fn oob<E>(&mut self, kind: E)
@@ -46,7 +105,7 @@ impl Events {
             kind: kind.into(),
         };
 
-        self.0.send(evt).await.expect("unable to send event");
+        self.send.send(evt).await.expect("unable to send event");
     }
 
     pub async fn bad_block(&mut self, block_hash: H256, error: crate::Error) {
@@ -57,20 +116,45 @@ impl Events {
         self.oob(EventKind::BadBundle(tx)).await;
     }
 
-    pub async fn good_bundle(&mut self, tx: EthTransaction) {
-        self.oob(EventKind::GoodBundle(tx)).await;
+    pub async fn good_bundle(
+        &mut self,
+        tx: EthTransaction,
+        claim: Claim,
+        traced: Vec<Correlation>,
+    ) {
+        self.oob(EventKind::GoodBundle(tx, claim, traced)).await;
     }
 
     pub async fn decode_error(&mut self, tx: EthTransaction, e: DecodeError) {
         self.oob(EventKind::DecodeError(tx, e)).await;
     }
 
+    pub async fn reorg(&mut self, orphaned: H256, bundles: usize) {
+        self.oob(EventKind::Reorg(orphaned, bundles)).await;
+    }
+
+    pub async fn saturated(&mut self, limit: usize) {
+        self.oob(EventKind::Saturated(limit)).await;
+    }
+
+    pub async fn stalled(&mut self, watcher: &'static str, idle: Duration) {
+        self.oob(EventKind::Stalled(watcher, idle)).await;
+    }
+
     pub async fn pending_tx(&mut self, tx: H256) {
-        self.oob(EventKind::PendingTransaction(tx)).await;
+        self.heartbeat(EventKind::PendingTransaction(tx));
+    }
+
+    pub async fn competing_bid(&mut self, bid: Bid) {
+        self.heartbeat(EventKind::CompetingBid(bid));
     }
 
     pub async fn new_block(&mut self, tx: H256) {
-        self.oob(EventKind::NewBlock(tx)).await;
+        self.heartbeat(EventKind::NewBlock(tx));
+    }
+
+    pub async fn new_deposit(&mut self, id: U256, deposit: Deposit) {
+        self.oob(EventKind::NewDeposit(id, deposit)).await;
     }
 
     pub async fn get<S, V>(&mut self, cmd: &Command, name: S, value: V)
@@ -83,6 +167,39 @@ impl Events {
     }
 }
 
+/// Collects the events sent to an `Events` created by `EventSink::new`, so
+/// tests can assert on them.
+#[cfg(test)]
+#[derive(Debug)]
+pub struct EventSink(Receiver<Event>);
+
+#[cfg(test)]
+impl EventSink {
+    /// Large enough that no test blocks on a full channel.
+    const CAPACITY: usize = 1024;
+
+    pub fn new() -> (Events, Self) {
+        Self::with_capacity(Self::CAPACITY)
+    }
+
+    pub fn with_capacity(capacity: usize) -> (Events, Self) {
+        let (send, recv) = mpsc::channel(capacity);
+        (Events::new(send), EventSink(recv))
+    }
+
+    /// Removes every event sent so far, in order, along with the id of the
+    /// command each replies to.
+    pub fn drain(&mut self) -> Vec<(Option<u8>, EventKind)> {
+        let mut drained = Vec::new();
+
+        while let Ok(evt) = self.0.try_recv() {
+            drained.push((evt.reply_to, evt.kind));
+        }
+
+        drained
+    }
+}
+
 #[derive(Debug)]
 pub struct Event {
     reply_to: Option<u8>,
@@ -105,19 +222,80 @@ impl fmt::Display for Event {
 #[derive(Debug)]
 pub enum EventKind {
     Info(String),
+    Warning(String),
     NewBlock(H256),
+    NewDeposit(U256, Deposit),
+    Reorg(H256, usize),
     BadBlock(H256, crate::Error),
     BadBundle(EthTransaction),
-    GoodBundle(EthTransaction),
+    /// Along with the ids of the transactions in it that went through our
+    /// pool.
+    GoodBundle(EthTransaction, Claim, Vec<Correlation>),
+    MinedElsewhere(H256, Vec<Txn>),
     DecodeError(EthTransaction, DecodeError),
-    Broadcast(Bundle),
+    Broadcast(Bundle, Option<U256>),
+    Underpriced {
+        estimate: U256,
+        base: U256,
+    },
+    AboveMaxGasPrice {
+        gas_price: U256,
+        maximum: U256,
+    },
+    AboveGasLimit {
+        estimate: U256,
+        limit: U256,
+    },
+    StaleBlock {
+        age: Duration,
+        maximum: Duration,
+    },
+    BelowMinBribe {
+        bribe: U256,
+        minimum: U256,
+    },
+    LowBalance {
+        balance: U256,
+        minimum: U256,
+    },
+    Outbid(U256, U256),
+    NonceGap {
+        missing: U256,
+        next: U256,
+    },
+    ChainChanged {
+        expected: u64,
+        actual: U256,
+    },
     PendingTransaction(H256),
+    CompetingBid(Bid),
+    Saturated(usize),
+    Stalled(&'static str, Duration),
     CommandError(crate::Error),
     PoolDrop(usize),
-    PoolAdd(usize),
+    DepositDrop(usize),
+    PoolAdd(Vec<Correlation>),
+    PoolReject {
+        input: U256,
+        pooled: U256,
+        offered: U256,
+    },
     Get(String, String),
 }
 
+/// What another bundler's pending bundle offers, as far as we can estimate.
+#[derive(Debug, Clone, Copy)]
+pub struct Bid {
+    pub tx: H256,
+
+    /// The output the bundler claims fees into, which tells bundlers apart,
+    /// since all of their bundles are sent from the entry point.
+    pub sponsor: Option<U256>,
+
+    pub price: U256,
+    pub base: U256,
+}
+
 impl From<&str> for EventKind {
     fn from(s: &str) -> Self {
         EventKind::Info(s.to_owned())
@@ -134,6 +312,7 @@ impl fmt::Display for EventKind {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             EventKind::Info(s) => write!(f, "{}", s),
+            EventKind::Warning(s) => write!(f, "Warning: {}", s),
             EventKind::BadBlock(bkhash, e) => {
                 write!(f, "Failed to process block {}: {}", bkhash, e)
             }
@@ -143,12 +322,52 @@ impl fmt::Display for EventKind {
                 tx.hash,
                 tx.block_number.unwrap()
             ),
-            EventKind::GoodBundle(tx) => write!(
-                f,
-                "Bundle mined in {} (block #{})",
-                tx.hash,
-                tx.block_number.unwrap()
-            ),
+            EventKind::GoodBundle(tx, claim, traced) => {
+                write!(
+                    f,
+                    "Bundle mined in {} (block #{})",
+                    tx.hash,
+                    tx.block_number.unwrap()
+                )?;
+
+                if !claim.deposits.is_empty() {
+                    let ids: Vec<_> = claim
+                        .deposits
+                        .iter()
+                        .map(|d| format!("#{}", d))
+                        .collect();
+
+                    write!(
+                        f,
+                        " claiming deposit(s) {} at {} wei for gas",
+                        ids.join(", "),
+                        claim.gasprice
+                    )?;
+                }
+
+                if !traced.is_empty() {
+                    write!(f, ", including pooled {}", join(traced))?;
+                }
+
+                Ok(())
+            }
+            EventKind::MinedElsewhere(txhash, lost) => {
+                let lost: Vec<_> = lost
+                    .iter()
+                    .map(|t| format!("{} {}", Correlation::of(t), t))
+                    .collect();
+
+                write!(
+                    f,
+                    concat!(
+                        "Lost {} pooled transaction(s) ",
+                        "to another bundler in {}: {}"
+                    ),
+                    lost.len(),
+                    txhash,
+                    lost.join("; ")
+                )
+            }
             EventKind::DecodeError(tx, e) => {
                 write!(f, "Unable to decode bundle for {}", tx.hash,)?;
 
@@ -158,39 +377,194 @@ impl fmt::Display for EventKind {
 
                 write!(f, ": {}", e)
             }
-            EventKind::Broadcast(bundle) => write!(
+            EventKind::Broadcast(bundle, gas) => {
+                write!(
+                    f,
+                    concat!(
+                        "Broadcasting bundle paying up to {} wei for gas with ",
+                        "{} deposit(s), ",
+                        "{} transfer(s) moving {} wei, and ",
+                        "{} withdrawal(s)"
+                    ),
+                    bundle.minimum_gas_price().unwrap_or_default(),
+                    bundle.claim.deposits.len(),
+                    bundle.transfers.len(),
+                    bundle.total_transfer_amount(),
+                    bundle.withdrawals.len(),
+                )?;
+
+                if let Some(gas) = gas {
+                    write!(f, ", using an estimated {} gas", gas)?;
+                }
+
+                let traced = Correlation::all_in(bundle);
+
+                if !traced.is_empty() {
+                    write!(f, ": {}", join(&traced))?;
+                }
+
+                Ok(())
+            }
+            EventKind::AboveMaxGasPrice { gas_price, maximum } => write!(
+                f,
+                concat!(
+                    "Refusing to broadcast at {} wei for gas, ",
+                    "above --max-gas-price {}"
+                ),
+                gas_price, maximum,
+            ),
+            EventKind::StaleBlock { age, maximum } => write!(
+                f,
+                concat!(
+                    "Refusing to broadcast, the newest block is {}s old, ",
+                    "above --max-block-age {}"
+                ),
+                age.as_secs(),
+                maximum.as_secs(),
+            ),
+            EventKind::AboveGasLimit { estimate, limit } => write!(
+                f,
+                concat!(
+                    "Refusing to broadcast bundle estimated at {} gas, ",
+                    "above --gas-limit {}"
+                ),
+                estimate, limit,
+            ),
+            EventKind::BelowMinBribe { bribe, minimum } => write!(
                 f,
                 concat!(
-                    "Broadcasting bundle paying up to {} wei for gas with ",
-                    "{} deposit(s), ",
-                    "{} transfer(s), and ",
-                    "{} withdrawal(s)"
+                    "Refusing to broadcast bundle bribing {} wei, ",
+                    "below --min-bribe {}"
                 ),
-                bundle.minimum_gas_price().unwrap_or_default(),
-                bundle.claim.deposits.len(),
-                bundle.transfers.len(),
-                bundle.withdrawals.len(),
+                bribe, minimum,
+            ),
+            EventKind::LowBalance { balance, minimum } => write!(
+                f,
+                "Warning: signer balance of {} wei is below --min-balance {}",
+                balance, minimum,
+            ),
+            EventKind::Underpriced { estimate, base } => write!(
+                f,
+                concat!(
+                    "Refusing to broadcast bundle paying {} wei for gas, ",
+                    "{} wei below the fee base of {} wei"
+                ),
+                estimate,
+                base - estimate,
+                base
+            ),
+            EventKind::NonceGap { missing, next } => write!(
+                f,
+                concat!(
+                    "Warning: nonce {} never reached the node, ",
+                    "so broadcasts up to nonce {} are stuck"
+                ),
+                missing,
+                *next - 1,
+            ),
+            EventKind::ChainChanged { expected, actual } => write!(
+                f,
+                concat!(
+                    "ERROR: node is now on chain {}, not --chain-id {}. ",
+                    "Broadcasting halted until it's back"
+                ),
+                actual, expected,
+            ),
+            EventKind::Outbid(competing, bumped) => write!(
+                f,
+                concat!(
+                    "Competing bundle pays {} wei for gas, ",
+                    "rebroadcasting at {} wei"
+                ),
+                competing, bumped
             ),
             EventKind::NewBlock(bk) => write!(f, "New Block: {}", bk,),
+            EventKind::NewDeposit(id, deposit) => write!(
+                f,
+                "New Deposit #{}: {} wei with a bounty of {} wei from {:?}",
+                id, deposit.amount, deposit.bounty, deposit.owner
+            ),
+            EventKind::Reorg(bk, c) => write!(
+                f,
+                "Block {} orphaned by reorg, restoring {} bundle(s)",
+                bk, c
+            ),
             EventKind::PendingTransaction(tx) => {
                 write!(f, "New Pending Tx: {}", tx,)
             }
+            EventKind::CompetingBid(bid) => {
+                write!(f, "Pending bundle {}", bid.tx)?;
+
+                if let Some(sponsor) = bid.sponsor {
+                    write!(f, " claiming into UTXO #{}", sponsor)?;
+                }
+
+                write!(
+                    f,
+                    " pays {} wei for gas ({} wei above the {} wei base fee)",
+                    bid.price,
+                    bid.price.saturating_sub(bid.base),
+                    bid.base,
+                )
+            }
+            EventKind::Saturated(limit) => write!(
+                f,
+                "Reached the limit of {} concurrent task(s), waiting",
+                limit
+            ),
+            EventKind::Stalled(watcher, idle) => write!(
+                f,
+                "Warning: {} saw nothing for {}s, restarting it",
+                watcher,
+                idle.as_secs()
+            ),
             EventKind::CommandError(e) => write!(f, "Command error: {}", e),
             EventKind::PoolDrop(c) => {
                 write!(f, "Dropped {} transaction(s) from pool", c)
             }
-            EventKind::PoolAdd(c) => {
-                write!(f, "Added {} transaction(s) to pool", c)
+            EventKind::DepositDrop(c) => {
+                write!(f, "Dropped {} claimed deposit(s) from pool", c)
             }
+            EventKind::PoolAdd(added) => write!(
+                f,
+                "Added {} transaction(s) to pool: {}",
+                added.len(),
+                join(added)
+            ),
+            EventKind::PoolReject {
+                input,
+                pooled,
+                offered,
+            } => write!(
+                f,
+                concat!(
+                    "Rejected: input {} already pooled at gas price {}, ",
+                    "at least the {} offered"
+                ),
+                input, pooled, offered
+            ),
             EventKind::Get(name, value) => write!(f, "{} = {}", name, value),
         }
     }
 }
 
+/// Lists `items` separated by commas.
+fn join<D>(items: &[D]) -> String
+where
+    D: fmt::Display,
+{
+    let items: Vec<_> = items.iter().map(D::to_string).collect();
+    items.join(", ")
+}
+
 #[derive(Debug)]
 pub struct Command {
     id: u8,
     kind: CommandKind,
+    contract: Option<Address>,
+
+    /// Where replies go when the command came from the control socket.
+    client: Option<Sender<String>>,
 }
 
 impl Command {
@@ -201,98 +575,310 @@ impl Command {
     pub fn kind(&self) -> &CommandKind {
         &self.kind
     }
+
+    pub fn contract(&self) -> Option<Address> {
+        self.contract
+    }
+
+    #[cfg(test)]
+    pub fn new(id: u8, kind: CommandKind) -> Self {
+        Self {
+            id,
+            kind,
+            contract: None,
+            client: None,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct Ui {
-    print_thread: JoinHandle<()>,
-    read_thread: JoinHandle<()>,
+    print_thread: Option<JoinHandle<()>>,
+    read_thread: Option<JoinHandle<()>>,
+
+    /// Tells the printer to flush what's queued and stop.
+    print_stop: Option<oneshot::Sender<()>>,
 
-    evt_send: Sender<Event>,
-    cmd_recv: Receiver<Command>,
+    /// Tells the reader to stop once its current line is read.
+    read_stop: Arc<AtomicBool>,
+
+    /// Closed when the reader finishes (ex. on ctrl-d.) When headless, the
+    /// sender is kept in `read_open` instead, so this never closes.
+    read_done: oneshot::Receiver<()>,
+    read_closed: bool,
+    read_open: Option<oneshot::Sender<()>>,
+
+    events: Events,
+    cmd_recv: Option<Receiver<Command>>,
 }
 
 impl Ui {
-    pub fn start(handle: Handle, oob: bool) -> Result<Ui, std::io::Error> {
+    /// Starts printing events and, unless `headless`, reading commands from
+    /// the terminal. Commands are also accepted from a Unix socket bound at
+    /// `control`, if given. With neither, the command channel is closed at
+    /// once, or after the commands in `script`, if given.
+    ///
+    /// The commands in `script` run first, in order, and the terminal isn't
+    /// read until they've finished.
+    ///
+    /// Up to `capacity` events wait for the printer before senders have to,
+    /// except heartbeats, which are dropped instead.
+    pub fn start(
+        handle: Handle,
+        oob: bool,
+        headless: bool,
+        control: Option<&Path>,
+        script: Option<&Path>,
+        capacity: usize,
+    ) -> Result<Ui, std::io::Error> {
         let (cmd_send, cmd_recv) = mpsc::channel(1);
-        let (evt_send, evt_recv) = mpsc::channel(1);
+        let (evt_send, evt_recv) = mpsc::channel(capacity.max(1));
 
-        let print_handle = handle.clone();
-        let print_thread =
-            thread::Builder::new()
-                .name("ui-print".to_owned())
-                .spawn(move || printer(print_handle, evt_recv, oob))?;
+        let ids = Arc::new(AtomicU8::new(0));
 
-        let read_thread = thread::Builder::new()
-            .name("ui-read".to_owned())
-            .spawn(move || reader(handle, cmd_send))?;
+        if let Some(path) = control {
+            // Clear out a socket left behind by a previous run, but nothing
+            // else that happens to be in the way.
+            match std::fs::symlink_metadata(path) {
+                Ok(m) if m.file_type().is_socket() => {
+                    std::fs::remove_file(path)?
+                }
+                Ok(_) => {
+                    let msg = format!("{} is not a socket", path.display());
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::AlreadyExists,
+                        msg,
+                    ));
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e),
+            }
+
+            let listener = UnixListener::bind(path)?;
+            let ids = ids.clone();
+            let send = cmd_send.clone();
+            let control_handle = handle.clone();
+            thread::Builder::new().name("ui-control".to_owned()).spawn(
+                move || control::serve(control_handle, listener, ids, send),
+            )?;
+        }
+
+        let script = match script {
+            Some(path) => {
+                let path = path.to_owned();
+                let contents = std::fs::read_to_string(&path)?;
+                let ids = ids.clone();
+                let send = cmd_send.clone();
+
+                Some(handle.spawn(async move {
+                    script::run(&path, &contents, ids, send).await
+                }))
+            }
+            None => None,
+        };
+
+        let (print_stop, print_stopped) = oneshot::channel();
+        let print_handle = handle.clone();
+        let print_thread = thread::Builder::new()
+            .name("ui-print".to_owned())
+            .spawn(move || {
+            printer(print_handle, evt_recv, print_stopped, oob, headless)
+        })?;
+
+        let read_stop = Arc::new(AtomicBool::new(false));
+        let (read_open, read_done) = oneshot::channel();
+
+        let (read_thread, read_open) = if headless {
+            (None, Some(read_open))
+        } else {
+            let stop = read_stop.clone();
+            let thread = thread::Builder::new()
+                .name("ui-read".to_owned())
+                .spawn(move || {
+                    // Dropped when the reader returns, closing `read_done`.
+                    let _open = read_open;
+
+                    if let Some(script) = script {
+                        let _ = handle.block_on(script);
+                    }
+
+                    reader(handle, ids, cmd_send, stop)
+                })?;
+
+            (Some(thread), None)
+        };
 
         Ok(Ui {
-            print_thread,
+            print_thread: Some(print_thread),
             read_thread,
-            evt_send,
-            cmd_recv,
+            print_stop: Some(print_stop),
+            read_stop,
+            read_done,
+            read_closed: false,
+            read_open,
+            events: Events::new(evt_send),
+            cmd_recv: Some(cmd_recv),
         })
     }
 
     pub fn events(&self) -> Events {
-        Events(self.evt_send.clone())
+        self.events.clone()
+    }
+
+    /// Takes the receiving end of the command channel. Panics if called more
+    /// than once.
+    pub fn commands(&mut self) -> Receiver<Command> {
+        self.cmd_recv.take().expect("commands already taken")
+    }
+
+    /// Resolves once the terminal reader has finished, which is how the user
+    /// asks to exit. Never resolves when headless.
+    pub async fn closed(&mut self) {
+        if !self.read_closed {
+            let _ = (&mut self.read_done).await;
+            self.read_closed = true;
+        }
+    }
+
+    /// Stops both threads, printing whatever events are already queued.
+    pub fn shutdown(mut self) {
+        self.stop();
+    }
+
+    fn stop(&mut self) {
+        self.read_stop.store(true, Ordering::SeqCst);
+        self.read_open.take();
+
+        if let Some(stop) = self.print_stop.take() {
+            // The printer may have already stopped on its own.
+            let _ = stop.send(());
+        }
+
+        if let Some(thread) = self.print_thread.take() {
+            let _ = thread.join();
+        }
+
+        // Rustyline can't interrupt a `readline` in progress, so only join
+        // a reader that's already done. One still waiting on the terminal
+        // sees `read_stop` after its next line, and otherwise ends with the
+        // process.
+        if let Some(thread) = self.read_thread.take() {
+            let done = self.read_closed
+                || matches!(
+                    self.read_done.try_recv(),
+                    Err(oneshot::error::TryRecvError::Closed)
+                );
+
+            if done {
+                let _ = thread.join();
+            }
+        }
     }
+}
 
-    pub async fn recv_command(&mut self) -> Option<Command> {
-        self.cmd_recv.recv().await
+impl Drop for Ui {
+    fn drop(&mut self) {
+        self.stop();
     }
 }
 
-fn printer(handle: Handle, mut events: Receiver<Event>, oob: bool) {
-    while let Some(msg) = handle.block_on(events.recv()) {
-        if msg.reply_to.is_some() || oob {
+fn printer(
+    handle: Handle,
+    mut events: Receiver<Event>,
+    mut stop: oneshot::Receiver<()>,
+    oob: bool,
+    headless: bool,
+) {
+    let print = |msg: Event| {
+        if msg.reply_to.is_none() && !oob {
+            return;
+        }
+
+        if headless {
+            // No prompt to step around, so keep to one event per line.
+            eprintln!("{}", msg);
+        } else {
             eprint!("\n{}", msg);
         }
+    };
+
+    loop {
+        let msg = handle.block_on(async {
+            tokio::select! {
+                msg = events.recv() => msg,
+                _ = &mut stop => None,
+            }
+        });
+
+        match msg {
+            Some(msg) => print(msg),
+            None => break,
+        }
+    }
+
+    // Don't lose whatever was sent before stopping.
+    while let Ok(msg) = events.try_recv() {
+        print(msg);
     }
 }
 
-fn reader(handle: Handle, commands: Sender<Command>) {
-    if let Err(e) = try_reader(handle, commands) {
+fn reader(
+    handle: Handle,
+    ids: Arc<AtomicU8>,
+    commands: Sender<Command>,
+    stop: Arc<AtomicBool>,
+) {
+    if let Err(e) = try_reader(handle, ids, commands, stop) {
         eprintln!("Reader error: {}", e);
         std::process::abort();
     }
 }
 
+/// Parses a line of input into a command, returning `Ok(None)` if the line
+/// is blank.
+fn parse_line(line: &str) -> Result<Option<CommandLine>, String> {
+    let parsed = match shell_words::split(line) {
+        Ok(p) if !p.is_empty() => p,
+        Ok(_) => return Ok(None),
+        Err(_) => return Err("parse error".to_owned()),
+    };
+
+    CommandLine::from_iter_safe(parsed)
+        .map(Some)
+        .map_err(|e| e.to_string())
+}
+
 fn try_reader(
     handle: Handle,
+    ids: Arc<AtomicU8>,
     mut commands: Sender<Command>,
+    stop: Arc<AtomicBool>,
 ) -> Result<(), crate::Error> {
-    let mut command_id: u8 = 0;
     let mut rl = Editor::<()>::new();
 
-    loop {
-        let cid = command_id;
-        command_id = command_id.wrapping_add(1);
+    while !stop.load(Ordering::SeqCst) {
+        // Shared with the control socket, so ids stay unique (until they
+        // wrap around).
+        let cid = ids.fetch_add(1, Ordering::SeqCst);
 
         let prompt = format!("<{:02X}> ", cid);
         let line = match rl.readline(&prompt) {
             Ok(l) => l,
             Err(ReadlineError::Eof) | Err(ReadlineError::Interrupted) => {
-                // TODO: Exit gracefully
-                std::process::exit(0);
+                return Ok(());
             }
             Err(e) => return Err(e.into()),
         };
 
-        rl.add_history_entry(&line);
+        if stop.load(Ordering::SeqCst) {
+            break;
+        }
 
-        let parsed = match shell_words::split(&line) {
-            Ok(p) if !p.is_empty() => p,
-            Ok(_) => continue,
-            Err(_) => {
-                print!("parse error");
-                continue;
-            }
-        };
+        rl.add_history_entry(&line);
 
-        let cmd_kind = match CommandKind::from_iter_safe(parsed) {
-            Ok(c) => c,
+        let cmd_line = match parse_line(&line) {
+            Ok(Some(c)) => c,
+            Ok(None) => continue,
             Err(e) => {
                 print!("\n{}", e);
                 continue;
@@ -301,9 +887,93 @@ fn try_reader(
 
         let cmd = Command {
             id: cid,
-            kind: cmd_kind,
+            kind: cmd_line.kind,
+            contract: cmd_line.contract,
+            client: None,
         };
 
         handle.block_on(commands.send(cmd))?;
     }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn event_sink_collects_in_order() {
+        let (mut events, mut sink) = EventSink::new();
+        let cmd = Command::new(7, CommandKind::Get(GetType::FeeBase));
+
+        let added = vec![
+            Correlation::from_inputs(vec![1.into()]),
+            Correlation::from_inputs(vec![2.into(), 3.into()]),
+        ];
+
+        events.oob(EventKind::PoolAdd(added)).await;
+        events.reply(&cmd, "done").await;
+        events.oob(EventKind::PoolDrop(1)).await;
+
+        let drained = sink.drain();
+
+        assert_eq!(drained.len(), 3);
+
+        let pool_add = matches!(
+            drained[0],
+            (None, EventKind::PoolAdd(ref a)) if a.len() == 2
+        );
+        assert!(pool_add);
+
+        let reply = matches!(
+            drained[1],
+            (Some(7), EventKind::Info(ref s)) if s == "done"
+        );
+        assert!(reply);
+
+        assert!(matches!(drained[2], (None, EventKind::PoolDrop(1))));
+
+        assert!(sink.drain().is_empty());
+    }
+
+    #[test]
+    fn competing_bid_shows_margin_over_base() {
+        let bid = Bid {
+            tx: H256::zero(),
+            sponsor: Some(7.into()),
+            price: 130.into(),
+            base: 100.into(),
+        };
+
+        let shown = EventKind::CompetingBid(bid).to_string();
+        assert!(shown.contains("claiming into UTXO #7 pays"), "{}", shown);
+        assert!(shown.contains("pays 130 wei for gas"), "{}", shown);
+        assert!(
+            shown.contains("(30 wei above the 100 wei base"),
+            "{}",
+            shown
+        );
+    }
+
+    #[tokio::test]
+    async fn heartbeats_dropped_when_full() {
+        let (mut events, mut sink) = EventSink::with_capacity(1);
+
+        events.new_block(H256::zero()).await;
+        events.pending_tx(H256::zero()).await;
+        assert_eq!(events.dropped(), 1);
+
+        // Clones share the count.
+        let mut other = events.clone();
+        other.new_block(H256::zero()).await;
+        assert_eq!(events.dropped(), 2);
+
+        let drained = sink.drain();
+        assert_eq!(drained.len(), 1);
+        assert!(matches!(drained[0], (None, EventKind::NewBlock(_))));
+
+        events.pending_tx(H256::zero()).await;
+        assert_eq!(events.dropped(), 2);
+    }
 }
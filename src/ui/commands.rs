@@ -4,18 +4,288 @@
 
 use ethers::types::{Address, Signature, U256};
 
+use snafu::{ensure, OptionExt, Snafu};
+
+use std::convert::TryFrom;
+use std::path::PathBuf;
+use std::str::FromStr;
+
 use structopt::StructOpt;
 
+#[derive(Debug, Snafu)]
+#[snafu(visibility = "pub(crate)")]
+pub enum InvalidCommand {
+    /// A zero gas price can never make it into a bundle, but would still take
+    /// up a slot in the pool.
+    #[snafu(display("gasprice must be greater than zero"))]
+    ZeroGasPrice,
+}
+
+#[derive(Debug, Snafu)]
+pub enum ParseError {
+    #[snafu(display("`{}` isn't a decimal or 0x-prefixed hex number", text))]
+    InvalidNumber { text: String },
+
+    #[snafu(display("`{}` isn't a hex address", text))]
+    InvalidAddress { text: String },
+}
+
+fn strip_hex_prefix(text: &str) -> Option<&str> {
+    text.strip_prefix("0x").or_else(|| text.strip_prefix("0X"))
+}
+
+/// A `U256` written in decimal, or in hex with a `0x` prefix. `U256` itself
+/// only parses hex, with no way to tell `10` from `0x10`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Number(pub U256);
+
+impl Number {
+    /// For `parse(try_from_str)`, so fields can stay `U256`.
+    pub fn parse(text: &str) -> Result<U256, ParseError> {
+        text.parse().map(|Number(n)| n)
+    }
+}
+
+impl FromStr for Number {
+    type Err = ParseError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let parsed = match strip_hex_prefix(text) {
+            Some(hex) if !hex.is_empty() => U256::from_str(hex).ok(),
+            Some(_) => None,
+            None => U256::from_dec_str(text).ok(),
+        };
+
+        parsed.map(Number).context(InvalidNumber { text })
+    }
+}
+
+/// An `Address` in hex, with or without a `0x` prefix.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct HexAddress(pub Address);
+
+impl HexAddress {
+    /// For `parse(try_from_str)`, so fields can stay `Address`.
+    pub fn parse(text: &str) -> Result<Address, ParseError> {
+        text.parse().map(|HexAddress(a)| a)
+    }
+}
+
+impl FromStr for HexAddress {
+    type Err = ParseError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let hex = strip_hex_prefix(text).unwrap_or(text);
+
+        ensure!(hex.len() == 40, InvalidAddress { text });
+
+        Address::from_str(hex)
+            .ok()
+            .map(HexAddress)
+            .context(InvalidAddress { text })
+    }
+}
+
 #[derive(Debug, StructOpt)]
 #[structopt(setting=structopt::clap::AppSettings::NoBinaryName)]
 #[structopt(setting=structopt::clap::AppSettings::DisableVersion)]
 #[structopt(setting=structopt::clap::AppSettings::VersionlessSubcommands)]
+pub struct CommandLine {
+    /// UTXO contract the command applies to. Only required when relaying for
+    /// more than one.
+    #[structopt(
+        long = "contract",
+        global = true,
+        parse(try_from_str = HexAddress::parse)
+    )]
+    pub contract: Option<Address>,
+
+    #[structopt(subcommand)]
+    pub kind: CommandKind,
+}
+
+#[derive(Debug, StructOpt)]
 pub enum CommandKind {
     Deposit(Deposit),
     Withdraw(Withdraw),
     Transfer(Transfer),
+
+    /// Evicts the pooled transfer spending an input, whatever it pays, with
+    /// one sending the input's whole value to `--change`.
+    CancelTransfer(CancelTransfer),
     Show(Show),
     Get(GetType),
+    Stats(Stats),
+    Load(LoadType),
+    Simulate(SimulateType),
+
+    /// Reports what the best bundle would look like under other conditions.
+    #[structopt(name = "whatif")]
+    WhatIf(WhatIfType),
+
+    /// Checks the transaction pool's indexes for inconsistencies.
+    Validate,
+
+    /// Lists the last bundles broadcast, and whether they were mined.
+    History(History),
+
+    /// Follows the transactions spending an input from being pooled, through
+    /// broadcast, to being mined.
+    Trace(Trace),
+
+    /// Stops sending bundles, while still watching and updating the pools.
+    Pause,
+
+    /// Starts sending bundles again, beginning with the best one so far.
+    Resume,
+
+    /// Reports why the transaction spending an input is or isn't in the best
+    /// bundle.
+    Explain(Explain),
+
+    /// Keeps slots in each bundle free for withdrawals and transfers.
+    Reserve(Reserve),
+
+    /// Changes a setting without restarting.
+    Set(SetType),
+
+    Export(ExportType),
+
+    /// Decodes hex `transact` calldata (ex. from a block explorer) and prints
+    /// the bundle it holds.
+    Decode(Decode),
+
+    /// Empties a pool (ex. after a contract upgrade invalidates everything
+    /// pending), and forgets the best bundle.
+    Clear(ClearType),
+
+    /// Fills scratch pools, like the contract's but never broadcast from, with
+    /// random transfers and deposits, then times regenerating the best bundle
+    /// from them. Needs `--testing`.
+    #[structopt(setting = structopt::clap::AppSettings::Hidden)]
+    GenerateLoad(GenerateLoad),
+}
+
+#[derive(Debug, StructOpt)]
+pub struct GenerateLoad {
+    #[structopt(long = "transfers", default_value = "0")]
+    pub transfers: u64,
+
+    #[structopt(long = "deposits", default_value = "0")]
+    pub deposits: u64,
+
+    /// Seed for the generator, to repeat an earlier load. Defaults to the
+    /// current time.
+    #[structopt(long = "seed")]
+    pub seed: Option<u64>,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum ClearType {
+    Transfers,
+    Withdrawals,
+    Deposits,
+
+    /// Transfers, withdrawals, and deposits.
+    All,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Decode {
+    pub calldata: String,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum ExportType {
+    /// Packs the whole transaction pool into as many bundles as needed, and
+    /// prints each as hex calldata for `transact`.
+    Bundles,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Trace {
+    #[structopt(parse(try_from_str = Number::parse))]
+    pub input: U256,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct History {
+    /// Also list what each bundle claimed, transferred, and withdrew.
+    #[structopt(long = "full")]
+    pub full: bool,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Reserve {
+    #[structopt(long = "withdrawals", short = "w", default_value = "0")]
+    pub withdrawals: usize,
+
+    #[structopt(long = "transfers", short = "t", default_value = "0")]
+    pub transfers: usize,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum SetType {
+    /// Most transfers and withdrawals pooled at once. Lowering it evicts the
+    /// cheapest straight away.
+    MaxPoolSize(PoolSize),
+
+    /// Most deposits pooled at once. Lowering it evicts those with the
+    /// smallest bounties straight away.
+    MaxDepositPoolSize(PoolSize),
+}
+
+#[derive(Debug, StructOpt)]
+pub struct PoolSize {
+    pub size: usize,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Explain {
+    #[structopt(parse(try_from_str = Number::parse))]
+    pub input: U256,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum SimulateType {
+    /// Reports whether claiming a deposit, alongside the best bundle, would be
+    /// profitable at the current fee base.
+    Claim(SimulateClaim),
+}
+
+#[derive(Debug, StructOpt)]
+pub enum WhatIfType {
+    /// Picks a bundle at each fee base from `low` to `high` (in wei), and
+    /// reports its estimated price and the slots it fills.
+    Base(WhatIfBase),
+}
+
+#[derive(Debug, StructOpt)]
+pub struct WhatIfBase {
+    #[structopt(parse(try_from_str = Number::parse))]
+    pub low: U256,
+    #[structopt(parse(try_from_str = Number::parse))]
+    pub high: U256,
+    #[structopt(parse(try_from_str = Number::parse))]
+    pub step: U256,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct SimulateClaim {
+    #[structopt(parse(try_from_str = Number::parse))]
+    pub id: U256,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum LoadType {
+    Deposits(LoadDeposits),
+}
+
+/// Reads deposits from a file with one `id amount bounty owner` per line.
+#[derive(Debug, StructOpt)]
+pub struct LoadDeposits {
+    #[structopt(parse(from_os_str))]
+    pub path: PathBuf,
 }
 
 #[derive(Debug, StructOpt)]
@@ -23,18 +293,74 @@ pub enum GetType {
     FeeBase,
     UtxoCount,
     Utxo(GetUtxo),
+
+    /// A pooled deposit, and what claiming it earns at the fee base.
+    Deposit(GetDeposit),
+
+    MaxPoolSize,
+    MaxDepositPoolSize,
 }
 
 #[derive(Debug, StructOpt)]
 pub struct GetUtxo {
+    #[structopt(parse(try_from_str = Number::parse))]
+    pub id: U256,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct GetDeposit {
+    #[structopt(parse(try_from_str = Number::parse))]
     pub id: U256,
 }
 
 #[derive(Debug, StructOpt)]
 pub enum PoolType {
-    Deposits,
-    Transfers,
-    Withdrawals,
+    Deposits(Limit),
+    Transfers(GasFilter),
+    Withdrawals(GasFilter),
+
+    /// Transfers and withdrawals together, highest gas price first.
+    Transactions(GasFilter),
+
+    /// The Dropsafe contract escrowing deposits until they're claimed, and
+    /// how much of its balance the deposit pool accounts for.
+    Dropsafe,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Limit {
+    /// Show at most this many entries, or everything if zero.
+    #[structopt(long = "limit", short = "n", default_value = "20")]
+    limit: usize,
+}
+
+impl Limit {
+    pub fn max_entries(&self) -> usize {
+        if self.limit == 0 {
+            usize::MAX
+        } else {
+            self.limit
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub struct GasFilter {
+    #[structopt(flatten)]
+    pub limit: Limit,
+
+    /// Hide transactions paying less than this gas price.
+    #[structopt(long = "min-gas", parse(try_from_str = Number::parse))]
+    pub min_gas: Option<U256>,
+}
+
+impl GasFilter {
+    pub fn admits(&self, gasprice: &U256) -> bool {
+        match self.min_gas {
+            Some(ref min) => gasprice >= min,
+            None => true,
+        }
+    }
 }
 
 #[derive(Debug, StructOpt)]
@@ -43,23 +369,41 @@ pub struct Show {
     pub what: PoolType,
 }
 
+#[derive(Debug, StructOpt)]
+pub struct Stats {
+    #[structopt(long = "buckets", short = "b", default_value = "8")]
+    pub buckets: usize,
+}
+
 #[derive(Debug, StructOpt)]
 pub struct Deposit {}
 
 #[derive(Clone, Debug, StructOpt)]
 pub struct Withdraw {
-    #[structopt(long = "input0", short = "0")]
+    #[structopt(
+        long = "input0",
+        short = "0",
+        parse(try_from_str = Number::parse)
+    )]
     input0: U256,
 
-    #[structopt(long = "gasprice", short = "-g")]
+    #[structopt(
+        long = "gasprice",
+        short = "-g",
+        parse(try_from_str = Number::parse)
+    )]
     gasprice: U256,
 }
 
-impl From<Withdraw> for crate::contracts::Withdrawal {
-    fn from(cmd: Withdraw) -> Self {
+impl TryFrom<Withdraw> for crate::contracts::Withdrawal {
+    type Error = InvalidCommand;
+
+    fn try_from(cmd: Withdraw) -> Result<Self, Self::Error> {
+        ensure!(!cmd.gasprice.is_zero(), ZeroGasPrice);
+
         // TODO: Should generate a valid signature
 
-        Self {
+        Ok(Self {
             input: cmd.input0,
             gasprice: cmd.gasprice,
             signature: Signature {
@@ -67,36 +411,64 @@ impl From<Withdraw> for crate::contracts::Withdrawal {
                 r: Default::default(),
                 s: Default::default(),
             },
-        }
+        })
     }
 }
 
 #[derive(Debug, Clone, StructOpt)]
 pub struct Transfer {
-    #[structopt(long = "input0", short = "0")]
+    #[structopt(
+        long = "input0",
+        short = "0",
+        parse(try_from_str = Number::parse)
+    )]
     input0: Option<U256>,
 
-    #[structopt(long = "input1", short = "1")]
+    #[structopt(
+        long = "input1",
+        short = "1",
+        parse(try_from_str = Number::parse)
+    )]
     input1: Option<U256>,
 
-    #[structopt(long = "destination", short = "-d")]
+    #[structopt(
+        long = "destination",
+        short = "-d",
+        parse(try_from_str = HexAddress::parse)
+    )]
     destination: Address,
 
-    #[structopt(long = "change", short = "-c")]
+    #[structopt(
+        long = "change",
+        short = "-c",
+        parse(try_from_str = HexAddress::parse)
+    )]
     change: Address,
 
-    #[structopt(long = "amount", short = "-a")]
+    #[structopt(
+        long = "amount",
+        short = "-a",
+        parse(try_from_str = Number::parse)
+    )]
     amount: U256,
 
-    #[structopt(long = "gasprice", short = "-g")]
+    #[structopt(
+        long = "gasprice",
+        short = "-g",
+        parse(try_from_str = Number::parse)
+    )]
     gasprice: U256,
 }
 
-impl From<Transfer> for crate::contracts::Transfer {
-    fn from(cmd: Transfer) -> Self {
+impl TryFrom<Transfer> for crate::contracts::Transfer {
+    type Error = InvalidCommand;
+
+    fn try_from(cmd: Transfer) -> Result<Self, Self::Error> {
+        ensure!(!cmd.gasprice.is_zero(), ZeroGasPrice);
+
         // TODO: Should generate a real signature.
 
-        Self {
+        Ok(Self {
             amount: cmd.amount,
             change: cmd.change,
             destination: cmd.destination,
@@ -108,6 +480,166 @@ impl From<Transfer> for crate::contracts::Transfer {
                 r: Default::default(),
                 s: Default::default(),
             },
+        })
+    }
+}
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct CancelTransfer {
+    #[structopt(
+        long = "input0",
+        short = "0",
+        parse(try_from_str = Number::parse)
+    )]
+    pub input0: U256,
+
+    #[structopt(
+        long = "gasprice",
+        short = "-g",
+        parse(try_from_str = Number::parse)
+    )]
+    pub gasprice: U256,
+
+    /// Where the input's value goes. Defaults to the address of the
+    /// `--sign-with` key.
+    #[structopt(
+        long = "change",
+        short = "-c",
+        parse(try_from_str = HexAddress::parse)
+    )]
+    pub change: Option<Address>,
+}
+
+impl CancelTransfer {
+    /// The transfer spending `input0` into `change` and nothing else, which
+    /// conflicts with (and so cancels) any other transfer spending it.
+    pub fn into_transfer(
+        self,
+        change: Address,
+    ) -> Result<crate::contracts::Transfer, InvalidCommand> {
+        ensure!(!self.gasprice.is_zero(), ZeroGasPrice);
+
+        Ok(crate::contracts::Transfer {
+            amount: U256::zero(),
+            change,
+            destination: change,
+            gasprice: self.gasprice,
+            input0: self.input0,
+            input1: U256::zero(),
+            signature: Signature {
+                v: 0,
+                r: Default::default(),
+                s: Default::default(),
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> CommandKind {
+        CommandLine::from_iter_safe(line.split_whitespace())
+            .unwrap()
+            .kind
+    }
+
+    #[test]
+    fn number_decimal_and_hex() {
+        assert_eq!(Number::parse("10").unwrap(), 10.into());
+        assert_eq!(Number::parse("0x10").unwrap(), 16.into());
+        assert_eq!(Number::parse("0XfF").unwrap(), 255.into());
+        assert_eq!(Number::parse("0").unwrap(), U256::zero());
+    }
+
+    #[test]
+    fn number_invalid() {
+        for text in &["", "0x", "ten", "1f", "0xg", "-1", "1.5"] {
+            match Number::parse(text) {
+                Err(ParseError::InvalidNumber { .. }) => (),
+                other => panic!("{:?} parsed as {:?}", text, other),
+            }
+        }
+
+        let too_big = format!("0x1{}", "0".repeat(64));
+        assert!(Number::parse(&too_big).is_err());
+    }
+
+    #[test]
+    fn hex_address_with_and_without_prefix() {
+        let expected = Address::from([0x1f; 20]);
+        let bare = "1f1f1f1f1f1f1f1f1f1f1f1f1f1f1f1f1f1f1f1f";
+
+        assert_eq!(HexAddress::parse(bare).unwrap(), expected);
+        assert_eq!(
+            HexAddress::parse(&format!("0x{}", bare)).unwrap(),
+            expected
+        );
+
+        assert!(HexAddress::parse("0x1f").is_err());
+        assert!(HexAddress::parse(&format!("{}zz", &bare[2..])).is_err());
+    }
+
+    #[test]
+    fn command_fields_accept_hex() {
+        let wdw = match parse("withdraw --input0 0x1f --gasprice 31") {
+            CommandKind::Withdraw(wdw) => wdw,
+            other => panic!("unexpected command {:?}", other),
+        };
+
+        let withdrawal = crate::contracts::Withdrawal::try_from(wdw).unwrap();
+        assert_eq!(withdrawal.input, 31.into());
+        assert_eq!(withdrawal.gasprice, 31.into());
+    }
+
+    #[test]
+    fn transfer_zero_gasprice_refused() {
+        let line = concat!(
+            "transfer --gasprice 0 --amount 1 ",
+            "--destination 0101010101010101010101010101010101010101 ",
+            "--change 0202020202020202020202020202020202020202",
+        );
+
+        let xfr = match parse(line) {
+            CommandKind::Transfer(xfr) => xfr,
+            other => panic!("unexpected command {:?}", other),
+        };
+
+        match crate::contracts::Transfer::try_from(xfr) {
+            Err(InvalidCommand::ZeroGasPrice) => (),
+            other => panic!("unexpected result {:?}", other),
         }
     }
+
+    #[test]
+    fn withdraw_nonzero_gasprice_accepted() {
+        let wdw = match parse("withdraw --input0 1 --gasprice 1") {
+            CommandKind::Withdraw(wdw) => wdw,
+            other => panic!("unexpected command {:?}", other),
+        };
+
+        let withdrawal = crate::contracts::Withdrawal::try_from(wdw).unwrap();
+        assert_eq!(withdrawal.gasprice, U256::one());
+    }
+
+    #[test]
+    fn cancel_transfer_spends_only_input0() {
+        let cancel = match parse("cancel-transfer --input0 7 --gasprice 3") {
+            CommandKind::CancelTransfer(c) => c,
+            other => panic!("unexpected command {:?}", other),
+        };
+
+        assert_eq!(cancel.change, None);
+
+        let owner = Address::from([0x11; 20]);
+        let xfr = cancel.into_transfer(owner).unwrap();
+
+        assert_eq!(xfr.input0, 7.into());
+        assert!(xfr.input1.is_zero());
+        assert!(xfr.amount.is_zero());
+        assert_eq!(xfr.change, owner);
+        assert_eq!(xfr.destination, owner);
+        assert_eq!(xfr.gasprice, 3.into());
+    }
 }
@@ -0,0 +1,162 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::{parse_line, Command};
+
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use tokio::runtime::Handle;
+use tokio::sync::mpsc::{self, Sender};
+
+/// Number of replies buffered for a client before commands replying to it
+/// have to wait.
+const CLIENT_BUFFER: usize = 16;
+
+/// Accepts clients on `listener`, each sending commands one per line. Every
+/// client gets its own thread.
+pub(super) fn serve(
+    handle: Handle,
+    listener: UnixListener,
+    ids: Arc<AtomicU8>,
+    commands: Sender<Command>,
+) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("\nControl socket error: {}", e);
+                continue;
+            }
+        };
+
+        let handle = handle.clone();
+        let ids = ids.clone();
+        let commands = commands.clone();
+
+        let spawned = thread::Builder::new()
+            .name("ui-control".to_owned())
+            .spawn(move || client(handle, stream, ids, commands));
+
+        if let Err(e) = spawned {
+            eprintln!("\nControl socket error: {}", e);
+        }
+    }
+}
+
+/// Reads commands from `stream`, writing back every reply to them. Replies
+/// keep flowing after the client stops sending, until every command it sent
+/// has finished.
+fn client(
+    handle: Handle,
+    stream: UnixStream,
+    ids: Arc<AtomicU8>,
+    mut commands: Sender<Command>,
+) {
+    let mut write = match stream.try_clone() {
+        Ok(w) => w,
+        Err(e) => {
+            eprintln!("\nControl socket error: {}", e);
+            return;
+        }
+    };
+
+    let (reply_send, mut reply_recv) = mpsc::channel::<String>(CLIENT_BUFFER);
+
+    let write_handle = handle.clone();
+    let writer = thread::spawn(move || {
+        while let Some(line) = write_handle.block_on(reply_recv.recv()) {
+            if write.write_all(line.as_bytes()).is_err() {
+                break;
+            }
+        }
+    });
+
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(l) => l,
+            Err(_) => break,
+        };
+
+        let mut replies = reply_send.clone();
+
+        let cmd_line = match parse_line(&line) {
+            Ok(Some(k)) => k,
+            Ok(None) => continue,
+            Err(e) => {
+                let _ = handle.block_on(replies.send(format!("{}\n", e)));
+                continue;
+            }
+        };
+
+        let cmd = Command {
+            id: ids.fetch_add(1, Ordering::SeqCst),
+            kind: cmd_line.kind,
+            contract: cmd_line.contract,
+            client: Some(replies),
+        };
+
+        if handle.block_on(commands.send(cmd)).is_err() {
+            break;
+        }
+    }
+
+    drop(reply_send);
+    let _ = writer.join();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::ui::{CommandKind, EventKind, EventSink, GetType};
+
+    use std::net::Shutdown;
+
+    #[test]
+    fn client_round_trip() {
+        let runtime = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let handle = runtime.handle().clone();
+
+        let (mut local, remote) = UnixStream::pair().unwrap();
+        let (cmd_send, mut cmd_recv) = mpsc::channel(1);
+
+        let ids = Arc::new(AtomicU8::new(0x2A));
+        let client_handle = handle.clone();
+        let session =
+            thread::spawn(move || client(client_handle, remote, ids, cmd_send));
+
+        let line = concat!(
+            "\nget --contract 0101010101010101010101010101010101010101",
+            " fee-base\n",
+        );
+        local.write_all(line.as_bytes()).unwrap();
+
+        let cmd = handle.block_on(cmd_recv.recv()).unwrap();
+        assert_eq!(cmd.id(), 0x2A);
+        assert_eq!(cmd.contract(), Some([0x01; 20].into()));
+        match cmd.kind() {
+            CommandKind::Get(GetType::FeeBase) => (),
+            other => panic!("unexpected command {:?}", other),
+        }
+
+        let (mut events, _sink) = EventSink::new();
+        handle.block_on(events.reply(&cmd, EventKind::Info("hello".into())));
+        drop(cmd);
+        local.shutdown(Shutdown::Write).unwrap();
+
+        let mut lines = BufReader::new(local).lines();
+        let reply = lines.next().unwrap().unwrap();
+        assert_eq!(reply, "[2A] hello");
+
+        session.join().unwrap();
+        assert!(lines.next().is_none());
+    }
+}
@@ -0,0 +1,103 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::{parse_line, Command};
+
+use std::path::Path;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+
+use tokio::sync::mpsc::{self, Sender};
+
+/// Number of replies buffered for a command before it has to wait for them
+/// to be printed.
+const REPLY_BUFFER: usize = 16;
+
+/// Sends the commands in `script`, one per line, waiting for each to finish
+/// before sending the next. Replies and errors are printed prefixed with the
+/// line they came from, and a bad line doesn't stop the rest. Blank lines and
+/// lines starting with `#` are skipped.
+pub(super) async fn run(
+    path: &Path,
+    script: &str,
+    ids: Arc<AtomicU8>,
+    mut commands: Sender<Command>,
+) {
+    for (index, line) in script.lines().enumerate() {
+        let at = format!("{}:{}", path.display(), index + 1);
+
+        if line.trim_start().starts_with('#') {
+            continue;
+        }
+
+        let cmd_line = match parse_line(line) {
+            Ok(Some(c)) => c,
+            Ok(None) => continue,
+            Err(e) => {
+                eprintln!("{}: {}", at, e);
+                continue;
+            }
+        };
+
+        let (reply_send, mut reply_recv) = mpsc::channel(REPLY_BUFFER);
+
+        let cmd = Command {
+            id: ids.fetch_add(1, Ordering::SeqCst),
+            kind: cmd_line.kind,
+            contract: cmd_line.contract,
+            client: Some(reply_send),
+        };
+
+        if commands.send(cmd).await.is_err() {
+            eprintln!("{}: no longer accepting commands", at);
+            return;
+        }
+
+        // Closes once the command, and with it the sender, is dropped.
+        while let Some(reply) = reply_recv.recv().await {
+            eprint!("{}: {}", at, reply);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::ui::{CommandKind, GetType};
+
+    #[tokio::test]
+    async fn run_in_order_past_bad_lines() {
+        let (cmd_send, mut cmd_recv) = mpsc::channel(1);
+        let ids = Arc::new(AtomicU8::new(0x10));
+
+        let script = "get fee-base\n# comment\nbogus\n\nget max-pool-size\n";
+        let session = tokio::spawn(async move {
+            run(Path::new("test"), script, ids, cmd_send).await
+        });
+
+        let first = cmd_recv.recv().await.unwrap();
+        assert_eq!(first.id(), 0x10);
+        match first.kind() {
+            CommandKind::Get(GetType::FeeBase) => (),
+            other => panic!("unexpected command {:?}", other),
+        }
+
+        // Nothing more is sent until the first command finishes.
+        tokio::task::yield_now().await;
+        assert!(cmd_recv.try_recv().is_err());
+        drop(first);
+
+        let second = cmd_recv.recv().await.unwrap();
+        assert_eq!(second.id(), 0x11);
+        match second.kind() {
+            CommandKind::Get(GetType::MaxPoolSize) => (),
+            other => panic!("unexpected command {:?}", other),
+        }
+        drop(second);
+
+        session.await.unwrap();
+        assert!(cmd_recv.recv().await.is_none());
+    }
+}
@@ -0,0 +1,212 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use ethers::types::U256;
+
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// When a watcher last saw something (ex. a block or pending transaction).
+#[derive(Debug)]
+pub struct Activity(Mutex<Instant>);
+
+impl Default for Activity {
+    fn default() -> Self {
+        Self(Mutex::new(Instant::now()))
+    }
+}
+
+impl Activity {
+    pub fn touch(&self) {
+        *self.0.lock().unwrap() = Instant::now();
+    }
+
+    pub fn idle(&self) -> Duration {
+        self.0.lock().unwrap().elapsed()
+    }
+
+    /// Completes once `window` passes without a call to `touch`.
+    pub async fn stalled(&self, window: Duration) {
+        loop {
+            let idle = self.idle();
+
+            if idle >= window {
+                return;
+            }
+
+            tokio::time::delay_for(window - idle).await;
+        }
+    }
+}
+
+/// How far behind the chain or the node's pool a watcher has run.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct LagStats {
+    pub count: u32,
+    pub last: Duration,
+    pub total: Duration,
+    pub max: Duration,
+}
+
+impl LagStats {
+    pub fn mean(&self) -> Duration {
+        if self.count == 0 {
+            Duration::default()
+        } else {
+            self.total / self.count
+        }
+    }
+}
+
+impl fmt::Display for LagStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} observation(s), {:?} last, {:?} on average and {:?} at most",
+            self.count,
+            self.last,
+            self.mean(),
+            self.max
+        )
+    }
+}
+
+/// Gauge of the time between something happening (ex. a block being mined)
+/// and a watcher processing it.
+#[derive(Debug, Default)]
+pub struct Lag {
+    stats: Mutex<LagStats>,
+
+    /// Timestamp of the newest block given to `record_block`.
+    newest_block: Mutex<Option<U256>>,
+}
+
+impl Lag {
+    pub fn record(&self, lag: Duration) {
+        let mut stats = self.stats.lock().unwrap();
+        stats.count = stats.count.saturating_add(1);
+        stats.last = lag;
+        stats.total += lag;
+        stats.max = stats.max.max(lag);
+    }
+
+    /// Records the lag behind a block mined at `timestamp` (in seconds since
+    /// the epoch), returning it.
+    pub fn record_block(&self, timestamp: U256) -> Duration {
+        let lag = block_lag(timestamp, SystemTime::now());
+        self.record(lag);
+
+        let mut newest = self.newest_block.lock().unwrap();
+        *newest = Some(newest.map_or(timestamp, |n| n.max(timestamp)));
+
+        lag
+    }
+
+    pub fn stats(&self) -> LagStats {
+        *self.stats.lock().unwrap()
+    }
+
+    /// How long ago the newest block given to `record_block` was mined, or
+    /// `None` before the first.
+    pub fn block_age(&self) -> Option<Duration> {
+        let newest = (*self.newest_block.lock().unwrap())?;
+        Some(block_lag(newest, SystemTime::now()))
+    }
+}
+
+/// Time from a block's `timestamp` until `now`. Blocks from the future (ex.
+/// from clock skew) count as no lag at all.
+fn block_lag(timestamp: U256, now: SystemTime) -> Duration {
+    let now = match now.duration_since(UNIX_EPOCH) {
+        Ok(n) => n,
+        Err(_) => return Duration::default(),
+    };
+
+    if timestamp > U256::from(u64::max_value()) {
+        return Duration::default();
+    }
+
+    let mined = Duration::from_secs(timestamp.as_u64());
+    now.checked_sub(mined).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lag_stats() {
+        let lag = Lag::default();
+
+        lag.record(Duration::from_millis(30));
+        lag.record(Duration::from_millis(10));
+
+        let stats = lag.stats();
+        assert_eq!(stats.count, 2);
+        assert_eq!(stats.last, Duration::from_millis(10));
+        assert_eq!(stats.mean(), Duration::from_millis(20));
+        assert_eq!(stats.max, Duration::from_millis(30));
+    }
+
+    #[test]
+    fn block_age_from_newest() {
+        let lag = Lag::default();
+        assert_eq!(lag.block_age(), None);
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let recent = now.as_secs() - 5;
+
+        lag.record_block(recent.into());
+        lag.record_block((recent - 100).into());
+
+        let age = lag.block_age().unwrap();
+        assert!(age >= Duration::from_secs(5));
+        assert!(age < Duration::from_secs(100));
+    }
+
+    #[test]
+    fn block_lag_from_timestamp() {
+        let now = UNIX_EPOCH + Duration::from_millis(1_500_500);
+
+        let lag = block_lag(1_490.into(), now);
+        assert_eq!(lag, Duration::from_millis(10_500));
+    }
+
+    #[test]
+    fn block_lag_future_block() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_000);
+
+        assert_eq!(block_lag(1_005.into(), now), Duration::default());
+        assert_eq!(block_lag(U256::max_value(), now), Duration::default());
+    }
+
+    #[tokio::test]
+    async fn stalled_after_window() {
+        let activity = Activity::default();
+        let window = Duration::from_millis(20);
+
+        activity.stalled(window).await;
+
+        assert!(activity.idle() >= window);
+    }
+
+    #[tokio::test]
+    async fn touch_postpones_stall() {
+        let activity = Activity::default();
+        let window = Duration::from_millis(50);
+
+        let touches = async {
+            for _ in 0..4 {
+                tokio::time::delay_for(Duration::from_millis(20)).await;
+                activity.touch();
+            }
+        };
+
+        tokio::select! {
+            _ = activity.stalled(window) => panic!("stalled while active"),
+            _ = touches => (),
+        }
+    }
+}